@@ -3,19 +3,182 @@
 //! The `NodeBuilder` and `TreeBuilder` types enable building tree structures in a composable way.
 //!
 
-use std::{collections::HashMap, hash::Hasher as _, marker::PhantomData};
+use std::{collections::HashMap, convert::Infallible, hash::Hasher as _, marker::PhantomData};
 
 use tracing::{debug, debug_span};
 use xxhash_rust::xxh64::Xxh64;
 
 use crate::{
-    id::UniqueGenerator,
-    node::{arc, TreeNode},
-    NodeDepth, NodeIndex, NodePosition, Tree, TreeNodeRef,
+    compare::subtree_eq,
+    hash::subtree_size,
+    id::{SeedableGenerator, UniqueGenerator},
+    index::{BTreeIndex, TreeIndex as _},
+    node::{arc, arena, TreeNode},
+    IndexedTree, NodeDepth, NodeIndex, NodePosition, Tree, TreeNodeRef,
 };
 
+mod assembler;
+mod channel;
+
+pub use assembler::{BuildCommand, BuildProgress, TreeAssembler};
+pub use channel::ChannelBuilder;
+
 type DefaultNodeRef<T> = crate::noderef::arc::NodeRef<T>;
 type DefaultNode<Data, IdGen> = arc::Node<Data, <IdGen as UniqueGenerator>::Output>;
+type ArenaNode<Data, IdGen> = arena::Node<Data, <IdGen as UniqueGenerator>::Output>;
+
+/// [`TreeBuilder`] allocating nodes from an [`Arena`](crate::noderef::arena::Arena), so nodes are
+/// stored contiguously rather than in one allocation each. The arena is handed to the built tree.
+/// Combined with [`TreeBuilder::with_capacity`], the whole tree is allocated in a single chunk.
+pub type ArenaTreeBuilder<D, E = Infallible, G = crate::IdGenerator> =
+    TreeBuilder<D, E, G, ArenaNode<D, G>, crate::noderef::arena::NodeRef<ArenaNode<D, G>>>;
+
+/// Upper bound of depth index entries reserved from a capacity hint
+const MAX_DEPTH_CAPACITY: usize = 256;
+
+/// Errors raised when a tree being built violates the constraints configured on the [`TreeBuilder`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// A node was added below the maximum allowed depth
+    MaxDepthExceeded { depth: NodeDepth, max: NodeDepth },
+
+    /// A node was given more than the maximum allowed number of children
+    MaxChildrenExceeded { position: NodePosition, max: usize },
+
+    /// A node was left without first being entered
+    UnbalancedLeave,
+
+    /// A second root node was entered after the first root was left
+    MultipleRoots,
+
+    /// The build finished while nodes were still open
+    UnclosedNodes { open: usize },
+
+    /// A node in a depth stream skipped past the depth of a child of the previous node
+    InvalidDepth { depth: NodeDepth, max: NodeDepth },
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::MaxDepthExceeded { depth, max } => {
+                write!(f, "node depth {depth} exceeds maximum depth {max}")
+            }
+            BuildError::MaxChildrenExceeded { position, max } => {
+                write!(
+                    f,
+                    "node at depth {} index {} exceeds maximum of {max} children",
+                    position.depth, position.index
+                )
+            }
+            BuildError::UnbalancedLeave => write!(f, "node left without being entered"),
+            BuildError::MultipleRoots => write!(f, "tree already has a root node"),
+            BuildError::UnclosedNodes { open } => {
+                write!(f, "build finished with {open} unclosed nodes")
+            }
+            BuildError::InvalidDepth { depth, max } => {
+                write!(f, "invalid node depth {depth}, maximum allowed depth {max}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Callback invoked with each node as it is finalized by a [`NodeBuilder`]
+pub struct NodeHook<R>(Box<dyn FnMut(&mut R)>);
+
+impl<R> std::fmt::Debug for NodeHook<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("NodeHook")
+    }
+}
+
+/// Nodes finalized by the builder, used to index the tree without traversing it again
+#[derive(Debug)]
+struct BuiltNodes<R> {
+    nodes: Vec<R>,
+    leaves: Vec<R>,
+}
+
+/// Finalized subtrees keyed by subtree hash, shared by a deduplicating builder
+struct SharedSubtrees<R> {
+    // Distinct subtrees with each hash, as different subtrees may have colliding hashes
+    subtrees: HashMap<u64, Vec<R>>,
+
+    // Node by node comparison of subtrees with equal hashes
+    eq: fn(&R, &R) -> bool,
+}
+
+impl<R> std::fmt::Debug for SharedSubtrees<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedSubtrees")
+            .field("hashes", &self.subtrees.len())
+            .finish()
+    }
+}
+
+/// Structural limits enforced while building a tree
+struct BuildLimits<E> {
+    max_depth: Option<NodeDepth>,
+    max_children: Option<usize>,
+
+    // Conversion of a constraint violation into the builder error type
+    into_error: fn(BuildError) -> E,
+}
+
+impl<E> Clone for BuildLimits<E> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<E> Copy for BuildLimits<E> {}
+
+impl<E> std::fmt::Debug for BuildLimits<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BuildLimits")
+            .field("max_depth", &self.max_depth)
+            .field("max_children", &self.max_children)
+            .finish()
+    }
+}
+
+impl<E> BuildLimits<E> {
+    fn new() -> Self
+    where
+        E: From<BuildError>,
+    {
+        Self {
+            max_depth: None,
+            max_children: None,
+            into_error: E::from,
+        }
+    }
+
+    /// Check a new child at the given position can be added
+    fn check(&self, position: &NodePosition, parent: &NodePosition) -> Result<(), E> {
+        if let Some(max) = self.max_depth {
+            if position.depth > max {
+                return Err((self.into_error)(BuildError::MaxDepthExceeded {
+                    depth: position.depth,
+                    max,
+                }));
+            }
+        }
+
+        if let Some(max) = self.max_children {
+            if position.child_index >= max {
+                return Err((self.into_error)(BuildError::MaxChildrenExceeded {
+                    position: *parent,
+                    max,
+                }));
+            }
+        }
+
+        Ok(())
+    }
+}
 
 /// A builder for constructing children from a parent node.
 ///
@@ -26,13 +189,13 @@ type DefaultNode<Data, IdGen> = arc::Node<Data, <IdGen as UniqueGenerator>::Outp
 pub struct NodeBuilder<
     'a,
     D,
-    E,
+    E = Infallible,
     G = crate::IdGenerator,
     N = DefaultNode<D, G>,
     R = DefaultNodeRef<N>,
 > where
     G: UniqueGenerator,
-    D: std::fmt::Display + 'static,
+    D: 'static,
     N: TreeNode<Id = G::Output, NodeRef = R>,
     R: TreeNodeRef<Inner = N>,
 {
@@ -40,6 +203,8 @@ pub struct NodeBuilder<
     node_ref: &'a mut R,
     // UniqueGenerator handle
     idgen: &'a mut G,
+    // Storage child nodes are allocated from
+    allocator: &'a R::Allocator,
 
     depth_index: &'a mut HashMap<NodeDepth, NodeIndex>,
 
@@ -47,6 +212,14 @@ pub struct NodeBuilder<
 
     hasher: Xxh64,
 
+    limits: Option<BuildLimits<E>>,
+
+    on_node: Option<&'a mut NodeHook<R>>,
+
+    built: Option<&'a mut BuiltNodes<R>>,
+
+    shared: Option<&'a mut SharedSubtrees<R>>,
+
     _phantom: (
         PhantomData<D>,
         PhantomData<E>,
@@ -57,7 +230,6 @@ pub struct NodeBuilder<
 
 impl<'a, D, E, G, N, R> Drop for NodeBuilder<'a, D, E, G, N, R>
 where
-    D: std::fmt::Display,
     G: UniqueGenerator,
     N: TreeNode<Id = G::Output, NodeRef = R>,
     R: TreeNodeRef<Inner = N>,
@@ -69,12 +241,43 @@ where
         let subtree_hash = self.hasher.finish();
         debug!("Drop {} hash finish 0x{:X}", node.id(), subtree_hash);
         node.set_subtree_hash(subtree_hash);
+        let size = subtree_size(&*node);
+        node.set_subtree_size(size);
+        let is_leaf = node.num_children() == 0;
+        drop(node);
+
+        if let Some(shared) = &mut self.shared {
+            let eq = shared.eq;
+            let candidates = shared.subtrees.entry(subtree_hash).or_default();
+            if let Some(existing) = candidates
+                .iter()
+                .find(|existing| eq(existing, self.node_ref))
+            {
+                // An identical subtree has already been built, so replace this node with it.
+                // The existing subtree has already been recorded and passed to the hook.
+                debug!("Sharing existing subtree with hash 0x{subtree_hash:X}");
+                *self.node_ref = existing.clone();
+                return;
+            }
+            candidates.push(self.node_ref.clone());
+        }
+
+        if let Some(built) = &mut self.built {
+            built.nodes.push(self.node_ref.clone());
+            if is_leaf {
+                built.leaves.push(self.node_ref.clone());
+            }
+        }
+
+        // The node is finalized, notify the hook
+        if let Some(hook) = &mut self.on_node {
+            (hook.0)(self.node_ref);
+        }
     }
 }
 
 impl<'a, D, E, G, N, R> NodeBuilder<'a, D, E, G, N, R>
 where
-    D: std::fmt::Display,
     G: UniqueGenerator,
     N: TreeNode<Id = G::Output, NodeRef = R>,
     R: TreeNodeRef<Inner = N>,
@@ -85,18 +288,25 @@ where
     ///
     /// * `node`: The parent node to build children for.
     /// * `idgen`: The ID generator to use for child nodes.
+    /// * `allocator`: The storage to allocate child nodes from.
     pub fn new(
         node_ref: &'a mut R,
         idgen: &'a mut G,
+        allocator: &'a R::Allocator,
         position: NodePosition,
         depth_index: &'a mut HashMap<NodeDepth, NodeIndex>,
     ) -> Self {
         Self {
             node_ref,
             idgen,
+            allocator,
             position,
             depth_index,
             hasher: Xxh64::new(0),
+            limits: None,
+            on_node: None,
+            built: None,
+            shared: None,
             _phantom: (PhantomData, PhantomData, PhantomData, PhantomData),
         }
     }
@@ -114,9 +324,6 @@ where
         // Get the current number of children of this node to determine the node index
         let child_index = self.node_ref.node().num_children();
 
-        // Generate a new ID for this child
-        let id = self.idgen.generate();
-
         let depth_index = self
             .depth_index
             .entry(self.position().depth() + 1)
@@ -128,19 +335,31 @@ where
             child_index,
         };
 
+        if let Some(limits) = &self.limits {
+            limits.check(&position, &self.position)?;
+        }
+
+        // Generate a new ID for this child
+        let id = self.idgen.generate();
+
         *depth_index += 1;
 
         // Create a new node for this child
         let node = N::new(id, data, None)
             .with_parent(self.node_ref.clone())
             .with_position(position);
-        let mut child_node_ref = R::new(node);
+        let mut child_node_ref = R::new_in(node, self.allocator);
         let mut node_builder = NodeBuilder::<D, E, G, N, R>::new(
             &mut child_node_ref,
             self.idgen,
+            self.allocator,
             position,
             self.depth_index,
         );
+        node_builder.limits = self.limits;
+        node_builder.on_node = self.on_node.as_deref_mut();
+        node_builder.built = self.built.as_deref_mut();
+        node_builder.shared = self.shared.as_deref_mut();
 
         // Call the supplied closure with the NodeBuilder to add this node's children
         f(&mut node_builder)?;
@@ -160,12 +379,12 @@ where
         Ok(())
     }
 
-    pub fn node<'b>(&'b mut self) -> &'b R {
-        &self.node_ref
+    pub fn node(&mut self) -> &R {
+        self.node_ref
     }
 
-    pub fn node_mut<'b>(&'b mut self) -> &'b mut R {
-        &mut self.node_ref
+    pub fn node_mut(&mut self) -> &mut R {
+        self.node_ref
     }
 
     pub fn position(&self) -> &NodePosition {
@@ -173,6 +392,30 @@ where
     }
 }
 
+impl<D, G, N, R> NodeBuilder<'_, D, Infallible, G, N, R>
+where
+    G: UniqueGenerator,
+    N: TreeNode<Id = G::Output, NodeRef = R>,
+    R: TreeNodeRef<Inner = N>,
+{
+    /// Adds a child to the current node, for infallible builders where the closure
+    /// does not need to return a `Result`.
+    ///
+    /// # Arguments
+    ///
+    /// * `data`: The data to associate with the child node.
+    /// * `f`: A closure that takes the child builder and adds its own children.
+    pub fn child_ok<F>(&mut self, data: N::Data, f: F)
+    where
+        F: FnOnce(&mut NodeBuilder<'_, D, Infallible, G, N, R>),
+    {
+        let Ok(()) = self.child(data, |builder| {
+            f(builder);
+            Ok(())
+        });
+    }
+}
+
 /// A builder for constructing trees.
 ///
 /// The `TreeBuilder` type provides methods for adding nodes and children to the tree structure.
@@ -196,22 +439,44 @@ where
 /// let done = root_builder.unwrap().done();
 /// ```
 #[derive(Debug)]
-pub struct TreeBuilder<D, E, G = crate::IdGenerator, N = DefaultNode<D, G>, R = DefaultNodeRef<N>>
-where
+pub struct TreeBuilder<
+    D,
+    E = Infallible,
+    G = crate::IdGenerator,
+    N = DefaultNode<D, G>,
+    R = DefaultNodeRef<N>,
+> where
     G: UniqueGenerator,
     N: TreeNode<Id = G::Output, NodeRef = R>,
     R: TreeNodeRef<Inner = N>,
 {
     idgen: G,
+    allocator: R::Allocator,
     root: Option<R>,
     depth_index: HashMap<NodeDepth, NodeIndex>,
+    limits: Option<BuildLimits<E>>,
+    on_node: Option<NodeHook<R>>,
+    // Nodes recorded for done_indexed, when enabled with with_indexing
+    built: Option<BuiltNodes<R>>,
+    capacity: usize,
+    shared: Option<SharedSubtrees<R>>,
     debug_span: tracing::Span,
     _phantom: (PhantomData<E>, PhantomData<N>, PhantomData<D>),
 }
 
+impl<D, E, G, N, R> Default for TreeBuilder<D, E, G, N, R>
+where
+    G: UniqueGenerator,
+    N: TreeNode<Id = G::Output, NodeRef = R>,
+    R: TreeNodeRef<Inner = N> + std::fmt::Debug,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<D, E, G, N, R> TreeBuilder<D, E, G, N, R>
 where
-    D: std::fmt::Display,
     G: UniqueGenerator,
     N: TreeNode<Id = G::Output, NodeRef = R>,
     R: TreeNodeRef<Inner = N> + std::fmt::Debug,
@@ -225,26 +490,218 @@ where
 
         Self {
             idgen: G::default(),
+            allocator: R::Allocator::default(),
             root: None,
             debug_span,
             depth_index: HashMap::new(),
+            limits: None,
+            on_node: None,
+            built: None,
+            capacity: 0,
+            shared: None,
             _phantom: (PhantomData, PhantomData, PhantomData),
         }
     }
 
+    /// Creates a new `TreeBuilder` instance with storage reserved for building a tree of
+    /// approximately `nodes` nodes. Node storage is reserved through [`TreeNodeRef::reserve`],
+    /// which preallocates the arena of an [`ArenaTreeBuilder`], while the reference counted
+    /// backends still allocate each node separately.
+    pub fn with_capacity(nodes: usize) -> Self {
+        let mut builder = Self::new();
+
+        R::reserve(&builder.allocator, nodes);
+        builder.capacity = nodes;
+
+        // The depth of a tree is bounded by its number of nodes
+        builder.depth_index.reserve(nodes.min(MAX_DEPTH_CAPACITY));
+
+        builder
+    }
+
+    /// Creates a new `TreeBuilder` which assigns node IDs from a generator seeded with `seed`.
+    ///
+    /// Subtree hashes are always computed with a fixed seed as specified by
+    /// [`HashVersion`](crate::HashVersion), so building the same data with the same seed produces
+    /// identical IDs and hashes across runs. This allows trees and patches to be snapshot tested.
+    pub fn deterministic(seed: u64) -> Self
+    where
+        G: SeedableGenerator,
+    {
+        Self::with_generator(G::from_seed(seed))
+    }
+
+    /// Creates a new `TreeBuilder` which assigns node IDs from the provided generator. The
+    /// generator is handed to the built tree, so a clone of a shared generator can be used to
+    /// build several trees with disjoint IDs.
+    pub fn with_generator(idgen: G) -> Self {
+        let mut builder = Self::new();
+        builder.idgen = idgen;
+        builder
+    }
+
+    /// Allocate nodes from the provided storage, such as an
+    /// [`Arena`](crate::noderef::arena::Arena) created with
+    /// [`Arena::with_capacity`](crate::noderef::arena::Arena::with_capacity). The storage is
+    /// handed to the built tree, which allocates the nodes it creates from it.
+    pub fn with_allocator(mut self, allocator: R::Allocator) -> Self {
+        self.allocator = allocator;
+        self
+    }
+
+    /// Limit the depth of the tree. Adding a node deeper than `max_depth` returns
+    /// [`BuildError::MaxDepthExceeded`], converted into the builder error type.
+    pub fn with_max_depth(mut self, max_depth: NodeDepth) -> Self
+    where
+        E: From<BuildError>,
+    {
+        self.limits.get_or_insert_with(BuildLimits::new).max_depth = Some(max_depth);
+        self
+    }
+
+    /// Limit the number of children of each node. Adding more than `max_children` to a node
+    /// returns [`BuildError::MaxChildrenExceeded`], converted into the builder error type.
+    pub fn with_max_children(mut self, max_children: usize) -> Self
+    where
+        E: From<BuildError>,
+    {
+        self.limits
+            .get_or_insert_with(BuildLimits::new)
+            .max_children = Some(max_children);
+        self
+    }
+
+    /// Record each node as it is finalized, so [`done_indexed`](Self::done_indexed) populates
+    /// the index and leaves without traversing the tree again. Without it, nodes are not
+    /// recorded and `done_indexed` indexes the built tree with a traversal.
+    pub fn with_indexing(mut self) -> Self {
+        self.built.get_or_insert_with(|| BuiltNodes {
+            nodes: Vec::with_capacity(self.capacity),
+            leaves: Vec::new(),
+        });
+        self
+    }
+
+    /// Share a single node for repeated subtrees. When a node is finalized with the same subtree
+    /// hash as a previously built subtree, and the subtrees are equal node by node, the existing
+    /// [`TreeNodeRef`] is used in its place. Subtrees with colliding hashes are not shared.
+    ///
+    /// Shared nodes keep the ID, parent and position of the first occurrence, and are only
+    /// recorded and passed to the [`on_node`](Self::on_node) hook once. Sharing is not
+    /// copy-on-write: mutating a shared node affects every occurrence, so this is intended for
+    /// trees built with [`done`](Self::done) and treated as immutable.
+    /// [`done_indexed`](Self::done_indexed) copies shared subtrees back into distinct nodes.
+    pub fn with_dedup(mut self) -> Self
+    where
+        N::Data: PartialEq,
+    {
+        self.shared.get_or_insert_with(|| SharedSubtrees {
+            subtrees: HashMap::new(),
+            eq: subtree_eq,
+        });
+        self
+    }
+
+    /// Register a callback invoked with each node as it is finalized, after all of its children
+    /// have been built and its subtree hash computed. Nodes are finalized in post-order, so
+    /// external indexes or derived data can be populated without a second pass over the tree.
+    pub fn on_node<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&mut R) + 'static,
+    {
+        self.on_node = Some(NodeHook(Box::new(f)));
+        self
+    }
+
+    /// Constructs a tree from a depth first sequence of `(depth, data)` pairs, such as the
+    /// items of a parsed outline. Each node becomes a child of the closest preceding node
+    /// with a depth one less than its own.
+    ///
+    /// The first node must have a depth of 0, and each following node may be at most one
+    /// level deeper than the node before it, otherwise [`BuildError::InvalidDepth`] is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use arbutus::TreeBuilder;
+    ///
+    /// let outline = [(0, "root"), (1, "a"), (2, "b"), (1, "c")];
+    /// let tree = TreeBuilder::<&str>::from_depth_stream(outline).unwrap().unwrap();
+    /// ```
+    pub fn from_depth_stream<I>(iter: I) -> Result<Option<Tree<R, G>>, BuildError>
+    where
+        I: IntoIterator<Item = (NodeDepth, D)>,
+        N: TreeNode<Data = D>,
+    {
+        let mut assembler = TreeAssembler::<D, G, N, R>::new();
+
+        for (depth, data) in iter {
+            let open = assembler.progress().open;
+
+            if depth > open {
+                return Err(BuildError::InvalidDepth { depth, max: open });
+            }
+
+            // Close nodes until the parent of this node is the innermost open node
+            for _ in depth..open {
+                assembler.leave()?;
+            }
+
+            assembler.enter(data)?;
+        }
+
+        for _ in 0..assembler.progress().open {
+            assembler.leave()?;
+        }
+
+        assembler.finish()
+    }
+
     /// Returns the constructed tree when finished building it.
     pub fn done(self) -> Result<Option<Tree<R, G>>, E> {
         self.debug_span.in_scope(|| {
             debug!("Finished building tree");
 
             if let Some(root) = self.root {
-                Ok(Some(Tree::from_node(root, Some(self.idgen))))
+                Ok(Some(
+                    Tree::from_node(root, Some(self.idgen)).with_allocator(self.allocator),
+                ))
             } else {
                 Ok(None)
             }
         })
     }
 
+    /// Returns the constructed tree as an [`IndexedTree`]. With
+    /// [`with_indexing`](Self::with_indexing), the index and leaves are populated from the nodes
+    /// recorded while building, without traversing the tree again. With
+    /// [`with_dedup`](Self::with_dedup), shared subtrees are copied into distinct nodes with
+    /// new IDs, as with [`Tree::unshare`].
+    pub fn done_indexed(self) -> Result<Option<IndexedTree<R, G>>, E> {
+        self.debug_span.in_scope(|| {
+            debug!("Finished building indexed tree");
+
+            let Some(root) = self.root else {
+                return Ok(None);
+            };
+
+            let tree = Tree::from_node(root, Some(self.idgen)).with_allocator(self.allocator);
+
+            // Shared subtrees are copied when indexing, so the recorded nodes don't cover them
+            let (Some(built), None) = (self.built, &self.shared) else {
+                return Ok(Some(tree.index()));
+            };
+
+            let mut index = BTreeIndex::new();
+            for node in built.nodes {
+                let id = node.node().id();
+                index.insert(id, node);
+            }
+
+            Ok(Some(IndexedTree::from_parts(tree, index, built.leaves)))
+        })
+    }
+
     /// Adds a root node to the tree and returns the updated builder.
     ///
     /// # Arguments
@@ -262,14 +719,19 @@ where
 
         self.debug_span.in_scope(|| {
             let node = N::new(id, data, None).with_position(NodePosition::zero());
-            let mut node_ref = R::new(node);
+            let mut node_ref = R::new_in(node, &self.allocator);
 
             let mut node_builder = NodeBuilder::<D, E, G, N, R>::new(
                 &mut node_ref,
                 &mut self.idgen,
+                &self.allocator,
                 NodePosition::zero(),
                 &mut self.depth_index,
             );
+            node_builder.limits = self.limits;
+            node_builder.on_node = self.on_node.as_mut();
+            node_builder.built = self.built.as_mut();
+            node_builder.shared = self.shared.as_mut();
 
             // Call the supplied closure with the NodeBuilder to add this node's children
             f(&mut node_builder)?;
@@ -289,10 +751,48 @@ where
     }
 }
 
+impl<D, G, N, R> TreeBuilder<D, Infallible, G, N, R>
+where
+    G: UniqueGenerator,
+    N: TreeNode<Id = G::Output, NodeRef = R>,
+    R: TreeNodeRef<Inner = N> + std::fmt::Debug,
+{
+    /// Adds a root node to the tree, for infallible builders where the closure
+    /// does not need to return a `Result`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use arbutus::TreeBuilder;
+    ///
+    /// let Ok(tree) = TreeBuilder::<&str>::new()
+    ///     .root_ok("root", |root| {
+    ///         root.child_ok("a", |a| a.child_ok("b", |_| ()));
+    ///         root.child_ok("c", |_| ());
+    ///     })
+    ///     .done();
+    /// ```
+    pub fn root_ok<F>(self, data: N::Data, f: F) -> Self
+    where
+        D: std::fmt::Debug + 'static,
+        F: FnOnce(&mut NodeBuilder<'_, D, Infallible, G, N, R>),
+    {
+        let Ok(builder) = self.root(data, |builder| {
+            f(builder);
+            Ok(())
+        });
+        builder
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
     use tracing_test::traced_test;
 
+    use crate::test::Colliding;
+
     use super::*;
 
     #[traced_test]
@@ -384,4 +884,301 @@ mod tests {
 
         println!("{}", tree.root());
     }
+
+    #[traced_test]
+    #[test]
+    fn max_depth() {
+        let result = TreeBuilder::<&'static str, BuildError>::new()
+            .with_max_depth(1)
+            .root("root", |root| {
+                root.child("a", |a| a.child("b", |_| Ok(())))?;
+                Ok(())
+            });
+
+        assert_eq!(
+            result.unwrap_err(),
+            BuildError::MaxDepthExceeded { depth: 2, max: 1 }
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn max_children() {
+        let build = |count| {
+            TreeBuilder::<&'static str, BuildError>::new()
+                .with_max_children(2)
+                .root("root", |root| {
+                    for _ in 0..count {
+                        root.child("a", |_| Ok(()))?;
+                    }
+                    Ok(())
+                })
+        };
+
+        assert!(build(2).is_ok());
+        assert_eq!(
+            build(3).unwrap_err(),
+            BuildError::MaxChildrenExceeded {
+                position: NodePosition::zero(),
+                max: 2
+            }
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn on_node() {
+        let finalized = Rc::new(RefCell::new(Vec::new()));
+        let hook = finalized.clone();
+
+        TreeBuilder::<&'static str, ()>::new()
+            .on_node(move |node| {
+                let node = node.node();
+                assert_ne!(node.get_subtree_hash(), 0);
+                hook.borrow_mut().push(*node.data());
+            })
+            .root("root", |root| {
+                root.child("a", |a| a.child("b", |_| Ok(())))?;
+                root.child("c", |_| Ok(()))
+            })
+            .unwrap();
+
+        // Nodes are finalized in post-order
+        assert_eq!(*finalized.borrow(), vec!["b", "a", "c", "root"]);
+    }
+
+    #[traced_test]
+    #[test]
+    fn done_indexed() {
+        let build = || {
+            TreeBuilder::<&'static str, ()>::new()
+                .with_indexing()
+                .root("root", |root| {
+                    root.child("a", |a| {
+                        a.child("b", |_| Ok(()))?;
+                        a.child("c", |_| Ok(()))
+                    })?;
+                    root.child("d", |_| Ok(()))
+                })
+                .unwrap()
+        };
+
+        let indexed = build().done_indexed().unwrap().unwrap();
+        let expected = build().done().unwrap().unwrap().index();
+
+        let leaf_ids: Vec<_> = indexed.leaves().iter().map(|l| l.node().id()).collect();
+        let expected_leaf_ids: Vec<_> = expected.leaves().iter().map(|l| l.node().id()).collect();
+
+        assert_eq!(indexed.index().get_ids(), expected.index().get_ids());
+        assert_eq!(leaf_ids, expected_leaf_ids);
+        assert_eq!(indexed, expected);
+
+        // Without recording, the built tree is indexed by traversal
+        let builder = TreeBuilder::<&'static str, ()>::new()
+            .root("root", |root| root.child("a", |_| Ok(())))
+            .unwrap();
+        assert!(builder.built.is_none());
+        let traversed = builder.done_indexed().unwrap().unwrap();
+        assert_eq!(traversed.node_count(), 2);
+        assert_eq!(traversed.leaves().len(), 1);
+    }
+
+    #[traced_test]
+    #[test]
+    fn infallible() {
+        let Ok(tree) = TreeBuilder::<&'static str>::new()
+            .root_ok("root", |root| {
+                root.child_ok("a", |a| a.child_ok("b", |_| ()));
+                root.child_ok("c", |_| ());
+            })
+            .done();
+
+        let expected = TreeBuilder::<&'static str, ()>::new()
+            .root("root", |root| {
+                root.child("a", |a| a.child("b", |_| Ok(())))?;
+                root.child("c", |_| Ok(()))
+            })
+            .unwrap()
+            .done()
+            .unwrap();
+
+        assert_eq!(tree.unwrap(), expected.unwrap());
+    }
+
+    #[traced_test]
+    #[test]
+    fn with_capacity() {
+        let tree = ArenaTreeBuilder::<u32>::with_capacity(1000)
+            .root_ok(0, |root| {
+                for i in 1..1000 {
+                    root.child_ok(i, |_| ());
+                }
+            })
+            .done()
+            .unwrap()
+            .unwrap();
+
+        // Every node was allocated from the reserved chunk
+        assert_eq!(tree.root().node().num_children(), 999);
+        assert_eq!(tree.allocator().len(), 1000);
+        assert_eq!(tree.allocator().capacity(), 1000);
+    }
+
+    #[traced_test]
+    #[test]
+    fn depth_stream() {
+        let tree = TreeBuilder::<&'static str>::from_depth_stream([
+            (0, "root"),
+            (1, "a"),
+            (2, "b"),
+            (1, "c"),
+        ])
+        .unwrap();
+
+        let Ok(expected) = TreeBuilder::<&'static str>::new()
+            .root_ok("root", |root| {
+                root.child_ok("a", |a| a.child_ok("b", |_| ()));
+                root.child_ok("c", |_| ());
+            })
+            .done();
+
+        assert_eq!(tree.unwrap(), expected.unwrap());
+    }
+
+    #[traced_test]
+    #[test]
+    fn depth_stream_invalid() {
+        let result = TreeBuilder::<&'static str>::from_depth_stream([(0, "root"), (2, "a")]);
+        assert_eq!(
+            result.unwrap_err(),
+            BuildError::InvalidDepth { depth: 2, max: 1 }
+        );
+
+        let result = TreeBuilder::<&'static str>::from_depth_stream([(0, "a"), (0, "b")]);
+        assert_eq!(result.unwrap_err(), BuildError::MultipleRoots);
+
+        let result = TreeBuilder::<&'static str>::from_depth_stream([]);
+        assert!(result.unwrap().is_none());
+    }
+
+    #[traced_test]
+    #[test]
+    fn deterministic() {
+        fn build() -> Vec<(u64, u64)> {
+            let Ok(tree) = TreeBuilder::<&'static str>::deterministic(100)
+                .root_ok("root", |root| {
+                    root.child_ok("a", |a| a.child_ok("b", |_| ()));
+                    root.child_ok("c", |_| ());
+                })
+                .done();
+
+            tree.unwrap()
+                .root()
+                .into_iter()
+                .map(|node| {
+                    let node = node.node();
+                    (node.id(), node.get_subtree_hash())
+                })
+                .collect()
+        }
+
+        let first = build();
+        assert_eq!(first.len(), 4);
+        assert_eq!(first[0].0, 100);
+        assert_eq!(first, build());
+    }
+
+    #[traced_test]
+    #[test]
+    fn with_generator() {
+        let idgen = crate::SequentialGenerator::starting_at(10);
+
+        let build = |idgen: crate::SequentialGenerator| {
+            let Ok(tree) = TreeBuilder::<&'static str>::with_generator(idgen)
+                .root_ok("root", |root| {
+                    root.child_ok("a", |_| ());
+                })
+                .done();
+
+            tree.unwrap()
+                .root()
+                .into_iter()
+                .map(|node| node.node().id())
+                .collect::<Vec<u64>>()
+        };
+
+        assert_eq!(build(idgen.clone()), vec![10, 11]);
+        assert_eq!(build(idgen.clone()), vec![12, 13]);
+
+        // The built tree continues allocating from the same generator
+        assert_eq!(idgen.generate(), 14);
+    }
+
+    #[traced_test]
+    #[test]
+    fn dedup() {
+        let build = || {
+            TreeBuilder::<&'static str>::new()
+                .with_dedup()
+                .with_indexing()
+                .root_ok("root", |root| {
+                    root.child_ok("a", |a| a.child_ok("x", |_| ()));
+                    root.child_ok("a", |a| a.child_ok("x", |_| ()));
+                    root.child_ok("b", |b| b.child_ok("x", |_| ()));
+                })
+        };
+        let Ok(tree) = build().done();
+        let tree = tree.unwrap();
+        let hash = tree.root().node().get_subtree_hash();
+
+        let children = tree.root().node().children().unwrap().clone();
+        let ids: Vec<_> = children.iter().map(|child| child.node().id()).collect();
+        assert_eq!(ids[0], ids[1]);
+        assert_ne!(ids[0], ids[2]);
+
+        // The leaf under b is shared with the leaf under a
+        let leaves: Vec<_> = children
+            .iter()
+            .map(|child| child.node().children().unwrap()[0].node().id())
+            .collect();
+        assert_eq!(leaves[0], leaves[2]);
+
+        // Indexing copies the shared subtrees, so every node has a unique ID and one parent
+        let indexed = tree.index();
+        assert_eq!(indexed.node_count(), 7);
+        assert_eq!(indexed.validate(), Ok(()));
+        assert_eq!(indexed.root().node().get_subtree_hash(), hash);
+
+        let Ok(indexed) = build().done_indexed();
+        let indexed = indexed.unwrap();
+        assert_eq!(indexed.node_count(), 7);
+        assert_eq!(indexed.leaves().len(), 3);
+        assert_eq!(indexed.validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn dedup_collision() {
+        let Ok(tree) = TreeBuilder::<Colliding>::new()
+            .with_dedup()
+            .root_ok(Colliding("root"), |root| {
+                root.child_ok(Colliding("a"), |_| ());
+                root.child_ok(Colliding("b"), |_| ());
+                root.child_ok(Colliding("a"), |_| ());
+            })
+            .done();
+        let tree = tree.unwrap();
+
+        let children = tree.root().node().children().unwrap().clone();
+        assert_eq!(
+            children[0].node().get_subtree_hash(),
+            children[1].node().get_subtree_hash()
+        );
+
+        // Leaves with colliding hashes keep their own data, equal leaves are shared
+        let data: Vec<_> = children.iter().map(|child| child.node().data().0).collect();
+        assert_eq!(data, ["a", "b", "a"]);
+        assert!(children[0].ptr_eq(&children[2]));
+        assert!(!children[0].ptr_eq(&children[1]));
+    }
 }
@@ -0,0 +1,200 @@
+use std::{collections::HashMap, hash::Hasher as _};
+
+use tracing::debug;
+use xxhash_rust::xxh64::Xxh64;
+
+use crate::{
+    hash::subtree_size, id::UniqueGenerator, node::TreeNode, NodeDepth, NodeIndex, NodePosition,
+    Tree, TreeNodeRef,
+};
+
+use super::{BuildError, DefaultNode, DefaultNodeRef, MAX_DEPTH_CAPACITY};
+
+/// Command used to incrementally describe a tree in depth first order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildCommand<D> {
+    /// Open a new node with the provided data as a child of the currently open node
+    Enter(D),
+
+    /// Close the currently open node
+    Leave,
+}
+
+/// Snapshot of the progress of an incremental build
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildProgress {
+    /// Number of nodes created so far
+    pub nodes: usize,
+
+    /// Number of currently open nodes
+    pub open: usize,
+}
+
+/// Assembles a tree incrementally from a sequence of [`BuildCommand`]s.
+///
+/// Nodes are assigned IDs and positions as they are entered, and their subtree hashes are
+/// computed as they are left, producing the same tree as the equivalent [`super::TreeBuilder`].
+pub struct TreeAssembler<D, G = crate::IdGenerator, N = DefaultNode<D, G>, R = DefaultNodeRef<N>>
+where
+    G: UniqueGenerator,
+    N: TreeNode<Id = G::Output, NodeRef = R, Data = D>,
+    R: TreeNodeRef<Inner = N>,
+{
+    idgen: G,
+    root: Option<R>,
+
+    // Storage nodes are allocated from, handed to the assembled tree
+    allocator: R::Allocator,
+
+    // Stack of open nodes, each with a hasher accumulating child subtree hashes
+    stack: Vec<(R, Xxh64)>,
+
+    depth_index: HashMap<NodeDepth, NodeIndex>,
+    nodes: usize,
+}
+
+impl<D, G, N, R> Default for TreeAssembler<D, G, N, R>
+where
+    G: UniqueGenerator,
+    N: TreeNode<Id = G::Output, NodeRef = R, Data = D>,
+    R: TreeNodeRef<Inner = N>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D, G, N, R> TreeAssembler<D, G, N, R>
+where
+    G: UniqueGenerator,
+    N: TreeNode<Id = G::Output, NodeRef = R, Data = D>,
+    R: TreeNodeRef<Inner = N>,
+{
+    pub fn new() -> Self {
+        Self {
+            idgen: G::default(),
+            root: None,
+            allocator: R::Allocator::default(),
+            stack: Vec::new(),
+            depth_index: HashMap::new(),
+            nodes: 0,
+        }
+    }
+
+    /// Create a new assembler with storage reserved for a tree of approximately `nodes` nodes
+    pub fn with_capacity(nodes: usize) -> Self {
+        let capacity = nodes.min(MAX_DEPTH_CAPACITY);
+
+        let assembler = Self {
+            stack: Vec::with_capacity(capacity),
+            depth_index: HashMap::with_capacity(capacity),
+            ..Self::new()
+        };
+        R::reserve(&assembler.allocator, nodes);
+        assembler
+    }
+
+    /// Allocate nodes from the provided storage, which is handed to the assembled tree. See
+    /// [`TreeBuilder::with_allocator`](super::TreeBuilder::with_allocator).
+    pub fn with_allocator(mut self, allocator: R::Allocator) -> Self {
+        self.allocator = allocator;
+        self
+    }
+
+    /// Apply a single [`BuildCommand`]
+    pub fn apply(&mut self, command: BuildCommand<D>) -> Result<(), BuildError> {
+        match command {
+            BuildCommand::Enter(data) => self.enter(data),
+            BuildCommand::Leave => self.leave(),
+        }
+    }
+
+    /// Open a new node as a child of the currently open node
+    pub fn enter(&mut self, data: D) -> Result<(), BuildError> {
+        if self.stack.is_empty() && self.root.is_some() {
+            return Err(BuildError::MultipleRoots);
+        }
+
+        let id = self.idgen.generate();
+        let depth = self.stack.len();
+
+        let node_ref = if let Some((parent, _)) = self.stack.last_mut() {
+            let depth_index = self.depth_index.entry(depth).or_insert(0);
+
+            let position = NodePosition {
+                depth,
+                index: *depth_index,
+                child_index: parent.node().num_children(),
+            };
+
+            *depth_index += 1;
+
+            let node = N::new(id, data, None)
+                .with_parent(parent.clone())
+                .with_position(position);
+            let node_ref = R::new_in(node, &self.allocator);
+
+            parent.node_mut().push_child(node_ref.clone());
+            node_ref
+        } else {
+            R::new_in(
+                N::new(id, data, None).with_position(NodePosition::zero()),
+                &self.allocator,
+            )
+        };
+
+        self.stack.push((node_ref, Xxh64::new(0)));
+        self.nodes += 1;
+
+        Ok(())
+    }
+
+    /// Close the currently open node, finalizing its subtree hash
+    pub fn leave(&mut self) -> Result<(), BuildError> {
+        let (mut node_ref, mut hasher) = self.stack.pop().ok_or(BuildError::UnbalancedLeave)?;
+
+        let subtree_hash = {
+            let mut node = node_ref.node_mut();
+            node.hash(&mut hasher);
+            let subtree_hash = hasher.finish();
+            debug!("Leave {} hash finish 0x{:X}", node.id(), subtree_hash);
+            node.set_subtree_hash(subtree_hash);
+            let size = subtree_size(&*node);
+            node.set_subtree_size(size);
+            subtree_hash
+        };
+
+        if let Some((_, parent_hasher)) = self.stack.last_mut() {
+            parent_hasher.write_u64(subtree_hash);
+        } else {
+            self.root = Some(node_ref);
+        }
+
+        Ok(())
+    }
+
+    /// Get the current progress of the build
+    pub fn progress(&self) -> BuildProgress {
+        BuildProgress {
+            nodes: self.nodes,
+            open: self.stack.len(),
+        }
+    }
+
+    /// Returns the constructed tree. All opened nodes must have been closed.
+    pub fn finish(self) -> Result<Option<Tree<R, G>>, BuildError>
+    where
+        R: std::fmt::Debug,
+    {
+        if !self.stack.is_empty() {
+            return Err(BuildError::UnclosedNodes {
+                open: self.stack.len(),
+            });
+        }
+
+        let allocator = self.allocator;
+        Ok(self
+            .root
+            .map(|root| Tree::from_node(root, Some(self.idgen)).with_allocator(allocator)))
+    }
+}
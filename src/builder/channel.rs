@@ -0,0 +1,175 @@
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+
+use tracing::debug;
+
+use crate::{id::UniqueGenerator, node::TreeNode, Tree, TreeNodeRef};
+
+use super::{BuildCommand, BuildError, BuildProgress, DefaultNode, DefaultNodeRef, TreeAssembler};
+
+type ProgressCallback = Box<dyn FnMut(&BuildProgress)>;
+
+/// A builder which assembles a tree from [`BuildCommand`]s sent over a channel.
+///
+/// Producers on other threads send commands through the [`Sender`] returned from
+/// [`ChannelBuilder::channel`], while the consumer assembles the tree incrementally
+/// as commands arrive, so the full input never needs to be buffered.
+///
+/// # Examples
+///
+/// ```
+/// use arbutus::{BuildCommand, ChannelBuilder};
+///
+/// let (sender, builder) = ChannelBuilder::<String>::channel();
+///
+/// std::thread::spawn(move || {
+///     sender.send(BuildCommand::Enter("root".to_string())).unwrap();
+///     sender.send(BuildCommand::Enter("child".to_string())).unwrap();
+///     sender.send(BuildCommand::Leave).unwrap();
+///     sender.send(BuildCommand::Leave).unwrap();
+/// });
+///
+/// let tree = builder.build().unwrap().unwrap();
+/// ```
+pub struct ChannelBuilder<D, G = crate::IdGenerator, N = DefaultNode<D, G>, R = DefaultNodeRef<N>>
+where
+    G: UniqueGenerator,
+    N: TreeNode<Id = G::Output, NodeRef = R, Data = D>,
+    R: TreeNodeRef<Inner = N>,
+{
+    receiver: Receiver<BuildCommand<D>>,
+    assembler: TreeAssembler<D, G, N, R>,
+    progress: Option<ProgressCallback>,
+}
+
+impl<D, G, N, R> ChannelBuilder<D, G, N, R>
+where
+    G: UniqueGenerator,
+    N: TreeNode<Id = G::Output, NodeRef = R, Data = D>,
+    R: TreeNodeRef<Inner = N> + std::fmt::Debug,
+{
+    /// Create a new builder consuming commands from the provided receiver
+    pub fn new(receiver: Receiver<BuildCommand<D>>) -> Self {
+        Self {
+            receiver,
+            assembler: TreeAssembler::new(),
+            progress: None,
+        }
+    }
+
+    /// Create a new builder along with a [`Sender`] for producers to send commands
+    pub fn channel() -> (Sender<BuildCommand<D>>, Self) {
+        let (sender, receiver) = channel();
+        (sender, Self::new(receiver))
+    }
+
+    /// Register a callback invoked with the build progress after each command is applied
+    pub fn on_progress<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&BuildProgress) + 'static,
+    {
+        self.progress = Some(Box::new(f));
+        self
+    }
+
+    fn apply(&mut self, command: BuildCommand<D>) -> Result<(), BuildError> {
+        self.assembler.apply(command)?;
+
+        if let Some(progress) = &mut self.progress {
+            progress(&self.assembler.progress());
+        }
+
+        Ok(())
+    }
+
+    /// Apply all commands currently queued in the channel without blocking.
+    /// Returns `true` once all producers have disconnected.
+    pub fn try_process(&mut self) -> Result<bool, BuildError> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(command) => self.apply(command)?,
+                Err(TryRecvError::Empty) => return Ok(false),
+                Err(TryRecvError::Disconnected) => return Ok(true),
+            }
+        }
+    }
+
+    /// Block applying commands until all producers have disconnected, and return the constructed tree
+    pub fn build(mut self) -> Result<Option<Tree<R, G>>, BuildError> {
+        while let Ok(command) = self.receiver.recv() {
+            self.apply(command)?;
+        }
+
+        debug!("All producers disconnected, finishing tree");
+
+        self.assembler.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use tracing_test::traced_test;
+
+    use crate::{BuildCommand, BuildError, TreeBuilder};
+
+    use super::ChannelBuilder;
+
+    #[traced_test]
+    #[test]
+    fn build_from_thread() {
+        let (sender, builder) = ChannelBuilder::<&'static str>::channel();
+
+        let producer = std::thread::spawn(move || {
+            for command in [
+                BuildCommand::Enter("root"),
+                BuildCommand::Enter("a"),
+                BuildCommand::Enter("b"),
+                BuildCommand::Leave,
+                BuildCommand::Leave,
+                BuildCommand::Enter("c"),
+                BuildCommand::Leave,
+                BuildCommand::Leave,
+            ] {
+                sender.send(command).unwrap();
+            }
+        });
+
+        let commands = Rc::new(Cell::new(0));
+        let counter = commands.clone();
+
+        let tree = builder
+            .on_progress(move |_| counter.set(counter.get() + 1))
+            .build()
+            .unwrap()
+            .unwrap();
+
+        producer.join().unwrap();
+
+        let expected = TreeBuilder::<&'static str, ()>::new()
+            .root("root", |root| {
+                root.child("a", |a| a.child("b", |_| Ok(())))?;
+                root.child("c", |_| Ok(()))
+            })
+            .unwrap()
+            .done()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(commands.get(), 8);
+        assert_eq!(tree, expected);
+    }
+
+    #[traced_test]
+    #[test]
+    fn unclosed() {
+        let (sender, builder) = ChannelBuilder::<&'static str>::channel();
+        sender.send(BuildCommand::Enter("root")).unwrap();
+        drop(sender);
+
+        assert_eq!(
+            builder.build().unwrap_err(),
+            BuildError::UnclosedNodes { open: 1 }
+        );
+    }
+}
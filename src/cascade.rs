@@ -0,0 +1,268 @@
+//! Property cascade resolution.
+//!
+//! A [`CascadeResolver`] computes the effective properties of every node in a tree from the
+//! properties declared on each node, combined with the effective properties inherited from
+//! its parent. This is the core primitive of style systems, where values such as fonts or
+//! colors flow down the tree unless overridden.
+//!
+//! Results are memoized per node ID, keyed by the hash of the node's data and the hash of the
+//! properties inherited from its parent. Every resolution walks the tree, but only nodes which
+//! are new, or whose data or inherited properties changed, are recomputed, so re-resolving after
+//! applying a patch only recomputes the dirty regions. Nodes no longer in the tree are pruned.
+
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher as _},
+};
+
+use tracing::debug;
+use xxhash_rust::xxh64::Xxh64;
+
+use crate::{noderef::NodeRefId, TreeNode, TreeNodeRef};
+
+/// Rules describing how properties are declared on nodes and inherited from parents
+pub trait CascadeRules<D> {
+    /// Set of properties resolved for each node
+    type Props: Clone + Hash;
+
+    /// Properties declared directly on a node
+    fn declared(&self, data: &D) -> Self::Props;
+
+    /// Combine the effective properties of the parent (`None` for the root node) with the
+    /// properties declared on a node, returning the effective properties of the node
+    fn cascade(&self, parent: Option<&Self::Props>, declared: Self::Props) -> Self::Props;
+}
+
+struct CascadeEntry<P> {
+    // Hash of the inputs which produced these properties
+    key: u64,
+    props: P,
+}
+
+/// Resolves and memoizes effective properties for each node of a tree
+pub struct CascadeResolver<R, C>
+where
+    R: TreeNodeRef,
+    C: CascadeRules<<<R as TreeNodeRef>::Inner as TreeNode>::Data>,
+{
+    rules: C,
+    cache: HashMap<NodeRefId<R>, CascadeEntry<C::Props>>,
+}
+
+impl<R, C> CascadeResolver<R, C>
+where
+    R: TreeNodeRef,
+    C: CascadeRules<<<R as TreeNodeRef>::Inner as TreeNode>::Data>,
+{
+    /// Create a new resolver with an empty cache
+    pub fn new(rules: C) -> Self {
+        Self {
+            rules,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Get the cascade rules of this resolver
+    pub fn rules(&self) -> &C {
+        &self.rules
+    }
+
+    /// Resolve effective properties for every node under `root` in a single traversal.
+    /// Nodes whose inputs are unchanged since the last resolution keep their memoized
+    /// properties, and memoized properties of nodes no longer under `root` are dropped.
+    ///
+    /// Returns the number of nodes which were recomputed.
+    pub fn resolve(&mut self, root: &R) -> usize {
+        let mut recomputed = 0;
+        let mut visited = HashSet::with_capacity(self.cache.len());
+
+        // Stack of nodes to visit, with the effective properties of the parent and their hash
+        let mut stack: Vec<(R, Option<C::Props>, u64)> = Vec::from([(root.clone(), None, 0)]);
+
+        while let Some((node, parent_props, parent_hash)) = stack.pop() {
+            let inner = node.node();
+            let id = inner.id();
+
+            visited.insert(id);
+
+            let mut hasher = Xxh64::new(0);
+            inner.data_xxhash().hash(&mut hasher);
+            parent_hash.hash(&mut hasher);
+            let key = hasher.finish();
+
+            // Nodes are memoized by ID, so subtrees replaced by identical content with new IDs
+            // are still resolved
+            let props = match self.cache.get(&id) {
+                Some(entry) if entry.key == key => entry.props.clone(),
+                _ => {
+                    let declared = self.rules.declared(&inner.data());
+                    let props = self.rules.cascade(parent_props.as_ref(), declared);
+                    self.cache.insert(
+                        id,
+                        CascadeEntry {
+                            key,
+                            props: props.clone(),
+                        },
+                    );
+                    recomputed += 1;
+                    props
+                }
+            };
+
+            let mut hasher = Xxh64::new(0);
+            props.hash(&mut hasher);
+            let props_hash = hasher.finish();
+
+            if let Some(children) = inner.children() {
+                for child in children.iter().rev() {
+                    stack.push((child.clone(), Some(props.clone()), props_hash));
+                }
+            };
+        }
+
+        // Drop nodes which were removed from the tree
+        self.cache.retain(|id, _| visited.contains(id));
+
+        debug!("Cascade resolved {recomputed} nodes");
+
+        recomputed
+    }
+
+    /// Get the resolved effective properties of a node
+    pub fn get(&self, id: &NodeRefId<R>) -> Option<&C::Props> {
+        self.cache.get(id).map(|entry| &entry.props)
+    }
+
+    /// Force a node to be recomputed on the next resolution
+    pub fn invalidate(&mut self, id: &NodeRefId<R>) {
+        self.cache.remove(id);
+    }
+
+    /// Remove all memoized properties
+    pub fn clear(&mut self) {
+        self.cache.clear();
+    }
+
+    /// Number of nodes with memoized properties
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        node::arc::Node,
+        noderef::arc::NodeRef,
+        test::{test_tree, test_tree_node, TestData, TestNode},
+        TreeDiff, TreeNode as _, TreeNodeRef as _,
+    };
+
+    use super::{CascadeResolver, CascadeRules};
+
+    /// Effective property is the path of data from the root
+    struct PathRules;
+
+    impl CascadeRules<TestData> for PathRules {
+        type Props = String;
+
+        fn declared(&self, data: &TestData) -> String {
+            data.to_string()
+        }
+
+        fn cascade(&self, parent: Option<&String>, declared: String) -> String {
+            match parent {
+                Some(parent) => format!("{parent}/{declared}"),
+                None => declared,
+            }
+        }
+    }
+
+    /// Effective property is the path of string data from the root
+    struct DataRules;
+
+    impl CascadeRules<&'static str> for DataRules {
+        type Props = String;
+
+        fn declared(&self, data: &&'static str) -> String {
+            data.to_string()
+        }
+
+        fn cascade(&self, parent: Option<&String>, declared: String) -> String {
+            PathRules.cascade(parent, declared)
+        }
+    }
+
+    #[traced_test]
+    #[test]
+    fn resolve() {
+        let tree = test_tree(vec!["foo", "bar"]);
+        let mut resolver = CascadeResolver::new(PathRules);
+
+        assert_eq!(resolver.resolve(&tree.root()), 3);
+
+        let bar = tree.root().node().children().unwrap()[1].clone();
+        assert_eq!(
+            resolver.get(&bar.node().id()).unwrap(),
+            "Root/String(\"bar\")"
+        );
+
+        // Nothing changed, so nothing is recomputed
+        assert_eq!(resolver.resolve(&tree.root()), 0);
+    }
+
+    #[traced_test]
+    #[test]
+    fn resolve_after_patch() {
+        let mut a = test_tree(vec!["foo", "a", "bar"]);
+        let b = test_tree(vec!["foo", "b", "bar"]);
+
+        let mut resolver = CascadeResolver::new(PathRules);
+        assert_eq!(resolver.resolve(&a.root()), 4);
+
+        TreeDiff::new(a.root(), b.root()).diff().patch_tree(&mut a);
+
+        // Only the replaced child is recomputed
+        assert_eq!(resolver.resolve(&a.root()), 1);
+        assert_eq!(resolver.len(), a.node_count());
+
+        let replaced = a.root().node().children().unwrap()[1].clone();
+        assert_eq!(
+            resolver.get(&replaced.node().id()).unwrap(),
+            "Root/String(\"b\")"
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn resolve_replaced_descendant() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![TestNode("b", vec![])])]);
+        let mut resolver = CascadeResolver::new(DataRules);
+        assert_eq!(resolver.resolve(&tree.root()), 3);
+
+        // Replace the grandchild with identical content, which allocates a new ID
+        let a = tree.root().node().children().unwrap()[0].node().id();
+        let old = tree.get_node(&a).unwrap().node().children().unwrap()[0]
+            .node()
+            .id();
+        tree.replace_child(a, 0, NodeRef::new(Node::new(0, "b", None)))
+            .unwrap();
+        let new = tree.get_node(&a).unwrap().node().children().unwrap()[0]
+            .node()
+            .id();
+        assert_ne!(old, new);
+
+        assert_eq!(resolver.resolve(&tree.root()), 1);
+        assert_eq!(resolver.get(&new).unwrap(), "root/a/b");
+
+        // The replaced node is pruned
+        assert_eq!(resolver.get(&old), None);
+        assert_eq!(resolver.len(), tree.node_count());
+    }
+}
@@ -1,7 +1,43 @@
-use crate::{noderef::NodeRefId, IndexedTree, Tree, TreeNode, TreeNodeRef, UniqueGenerator};
+use crate::{
+    hash::{content_hash, subtree_digest128},
+    index::TreeIndex,
+    noderef::NodeRefId,
+    IndexedTree, Tree, TreeNode, TreeNodeRef, UniqueGenerator,
+};
 
-/// Tree Comparison
+// Tree Comparison
 
+/// Compare two subtrees node by node, without relying on their subtree hashes. Subtrees shared
+/// by both are not visited.
+pub(crate) fn subtree_eq<R>(a: &R, b: &R) -> bool
+where
+    R: TreeNodeRef,
+    <R::Inner as TreeNode>::Data: PartialEq,
+{
+    let mut stack = vec![(a.clone(), b.clone())];
+
+    while let Some((a, b)) = stack.pop() {
+        if a.ptr_eq(&b) {
+            continue;
+        }
+
+        let (a, b) = (a.node(), b.node());
+        if *a.data() != *b.data() || a.num_children() != b.num_children() {
+            return false;
+        }
+
+        let children = (a.children(), b.children());
+        if let (Some(a), Some(b)) = children {
+            stack.extend(a.iter().cloned().zip(b.iter().cloned()));
+        }
+    }
+
+    true
+}
+
+/// Trees are equal if their root subtree hashes are equal. With the `strict-eq` feature, trees
+/// are instead compared node by node with [`Tree::structural_eq`].
+#[cfg(not(feature = "strict-eq"))]
 impl<R, G> PartialEq for Tree<R, G>
 where
     R: TreeNodeRef + 'static,
@@ -31,19 +67,76 @@ where
     }
 }
 
-impl<R, G> PartialEq for IndexedTree<R, G>
+#[cfg(feature = "strict-eq")]
+impl<R, G> PartialEq for Tree<R, G>
+where
+    R: TreeNodeRef + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    <R::Inner as TreeNode>::Data: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.structural_eq(other)
+    }
+}
+
+impl<R, G> Tree<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    /// Compare trees by their 128 bit [`subtree_digest128`], for when a collision of the 64 bit
+    /// subtree hashes used by `==` is unacceptable. Trees with different subtree hashes are
+    /// rejected without computing the digests.
+    pub fn strong_eq(&self, other: &Self) -> bool {
+        if self.is_empty() || other.is_empty() {
+            return self.is_empty() && other.is_empty();
+        }
+
+        self.node().get_subtree_hash() == other.node().get_subtree_hash()
+            && subtree_digest128(self.root_ref()) == subtree_digest128(other.root_ref())
+    }
+
+    /// Compare trees node by node, without relying on subtree hashes, which may collide or be
+    /// stale. Trees are equal if every pair of corresponding nodes has equal data and the same
+    /// number of children. Subtrees shared by both trees are not visited.
+    pub fn structural_eq(&self, other: &Self) -> bool
+    where
+        <R::Inner as TreeNode>::Data: PartialEq,
+    {
+        if self.is_empty() || other.is_empty() {
+            return self.is_empty() && other.is_empty();
+        }
+
+        subtree_eq(self.root_ref(), other.root_ref())
+    }
+
+    /// Get the [`content_hash`] of the whole tree, which is equal for trees holding the same
+    /// data values regardless of their structure
+    pub fn content_hash(&self) -> u64 {
+        if self.is_empty() {
+            return 0;
+        }
+        content_hash(self.root_ref())
+    }
+}
+
+impl<R, G, I> PartialEq for IndexedTree<R, G, I>
 where
     R: TreeNodeRef + std::fmt::Debug,
     G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+    Tree<R, G>: PartialEq,
 {
     fn eq(&self, other: &Self) -> bool {
         self.tree() == other.tree()
     }
 }
 
-impl<R, G> Eq for IndexedTree<R, G>
+impl<R, G, I> Eq for IndexedTree<R, G, I>
 where
     R: TreeNodeRef + std::hash::Hash + PartialEq + 'static,
     G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+    Tree<R, G>: PartialEq,
 {
 }
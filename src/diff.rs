@@ -1,9 +1,10 @@
 use colored::Colorize;
-use tracing::{debug, debug_span};
+use tracing::{debug, debug_span, warn};
 
 use crate::{
     edit::{vec_edits, Edit},
-    hash::update_subtree_hash,
+    hash::{HashVersion, IncompatibleHashVersion},
+    index::TreeIndex,
     noderef::NodeRefId,
     IndexedTree, TreeNode, TreeNodeRef, UniqueGenerator,
 };
@@ -27,6 +28,9 @@ where
     R: TreeNodeRef + 'static,
 {
     patches: Vec<TreePatchOperation<R>>,
+
+    // Hash version of the trees this patch was produced from
+    hash_version: HashVersion,
 }
 
 impl<R> TreePatch<R>
@@ -34,56 +38,97 @@ where
     R: TreeNodeRef + std::fmt::Debug + 'static,
 {
     pub fn new(patches: Vec<TreePatchOperation<R>>) -> Self {
-        Self { patches }
+        Self {
+            patches,
+            hash_version: HashVersion::CURRENT,
+        }
+    }
+
+    /// Set the hash version of the trees this patch was produced from
+    pub fn with_hash_version(mut self, hash_version: HashVersion) -> Self {
+        self.hash_version = hash_version;
+        self
+    }
+
+    /// Get the hash version of the trees this patch was produced from
+    pub fn hash_version(&self) -> HashVersion {
+        self.hash_version
+    }
+
+    /// Check this patch was produced with the [`HashVersion`] implemented by this release
+    pub fn check_hash_version(&self) -> Result<(), IncompatibleHashVersion> {
+        if self.hash_version == HashVersion::CURRENT {
+            Ok(())
+        } else {
+            Err(IncompatibleHashVersion {
+                expected: HashVersion::CURRENT,
+                found: self.hash_version,
+            })
+        }
+    }
+
+    /// Get the operations of this patch, in the order they are applied
+    pub fn operations(&self) -> &[TreePatchOperation<R>] {
+        &self.patches
     }
 
     pub fn len(&self) -> usize {
         self.patches.len()
     }
 
-    pub fn patch_tree<G>(&self, tree: &mut IndexedTree<R, G>)
+    pub fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+
+    pub fn patch_tree<G, I>(&self, tree: &mut IndexedTree<R, G, I>)
     where
         R::Data: Clone,
         G: UniqueGenerator<Output = NodeRefId<R>>,
+        I: TreeIndex<R>,
     {
         debug_span!("patch").in_scope(|| {
             for patch in self.patches.clone().into_iter() {
                 debug!("{} {:#?}", "Patching".bright_purple(), patch);
-                match patch {
+                // The indexed operations keep the index and leaves of the tree in sync
+                let result = match patch {
                     TreePatchOperation::InsertChild {
-                        mut dest,
+                        dest,
                         index,
                         source,
                     } => {
-                        tree.insert_subtree(&mut dest, index, source);
-                        update_subtree_hash(dest);
+                        let id = dest.node().id();
+                        tree.insert_subtree(id, index, source)
                     }
-                    TreePatchOperation::DeleteChild { mut dest, index } => {
-                        tree.remove_child(&mut dest, index);
-                        update_subtree_hash(dest);
+                    TreePatchOperation::DeleteChild { dest, index } => {
+                        let id = dest.node().id();
+                        tree.remove_child(id, index).map(|_| ())
                     }
                     TreePatchOperation::ReplaceChild {
-                        mut dest,
+                        dest,
                         index,
                         source,
                     } => {
-                        tree.replace_child(&mut dest, index, source);
-                        update_subtree_hash(dest);
+                        let id = dest.node().id();
+                        tree.replace_child(id, index, source)
                     }
-                    TreePatchOperation::RemoveChildren { mut dest } => {
-                        //dest.node_mut().set_children(None);
-                        tree.remove_children(&mut dest);
-                        update_subtree_hash(dest);
+                    TreePatchOperation::RemoveChildren { dest } => {
+                        let id = dest.node().id();
+                        tree.remove_children(id).map(|_| ())
                     }
-                    TreePatchOperation::SetChildren { mut dest, nodes } => {
-                        tree.set_children(&mut dest, nodes);
-                        update_subtree_hash(dest);
+                    TreePatchOperation::SetChildren { dest, nodes } => {
+                        let id = dest.node().id();
+                        tree.set_children(id, nodes)
                     }
-                    TreePatchOperation::ReplaceNode { mut dest, source } => {
-                        tree.replace_node(&mut dest, &source);
-                        update_subtree_hash(dest);
+                    TreePatchOperation::ReplaceNode { dest, source } => {
+                        let id = dest.node().id();
+                        let data = source.node().data().clone();
+                        tree.set_data(id, data).map(|_| ())
                     }
                 };
+
+                if let Err(e) = result {
+                    warn!("Failed to apply patch operation: {e}");
+                }
             }
         })
     }
@@ -99,7 +144,7 @@ where
 
 impl<R> TreeDiff<R>
 where
-    R: TreeNodeRef + std::fmt::Debug + std::fmt::Display + 'static,
+    R: TreeNodeRef + std::fmt::Debug + 'static,
 {
     pub fn new(dest_tree: R, source_tree: R) -> Self {
         Self {
@@ -157,8 +202,7 @@ where
                         (None, Some(source_children)) => {
                             debug!("Only source has children. Adding all source children to dest");
 
-                            let children: Vec<R> =
-                                source_children.iter().map(|child| child.clone()).collect();
+                            let children: Vec<R> = source_children.iter().cloned().collect();
                             patches.push(TreePatchOperation::SetChildren {
                                 dest: dest.clone(),
                                 nodes: children,
@@ -205,10 +249,8 @@ where
                                                     .yellow()
                                             );
 
-                                            let children: Vec<R> = source_children
-                                                .iter()
-                                                .map(|child| child.clone())
-                                                .collect();
+                                            let children: Vec<R> =
+                                                source_children.iter().cloned().collect();
                                             patches.push(TreePatchOperation::SetChildren {
                                                 dest: dest.clone(),
                                                 nodes: children,
@@ -296,11 +338,16 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::sync::{Arc, Mutex};
+
     use colored::Colorize as _;
     use tracing_test::traced_test;
 
-    use crate::test::{
-        test_tree, test_tree_deep, test_tree_nested, test_tree_node, test_tree_vec, TestNode,
+    use crate::{
+        test::{
+            test_tree, test_tree_deep, test_tree_nested, test_tree_node, test_tree_vec, TestNode,
+        },
+        TreeEvent,
     };
 
     use super::TreeDiff;
@@ -333,6 +380,34 @@ mod tests {
         assert_eq!(a, b);
     }
 
+    #[traced_test]
+    #[test]
+    fn replace_node_event() {
+        let mut a = test_tree_node(vec![TestNode("a", vec![TestNode("1", vec![])])])
+            .with_data_index(|data: &&str| *data);
+        let b = test_tree_node(vec![TestNode("b", vec![TestNode("1", vec![])])]);
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let listener_events = events.clone();
+        let _listener = a
+            .on_event(move |event| {
+                if let TreeEvent::NodeReplaced { previous, .. } = event {
+                    listener_events.lock().unwrap().push(*previous);
+                }
+            })
+            .unwrap();
+
+        let mut diff = TreeDiff::new(a.root(), b.root());
+        diff.diff().patch_tree(&mut a);
+
+        assert_eq!(a, b);
+        assert_eq!(*events.lock().unwrap(), vec!["a"]);
+        assert_eq!(a.validate(), Ok(()));
+        // The replaced data is indexed
+        assert_eq!(a.get_by_key(&"a").count(), 0);
+        assert_eq!(a.get_by_key(&"b").count(), 1);
+    }
+
     #[traced_test]
     #[test]
     fn insert_child() {
@@ -91,3 +91,147 @@ impl TreeDisplay {
         }
     }
 }
+
+/// Visit a subtree in depth first order with the depth of each node relative to the provided node
+fn walk<R>(node: &R, mut f: impl FnMut(&R, usize))
+where
+    R: TreeNodeRef,
+{
+    let mut stack = vec![(node.clone(), 0)];
+    while let Some((node, depth)) = stack.pop() {
+        f(&node, depth);
+        if let Some(children) = node.node().children() {
+            stack.extend(
+                children
+                    .iter()
+                    .rev()
+                    .map(|child| (child.clone(), depth + 1)),
+            );
+        }
+    }
+}
+
+/// Mermaid node identifier for a node ID. IDs such as [`ShardedId`](crate::ShardedId) or UUIDs
+/// contain characters which are not valid in identifiers, so these are replaced with `_`.
+fn mermaid_id(id: impl std::fmt::Display) -> String {
+    id.to_string()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Escape a node label for a quoted Mermaid label, using Mermaid entity codes for characters which
+/// would otherwise end the label or be interpreted as markup
+fn mermaid_label(label: &str) -> String {
+    let mut out = String::with_capacity(label.len());
+    for c in label.chars() {
+        match c {
+            '#' => out.push_str("#35;"),
+            '"' => out.push_str("#quot;"),
+            '<' => out.push_str("#lt;"),
+            '>' => out.push_str("#gt;"),
+            '&' => out.push_str("#amp;"),
+            '\n' => out.push_str("<br>"),
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Export a subtree as a Mermaid `graph TD` flowchart, labelling each node with its data. Nodes
+/// are named by their ID, so the output of trees sharing IDs can be combined.
+pub fn to_mermaid<R>(node: &R) -> String
+where
+    R: TreeNodeRef,
+    <R::Inner as TreeNode>::Data: std::fmt::Display,
+{
+    let mut out = String::from("graph TD\n");
+
+    walk(node, |node, _| {
+        let inner = node.node();
+        let id = mermaid_id(inner.id());
+        let label = mermaid_label(&inner.data().to_string());
+        let _ = writeln!(out, "    n{id}[\"{label}\"]");
+
+        if let Some(children) = inner.children() {
+            for child in children.iter() {
+                let _ = writeln!(out, "    n{id} --> n{}", mermaid_id(child.node().id()));
+            }
+        };
+    });
+
+    out
+}
+
+/// Export a subtree as a PlantUML work breakdown structure, with one line per node prefixed by
+/// one `*` per level of depth, and labelled with its data
+pub fn to_plantuml<R>(node: &R) -> String
+where
+    R: TreeNodeRef,
+    <R::Inner as TreeNode>::Data: std::fmt::Display,
+{
+    let mut out = String::from("@startwbs\n");
+
+    walk(node, |node, depth| {
+        let label = node.node().data().to_string().replace('\n', " ");
+        let _ = writeln!(out, "{} {label}", "*".repeat(depth + 1));
+    });
+
+    out.push_str("@endwbs\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{test::test_tree_vec, TreeNode as _, TreeNodeRef as _};
+
+    use super::{mermaid_id, mermaid_label, to_mermaid, to_plantuml};
+
+    #[traced_test]
+    #[test]
+    fn diagrams() {
+        let tree = test_tree_vec(vec![("a", vec!["1", "2"]), ("b", vec![])]);
+        let ids: Vec<_> = tree
+            .root()
+            .into_iter()
+            .map(|node| node.node().id())
+            .collect();
+
+        let mermaid = to_mermaid(tree.root_ref());
+        let expected = format!(
+            "graph TD\n    n{0}[\"root\"]\n    n{0} --> n{1}\n    n{0} --> n{4}\n    \
+             n{1}[\"a\"]\n    n{1} --> n{2}\n    n{1} --> n{3}\n    n{2}[\"1\"]\n    \
+             n{3}[\"2\"]\n    n{4}[\"b\"]\n",
+            ids[0], ids[1], ids[2], ids[3], ids[4]
+        );
+        assert_eq!(mermaid, expected);
+
+        assert_eq!(
+            to_plantuml(tree.root_ref()),
+            "@startwbs\n* root\n** a\n*** 1\n*** 2\n** b\n@endwbs\n"
+        );
+
+        // Subtrees are exported relative to the provided node
+        let a = tree.root().node().children().unwrap()[0].clone();
+        assert_eq!(to_plantuml(&a), "@startwbs\n* a\n** 1\n** 2\n@endwbs\n");
+    }
+
+    #[traced_test]
+    #[test]
+    fn mermaid_escaping() {
+        // Sharded IDs and UUIDs are mapped to valid identifiers
+        assert_eq!(mermaid_id(crate::ShardedId::from((3, 7))), "3_7");
+        assert_eq!(
+            mermaid_id("67e55044-10b1-426f-9247-bb680e5fe0c8"),
+            "67e55044_10b1_426f_9247_bb680e5fe0c8"
+        );
+
+        assert_eq!(
+            mermaid_label("a \"b\" #1 <c> & d\ne"),
+            "a #quot;b#quot; #35;1 #lt;c#gt; #amp; d<br>e"
+        );
+    }
+}
@@ -56,7 +56,7 @@ impl Ord for Edit {
     }
 }
 
-pub fn _vec_apply_edits<T: Copy>(dest: &mut Vec<T>, source: &Vec<T>, edits: Vec<Edit>) {
+pub fn _vec_apply_edits<T: Copy>(dest: &mut Vec<T>, source: &[T], edits: Vec<Edit>) {
     for edit in edits {
         match edit {
             Edit::Insert {
@@ -77,7 +77,7 @@ pub fn _vec_apply_edits<T: Copy>(dest: &mut Vec<T>, source: &Vec<T>, edits: Vec<
 }
 
 /// Find minimum edits required to dest to make it equal to source
-pub fn vec_edits<T>(dest: &Vec<T>, source: &Vec<T>) -> Vec<Edit>
+pub fn vec_edits<T>(dest: &[T], source: &[T]) -> Vec<Edit>
 where
     T: std::fmt::Debug + PartialEq,
 {
@@ -87,11 +87,11 @@ where
     // Matrix of edit distances
     let mut dist = vec![vec![0u64; source_len + 1]; dest_len + 1];
 
-    for i in 0..=dest_len {
-        dist[i][0] = i as u64;
+    for (i, row) in dist.iter_mut().enumerate() {
+        row[0] = i as u64;
     }
-    for j in 0..=source_len {
-        dist[0][j] = j as u64;
+    for (j, cell) in dist[0].iter_mut().enumerate() {
+        *cell = j as u64;
     }
 
     // Fill the matrix
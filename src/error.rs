@@ -0,0 +1,99 @@
+//! Crate-wide error type.
+
+use crate::{BuildError, IncompatibleHashVersion};
+
+/// Errors returned by fallible tree operations
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The tree does not have a root node
+    EmptyTree,
+
+    /// The tree was created without an ID generator
+    NoGenerator,
+
+    /// The operation requires a node with a parent, but was given the root node
+    NoParent,
+
+    /// A node could not be found in the children of its parent
+    NodeNotFound,
+
+    /// A node cannot be moved into its own subtree
+    CyclicMove,
+
+    /// A node was mutated since the expected generation was observed
+    StaleNode { expected: u64, found: u64 },
+
+    /// A child index was beyond the number of children of a node
+    IndexOutOfBounds { index: usize, len: usize },
+
+    /// A tree could not be built
+    Build(BuildError),
+
+    /// Hashes were produced by an incompatible hash version
+    HashVersion(IncompatibleHashVersion),
+
+    /// The event listener registry is unusable, as a listener panicked while it was locked
+    ListenersPoisoned,
+
+    /// Events requested for replay are no longer retained in the event history, which starts
+    /// at the provided sequence number
+    HistoryTruncated { oldest: u64 },
+
+    /// A binary snapshot could not be encoded or decoded
+    Snapshot(String),
+
+    /// A sync message could not be encoded or decoded, or was unexpected
+    Sync(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::EmptyTree => write!(f, "tree is empty"),
+            Error::NoGenerator => write!(f, "tree has no ID generator"),
+            Error::NoParent => write!(f, "node has no parent"),
+            Error::NodeNotFound => write!(f, "node not found"),
+            Error::CyclicMove => write!(f, "node cannot be moved into its own subtree"),
+            Error::StaleNode { expected, found } => {
+                write!(
+                    f,
+                    "node generation {found} does not match expected {expected}"
+                )
+            }
+            Error::IndexOutOfBounds { index, len } => {
+                write!(f, "child index {index} out of bounds for {len} children")
+            }
+            Error::Build(e) => write!(f, "build error: {e}"),
+            Error::HashVersion(e) => write!(f, "{e}"),
+            Error::ListenersPoisoned => write!(f, "event listener registry is poisoned"),
+            Error::HistoryTruncated { oldest } => {
+                write!(f, "event history starts at sequence {oldest}")
+            }
+            Error::Snapshot(e) => write!(f, "snapshot error: {e}"),
+            Error::Sync(e) => write!(f, "sync error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Build(e) => Some(e),
+            Error::HashVersion(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<BuildError> for Error {
+    fn from(e: BuildError) -> Self {
+        Error::Build(e)
+    }
+}
+
+impl From<IncompatibleHashVersion> for Error {
+    fn from(e: IncompatibleHashVersion) -> Self {
+        Error::HashVersion(e)
+    }
+}
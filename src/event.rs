@@ -1,6 +1,14 @@
-use crate::TreeNodeRef;
+use std::collections::HashMap;
 
-#[derive(Debug)]
+use crate::{TreeNode, TreeNodeRef};
+
+#[cfg(feature = "stream")]
+mod stream;
+
+#[cfg(feature = "stream")]
+pub use stream::TreeEventStream;
+
+#[derive(Clone)]
 pub enum TreeEvent<R>
 where
     R: TreeNodeRef,
@@ -9,14 +17,17 @@ where
     NodeRemoved { node: R },
 
     /// Node data was replaced. The node retains it's original ID and inner node container,
-    /// but the inner data was replaced.
-    NodeReplaced { node: R },
+    /// but the inner data was replaced. The previous data is included.
+    NodeReplaced {
+        node: R,
+        previous: <R::Inner as TreeNode>::Data,
+    },
 
     /// A subtree was inserted at this node_id
     SubtreeInserted { node: R },
 
-    /// Single child removed from a parent
-    ChildRemoved { parent: R, index: usize },
+    /// Single child removed from a parent, which was at index
+    ChildRemoved { parent: R, index: usize, node: R },
 
     /// Multiple children removed from a parent
     ChildrenRemoved { parent: R, children: Vec<R> },
@@ -24,9 +35,304 @@ where
     /// Multiple children added to a parent
     ChildrenAdded { parent: R, children: Vec<R> },
 
-    /// Child node replaced
-    ChildReplaced { parent: R, index: usize },
+    /// Child node replaced, along with the previous child at index, unless it was replaced
+    /// with itself
+    ChildReplaced {
+        parent: R,
+        index: usize,
+        previous: Option<R>,
+    },
 
     /// Child inserted into a parent at index
     ChildInserted { parent: R, index: usize },
+
+    /// Children of a parent were reordered
+    ChildrenReordered { parent: R },
+
+    /// Node moved from one parent to another, and inserted at index of the new parent
+    NodeMoved {
+        node: R,
+        old_parent: R,
+        new_parent: R,
+        index: usize,
+    },
+
+    /// Events sent by the mutations within [`Tree::batch`](crate::Tree::batch), delivered
+    /// together when the batch ends
+    Batch(Vec<TreeEvent<R>>),
+
+    /// Summary of the events suppressed within
+    /// [`Tree::silent_summarized`](crate::Tree::silent_summarized), with the number of events
+    /// that were not delivered. Any node of the tree may have changed.
+    Suppressed { count: usize },
+}
+
+// Node data isn't required to implement Debug, so previous data is omitted
+impl<R> std::fmt::Debug for TreeEvent<R>
+where
+    R: TreeNodeRef,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TreeEvent::NodeRemoved { node } => {
+                f.debug_struct("NodeRemoved").field("node", node).finish()
+            }
+            TreeEvent::NodeReplaced { node, .. } => f
+                .debug_struct("NodeReplaced")
+                .field("node", node)
+                .finish_non_exhaustive(),
+            TreeEvent::SubtreeInserted { node } => f
+                .debug_struct("SubtreeInserted")
+                .field("node", node)
+                .finish(),
+            TreeEvent::ChildRemoved {
+                parent,
+                index,
+                node,
+            } => f
+                .debug_struct("ChildRemoved")
+                .field("parent", parent)
+                .field("index", index)
+                .field("node", node)
+                .finish(),
+            TreeEvent::ChildrenRemoved { parent, children } => f
+                .debug_struct("ChildrenRemoved")
+                .field("parent", parent)
+                .field("children", children)
+                .finish(),
+            TreeEvent::ChildrenAdded { parent, children } => f
+                .debug_struct("ChildrenAdded")
+                .field("parent", parent)
+                .field("children", children)
+                .finish(),
+            TreeEvent::ChildReplaced {
+                parent,
+                index,
+                previous,
+            } => f
+                .debug_struct("ChildReplaced")
+                .field("parent", parent)
+                .field("index", index)
+                .field("previous", previous)
+                .finish(),
+            TreeEvent::ChildInserted { parent, index } => f
+                .debug_struct("ChildInserted")
+                .field("parent", parent)
+                .field("index", index)
+                .finish(),
+            TreeEvent::ChildrenReordered { parent } => f
+                .debug_struct("ChildrenReordered")
+                .field("parent", parent)
+                .finish(),
+            TreeEvent::NodeMoved {
+                node,
+                old_parent,
+                new_parent,
+                index,
+            } => f
+                .debug_struct("NodeMoved")
+                .field("node", node)
+                .field("old_parent", old_parent)
+                .field("new_parent", new_parent)
+                .field("index", index)
+                .finish(),
+            TreeEvent::Batch(events) => f.debug_tuple("Batch").field(events).finish(),
+            TreeEvent::Suppressed { count } => {
+                f.debug_struct("Suppressed").field("count", count).finish()
+            }
+        }
+    }
+}
+
+/// The kind of a [`TreeEvent`], without its payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TreeEventKind {
+    NodeRemoved,
+    NodeReplaced,
+    SubtreeInserted,
+    ChildRemoved,
+    ChildrenRemoved,
+    ChildrenAdded,
+    ChildReplaced,
+    ChildInserted,
+    ChildrenReordered,
+    NodeMoved,
+    Batch,
+    Suppressed,
+}
+
+impl<R> TreeEvent<R>
+where
+    R: TreeNodeRef,
+{
+    /// Get the kind of this event
+    pub fn kind(&self) -> TreeEventKind {
+        match self {
+            TreeEvent::NodeRemoved { .. } => TreeEventKind::NodeRemoved,
+            TreeEvent::NodeReplaced { .. } => TreeEventKind::NodeReplaced,
+            TreeEvent::SubtreeInserted { .. } => TreeEventKind::SubtreeInserted,
+            TreeEvent::ChildRemoved { .. } => TreeEventKind::ChildRemoved,
+            TreeEvent::ChildrenRemoved { .. } => TreeEventKind::ChildrenRemoved,
+            TreeEvent::ChildrenAdded { .. } => TreeEventKind::ChildrenAdded,
+            TreeEvent::ChildReplaced { .. } => TreeEventKind::ChildReplaced,
+            TreeEvent::ChildInserted { .. } => TreeEventKind::ChildInserted,
+            TreeEvent::ChildrenReordered { .. } => TreeEventKind::ChildrenReordered,
+            TreeEvent::NodeMoved { .. } => TreeEventKind::NodeMoved,
+            TreeEvent::Batch(_) => TreeEventKind::Batch,
+            TreeEvent::Suppressed { .. } => TreeEventKind::Suppressed,
+        }
+    }
+
+    /// Get the node this event concerns. For events describing a change to the children of a
+    /// node, this is the parent. Batches and summaries don't concern a single node.
+    pub fn node(&self) -> Option<&R> {
+        match self {
+            TreeEvent::NodeRemoved { node }
+            | TreeEvent::NodeReplaced { node, .. }
+            | TreeEvent::SubtreeInserted { node }
+            | TreeEvent::NodeMoved { node, .. } => Some(node),
+            TreeEvent::ChildRemoved { parent, .. }
+            | TreeEvent::ChildrenRemoved { parent, .. }
+            | TreeEvent::ChildrenAdded { parent, .. }
+            | TreeEvent::ChildReplaced { parent, .. }
+            | TreeEvent::ChildInserted { parent, .. }
+            | TreeEvent::ChildrenReordered { parent } => Some(parent),
+            TreeEvent::Batch(_) | TreeEvent::Suppressed { .. } => None,
+        }
+    }
+
+    /// Drop events made redundant by a later event of the same kind for the same node. Only
+    /// [`TreeEvent::NodeReplaced`] and [`TreeEvent::ChildrenReordered`] carry no payload beyond
+    /// their node, so only the last of these is kept for each node. A kept replacement reports
+    /// the data from before the first replacement.
+    pub(crate) fn coalesce(events: Vec<TreeEvent<R>>) -> Vec<TreeEvent<R>> {
+        let mut kept: Vec<Option<TreeEvent<R>>> = Vec::with_capacity(events.len());
+        let mut last = HashMap::new();
+
+        for mut event in events {
+            let key = match &event {
+                TreeEvent::NodeReplaced { node, .. }
+                | TreeEvent::ChildrenReordered { parent: node } => {
+                    Some((event.kind(), node.node().id()))
+                }
+                _ => None,
+            };
+
+            if let Some(key) = key {
+                let earlier = last
+                    .insert(key, kept.len())
+                    .and_then(|index| kept[index].take());
+
+                if let (
+                    Some(TreeEvent::NodeReplaced {
+                        previous: first, ..
+                    }),
+                    TreeEvent::NodeReplaced { previous, .. },
+                ) = (earlier, &mut event)
+                {
+                    *previous = first;
+                }
+            }
+
+            kept.push(Some(event));
+        }
+
+        kept.into_iter().flatten().collect()
+    }
+}
+
+/// Restricts the events delivered to a listener registered with
+/// [`Tree::on_event_filtered`](crate::Tree::on_event_filtered). An event is delivered if it
+/// passes every restriction, and the default filter passes every event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventFilter<Id> {
+    kinds: Option<Vec<TreeEventKind>>,
+    node: Option<Id>,
+    subtree: Option<Id>,
+}
+
+impl<Id> Default for EventFilter<Id> {
+    fn default() -> Self {
+        Self {
+            kinds: None,
+            node: None,
+            subtree: None,
+        }
+    }
+}
+
+impl<Id> EventFilter<Id>
+where
+    Id: PartialEq + Copy,
+{
+    /// Create a filter which passes every event
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pass events of the provided kind. May be called repeatedly to pass several kinds.
+    pub fn kind(mut self, kind: TreeEventKind) -> Self {
+        self.kinds.get_or_insert_with(Vec::new).push(kind);
+        self
+    }
+
+    /// Pass events concerning the node with the provided ID. See [`TreeEvent::node`].
+    pub fn node(mut self, id: Id) -> Self {
+        self.node = Some(id);
+        self
+    }
+
+    /// Pass events concerning the node with the provided ID or any of its descendants, at the
+    /// time the event is sent. Removed nodes are already detached when their events are sent,
+    /// so only match the subtree when it is rooted at the removed node.
+    pub fn subtree(mut self, id: Id) -> Self {
+        self.subtree = Some(id);
+        self
+    }
+
+    /// Check if an event passes this filter. A [`TreeEvent::Batch`] passes if any of its events
+    /// pass, or if batches are passed by kind. A [`TreeEvent::Suppressed`] summary may concern
+    /// any node, so passes unless excluded by kind.
+    pub fn matches<R>(&self, event: &TreeEvent<R>) -> bool
+    where
+        R: TreeNodeRef,
+        R::Inner: TreeNode<Id = Id>,
+    {
+        if let TreeEvent::Batch(events) = event {
+            let batches = self
+                .kinds
+                .as_ref()
+                .is_some_and(|kinds| kinds.contains(&TreeEventKind::Batch));
+            return batches || events.iter().any(|event| self.matches(event));
+        }
+
+        if let Some(kinds) = &self.kinds {
+            if !kinds.contains(&event.kind()) {
+                return false;
+            }
+        }
+
+        let Some(node) = event.node() else {
+            return matches!(event, TreeEvent::Suppressed { .. });
+        };
+
+        if let Some(id) = self.node {
+            if node.node().id() != id {
+                return false;
+            }
+        }
+
+        if let Some(root) = self.subtree {
+            let mut next = Some(node.clone());
+            while let Some(node) = next {
+                if node.node().id() == root {
+                    return true;
+                }
+                next = node.node().parent().cloned();
+            }
+            return false;
+        }
+
+        true
+    }
 }
@@ -0,0 +1,157 @@
+//! Tree events as an async [`Stream`], enabled with the `stream` feature.
+
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+use futures_core::Stream;
+use parking_lot::Mutex;
+
+use crate::{
+    index::TreeIndex, noderef::NodeRefId, Error, IndexedTree, Tree, TreeEvent, TreeEventListener,
+    TreeNode, TreeNodeRef, UniqueGenerator,
+};
+
+/// Events received by the listener, and the task waiting for them
+struct Queue<R>
+where
+    R: TreeNodeRef,
+{
+    events: VecDeque<TreeEvent<R>>,
+    waker: Option<Waker>,
+}
+
+impl<R> Queue<R>
+where
+    R: TreeNodeRef,
+{
+    /// Create an empty queue, shared by a stream and its listener
+    fn shared() -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Queue {
+            events: VecDeque::new(),
+            waker: None,
+        }))
+    }
+}
+
+/// Stream of the events sent by a tree, returned by [`Tree::event_stream`]. Events are queued
+/// until polled. The stream never ends; drop it to unsubscribe.
+pub struct TreeEventStream<R>
+where
+    R: TreeNodeRef + 'static,
+{
+    queue: Arc<Mutex<Queue<R>>>,
+    _listener: TreeEventListener<R>,
+}
+
+impl<R> Stream for TreeEventStream<R>
+where
+    R: TreeNodeRef + 'static,
+{
+    type Item = TreeEvent<R>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut queue = self.queue.lock();
+        match queue.events.pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => {
+                queue.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<R, G> Tree<R, G>
+where
+    R: TreeNodeRef + Send + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    <R::Inner as TreeNode>::Data: Send,
+{
+    /// Subscribe to the events sent by this tree as a [`Stream`], for use in async
+    /// applications. See [`Tree::on_event`].
+    pub fn event_stream(&mut self) -> Result<TreeEventStream<R>, Error> {
+        let queue = Queue::shared();
+        let listener = self.on_event(sender(queue.clone()))?;
+
+        Ok(TreeEventStream {
+            queue,
+            _listener: listener,
+        })
+    }
+}
+
+impl<R, G, I> IndexedTree<R, G, I>
+where
+    R: TreeNodeRef + Send + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+    <R::Inner as TreeNode>::Data: Send,
+{
+    /// Subscribe to the events sent by this tree as a [`Stream`]. See [`Tree::event_stream`].
+    pub fn event_stream(&mut self) -> Result<TreeEventStream<R>, Error> {
+        let queue = Queue::shared();
+        let listener = self.on_event(sender(queue.clone()))?;
+
+        Ok(TreeEventStream {
+            queue,
+            _listener: listener,
+        })
+    }
+}
+
+/// Listener callback which fills a queue, and wakes the task waiting for events
+fn sender<R>(queue: Arc<Mutex<Queue<R>>>) -> impl FnMut(&TreeEvent<R>) + Send + 'static
+where
+    R: TreeNodeRef + Send + 'static,
+    <R::Inner as TreeNode>::Data: Send,
+{
+    move |event| {
+        let mut queue = queue.lock();
+        queue.events.push_back(event.clone());
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use futures_core::Stream;
+    use tracing_test::traced_test;
+
+    use crate::{
+        test::{test_tree_node, TestNode},
+        TreeEventKind, TreeNode as _, TreeNodeRef as _,
+    };
+
+    #[traced_test]
+    #[test]
+    fn event_stream() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![])]);
+        let mut stream = tree.event_stream().unwrap();
+        let mut cx = Context::from_waker(Waker::noop());
+
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        tree.set_data(a, "b").unwrap();
+
+        match Pin::new(&mut stream).poll_next(&mut cx) {
+            Poll::Ready(Some(event)) => {
+                assert_eq!(event.kind(), TreeEventKind::NodeReplaced);
+                assert_eq!(event.node().unwrap().node().id(), a);
+            }
+            _ => panic!("expected an event"),
+        }
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+    }
+}
@@ -1,31 +1,539 @@
-use std::hash::Hasher;
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+};
 
-use xxhash_rust::xxh64::Xxh64;
+use xxhash_rust::{xxh3::Xxh3, xxh64::Xxh64};
 
-use crate::{TreeNode as _, TreeNodeRef};
+use crate::{node::internal::NodeInternal as _, noderef::NodeRefId, TreeNode, TreeNodeRef};
 
-/// Recursively update the subtree hashes, starting from an inner node down to the root
-pub fn update_subtree_hash<R>(mut node: R)
+/// Content hash of node data, used for subtree hashing and diffing.
+///
+/// Implemented for all [`Hash`] types. Data which can't implement `Hash`, such as types
+/// containing floats, can implement `Fingerprint` directly to choose what is hashed.
+///
+/// ```
+/// use std::hash::{Hash as _, Hasher};
+///
+/// #[derive(Debug, Clone)]
+/// struct Point(f64, f64);
+///
+/// impl arbutus::Fingerprint for Point {
+///     fn fingerprint<H: Hasher>(&self, state: &mut H) {
+///         self.0.to_bits().hash(state);
+///         self.1.to_bits().hash(state);
+///     }
+/// }
+/// ```
+pub trait Fingerprint {
+    /// Feed the content of this value into the hasher
+    fn fingerprint<H: Hasher>(&self, state: &mut H);
+}
+
+impl<T> Fingerprint for T
+where
+    T: Hash + ?Sized,
+{
+    fn fingerprint<H: Hasher>(&self, state: &mut H) {
+        self.hash(state)
+    }
+}
+
+/// Version of the subtree hash specification.
+///
+/// Subtree hashes are guaranteed not to change between crate releases supporting the same
+/// version, given the same node data and [`Fingerprint`] implementation of that data. Peers exchanging
+/// patches or snapshots should verify their hash versions match before comparing hashes.
+///
+/// # V1
+///
+/// All hashes are XXH64 with a seed of 0, fed through [`std::hash::Hasher`].
+///
+/// * The node hash is computed over the number of children of the node as a `usize`,
+///   followed by the node data.
+/// * The subtree hash of a node is computed over the subtree hash of each child in order
+///   as a `u64`, followed by the node hash input.
+///
+/// Integers are written in native byte order, so hashes are only comparable between
+/// peers with the same endianness and pointer width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum HashVersion {
+    V1,
+}
+
+impl HashVersion {
+    /// Hash version implemented by this release
+    pub const CURRENT: HashVersion = HashVersion::V1;
+
+    /// Numeric identifier of this version for encoding in snapshots and patches
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            HashVersion::V1 => 1,
+        }
+    }
+
+    /// Get the version from a numeric identifier
+    pub fn from_u8(version: u8) -> Option<Self> {
+        match version {
+            1 => Some(HashVersion::V1),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for HashVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "V{}", self.as_u8())
+    }
+}
+
+/// Error returned when hashes produced by a different [`HashVersion`] are encountered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IncompatibleHashVersion {
+    pub expected: HashVersion,
+    pub found: HashVersion,
+}
+
+impl std::fmt::Display for IncompatibleHashVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "incompatible hash version {}, expected {}",
+            self.found, self.expected
+        )
+    }
+}
+
+impl std::error::Error for IncompatibleHashVersion {}
+
+/// Compute a 128 bit XXH3 digest of a subtree, over the same input as the V1 subtree hash.
+///
+/// Subtree hashes are 64 bits, so unequal subtrees can collide with a probability which
+/// becomes significant for trees of many millions of nodes. The digest is computed on demand,
+/// visiting every node of the subtree, for comparisons where a collision is unacceptable.
+pub fn subtree_digest128<R>(root: &R) -> u128
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+{
+    let nodes: Vec<R> = root
+        .clone()
+        .into_iter()
+        .map(|node| (*node).clone())
+        .collect();
+
+    // Pre-order reversed visits every child before its parent, and the last child first, so the
+    // digests of the children of a node are on top of the stack in order. Digests are matched to
+    // nodes by traversal position rather than ID, so duplicate IDs can't mix up subtrees.
+    let mut digests: Vec<u128> = Vec::new();
+    for node in nodes.into_iter().rev() {
+        let mut hasher = Xxh3::new();
+
+        for _ in 0..node.node().num_children() {
+            hasher.write_u128(digests.pop().expect("child digest"));
+        }
+
+        node.hash(&mut hasher);
+        digests.push(hasher.digest128());
+    }
+
+    digests.pop().expect("root digest")
+}
+
+/// Compute a hash of the data in a subtree, ignoring its structure.
+///
+/// The subtree hash of a node covers the shape of its subtree, including the number of children
+/// of every node and their order. The content hash only covers the multiset of data values, so
+/// subtrees holding the same values arranged differently have equal content hashes.
+pub fn content_hash<R>(root: &R) -> u64
 where
     R: TreeNodeRef + std::fmt::Debug + 'static,
 {
+    // Addition is commutative, unlike a streaming hasher, and unlike XOR doesn't cancel out
+    // repeated values
+    let (count, sum) = root
+        .clone()
+        .into_iter()
+        .fold((0u64, 0u64), |(count, sum), node| {
+            (count + 1, sum.wrapping_add(node.node().data_xxhash()))
+        });
+
     let mut hasher = Xxh64::new(0);
+    hasher.write_u64(count);
+    hasher.write_u64(sum);
+    hasher.finish()
+}
+
+/// Count the nodes in the subtree of a node from the subtree sizes of its children
+pub(crate) fn subtree_size<N>(node: &N) -> usize
+where
+    N: TreeNode,
+{
+    1 + node
+        .children()
+        .iter()
+        .flat_map(|children| children.iter())
+        .map(|child| child.node().subtree_size())
+        .sum::<usize>()
+}
+
+/// Recompute the subtree hash and size of a node from the current values of its children,
+/// seeding the hasher with the hash context of the tree
+fn rehash_node<R>(node: &mut R, context: u64)
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+{
+    let mut hasher = Xxh64::new(context);
+    let mut size = 1;
 
     if let Some(children) = node.node().children() {
         for child in children.iter() {
-            let hash = child.node().get_subtree_hash();
-            hasher.write_u64(hash);
+            let child = child.node();
+            hasher.write_u64(child.get_subtree_hash());
+            size += child.subtree_size();
         }
     }
 
     node.hash(&mut hasher);
 
     let new_hash = hasher.finish();
+    let mut inner = node.node_mut();
+    inner.set_subtree_hash(new_hash);
+    inner.set_subtree_size(size);
+}
 
-    node.node_mut().set_subtree_hash(new_hash);
+/// Update the subtree hashes, starting from an inner node up to the root
+pub fn update_subtree_hash<R>(node: R)
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+{
+    update_subtree_hash_with(node, 0)
+}
+
+/// Update the subtree hashes from an inner node up to the root, with a hash context
+pub(crate) fn update_subtree_hash_with<R>(node: R, context: u64)
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+{
+    let mut next = Some(node);
+
+    while let Some(mut node) = next {
+        rehash_node(&mut node, context);
+        next = node.node().parent().cloned();
+    }
+}
+
+/// Update the subtree hashes of many changed nodes and their ancestors. Each shared ancestor is
+/// rehashed once, after all of its changed descendants, rather than once per changed node as
+/// with repeated calls to [`update_subtree_hash`].
+pub fn update_subtree_hashes<R, I>(nodes: I)
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    I: IntoIterator<Item = R>,
+{
+    update_subtree_hashes_with(nodes, 0)
+}
+
+/// Update the subtree hashes of many changed nodes and their ancestors, with a hash context
+pub(crate) fn update_subtree_hashes_with<R, I>(nodes: I, context: u64)
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    I: IntoIterator<Item = R>,
+{
+    // Nodes to rehash, with the number of their children which must be rehashed first
+    let mut pending: HashMap<NodeRefId<R>, (R, usize)> = HashMap::new();
+
+    for node in nodes {
+        let id = node.node().id();
+        if pending.contains_key(&id) {
+            continue;
+        }
+        pending.insert(id, (node.clone(), 0));
+
+        // Walk up until reaching an ancestor already on the path of another node
+        let mut next = node.node().parent().cloned();
+        while let Some(parent) = next {
+            let parent_id = parent.node().id();
+            if let Some((_, waiting)) = pending.get_mut(&parent_id) {
+                *waiting += 1;
+                break;
+            }
+            next = parent.node().parent().cloned();
+            pending.insert(parent_id, (parent, 1));
+        }
+    }
+
+    let mut ready: Vec<R> = pending
+        .values()
+        .filter(|(_, waiting)| *waiting == 0)
+        .map(|(node, _)| node.clone())
+        .collect();
+
+    while let Some(mut node) = ready.pop() {
+        rehash_node(&mut node, context);
+
+        let parent = node.node().parent().cloned();
+        if let Some(parent) = parent {
+            let parent_id = parent.node().id();
+            if let Some((_, waiting)) = pending.get_mut(&parent_id) {
+                *waiting -= 1;
+                if *waiting == 0 {
+                    ready.push(parent);
+                }
+            }
+        }
+    }
+}
+
+/// Recompute the subtree hash of every node in a subtree, visiting children before their parent.
+/// Ancestors of the subtree root are not updated.
+pub(crate) fn rehash_subtree<R>(root: R, context: u64)
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+{
+    let nodes: Vec<R> = root.into_iter().map(|node| (*node).clone()).collect();
+
+    // Pre-order reversed visits every child before its parent
+    for mut node in nodes.into_iter().rev() {
+        rehash_node(&mut node, context);
+    }
+}
+
+/// Recompute the subtree hash of every node in a subtree like [`rehash_subtree`], hashing the
+/// nodes of each depth in parallel. Nodes are visited deepest level first, so the children of a
+/// node are always hashed before it.
+#[cfg(feature = "rayon")]
+pub(crate) fn rehash_subtree_parallel<R>(root: R, context: u64)
+where
+    R: TreeNodeRef + std::fmt::Debug + Send + Sync + 'static,
+{
+    use rayon::prelude::*;
+
+    let mut levels: Vec<Vec<R>> = Vec::new();
+    let mut level = vec![root];
+
+    while !level.is_empty() {
+        let next: Vec<R> = level
+            .iter()
+            .flat_map(|node| {
+                node.node()
+                    .children()
+                    .map(|children| children.iter().cloned().collect::<Vec<_>>())
+                    .unwrap_or_default()
+            })
+            .collect();
+        levels.push(level);
+        level = next;
+    }
+
+    for level in levels.into_iter().rev() {
+        level
+            .into_par_iter()
+            .for_each(|mut node| rehash_node(&mut node, context));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::{Hash as _, Hasher};
+
+    use crate::{
+        node::internal::NodeInternal as _,
+        test::{test_tree_node, TestNode},
+        TreeBuilder, TreeDiff, TreeNode as _, TreeNodeRef as _,
+    };
+
+    use super::{
+        content_hash, subtree_digest128, update_subtree_hash, update_subtree_hashes, Fingerprint,
+        HashVersion,
+    };
+
+    /// Fixture corpus of trees and their V1 root subtree hashes. These values must never
+    /// change while [`HashVersion::CURRENT`] is V1.
+    fn fixtures() -> Vec<(Vec<TestNode>, u64)> {
+        vec![
+            (vec![], 0x2B7DD2C3A29E05F1),
+            (vec![TestNode("a", vec![])], 0x16F83D916AA839D6),
+            (
+                vec![
+                    TestNode("a", vec![TestNode("1", vec![])]),
+                    TestNode("b", vec![]),
+                ],
+                0xECD7402E8B0B2893,
+            ),
+            (
+                vec![TestNode(
+                    "a",
+                    vec![TestNode(
+                        "b",
+                        vec![TestNode("1", vec![TestNode("x", vec![])])],
+                    )],
+                )],
+                0x070BED025497A7F7,
+            ),
+        ]
+    }
+
+    #[test]
+    fn v1_fixtures() {
+        assert_eq!(HashVersion::CURRENT, HashVersion::V1);
+
+        for (nodes, expected) in fixtures() {
+            let tree = test_tree_node(nodes);
+            assert_eq!(tree.root().node().get_subtree_hash(), expected);
+
+            // Recomputing the hashes from the leaves must produce the same result as the builder
+            for leaf in tree.leaves() {
+                update_subtree_hash(leaf.clone());
+            }
+            assert_eq!(tree.root().node().get_subtree_hash(), expected);
+        }
+    }
+
+    #[test]
+    fn content() {
+        let nested = test_tree_node(vec![
+            TestNode("a", vec![TestNode("b", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+        let flat = test_tree_node(vec![
+            TestNode("b", vec![]),
+            TestNode("b", vec![]),
+            TestNode("a", vec![]),
+        ]);
+        let fewer = test_tree_node(vec![TestNode("a", vec![]), TestNode("b", vec![])]);
+
+        assert_ne!(nested, flat);
+        assert_eq!(
+            content_hash(nested.root_ref()),
+            content_hash(flat.root_ref())
+        );
+        assert_eq!(nested.content_hash(), flat.content_hash());
+        assert_ne!(nested.content_hash(), fewer.content_hash());
+
+        // Subtrees are hashed on their own
+        let a = nested.root().node().children().unwrap()[0].clone();
+        let leaf = test_tree_node(vec![TestNode("b", vec![])]);
+        assert_ne!(content_hash(&a), content_hash(leaf.root_ref()));
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Weight(f64);
+
+    impl Fingerprint for Weight {
+        fn fingerprint<H: Hasher>(&self, state: &mut H) {
+            self.0.to_bits().hash(state);
+        }
+    }
+
+    #[test]
+    fn fingerprint() {
+        let weights = |weights: &'static [f64]| {
+            TreeBuilder::<Weight>::new()
+                .root_ok(Weight(0.0), |root| {
+                    for weight in weights {
+                        root.child_ok(Weight(*weight), |_| {});
+                    }
+                })
+                .done_indexed()
+                .unwrap()
+                .unwrap()
+        };
+
+        let mut a = weights(&[0.5, 1.5]);
+        let b = weights(&[0.5, 2.5]);
+        assert_eq!(a, weights(&[0.5, 1.5]));
+        assert_ne!(a, b);
+
+        let mut diff = TreeDiff::new(a.root(), b.root());
+        diff.diff().patch_tree(&mut a);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn digest128() {
+        let tree = test_tree_node(vec![TestNode("a", vec![TestNode("1", vec![])])]);
+        let same = test_tree_node(vec![TestNode("a", vec![TestNode("1", vec![])])]);
+        let other = test_tree_node(vec![TestNode("a", vec![TestNode("2", vec![])])]);
+
+        assert_eq!(
+            subtree_digest128(&tree.root()),
+            subtree_digest128(&same.root())
+        );
+        assert_ne!(
+            subtree_digest128(&tree.root()),
+            subtree_digest128(&other.root())
+        );
+        assert!(tree.strong_eq(&same));
+        assert!(!tree.strong_eq(&other));
+
+        // Subtrees with duplicate IDs, as from deserialization, remain distinguished
+        type Node = crate::node::arc::Node<&'static str, u64>;
+        type NodeRef = crate::noderef::arc::NodeRef<Node>;
+        let subtree = |data, leaf| {
+            let leaf = NodeRef::new(Node::new(2, leaf, None));
+            NodeRef::new(Node::new(1, data, Some(vec![leaf])))
+        };
+        let duplicated =
+            |second| NodeRef::new(Node::new(0, "root", Some(vec![subtree("a", "x"), second])));
+        assert_ne!(
+            subtree_digest128(&duplicated(subtree("b", "y"))),
+            subtree_digest128(&duplicated(subtree("a", "x")))
+        );
+    }
+
+    #[test]
+    fn deep_update() {
+        type Node = crate::node::arc::Node<&'static str, u64>;
+        type NodeRef = crate::noderef::arc::NodeRef<Node>;
+
+        // Chain deep enough to overflow the stack if ancestors were updated recursively
+        let leaf = NodeRef::new(Node::new(0, "leaf", None));
+        let mut root = leaf.clone();
+        for id in 1..200_000 {
+            let parent = NodeRef::new(Node::new(id, "x", Some(vec![root.clone()])));
+            root.node_mut().set_parent(parent.clone());
+            root = parent;
+        }
+
+        update_subtree_hash(leaf.clone());
+        let hash = root.node().get_subtree_hash();
+        assert_eq!(root.node().subtree_size(), 200_000);
+
+        update_subtree_hashes([leaf.clone(), leaf]);
+        assert_eq!(root.node().get_subtree_hash(), hash);
+    }
+
+    #[test]
+    fn batched_update() {
+        let nodes = |leaf: &'static str| {
+            vec![
+                TestNode("a", vec![TestNode(leaf, vec![]), TestNode(leaf, vec![])]),
+                TestNode("b", vec![TestNode(leaf, vec![])]),
+            ]
+        };
+        let tree = test_tree_node(nodes("1"));
+
+        let changed: Vec<_> = tree
+            .root()
+            .into_iter()
+            .filter(|node| *node.node().data() == "1")
+            .map(|node| {
+                let mut node = (*node).clone();
+                *node.node_mut().data_mut() = "2";
+                node
+            })
+            .collect();
+        assert_eq!(changed.len(), 3);
+
+        update_subtree_hashes(changed);
+        assert_eq!(tree, test_tree_node(nodes("2")));
+    }
 
-    // If this node has a parent, recursively update the subtree hash of the parent
-    if let Some(parent) = node.node().parent() {
-        update_subtree_hash(parent.clone());
+    #[test]
+    fn version_roundtrip() {
+        let version = HashVersion::CURRENT;
+        assert_eq!(HashVersion::from_u8(version.as_u8()), Some(version));
+        assert_eq!(HashVersion::from_u8(0), None);
     }
 }
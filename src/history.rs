@@ -0,0 +1,498 @@
+//! Undo and redo of edits to an [`IndexedTree`].
+//!
+//! A [`History`] records the inverse of each edit as a list of [`Step`]s, which locate nodes by
+//! their path from the root and hold owned copies of the subtrees they restore. The steps are
+//! found by comparing the subtree hashes of the tree with a shadow copy of the tree as it was
+//! before the edit, so only the subtrees changed by an edit are copied. The shadow is then
+//! brought up to date by applying the forward steps to it.
+//!
+//! Nodes which were not affected by an edit retain their identity when it is undone. Restored
+//! subtrees are assigned new IDs.
+
+use std::collections::VecDeque;
+
+use tracing::{debug, warn};
+
+use crate::{
+    noderef::{boxed::Node, NodeRefId},
+    Error, IndexedTree, Tree, TreeNode, TreeNodeRef, UniqueGenerator,
+};
+
+/// Owned copy of a subtree of nodes of type `R`
+type Subtree<R> = Node<<<R as TreeNodeRef>::Inner as TreeNode>::Data, NodeRefId<R>>;
+
+/// Step converting one tree into another, with the node it applies to located by its path of
+/// child indexes from the root. The steps of one parent either change its children, or descend
+/// into them, so the paths of later steps are not shifted by earlier ones.
+enum Step<R>
+where
+    R: TreeNodeRef + 'static,
+{
+    /// Replace the data of the node
+    SetData {
+        path: Vec<usize>,
+        data: <R::Inner as TreeNode>::Data,
+    },
+
+    /// Remove `count` children of the node, starting at child `index`
+    RemoveChildren {
+        path: Vec<usize>,
+        index: usize,
+        count: usize,
+    },
+
+    /// Insert copies of subtrees as children of the node, starting at child `index`
+    InsertChildren {
+        path: Vec<usize>,
+        index: usize,
+        children: Vec<Subtree<R>>,
+    },
+}
+
+/// An [`IndexedTree`] with a bounded history of edits which can be undone and redone
+pub struct History<R, G = crate::IdGenerator>
+where
+    R: TreeNodeRef + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    tree: IndexedTree<R, G>,
+
+    // Copy of the tree as of the last recorded edit, undo or redo
+    shadow: IndexedTree<R, G>,
+
+    // Steps undoing each recorded edit, oldest first
+    undo: VecDeque<Vec<Step<R>>>,
+
+    // Steps redoing each undone edit, most recent last
+    redo: Vec<Vec<Step<R>>>,
+
+    // Maximum number of edits which can be undone
+    depth: usize,
+}
+
+impl<R, G> History<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    <<R as TreeNodeRef>::Inner as TreeNode>::Data: Clone,
+    R::Data: Clone,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    /// Wrap a tree, retaining up to `depth` edits which can be undone
+    pub fn new(tree: IndexedTree<R, G>, depth: usize) -> Self {
+        let shadow = Self::copy(&tree);
+        Self {
+            tree,
+            shadow,
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            depth,
+        }
+    }
+
+    /// Get the current tree
+    pub fn tree(&self) -> &IndexedTree<R, G> {
+        &self.tree
+    }
+
+    /// Unwrap the current tree, discarding the history
+    pub fn into_inner(self) -> IndexedTree<R, G> {
+        self.tree
+    }
+
+    /// Maximum number of edits which can be undone
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Set the maximum number of edits which can be undone, discarding the oldest edits
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        self.trim();
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Discard all recorded edits
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    /// Apply an edit to the tree, recording it so it can be undone. Edits which leave the
+    /// subtree hash of the root unchanged are not recorded. Recording an edit clears the redo
+    /// history. Edits which empty the tree, or add a root to an empty tree, can't be recorded,
+    /// and discard the history.
+    pub fn edit<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut IndexedTree<R, G>) -> T,
+    {
+        let hash = self.root_hash();
+
+        let value = f(&mut self.tree);
+
+        if self.root_hash() != hash {
+            match self.checkpoint() {
+                Ok(steps) => {
+                    debug!("Recording edit with {} steps", steps.len());
+                    self.undo.push_back(steps);
+                    self.redo.clear();
+                    self.trim();
+                }
+                Err(e) => {
+                    warn!("Discarding history, as the edit can't be recorded: {e}");
+                    self.reset();
+                }
+            }
+        }
+
+        value
+    }
+
+    /// Undo the most recent edit. Returns false if there is nothing to undo. If the edit can't
+    /// be undone, the tree may be partially restored, so the error is returned and the history
+    /// is discarded.
+    pub fn undo(&mut self) -> Result<bool, Error> {
+        let Some(steps) = self.undo.pop_back() else {
+            return Ok(false);
+        };
+
+        let steps = self.restore(steps)?;
+        self.redo.push(steps);
+
+        Ok(true)
+    }
+
+    /// Redo the most recently undone edit. Returns false if there is nothing to redo. Fails as
+    /// [`History::undo`] does.
+    pub fn redo(&mut self) -> Result<bool, Error> {
+        let Some(steps) = self.redo.pop() else {
+            return Ok(false);
+        };
+
+        let steps = self.restore(steps)?;
+        self.undo.push_back(steps);
+
+        Ok(true)
+    }
+
+    fn root_hash(&self) -> Option<u64> {
+        self.tree
+            .try_root_ref()
+            .ok()
+            .map(|root| root.node().get_subtree_hash())
+    }
+
+    /// Copy a tree, sharing its ID generator and hash context
+    fn copy(tree: &IndexedTree<R, G>) -> IndexedTree<R, G> {
+        IndexedTree::from_tree(tree.map(|data| data.clone()))
+    }
+
+    /// Apply recorded steps to the tree, returning the steps which revert them
+    fn restore(&mut self, steps: Vec<Step<R>>) -> Result<Vec<Step<R>>, Error> {
+        debug!("Restoring {} steps", steps.len());
+
+        let restored = Self::apply(&mut self.tree, steps).and_then(|()| self.checkpoint());
+        if restored.is_err() {
+            self.reset();
+        }
+        restored
+    }
+
+    /// Find the steps converting the tree back into the shadow, then bring the shadow up to
+    /// date with the tree
+    fn checkpoint(&mut self) -> Result<Vec<Step<R>>, Error> {
+        self.tree.flush_hashes();
+        let (root, shadow_root) = (self.tree.try_root()?, self.shadow.try_root()?);
+
+        let inverse = Self::steps(&root, &shadow_root);
+        let forward = Self::steps(&shadow_root, &root);
+        Self::apply(&mut self.shadow, forward)?;
+
+        Ok(inverse)
+    }
+
+    /// Discard the history, and copy the tree into the shadow
+    fn reset(&mut self) {
+        self.clear();
+        self.shadow = Self::copy(&self.tree);
+    }
+
+    /// Find the steps converting the subtree of `dest` into the subtree of `source`, descending
+    /// only into children with mismatched subtree hashes
+    fn steps(dest: &R, source: &R) -> Vec<Step<R>> {
+        let mut steps = Vec::new();
+        let mut stack = vec![(dest.clone(), source.clone(), Vec::new())];
+
+        while let Some((dest, source, path)) = stack.pop() {
+            let (dest, source) = (dest.node(), source.node());
+            if dest.get_subtree_hash() == source.get_subtree_hash() {
+                continue;
+            }
+
+            if dest.data_xxhash() != source.data_xxhash() {
+                steps.push(Step::SetData {
+                    path: path.clone(),
+                    data: source.data().clone(),
+                });
+            }
+
+            let dest_children = dest.children().map(|c| c.to_vec()).unwrap_or_default();
+            let source_children = source.children().map(|c| c.to_vec()).unwrap_or_default();
+            let hash = |child: &R| child.node().get_subtree_hash();
+
+            // Skip the children which match at the start and end of both lists
+            let prefix = dest_children
+                .iter()
+                .zip(&source_children)
+                .take_while(|(d, s)| hash(d) == hash(s))
+                .count();
+            let suffix = dest_children[prefix..]
+                .iter()
+                .rev()
+                .zip(source_children[prefix..].iter().rev())
+                .take_while(|(d, s)| hash(d) == hash(s))
+                .count();
+
+            let dest_changed = &dest_children[prefix..dest_children.len() - suffix];
+            let source_changed = &source_children[prefix..source_children.len() - suffix];
+
+            if dest_changed.len() == source_changed.len() {
+                for (offset, (d, s)) in dest_changed.iter().zip(source_changed).enumerate() {
+                    let mut child_path = path.clone();
+                    child_path.push(prefix + offset);
+                    stack.push((d.clone(), s.clone(), child_path));
+                }
+                continue;
+            }
+
+            if !dest_changed.is_empty() {
+                steps.push(Step::RemoveChildren {
+                    path: path.clone(),
+                    index: prefix,
+                    count: dest_changed.len(),
+                });
+            }
+            if !source_changed.is_empty() {
+                steps.push(Step::InsertChildren {
+                    path,
+                    index: prefix,
+                    children: source_changed
+                        .iter()
+                        .map(|child| Node::from_subtree(child.clone()))
+                        .collect(),
+                });
+            }
+        }
+
+        steps
+    }
+
+    /// Apply steps to a tree in order, stopping at the first which fails
+    fn apply(tree: &mut IndexedTree<R, G>, steps: Vec<Step<R>>) -> Result<(), Error> {
+        for step in steps {
+            match step {
+                Step::SetData { path, data } => {
+                    let id = Self::locate(tree, &path)?;
+                    tree.set_data(id, data)?;
+                }
+                Step::RemoveChildren { path, index, count } => {
+                    let id = Self::locate(tree, &path)?;
+                    for _ in 0..count {
+                        tree.remove_child(id, index)?;
+                    }
+                }
+                Step::InsertChildren {
+                    path,
+                    index,
+                    children,
+                } => {
+                    let id = Self::locate(tree, &path)?;
+                    let children = children
+                        .into_iter()
+                        .map(|child| Tree::<R, G>::from_boxed(child, None).try_root())
+                        .collect::<Result<Vec<_>, Error>>()?;
+                    tree.insert_children(id, index, children)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find the ID of the node at `path` from the root of a tree
+    fn locate(tree: &IndexedTree<R, G>, path: &[usize]) -> Result<NodeRefId<R>, Error> {
+        let mut node = tree.try_root()?;
+        for &index in path {
+            let child = node
+                .node()
+                .children()
+                .and_then(|children| children.get(index).cloned());
+            node = child.ok_or(Error::NodeNotFound)?;
+        }
+        let id = node.node().id();
+        Ok(id)
+    }
+
+    /// Discard the oldest edits beyond the depth of the history
+    fn trim(&mut self) {
+        while self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        index::TreeIndex as _,
+        test::{test_tree_node, TestNode},
+        Error, IndexedTree, Tree, TreeNode as _, TreeNodeRef as _,
+    };
+
+    use super::{History, Step};
+
+    #[traced_test]
+    #[test]
+    fn undo_redo() {
+        let tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+        let original = tree.root().node().get_subtree_hash();
+
+        let mut history = History::new(tree, 10);
+
+        history.edit(|tree| {
+            let root = tree.root().node().id();
+            tree.append_child(root, "c").unwrap();
+        });
+        let appended = history.tree().root().node().get_subtree_hash();
+
+        history.edit(|tree| {
+            let a = tree.root().node().children().unwrap()[0].clone();
+            tree.retain(|data| *data != "1");
+            assert_eq!(a.node().num_children(), 0);
+        });
+        let retained = history.tree().root().node().get_subtree_hash();
+
+        assert_eq!(history.undo(), Ok(true));
+        assert_eq!(history.tree().root().node().get_subtree_hash(), appended);
+        assert_eq!(history.undo(), Ok(true));
+        assert_eq!(history.tree().root().node().get_subtree_hash(), original);
+        assert_eq!(history.undo(), Ok(false));
+
+        assert_eq!(history.redo(), Ok(true));
+        assert_eq!(history.tree().root().node().get_subtree_hash(), appended);
+        assert_eq!(history.redo(), Ok(true));
+        assert_eq!(history.tree().root().node().get_subtree_hash(), retained);
+        assert_eq!(history.redo(), Ok(false));
+        assert_eq!(history.tree().index().get_ids().len(), 4);
+        assert_eq!(history.tree().validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn inverse_steps() {
+        let tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![]),
+            TestNode("c", vec![]),
+        ]);
+        let original = tree.root().node().get_subtree_hash();
+        let mut history = History::new(tree, 10);
+
+        // Only the changed node is recorded
+        history.edit(|tree| {
+            let two = tree.find(|data| *data == "2").unwrap().node().id();
+            tree.set_data(two, "3").unwrap();
+        });
+        assert!(matches!(
+            history.undo.back().unwrap().as_slice(),
+            [Step::SetData { path, data: "2" }] if path == &[0, 1]
+        ));
+
+        // Several siblings changed at once, which are removed and inserted again
+        history.edit(|tree| {
+            let root = tree.root().node().id();
+            let b = tree.find(|data| *data == "b").unwrap().node().id();
+            tree.set_data(b, "d").unwrap();
+            tree.remove_child(root, 2).unwrap();
+            tree.append_child(root, "e").unwrap();
+        });
+        let edited = history.tree().root().node().get_subtree_hash();
+
+        assert_eq!(history.undo(), Ok(true));
+        assert_eq!(history.undo(), Ok(true));
+        assert_eq!(history.tree().root().node().get_subtree_hash(), original);
+        assert_eq!(history.tree().validate(), Ok(()));
+
+        assert_eq!(history.redo(), Ok(true));
+        assert_eq!(history.redo(), Ok(true));
+        assert_eq!(history.tree().root().node().get_subtree_hash(), edited);
+        assert_eq!(history.tree().validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn empty() {
+        let tree: IndexedTree<
+            crate::noderef::arc::NodeRef<crate::node::arc::Node<&'static str, crate::NodeId>>,
+        > = IndexedTree::new();
+        let mut history = History::new(tree, 10);
+
+        // An edit leaving the tree empty records no steps
+        history.edit(|_| {});
+        assert!(!history.can_undo());
+        assert_eq!(history.undo(), Ok(false));
+        assert!(history.tree().is_empty());
+    }
+
+    #[traced_test]
+    #[test]
+    fn undo_error() {
+        // A tree without an ID generator can't insert the nodes removed by an edit
+        let built = test_tree_node(vec![TestNode("a", vec![]), TestNode("b", vec![])]);
+        let tree: IndexedTree<_> = IndexedTree::from_tree(Tree::from_node(built.root(), None));
+        let mut history = History::new(tree, 10);
+
+        history.edit(|tree| {
+            let root = tree.root().node().id();
+            tree.remove_child(root, 1).unwrap();
+        });
+        assert!(history.can_undo());
+
+        assert_eq!(history.undo(), Err(Error::NoGenerator));
+        assert!(!history.can_undo());
+        assert!(!history.can_redo());
+    }
+
+    #[traced_test]
+    #[test]
+    fn depth() {
+        let tree = test_tree_node(vec![TestNode("a", vec![])]);
+        let mut history = History::new(tree, 2);
+
+        for data in ["b", "c", "d"] {
+            history.edit(|tree| {
+                let root = tree.root().node().id();
+                tree.append_child(root, data).unwrap();
+            });
+        }
+
+        // Edits which don't change the tree are not recorded
+        history.edit(|_| ());
+
+        assert_eq!(history.undo(), Ok(true));
+        assert_eq!(history.undo(), Ok(true));
+        assert_eq!(history.undo(), Ok(false));
+        assert_eq!(history.tree().root().node().num_children(), 2);
+    }
+}
@@ -1,4 +1,12 @@
-use std::sync::{atomic::AtomicU64, Arc};
+use std::{
+    collections::BTreeSet,
+    num::NonZeroU64,
+    ops::Range,
+    sync::{atomic::AtomicU64, Arc},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
 
 pub trait UniqueId:
     Copy + Clone + Ord + PartialEq + std::fmt::Debug + std::fmt::Display + std::hash::Hash
@@ -6,22 +14,56 @@ pub trait UniqueId:
     type Output;
 }
 
+impl UniqueId for u32 {
+    type Output = Self;
+}
+
 impl UniqueId for u64 {
     type Output = Self;
 }
 
+impl UniqueId for u128 {
+    type Output = Self;
+}
+
+impl UniqueId for NonZeroU64 {
+    type Output = Self;
+}
+
 pub trait UniqueGenerator: Default + std::fmt::Debug + Clone + 'static {
     type Output: UniqueId;
 
     /// Generate a unique value
     fn generate(&self) -> Self::Output;
+
+    /// Called by a [`Tree`](crate::Tree) with the ID of each node disposed of by a removal, or
+    /// by [`Tree::gc`](crate::Tree::gc) in tombstone mode. Generators may hand out released IDs
+    /// again. The default implementation discards the ID.
+    fn release(&self, id: Self::Output) {
+        let _ = id;
+    }
 }
 
+/// Generator of sequential `u64` IDs. Clones share the same counter, so a generator can be
+/// shared between trees and threads.
 #[derive(Default, Debug, Clone)]
 pub struct AtomicU64Generator {
     next_id: Arc<AtomicU64>,
 }
 
+impl AtomicU64Generator {
+    /// Create a generator whose first ID is `start`
+    pub fn starting_at(start: u64) -> Self {
+        Self {
+            next_id: Arc::new(AtomicU64::new(start)),
+        }
+    }
+}
+
+/// Generator of sequential `u64` IDs from an explicit starting value, producing reproducible
+/// IDs across runs
+pub type SequentialGenerator = AtomicU64Generator;
+
 impl UniqueGenerator for AtomicU64Generator {
     type Output = u64;
 
@@ -31,41 +73,438 @@ impl UniqueGenerator for AtomicU64Generator {
     }
 }
 
+/// Generator of sequential [`NonZeroU64`] IDs starting at 1. `Option<NonZeroU64>` has the same
+/// size as `NonZeroU64`, which makes optional IDs free to store. Clones share the same counter.
+#[derive(Debug, Clone)]
+pub struct NonZeroU64Generator {
+    next_id: Arc<AtomicU64>,
+}
+
+impl NonZeroU64Generator {
+    /// Create a generator whose first ID is `start`
+    pub fn starting_at(start: NonZeroU64) -> Self {
+        Self {
+            next_id: Arc::new(AtomicU64::new(start.get())),
+        }
+    }
+}
+
+impl Default for NonZeroU64Generator {
+    fn default() -> Self {
+        Self::starting_at(NonZeroU64::MIN)
+    }
+}
+
+impl UniqueGenerator for NonZeroU64Generator {
+    type Output = NonZeroU64;
+
+    fn generate(&self) -> NonZeroU64 {
+        let id = self
+            .next_id
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        NonZeroU64::new(id).expect("NonZeroU64 IDs exhausted")
+    }
+}
+
+/// A generator which produces the same sequence of values for the same seed
+pub trait SeedableGenerator: UniqueGenerator {
+    /// Create a generator whose sequence of values is determined by `seed`
+    fn from_seed(seed: u64) -> Self;
+}
+
+impl SeedableGenerator for AtomicU64Generator {
+    fn from_seed(seed: u64) -> Self {
+        Self::starting_at(seed)
+    }
+}
+
+impl SeedableGenerator for NonZeroU64Generator {
+    /// Create a generator starting at `seed`, or at 1 if `seed` is zero
+    fn from_seed(seed: u64) -> Self {
+        Self::starting_at(NonZeroU64::new(seed).unwrap_or(NonZeroU64::MIN))
+    }
+}
+
+/// A generator which allocates IDs from a contiguous sequence, allowing blocks of IDs to be
+/// reserved up front. A batch operation can reserve the IDs of all the nodes it creates with a
+/// single allocation, and lay them out deterministically even while other threads share the
+/// generator.
+pub trait ReservableGenerator: UniqueGenerator {
+    /// Get the value the next call to [`UniqueGenerator::generate`] would return, without
+    /// allocating it. Another clone of the generator may allocate the value first.
+    fn peek(&self) -> Self::Output;
+
+    /// Allocate a block of `n` contiguous IDs. The IDs will not be returned by any later call
+    /// to [`UniqueGenerator::generate`] or `reserve` on this generator or its clones.
+    fn reserve(&self, n: u64) -> Range<Self::Output>;
+}
+
+impl ReservableGenerator for AtomicU64Generator {
+    fn peek(&self) -> u64 {
+        self.next_id.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    fn reserve(&self, n: u64) -> Range<u64> {
+        let start = self
+            .next_id
+            .fetch_add(n, std::sync::atomic::Ordering::Relaxed);
+        start..start + n
+    }
+}
+
+/// Generator which hands out IDs released by a [`Tree`](crate::Tree) before generating new
+/// IDs from the inner generator, bounding the range of IDs in trees with heavy churn. Released
+/// IDs are reused lowest first. Clones share the same free list.
+///
+/// Removed subtrees returned to the caller keep their IDs, which may be assigned to new nodes
+/// of the tree. Reinserting a removed subtree assigns it new IDs.
+#[derive(Debug, Clone)]
+pub struct RecyclingGenerator<G: UniqueGenerator> {
+    inner: G,
+    free: Arc<Mutex<BTreeSet<G::Output>>>,
+}
+
+impl<G: UniqueGenerator> RecyclingGenerator<G> {
+    pub fn new(inner: G) -> Self {
+        Self {
+            inner,
+            free: Arc::default(),
+        }
+    }
+
+    /// Get the number of released IDs waiting to be reused
+    pub fn free_count(&self) -> usize {
+        self.free.lock().len()
+    }
+}
+
+impl<G: UniqueGenerator> Default for RecyclingGenerator<G> {
+    fn default() -> Self {
+        Self::new(G::default())
+    }
+}
+
+impl<G: UniqueGenerator> UniqueGenerator for RecyclingGenerator<G> {
+    type Output = G::Output;
+
+    fn generate(&self) -> G::Output {
+        let recycled = self.free.lock().pop_first();
+        recycled.unwrap_or_else(|| self.inner.generate())
+    }
+
+    fn release(&self, id: G::Output) {
+        self.free.lock().insert(id);
+    }
+}
+
+impl<G: SeedableGenerator> SeedableGenerator for RecyclingGenerator<G> {
+    fn from_seed(seed: u64) -> Self {
+        Self::new(G::from_seed(seed))
+    }
+}
+
+/// ID composed of a shard number and a sequence number within the shard, for trees whose
+/// nodes are created by several independent writers
+#[derive(Copy, Clone, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ShardedId {
+    pub shard: u32,
+    pub seq: u64,
+}
+
+impl ShardedId {
+    /// Get the ID with the same sequence number in another shard
+    pub fn in_shard(self, shard: u32) -> Self {
+        Self { shard, ..self }
+    }
+}
+
+impl UniqueId for ShardedId {
+    type Output = Self;
+}
+
+impl std::fmt::Display for ShardedId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.shard, self.seq)
+    }
+}
+
+impl From<(u32, u64)> for ShardedId {
+    fn from((shard, seq): (u32, u64)) -> Self {
+        Self { shard, seq }
+    }
+}
+
+impl From<ShardedId> for (u32, u64) {
+    fn from(id: ShardedId) -> Self {
+        (id.shard, id.seq)
+    }
+}
+
+/// Generator of sequential [`ShardedId`]s within a single shard. Clones share the same counter.
+///
+/// The shard acts as an ID namespace. Giving each process its own shard prevents collisions
+/// between their IDs, and a tree imported with IDs from a foreign shard can be re-homed with
+/// [`Tree::remap_ids`](crate::Tree::remap_ids) using [`ShardedId::in_shard`].
 #[derive(Default, Debug, Clone)]
-pub struct UuidGenerator;
+pub struct ShardedGenerator {
+    shard: u32,
+    next_seq: Arc<AtomicU64>,
+}
 
-#[derive(Copy, Clone, Debug, Hash)]
-pub struct Uuid(uuid::Uuid);
+impl ShardedGenerator {
+    pub fn new(shard: u32) -> Self {
+        Self {
+            shard,
+            next_seq: Arc::new(AtomicU64::new(0)),
+        }
+    }
 
-impl UniqueId for Uuid {
+    /// Get the shard of the generated IDs
+    pub fn shard(&self) -> u32 {
+        self.shard
+    }
+}
+
+impl UniqueGenerator for ShardedGenerator {
+    type Output = ShardedId;
+
+    fn generate(&self) -> ShardedId {
+        ShardedId {
+            shard: self.shard,
+            seq: self
+                .next_seq
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+impl ReservableGenerator for ShardedGenerator {
+    fn peek(&self) -> ShardedId {
+        ShardedId {
+            shard: self.shard,
+            seq: self.next_seq.load(std::sync::atomic::Ordering::Relaxed),
+        }
+    }
+
+    fn reserve(&self, n: u64) -> Range<ShardedId> {
+        let start = self
+            .next_seq
+            .fetch_add(n, std::sync::atomic::Ordering::Relaxed);
+        (self.shard, start).into()..(self.shard, start + n).into()
+    }
+}
+
+impl SeedableGenerator for ShardedGenerator {
+    /// Create a generator for shard 0, starting at sequence number `seed`
+    fn from_seed(seed: u64) -> Self {
+        Self {
+            shard: 0,
+            next_seq: Arc::new(AtomicU64::new(seed)),
+        }
+    }
+}
+
+/// Snowflake ID, holding the milliseconds since the generator epoch in the upper 41 bits,
+/// followed by a 10 bit worker number and a 12 bit sequence number. IDs from the same
+/// generator increase over time, and IDs from different workers never collide.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SnowflakeId(u64);
+
+impl SnowflakeId {
+    const WORKER_BITS: u32 = 10;
+    const SEQUENCE_BITS: u32 = 12;
+
+    /// Maximum worker number
+    pub const MAX_WORKER: u16 = (1 << Self::WORKER_BITS) - 1;
+
+    /// Maximum sequence number within a millisecond
+    pub const MAX_SEQUENCE: u16 = (1 << Self::SEQUENCE_BITS) - 1;
+
+    fn new(timestamp: u64, worker: u16, sequence: u16) -> Self {
+        Self(
+            timestamp << (Self::WORKER_BITS + Self::SEQUENCE_BITS)
+                | (worker as u64) << Self::SEQUENCE_BITS
+                | sequence as u64,
+        )
+    }
+
+    /// Milliseconds between the generator epoch and the creation of this ID
+    pub fn timestamp(&self) -> u64 {
+        self.0 >> (Self::WORKER_BITS + Self::SEQUENCE_BITS)
+    }
+
+    pub fn worker(&self) -> u16 {
+        (self.0 >> Self::SEQUENCE_BITS) as u16 & Self::MAX_WORKER
+    }
+
+    pub fn sequence(&self) -> u16 {
+        self.0 as u16 & Self::MAX_SEQUENCE
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl UniqueId for SnowflakeId {
     type Output = Self;
 }
 
-impl std::fmt::Display for Uuid {
+impl std::fmt::Display for SnowflakeId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0.to_string())
+        write!(f, "{}", self.0)
     }
 }
 
-impl Ord for Uuid {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.0.cmp(&other.0)
+impl From<u64> for SnowflakeId {
+    fn from(id: u64) -> Self {
+        Self(id)
     }
 }
 
-impl PartialOrd for Uuid {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.0.partial_cmp(&other.0)
+/// Generator of [`SnowflakeId`]s for a single worker. Clones share the same sequence.
+///
+/// When more than [`SnowflakeId::MAX_SEQUENCE`] IDs are generated within a millisecond, or the
+/// system clock goes backwards, the timestamp of the generated IDs runs ahead of the clock so
+/// IDs keep increasing.
+#[derive(Debug, Clone)]
+pub struct SnowflakeGenerator {
+    worker: u16,
+    epoch: SystemTime,
+    // Timestamp and sequence number of the last generated ID
+    last: Arc<Mutex<Option<(u64, u16)>>>,
+}
+
+impl SnowflakeGenerator {
+    /// Default epoch of generated timestamps, 2024-01-01T00:00:00Z
+    pub const DEFAULT_EPOCH_MS: u64 = 1_704_067_200_000;
+
+    /// Create a generator for `worker`, which must not exceed [`SnowflakeId::MAX_WORKER`]
+    pub fn new(worker: u16) -> Self {
+        assert!(
+            worker <= SnowflakeId::MAX_WORKER,
+            "snowflake worker {worker} exceeds {}",
+            SnowflakeId::MAX_WORKER
+        );
+
+        Self {
+            worker,
+            epoch: UNIX_EPOCH + Duration::from_millis(Self::DEFAULT_EPOCH_MS),
+            last: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Set the epoch from which timestamps are measured
+    pub fn with_epoch(mut self, epoch: SystemTime) -> Self {
+        self.epoch = epoch;
+        self
+    }
+
+    pub fn worker(&self) -> u16 {
+        self.worker
     }
 }
 
-impl PartialEq for Uuid {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.eq(&other.0)
+impl Default for SnowflakeGenerator {
+    fn default() -> Self {
+        Self::new(0)
     }
 }
 
-impl Eq for Uuid {}
+impl UniqueGenerator for SnowflakeGenerator {
+    type Output = SnowflakeId;
+
+    fn generate(&self) -> SnowflakeId {
+        let now = SystemTime::now()
+            .duration_since(self.epoch)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+
+        let mut last = self.last.lock();
+
+        let (timestamp, sequence) = match *last {
+            Some((timestamp, _)) if now > timestamp => (now, 0),
+            Some((timestamp, sequence)) if sequence < SnowflakeId::MAX_SEQUENCE => {
+                (timestamp, sequence + 1)
+            }
+            Some((timestamp, _)) => (timestamp + 1, 0),
+            None => (now, 0),
+        };
+        *last = Some((timestamp, sequence));
+
+        SnowflakeId::new(timestamp, self.worker, sequence)
+    }
+}
+
+/// [ULID](https://github.com/ulid/spec), holding a millisecond timestamp followed by
+/// random bits
+#[cfg(feature = "ulid")]
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ulid(ulid::Ulid);
+
+#[cfg(feature = "ulid")]
+impl Ulid {
+    pub fn as_u128(&self) -> u128 {
+        self.0 .0
+    }
+}
+
+#[cfg(feature = "ulid")]
+impl UniqueId for Ulid {
+    type Output = Self;
+}
+
+#[cfg(feature = "ulid")]
+impl std::fmt::Display for Ulid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Generator of monotonic [`Ulid`]s. IDs generated within the same millisecond increment the
+/// random part of the previous ID, so IDs from a generator and its clones always increase.
+#[cfg(feature = "ulid")]
+#[derive(Default, Clone)]
+pub struct UlidGenerator {
+    generator: Arc<Mutex<ulid::Generator>>,
+}
+
+#[cfg(feature = "ulid")]
+impl std::fmt::Debug for UlidGenerator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UlidGenerator").finish()
+    }
+}
+
+#[cfg(feature = "ulid")]
+impl UniqueGenerator for UlidGenerator {
+    type Output = Ulid;
+
+    fn generate(&self) -> Ulid {
+        Ulid(
+            self.generator
+                .lock()
+                .generate()
+                .expect("random bits of ULID exhausted within a millisecond"),
+        )
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct UuidGenerator;
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Uuid(uuid::Uuid);
+
+impl UniqueId for Uuid {
+    type Output = Self;
+}
+
+impl std::fmt::Display for Uuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
 impl UniqueGenerator for UuidGenerator {
     type Output = Uuid;
@@ -74,3 +513,315 @@ impl UniqueGenerator for UuidGenerator {
         Uuid(uuid::Uuid::new_v4())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        num::NonZeroU64,
+        sync::{atomic::AtomicU64, Arc},
+        time::{Duration, SystemTime},
+    };
+
+    use tracing_test::traced_test;
+
+    use crate::{RemovalMode, TreeBuilder, TreeDiff, TreeNode as _, TreeNodeRef as _};
+
+    use super::{
+        AtomicU64Generator, NonZeroU64Generator, RecyclingGenerator, ReservableGenerator,
+        SeedableGenerator, ShardedGenerator, ShardedId, SnowflakeGenerator, SnowflakeId,
+        UniqueGenerator, UniqueId,
+    };
+
+    /// Newtype ID defined outside of the crate
+    #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+    struct DocId(u64);
+
+    impl UniqueId for DocId {
+        type Output = Self;
+    }
+
+    impl std::fmt::Display for DocId {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "doc-{}", self.0)
+        }
+    }
+
+    #[derive(Default, Debug, Clone)]
+    struct DocIdGenerator(Arc<AtomicU64>);
+
+    impl UniqueGenerator for DocIdGenerator {
+        type Output = DocId;
+
+        fn generate(&self) -> DocId {
+            DocId(self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed))
+        }
+    }
+
+    /// Generator of `u128` IDs in the upper half of the range
+    #[derive(Default, Debug, Clone)]
+    struct WideGenerator(Arc<AtomicU64>);
+
+    impl UniqueGenerator for WideGenerator {
+        type Output = u128;
+
+        fn generate(&self) -> u128 {
+            (1 << 64) | self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed) as u128
+        }
+    }
+
+    /// Build, index, display and diff a tree with IDs from the generator `G`
+    fn roundtrip<G: UniqueGenerator>(first: G::Output, display: &str) {
+        let build = |leaf: &'static str| {
+            TreeBuilder::<&'static str, Infallible, G>::new()
+                .root_ok("root", |root| {
+                    root.child_ok("a", |a| {
+                        a.child_ok(leaf, |_| {});
+                    });
+                })
+                .done_indexed()
+                .unwrap()
+                .unwrap()
+        };
+
+        let mut tree = build("1");
+        let other = build("2");
+
+        let root_id = tree.root().node().id();
+        assert_eq!(root_id, first);
+        assert!(tree.get_node(&root_id).is_some());
+
+        let one = tree.find(|data| *data == "1").unwrap();
+        assert!(tree.get_node(&one.node().id()).unwrap().ptr_eq(&one));
+        assert!(format!("{}", tree.root()).contains(display));
+
+        let mut diff = TreeDiff::new(tree.root(), other.root());
+        diff.diff().patch_tree(&mut tree);
+        assert_eq!(tree, other);
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn custom_ids() {
+        roundtrip::<WideGenerator>(1 << 64, "18446744073709551616");
+        roundtrip::<ShardedGenerator>(ShardedId { shard: 0, seq: 0 }, "0:0");
+        roundtrip::<DocIdGenerator>(DocId(0), "doc-0");
+    }
+
+    #[traced_test]
+    #[test]
+    fn non_zero() {
+        assert_eq!(
+            std::mem::size_of::<Option<NonZeroU64>>(),
+            std::mem::size_of::<u64>()
+        );
+        roundtrip::<NonZeroU64Generator>(NonZeroU64::MIN, "1");
+
+        assert_eq!(NonZeroU64Generator::from_seed(0).generate().get(), 1);
+        assert_eq!(NonZeroU64Generator::from_seed(5).generate().get(), 5);
+    }
+
+    /// Generated IDs increase, and can be used to build, index and diff a tree
+    fn ordered<G: UniqueGenerator>(idgen: G) {
+        let mut ids: Vec<G::Output> = (0..10_000).map(|_| idgen.generate()).collect();
+        assert!(ids.windows(2).all(|ids| ids[0] < ids[1]));
+
+        let build = |leaf: &'static str| {
+            TreeBuilder::<&'static str, Infallible, G>::with_generator(idgen.clone())
+                .root_ok("root", |root| {
+                    root.child_ok("a", |_| {});
+                    root.child_ok(leaf, |_| {});
+                })
+                .done_indexed()
+                .unwrap()
+                .unwrap()
+        };
+
+        let mut tree = build("1");
+        let other = build("2");
+
+        ids = tree
+            .root()
+            .into_iter()
+            .map(|node| node.node().id())
+            .collect();
+        assert!(ids.windows(2).all(|ids| ids[0] < ids[1]));
+        assert!(ids.iter().all(|id| tree.get_node(id).is_some()));
+
+        let mut diff = TreeDiff::new(tree.root(), other.root());
+        diff.diff().patch_tree(&mut tree);
+        assert_eq!(tree, other);
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn snowflake() {
+        ordered(SnowflakeGenerator::new(3));
+
+        let epoch = SystemTime::now() - Duration::from_secs(1);
+        let id = SnowflakeGenerator::new(5).with_epoch(epoch).generate();
+        assert_eq!(id.worker(), 5);
+        assert_eq!(id.sequence(), 0);
+        assert!(id.timestamp() >= 1000);
+        assert_eq!(SnowflakeId::from(id.as_u64()), id);
+
+        // Workers generating within the same millisecond never collide
+        let a = SnowflakeGenerator::new(1).with_epoch(epoch).generate();
+        let b = SnowflakeGenerator::new(2).with_epoch(epoch).generate();
+        assert_ne!(a, b);
+    }
+
+    #[cfg(feature = "ulid")]
+    #[traced_test]
+    #[test]
+    fn ulid() {
+        ordered(super::UlidGenerator::default());
+    }
+
+    #[traced_test]
+    #[test]
+    fn recycling() {
+        type Recycling = RecyclingGenerator<AtomicU64Generator>;
+
+        let mut tree = TreeBuilder::<&'static str, Infallible, Recycling>::new()
+            .root_ok("root", |root| {
+                root.child_ok("a", |a| {
+                    a.child_ok("1", |_| {});
+                });
+                root.child_ok("b", |_| {});
+            })
+            .done_indexed()
+            .unwrap()
+            .unwrap();
+
+        let root = tree.root().node().id();
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        let one = tree.find(|data| *data == "1").unwrap().node().id();
+
+        tree.remove_child(root, 0).unwrap();
+        assert_eq!(tree.generator().free_count(), 2);
+
+        // Released IDs are reused lowest first, before new IDs are generated
+        let c = tree.append_child(root, "c").unwrap().node().id();
+        let d = tree.append_child(root, "d").unwrap().node().id();
+        let e = tree.append_child(root, "e").unwrap().node().id();
+        assert_eq!((c, d), (a.min(one), a.max(one)));
+        assert_eq!(e, 4);
+        assert_eq!(tree.validate(), Ok(()));
+
+        // In tombstone mode, IDs are released when the tombstones are collected
+        tree.set_removal_mode(RemovalMode::Tombstone);
+        tree.remove_child(root, 0).unwrap();
+        assert_eq!(tree.generator().free_count(), 0);
+        tree.gc(tree.removal_seq());
+        assert_eq!(tree.generator().free_count(), 1);
+    }
+
+    #[traced_test]
+    #[test]
+    fn reserve() {
+        let idgen = AtomicU64Generator::starting_at(5);
+        assert_eq!(idgen.peek(), 5);
+        assert_eq!(idgen.reserve(3), 5..8);
+        assert_eq!(idgen.peek(), 8);
+        assert_eq!(idgen.generate(), 8);
+        assert_eq!(idgen.reserve(0), 9..9);
+
+        // Blocks reserved from clones on separate threads never overlap
+        let mut blocks: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    let idgen = idgen.clone();
+                    scope.spawn(move || idgen.reserve(100))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        blocks.sort_by_key(|block| block.start);
+        assert!(blocks.windows(2).all(|b| b[0].end == b[1].start));
+        assert_eq!(idgen.peek(), 409);
+
+        let sharded = ShardedGenerator::new(2);
+        let block = sharded.reserve(10);
+        assert!(block.contains(&ShardedId { shard: 2, seq: 9 }));
+        assert!(!block.contains(&ShardedId { shard: 3, seq: 0 }));
+        assert_eq!(sharded.generate(), (2, 10).into());
+    }
+
+    #[traced_test]
+    #[test]
+    fn sharded() {
+        let generator = ShardedGenerator::new(7);
+        let shared = generator.clone();
+        assert_eq!(generator.generate(), ShardedId { shard: 7, seq: 0 });
+        assert_eq!(shared.generate(), (7, 1).into());
+        assert!(ShardedGenerator::new(3).generate() < generator.generate());
+
+        // Trees imported from another shard can be re-homed into a free shard
+        let mut tree = TreeBuilder::<&'static str, Infallible, ShardedGenerator>::with_generator(
+            ShardedGenerator::new(1),
+        )
+        .root_ok("root", |root| {
+            root.child_ok("a", |_| {});
+        })
+        .done_indexed()
+        .unwrap()
+        .unwrap()
+        .with_path_index();
+
+        tree.remap_ids(|id| id.in_shard(9));
+        assert!(tree
+            .root()
+            .into_iter()
+            .all(|node| node.node().id().shard == 9));
+        assert!(tree.get_node(&(9, 1).into()).is_some());
+        assert!(tree.get_node(&(1, 1).into()).is_none());
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn threaded() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<AtomicU64Generator>();
+        assert_send_sync::<ShardedGenerator>();
+
+        // Trees built on separate threads from a shared generator have disjoint IDs
+        let idgen = AtomicU64Generator::default();
+        let mut ids: Vec<u64> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    let idgen = idgen.clone();
+                    scope.spawn(move || {
+                        let tree = TreeBuilder::<&'static str>::with_generator(idgen)
+                            .root_ok("root", |root| {
+                                root.child_ok("a", |_| {});
+                            })
+                            .done()
+                            .unwrap()
+                            .unwrap();
+                        tree.root()
+                            .into_iter()
+                            .map(|node| node.node().id())
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids, (0..8).collect::<Vec<_>>());
+        assert_eq!(idgen.generate(), 8);
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 use crate::{
     node::TreeNode,
@@ -11,7 +11,7 @@ where
     R: TreeNodeRef,
 {
     fn new() -> Self;
-    fn from_tree<G: UniqueGenerator>(tree: &Tree<R, G>) -> Self
+    fn from_tree<G>(tree: &Tree<R, G>) -> Self
     where
         G: UniqueGenerator<Output = NodeRefId<R>> + 'static;
     fn from_node(node: &R) -> Self;
@@ -20,8 +20,18 @@ where
     fn get_mut(&mut self, id: &<<R as TreeNodeRef>::Inner as TreeNode>::Id) -> Option<&mut R>;
     fn remove(&mut self, id: &<<R as TreeNodeRef>::Inner as TreeNode>::Id) -> Option<R>;
     fn get_ids(&self) -> Vec<<<R as TreeNodeRef>::Inner as TreeNode>::Id>;
+
+    /// Number of indexed nodes
+    fn len(&self) -> usize {
+        self.get_ids().len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
+/// [`TreeIndex`] backed by a [`BTreeMap`] keyed by node ID
 #[derive(Debug)]
 pub struct BTreeIndex<R>
 where
@@ -40,17 +50,20 @@ where
         }
     }
 
-    fn from_tree<G: UniqueGenerator>(tree: &Tree<R, G>) -> Self
+    fn from_tree<G>(tree: &Tree<R, G>) -> Self
     where
         G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
     {
-        Self::from_node(&tree.root())
+        match tree.try_root_ref() {
+            Ok(root) => Self::from_node(root),
+            Err(_) => Self::new(),
+        }
     }
 
     fn from_node(node: &R) -> Self {
         let mut index = Self::new();
         for node in node.clone().into_iter() {
-            index.insert(node.node().id().clone(), node.clone());
+            index.insert(node.node().id(), node.clone());
         }
         index
     }
@@ -72,6 +85,410 @@ where
     }
 
     fn get_ids(&self) -> Vec<<<R as TreeNodeRef>::Inner as TreeNode>::Id> {
-        self.index.keys().map(|k| *k).collect()
+        self.index.keys().copied().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+}
+
+/// [`TreeIndex`] backed by a [`HashMap`] keyed by node ID, for faster random access
+/// than [`BTreeIndex`]
+#[derive(Debug)]
+pub struct HashIndex<R>
+where
+    R: TreeNodeRef,
+{
+    index: HashMap<<<R as TreeNodeRef>::Inner as TreeNode>::Id, R>,
+}
+
+impl<R> TreeIndex<R> for HashIndex<R>
+where
+    R: TreeNodeRef + IntoIterator + Clone,
+{
+    fn new() -> Self {
+        Self {
+            index: HashMap::new(),
+        }
+    }
+
+    fn from_tree<G>(tree: &Tree<R, G>) -> Self
+    where
+        G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    {
+        match tree.try_root_ref() {
+            Ok(root) => Self::from_node(root),
+            Err(_) => Self::new(),
+        }
+    }
+
+    fn from_node(node: &R) -> Self {
+        let mut index = Self::new();
+        for node in node.clone().into_iter() {
+            index.insert(node.node().id(), node.clone());
+        }
+        index
+    }
+
+    fn insert(&mut self, id: <<R as TreeNodeRef>::Inner as TreeNode>::Id, node: R) {
+        self.index.insert(id, node);
+    }
+
+    fn get(&self, id: &<<R as TreeNodeRef>::Inner as TreeNode>::Id) -> Option<&R> {
+        self.index.get(id)
+    }
+
+    fn get_mut(&mut self, id: &<<R as TreeNodeRef>::Inner as TreeNode>::Id) -> Option<&mut R> {
+        self.index.get_mut(id)
+    }
+
+    fn remove(&mut self, id: &<<R as TreeNodeRef>::Inner as TreeNode>::Id) -> Option<R> {
+        self.index.remove(id)
+    }
+
+    /// Get the indexed IDs in ascending order, matching [`BTreeIndex`]
+    fn get_ids(&self) -> Vec<<<R as TreeNodeRef>::Inner as TreeNode>::Id> {
+        let mut ids: Vec<_> = self.index.keys().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+}
+
+/// Reverse index from subtree hashes to the IDs of the nodes with that subtree hash
+#[derive(Debug)]
+pub(crate) struct SubtreeHashIndex<Id> {
+    hashes: HashMap<Id, u64>,
+    nodes: BTreeMap<u64, BTreeSet<Id>>,
+}
+
+impl<Id> SubtreeHashIndex<Id>
+where
+    Id: std::hash::Hash + Ord + Copy,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            hashes: HashMap::new(),
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    /// Set the subtree hash of a node, replacing any previous entry
+    pub(crate) fn insert(&mut self, id: Id, hash: u64) {
+        if self.hashes.get(&id) == Some(&hash) {
+            return;
+        }
+
+        self.remove(&id);
+        self.nodes.entry(hash).or_default().insert(id);
+        self.hashes.insert(id, hash);
+    }
+
+    pub(crate) fn remove(&mut self, id: &Id) {
+        let Some(hash) = self.hashes.remove(id) else {
+            return;
+        };
+
+        if let Some(ids) = self.nodes.get_mut(&hash) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.nodes.remove(&hash);
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.hashes.clear();
+        self.nodes.clear();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Get the indexed subtree hash of a node
+    pub(crate) fn hash_of(&self, id: &Id) -> Option<u64> {
+        self.hashes.get(id).copied()
+    }
+
+    /// Iterate the indexed subtree hash of each node
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&Id, &u64)> {
+        self.hashes.iter()
+    }
+
+    /// Get the IDs of the nodes with the provided subtree hash, in ascending order
+    pub(crate) fn get(&self, hash: u64) -> impl Iterator<Item = &Id> {
+        self.nodes.get(&hash).into_iter().flatten()
+    }
+
+    /// Iterate the sets of IDs sharing a subtree hash with at least one other node, in
+    /// ascending order of hash
+    pub(crate) fn duplicates(&self) -> impl Iterator<Item = &BTreeSet<Id>> {
+        self.nodes.values().filter(|ids| ids.len() > 1)
+    }
+}
+
+/// Index of the IDs of the nodes by the path of child indices from the root
+#[derive(Debug)]
+pub(crate) struct PathIndex<Id> {
+    ids: BTreeMap<Vec<usize>, Id>,
+    paths: HashMap<Id, Vec<usize>>,
+}
+
+impl<Id> PathIndex<Id>
+where
+    Id: std::hash::Hash + Eq + Copy,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            ids: BTreeMap::new(),
+            paths: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, path: Vec<usize>, id: Id) {
+        if let Some(old) = self.paths.insert(id, path.clone()) {
+            if self.ids.get(&old) == Some(&id) {
+                self.ids.remove(&old);
+            }
+        }
+        self.ids.insert(path, id);
+    }
+
+    /// Remove the entries of the node at a path and all of its descendants
+    pub(crate) fn remove_prefix(&mut self, prefix: &[usize]) {
+        let removed: Vec<(Vec<usize>, Id)> = self
+            .ids
+            .range(prefix.to_vec()..)
+            .take_while(|(path, _)| path.starts_with(prefix))
+            .map(|(path, id)| (path.clone(), *id))
+            .collect();
+
+        for (path, id) in removed {
+            self.ids.remove(&path);
+            if self.paths.get(&id) == Some(&path) {
+                self.paths.remove(&id);
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.ids.clear();
+        self.paths.clear();
+    }
+
+    pub(crate) fn get(&self, path: &[usize]) -> Option<&Id> {
+        self.ids.get(path)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub(crate) fn path_of(&self, id: &Id) -> Option<&[usize]> {
+        self.paths.get(id).map(Vec::as_slice)
+    }
+
+    /// Iterate the indexed path of each node
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&Id, &Vec<usize>)> {
+        self.paths.iter()
+    }
+}
+
+/// An index of the nodes of an [`IndexedTree`](crate::IndexedTree) kept in sync alongside its
+/// [`TreeIndex`]. Secondary indexes are attached with
+/// [`IndexedTree::with_index`](crate::IndexedTree::with_index), and are notified of every node
+/// indexed, updated or removed through the tree.
+pub trait SecondaryIndex<R>: AsAny + Send + Sync
+where
+    R: TreeNodeRef,
+{
+    /// A node was added to the tree, or its data, parent or children changed
+    fn insert(&mut self, node: &R);
+
+    /// The node with the provided ID was removed from the tree
+    fn remove(&mut self, id: &NodeRefId<R>);
+
+    /// The tree is being reindexed, and every node will be inserted again
+    fn clear(&mut self);
+}
+
+/// Conversion of a [`SecondaryIndex`] to [`std::any::Any`] for downcasting, implemented for
+/// all types
+pub trait AsAny {
+    fn as_any(&self) -> &dyn std::any::Any;
+}
+
+impl<T: std::any::Any> AsAny for T {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+/// Secondary index mapping keys computed from node data to the IDs of the nodes
+pub(crate) struct KeyIndex<R, K>
+where
+    R: TreeNodeRef,
+{
+    key: KeyFn<R, K>,
+    keys: HashMap<NodeRefId<R>, K>,
+    nodes: HashMap<K, BTreeSet<NodeRefId<R>>>,
+}
+
+type KeyFn<R, K> = Box<dyn Fn(&<<R as TreeNodeRef>::Inner as TreeNode>::Data) -> K + Send + Sync>;
+
+impl<R, K> KeyIndex<R, K>
+where
+    R: TreeNodeRef,
+    K: std::hash::Hash + Eq + Clone,
+{
+    pub(crate) fn new<F>(key: F) -> Self
+    where
+        F: Fn(&<<R as TreeNodeRef>::Inner as TreeNode>::Data) -> K + Send + Sync + 'static,
+    {
+        Self {
+            key: Box::new(key),
+            keys: HashMap::new(),
+            nodes: HashMap::new(),
+        }
+    }
+
+    /// Get the IDs of the nodes with the provided key, in ascending order
+    pub(crate) fn get(&self, key: &K) -> impl Iterator<Item = &NodeRefId<R>> {
+        self.nodes.get(key).into_iter().flatten()
+    }
+}
+
+impl<R, K> SecondaryIndex<R> for KeyIndex<R, K>
+where
+    R: TreeNodeRef + 'static,
+    NodeRefId<R>: Send + Sync,
+    K: std::hash::Hash + Eq + Clone + Send + Sync + 'static,
+{
+    fn insert(&mut self, node: &R) {
+        let (id, key) = {
+            let inner = node.node();
+            let key = (self.key)(&inner.data());
+            (inner.id(), key)
+        };
+
+        self.remove(&id);
+        self.nodes.entry(key.clone()).or_default().insert(id);
+        self.keys.insert(id, key);
+    }
+
+    fn remove(&mut self, id: &NodeRefId<R>) {
+        let Some(key) = self.keys.remove(id) else {
+            return;
+        };
+
+        if let Some(ids) = self.nodes.get_mut(&key) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.nodes.remove(&key);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.nodes.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        node::arc::Node,
+        noderef::arc::NodeRef,
+        test::{test_tree_node, TestNode},
+        IndexedTree, NodeId, Tree, TreeNode as _, TreeNodeRef as _,
+    };
+
+    use super::{HashIndex, SecondaryIndex, TreeIndex as _};
+
+    type TestRef = NodeRef<Node<&'static str, NodeId>>;
+
+    /// Secondary index of the IDs of the leaves
+    #[derive(Default)]
+    struct Leaves(std::collections::BTreeSet<NodeId>);
+
+    impl SecondaryIndex<TestRef> for Leaves {
+        fn insert(&mut self, node: &TestRef) {
+            let (id, leaf) = {
+                let inner = node.node();
+                (inner.id(), inner.num_children() == 0)
+            };
+            if leaf {
+                self.0.insert(id);
+            } else {
+                self.0.remove(&id);
+            }
+        }
+
+        fn remove(&mut self, id: &NodeId) {
+            self.0.remove(id);
+        }
+
+        fn clear(&mut self) {
+            self.0.clear();
+        }
+    }
+
+    #[traced_test]
+    #[test]
+    fn hash_index() {
+        let tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+        let ids = tree.index().get_ids();
+
+        let copy: Tree<NodeRef<Node<&str, NodeId>>> = tree.tree().map(|data| *data);
+        let mut tree: IndexedTree<_, _, HashIndex<_>> = IndexedTree::from_tree(copy);
+        assert_eq!(tree.index().get_ids(), ids);
+        assert_eq!(tree.validate(), Ok(()));
+
+        let b = tree.find(|data| *data == "b").unwrap().node().id();
+        tree.append_child(b, "c").unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(*tree.get_node(&b).unwrap().node().data(), "b");
+    }
+
+    #[traced_test]
+    #[test]
+    fn secondary_index() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ])
+        .with_index(Leaves::default());
+
+        let leaves = |tree: &IndexedTree<TestRef>| {
+            let mut ids: Vec<NodeId> = tree.leaves().iter().map(|l| l.node().id()).collect();
+            ids.sort();
+            ids
+        };
+        let indexed = |tree: &IndexedTree<TestRef>| -> Vec<NodeId> {
+            let index = tree.secondary_index::<Leaves>().unwrap();
+            index.0.iter().copied().collect()
+        };
+        assert_eq!(indexed(&tree), leaves(&tree));
+
+        let b = tree.find(|data| *data == "b").unwrap().node().id();
+        tree.append_child(b, "c").unwrap();
+        let one = tree.find(|data| *data == "1").unwrap().node().id();
+        tree.remove_node(&tree.get_node(&one).unwrap().clone());
+        assert_eq!(indexed(&tree), leaves(&tree));
+
+        tree.reindex();
+        assert_eq!(indexed(&tree), leaves(&tree));
+        assert!(tree.secondary_index::<HashIndex<TestRef>>().is_none());
     }
 }
@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 use std::ops::Deref;
 use std::ops::DerefMut;
-use std::usize;
 
 use colored::Colorize;
 
@@ -152,7 +151,7 @@ where
         let current = self.stack.pop();
 
         current.map(|(child_index, index, depth, node)| {
-            node.node().children().map(|children| {
+            if let Some(children) = node.node().children() {
                 let index = self.index.entry(depth).or_insert(0);
 
                 // Increment the horizontal index in the iterator state by the number of children we have.
@@ -180,7 +179,7 @@ where
                             (*child).clone(),
                         ));
                     })
-            });
+            }
 
             IterNode {
                 position: NodePosition {
@@ -25,14 +25,13 @@ impl<R> LeafIter<R>
 where
     R: TreeNodeRef + std::fmt::Debug + 'static,
 {
-    pub fn new(leaves: &Vec<R>) -> Self {
-        let mut leaves = leaves.clone();
+    pub fn new(leaves: &[R]) -> Self {
+        let mut leaves = leaves.to_vec();
 
         leaves.reverse();
 
         // Track which nodes have been visited, initialized with initial set of leaf nodes
-        let visited: HashSet<NodeRefId<R>> =
-            leaves.iter().map(|leaf| leaf.node().id().clone()).collect();
+        let visited: HashSet<NodeRefId<R>> = leaves.iter().map(|leaf| leaf.node().id()).collect();
 
         Self {
             visited,
@@ -60,7 +59,7 @@ where
     fn mark_child_visited(&mut self, parent_id: NodeRefId<R>, child_id: NodeRefId<R>) {
         self.children_visited
             .entry(parent_id)
-            .or_insert_with(HashSet::new)
+            .or_default()
             .insert(child_id);
     }
 
@@ -76,10 +75,7 @@ where
             let expected_children = node.node().num_children();
 
             // Get the children visited HashSet for this node
-            let children_visited = self
-                .children_visited
-                .entry(node_id)
-                .or_insert(HashSet::new());
+            let children_visited = self.children_visited.entry(node_id).or_default();
 
             // Get the number of children we have visited for this node
             let have_children = children_visited.len();
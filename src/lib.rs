@@ -9,15 +9,19 @@
 //! flexibility, and performance.
 
 mod builder;
+mod cascade;
 mod compare;
 mod diff;
 mod display;
 mod edit;
+mod error;
 mod event;
 mod hash;
+mod history;
 mod id;
 mod index;
 mod iterator;
+mod proof;
 mod tree;
 
 #[cfg(test)]
@@ -26,20 +30,56 @@ pub(crate) mod test;
 pub mod node;
 pub mod noderef;
 
+#[cfg(feature = "proptest")]
+pub mod proptest_support;
+
 pub use builder::*;
+pub use cascade::{CascadeResolver, CascadeRules};
+pub use display::{to_mermaid, to_plantuml};
 pub use id::*;
+pub use index::{AsAny, BTreeIndex, HashIndex, SecondaryIndex, TreeIndex};
 pub use iterator::NodePosition;
+pub use proof::{verify_proof, MerkleProof, ProofStep};
+pub use tree::FlatRecord;
+pub use tree::FlatRecordError;
+pub use tree::HashMode;
+pub use tree::IdMode;
 pub use tree::IndexedTree;
+pub use tree::Invariant;
+pub use tree::RemovalMode;
+pub use tree::SexprError;
+#[cfg(feature = "snapshot")]
+pub use tree::SnapshotReader;
+pub use tree::SwapMode;
 pub use tree::Tree;
+pub use tree::TreeEventListener;
+pub use tree::TreeStats;
+pub use tree::TreeTransaction;
+#[cfg(feature = "fs")]
+pub use tree::{DirOptions, FileEntry, FileKind};
+#[cfg(feature = "sync")]
+pub use tree::{SyncMessage, SyncSession, WireOperation, MAX_PAYLOAD_LEN, SYNC_PROTOCOL_VERSION};
 
-pub use node::TreeNode;
-pub use noderef::TreeNodeRef;
+pub use node::{Attributes, TreeNode};
+pub use noderef::{AccessError, TreeNodeRef, TreeWeakNodeRef};
 
 pub use iterator::leaf;
 
-pub use diff::TreeDiff;
+pub use error::Error;
+
+pub use diff::{TreeDiff, TreePatch, TreePatchOperation};
+
+pub use hash::{
+    content_hash, subtree_digest128, update_subtree_hash, update_subtree_hashes, Fingerprint,
+    HashVersion, IncompatibleHashVersion,
+};
+
+pub use history::History;
+
+pub use event::{EventFilter, TreeEvent, TreeEventKind};
 
-pub use event::TreeEvent;
+#[cfg(feature = "stream")]
+pub use event::TreeEventStream;
 
 pub type NodeDepth = usize;
 pub type NodeIndex = usize;
@@ -3,12 +3,34 @@ use std::{
     ops::{Deref, DerefMut},
 };
 
-use crate::{id::UniqueId, noderef::TreeNodeRef, NodePosition};
+use crate::{id::UniqueId, noderef::TreeNodeRef, Fingerprint, NodePosition};
 use xxhash_rust::xxh64::Xxh64;
 
+mod attrs;
+
 pub mod arc;
+pub mod arena;
 pub mod rc;
 
+pub use attrs::Attributes;
+
+/// Container of the children of a node. With the `smallvec` feature this is a `SmallVec`
+/// storing up to three children inline, avoiding a separate allocation for small families.
+#[cfg(feature = "smallvec")]
+pub type Children<R> = smallvec::SmallVec<[R; 3]>;
+
+/// Container of the children of a node. With the `smallvec` feature this is a `SmallVec`
+/// storing up to three children inline, avoiding a separate allocation for small families.
+#[cfg(not(feature = "smallvec"))]
+pub type Children<R> = Vec<R>;
+
+/// Hash node data on its own, as returned by [`TreeNode::data_xxhash`]
+pub(crate) fn data_hash<D: Fingerprint + ?Sized>(data: &D) -> u64 {
+    let mut hasher = Xxh64::new(0);
+    data.fingerprint(&mut hasher);
+    hasher.finish()
+}
+
 /// Sealed trait for internal Node methods
 pub(crate) mod internal {
     use super::TreeNode;
@@ -20,15 +42,29 @@ pub(crate) mod internal {
         fn set_id(&mut self, id: Node::Id);
         fn set_parent(&mut self, parent: Node::NodeRef);
 
+        /// Take the parent out of this node, leaving it without a parent
+        fn take_parent(&mut self) -> Option<Node::NodeRef>;
+
+        fn set_position(&mut self, position: crate::NodePosition);
+
+        /// Set the number of nodes in the subtree rooted at this node
+        fn set_subtree_size(&mut self, size: usize);
+
+        /// Mark this node as tombstoned at the provided removal sequence number, or clear the mark
+        fn set_tombstone(&mut self, seq: Option<u64>);
+
         /// Take ownership of the children Vec out of the Option, leaving None in its place
-        fn take_children(&mut self) -> Option<Vec<Node::NodeRef>>;
+        fn take_children(&mut self) -> Option<super::Children<Node::NodeRef>>;
+
+        /// Mark the current generation of this node as clean
+        fn clear_dirty(&mut self);
     }
 }
 
 pub trait TreeNode:
     internal::NodeInternal<Self> + Clone + std::hash::Hash + std::fmt::Debug
 {
-    type Data: std::hash::Hash + Clone + std::fmt::Display;
+    type Data: Fingerprint + Clone;
     type Id: UniqueId;
     type DataRef<'b>: Deref<Target = Self::Data>
     where
@@ -39,11 +75,11 @@ pub trait TreeNode:
 
     type NodeRef: TreeNodeRef<Inner = Self>;
 
-    type ChildrenRef<'b>: Deref<Target = Vec<Self::NodeRef>>
+    type ChildrenRef<'b>: Deref<Target = Children<Self::NodeRef>>
     where
         Self: 'b;
 
-    type ChildrenRefMut<'b>: DerefMut<Target = Vec<Self::NodeRef>>
+    type ChildrenRefMut<'b>: DerefMut<Target = Children<Self::NodeRef>>
     where
         Self: 'b;
 
@@ -60,11 +96,37 @@ pub trait TreeNode:
     fn set_subtree_hash(&mut self, subtree_hash: u64);
     fn get_subtree_hash(&self) -> u64;
 
+    /// Number of nodes in the subtree rooted at this node, including this node.
+    /// Maintained alongside the subtree hash.
+    fn subtree_size(&self) -> usize;
+
+    /// Get the removal sequence number if this node was removed from a tree in tombstone mode
+    fn tombstone(&self) -> Option<u64>;
+
+    /// Check if this node was removed from a tree in tombstone mode
+    fn is_tombstoned(&self) -> bool {
+        self.tombstone().is_some()
+    }
+
+    /// Get the generation of this node, which is incremented whenever its data, children, parent
+    /// or tombstone may have been mutated
+    fn generation(&self) -> u64;
+
+    /// Check if this node was mutated since it was last marked clean by
+    /// [`Tree::clear_dirty`](crate::Tree::clear_dirty). New nodes are dirty.
+    fn is_dirty(&self) -> bool;
+
+    /// Get the auxiliary attributes attached to this node
+    fn attrs(&self) -> &Attributes;
+
+    /// Get a mutable reference to the auxiliary attributes attached to this node
+    fn attrs_mut(&mut self) -> &mut Attributes;
+
     fn data<'b>(&'b self) -> Self::DataRef<'b>;
     fn data_mut<'b>(&'b mut self) -> Self::DataRefMut<'b>;
 
-    fn parent<'b>(&'b self) -> Option<&'b Self::NodeRef>;
-    fn parent_mut<'b>(&'b mut self) -> Option<&'b mut Self::NodeRef>;
+    fn parent(&self) -> Option<&Self::NodeRef>;
+    fn parent_mut(&mut self) -> Option<&mut Self::NodeRef>;
 
     fn children<'b>(&'b self) -> Option<Self::ChildrenRef<'b>>;
     fn children_mut<'b>(&'b mut self) -> Option<Self::ChildrenRefMut<'b>>;
@@ -110,11 +172,8 @@ pub trait TreeNode:
     /// Remove a child node from this node at the specified index, returing
     /// the NodeRef to the removed child.
     fn remove_child_index(&mut self, index: usize) -> Option<Self::NodeRef> {
-        if let Some(mut children) = self.children_mut() {
-            Some(children.remove(index))
-        } else {
-            None
-        }
+        self.children_mut()
+            .map(|mut children| children.remove(index))
     }
 
     fn replace_child(&mut self, source: Self::NodeRef, index: usize) {
@@ -137,10 +196,10 @@ pub trait TreeNode:
         hasher.finish()
     }
 
+    /// Hash of the node data alone. Implementations may cache this until the data is next
+    /// borrowed with [`TreeNode::data_mut`].
     fn data_xxhash(&self) -> u64 {
-        let mut hasher = Xxh64::new(0);
-        self.data().hash(&mut hasher);
-        hasher.finish()
+        data_hash(&*self.data())
     }
 
     /// Hash the node including immediate children
@@ -191,7 +250,7 @@ pub trait TreeNode:
 mod tests {
     use tracing_test::traced_test;
 
-    use crate::{NodeId, Tree, TreeBuilder};
+    use crate::{NodeId, Tree, TreeBuilder, TreeNode as _, TreeNodeRef as _};
 
     type NodeRef<T> = crate::noderef::arc::NodeRef<T>;
     type Node<Data, Id> = crate::node::arc::Node<Data, Id>;
@@ -202,9 +261,10 @@ mod tests {
         Fail,
     }
 
-    #[derive(Debug, Clone, Hash)]
+    #[derive(Debug, Clone, Hash, Default)]
     #[allow(unused)]
     enum TestData {
+        #[default]
         Foo,
         Bar,
         Baz,
@@ -217,14 +277,10 @@ mod tests {
         }
     }
 
-    impl Default for TestData {
-        fn default() -> Self {
-            TestData::Foo
-        }
-    }
+    type TestTree = Tree<NodeRef<Node<TestData, NodeId>>>;
 
     /// Create a simple tree for tests using the TreeBuilder
-    fn simple_tree<'a>() -> Result<Option<Tree<NodeRef<Node<TestData, NodeId>>>>, TestError> {
+    fn simple_tree() -> Result<Option<TestTree>, TestError> {
         TreeBuilder::<TestData, TestError>::new()
             .root(TestData::Foo, |foo| {
                 foo.child(TestData::Bar, |bar| {
@@ -256,4 +312,45 @@ mod tests {
 
         println!("{:?}", tree);
     }
+
+    #[traced_test]
+    #[test]
+    fn data_hash_cache() {
+        let mut node = Node::<TestData, NodeId>::new(0, TestData::Foo, None);
+        let foo = node.data_xxhash();
+        assert_eq!(node.data_xxhash(), foo);
+
+        *node.data_mut() = TestData::String("Hello".into());
+        let hello = Node::<TestData, NodeId>::new(1, TestData::String("Hello".into()), None);
+        assert_ne!(node.data_xxhash(), foo);
+        assert_eq!(node.data_xxhash(), hello.data_xxhash());
+
+        // Clones carry the cached hash, and invalidate it independently
+        let mut clone = node.clone();
+        *clone.data_mut() = TestData::Foo;
+        assert_eq!(clone.data_xxhash(), foo);
+        assert_eq!(node.data_xxhash(), hello.data_xxhash());
+    }
+
+    #[traced_test]
+    #[test]
+    fn data_without_display() {
+        #[derive(Debug, Clone, Hash, PartialEq)]
+        struct Point(i32, i32);
+
+        let tree = TreeBuilder::<Point>::new()
+            .root_ok(Point(0, 0), |root| {
+                root.child_ok(Point(1, 2), |_| {});
+            })
+            .done_indexed()
+            .unwrap()
+            .unwrap();
+
+        let point = tree.find(|data| *data == Point(1, 2)).unwrap();
+        assert_eq!(
+            tree.parent_id(point.node().id()),
+            Some(tree.root().node().id())
+        );
+        assert!(format!("{:?}", point.node()).contains("Point(1, 2)"));
+    }
 }
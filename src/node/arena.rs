@@ -0,0 +1,229 @@
+use std::sync::OnceLock;
+
+use crate::{Fingerprint, NodePosition, TreeNodeRef as _, UniqueId};
+
+use super::{data_hash, internal::NodeInternal, Attributes, Children, TreeNode};
+
+#[derive(Clone)]
+pub struct Node<Data, Id = crate::NodeId>
+where
+    Id: UniqueId + 'static,
+    Data: Fingerprint + std::fmt::Debug + Clone + 'static,
+{
+    id: Id,
+    data: Data,
+    data_hash: OnceLock<u64>,
+    parent: Option<<Self as TreeNode>::NodeRef>,
+    children: Option<Children<<Self as TreeNode>::NodeRef>>,
+    position: Option<NodePosition>,
+    subtree_hash: u64,
+    subtree_size: usize,
+    tombstone: Option<u64>,
+    attrs: Attributes,
+    generation: u64,
+    clean_generation: Option<u64>,
+}
+
+impl<Data, Id> std::fmt::Debug for Node<Data, Id>
+where
+    Id: UniqueId + 'static,
+    Data: Fingerprint + std::fmt::Debug + Clone + 'static,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TreeNode")
+            .field("id", &self.id)
+            .field("hash", &format_args!("0x{:X}", self.xxhash()))
+            .field("data", self.data())
+            .field(
+                "parent_id",
+                &format_args!("{:?}", self.parent.as_ref().map(|p| p.node().id())),
+            )
+            .field(
+                "child_ids",
+                &format_args!(
+                    "{:?}",
+                    self.children().map(|children| children
+                        .iter()
+                        .map(|c| c.node().id())
+                        .collect::<Vec<Id>>())
+                ),
+            )
+            .finish()
+    }
+}
+
+impl<Data, Id> NodeInternal<Self> for Node<Data, Id>
+where
+    Id: UniqueId + 'static,
+    Data: Fingerprint + std::fmt::Debug + Clone + 'static,
+{
+    fn set_id(&mut self, id: Id) {
+        self.id = id;
+    }
+
+    fn set_parent(&mut self, parent: <Self as TreeNode>::NodeRef) {
+        self.generation += 1;
+        self.parent = Some(parent);
+    }
+
+    fn take_parent(&mut self) -> Option<<Self as TreeNode>::NodeRef> {
+        self.generation += 1;
+        self.parent.take()
+    }
+
+    fn set_position(&mut self, position: NodePosition) {
+        self.position = Some(position);
+    }
+
+    fn set_subtree_size(&mut self, size: usize) {
+        self.subtree_size = size;
+    }
+
+    fn set_tombstone(&mut self, seq: Option<u64>) {
+        self.generation += 1;
+        self.tombstone = seq;
+    }
+
+    fn take_children(&mut self) -> Option<Children<<Self as TreeNode>::NodeRef>> {
+        self.generation += 1;
+        self.children.take()
+    }
+
+    fn clear_dirty(&mut self) {
+        self.clean_generation = Some(self.generation);
+    }
+}
+
+impl<Data, Id> std::hash::Hash for Node<Data, Id>
+where
+    Id: UniqueId + 'static,
+    Data: Fingerprint + std::fmt::Debug + Clone + 'static,
+{
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.num_children().hash(state);
+        self.data().fingerprint(state);
+    }
+}
+
+impl<Data, Id> TreeNode for Node<Data, Id>
+where
+    Id: UniqueId + 'static,
+    Data: Fingerprint + Clone + std::fmt::Debug + 'static,
+{
+    type NodeRef = crate::noderef::arena::NodeRef<Self>;
+    type Data = Data;
+    type Id = Id;
+    type DataRef<'b> = &'b Data;
+    type DataRefMut<'b> = &'b mut Data;
+    type ChildrenRef<'b> = &'b Children<Self::NodeRef>;
+    type ChildrenRefMut<'b> = &'b mut Children<Self::NodeRef>;
+
+    fn new(id: Self::Id, data: Self::Data, children: Option<Vec<Self::NodeRef>>) -> Self {
+        let subtree_size = 1 + children
+            .iter()
+            .flatten()
+            .map(|child| child.node().subtree_size())
+            .sum::<usize>();
+
+        Self {
+            id,
+            data,
+            data_hash: OnceLock::new(),
+            children: children.map(|children| children.into_iter().collect()),
+            parent: None,
+            position: None,
+            subtree_hash: 0,
+            subtree_size,
+            tombstone: None,
+            attrs: Attributes::new(),
+            generation: 0,
+            clean_generation: None,
+        }
+    }
+
+    fn with_parent(mut self, parent: Self::NodeRef) -> Self {
+        self.parent = Some(parent);
+        self
+    }
+
+    fn with_position(mut self, position: NodePosition) -> Self {
+        self.position = Some(position);
+        self
+    }
+
+    fn id(&self) -> Self::Id {
+        self.id
+    }
+
+    fn data<'b>(&'b self) -> Self::DataRef<'b> {
+        &self.data
+    }
+
+    fn data_mut<'b>(&'b mut self) -> Self::DataRefMut<'b> {
+        self.generation += 1;
+        self.data_hash.take();
+        &mut self.data
+    }
+
+    fn data_xxhash(&self) -> u64 {
+        *self.data_hash.get_or_init(|| data_hash(&self.data))
+    }
+
+    fn children<'b>(&'b self) -> Option<Self::ChildrenRef<'b>> {
+        self.children.as_ref()
+    }
+
+    fn children_mut<'b>(&'b mut self) -> Option<Self::ChildrenRefMut<'b>> {
+        self.generation += 1;
+        self.children.as_mut()
+    }
+
+    fn parent(&self) -> Option<&Self::NodeRef> {
+        self.parent.as_ref()
+    }
+
+    fn parent_mut(&mut self) -> Option<&mut Self::NodeRef> {
+        self.parent.as_mut()
+    }
+
+    fn set_children(&mut self, children: Option<Vec<Self::NodeRef>>) {
+        self.generation += 1;
+        self.children = children.map(|children| children.into_iter().collect())
+    }
+
+    fn get_position(&self) -> Option<&NodePosition> {
+        self.position.as_ref()
+    }
+
+    fn set_subtree_hash(&mut self, tree_hash: u64) {
+        self.subtree_hash = tree_hash;
+    }
+
+    fn get_subtree_hash(&self) -> u64 {
+        self.subtree_hash
+    }
+
+    fn subtree_size(&self) -> usize {
+        self.subtree_size
+    }
+
+    fn tombstone(&self) -> Option<u64> {
+        self.tombstone
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.clean_generation != Some(self.generation)
+    }
+
+    fn attrs(&self) -> &Attributes {
+        &self.attrs
+    }
+
+    fn attrs_mut(&mut self) -> &mut Attributes {
+        &mut self.attrs
+    }
+}
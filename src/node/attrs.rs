@@ -0,0 +1,203 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+};
+
+/// Value stored in [`Attributes`]
+trait Attribute: Any + Send + Sync {
+    fn clone_box(&self) -> Box<dyn Attribute>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+    fn type_name(&self) -> &'static str;
+}
+
+impl<T> Attribute for T
+where
+    T: Any + Clone + Send + Sync,
+{
+    fn clone_box(&self) -> Box<dyn Attribute> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<T>()
+    }
+}
+
+/// Map of auxiliary values attached to a node, holding at most one value of each type.
+///
+/// Attributes are not part of the node hash, so they can hold derived state such as layout
+/// results or dirty flags without affecting diffs.
+#[derive(Default)]
+pub struct Attributes {
+    values: HashMap<TypeId, Box<dyn Attribute>>,
+}
+
+impl Attributes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the value of type `T`
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|value| (**value).as_any().downcast_ref())
+    }
+
+    /// Get a mutable reference to the value of type `T`
+    pub fn get_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.values
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| (**value).as_any_mut().downcast_mut())
+    }
+
+    /// Get a mutable reference to the value of type `T`, inserting the result of `f` if absent
+    pub fn get_or_insert_with<T, F>(&mut self, f: F) -> &mut T
+    where
+        T: Any + Clone + Send + Sync,
+        F: FnOnce() -> T,
+    {
+        self.values
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(f()))
+            .as_any_mut()
+            .downcast_mut()
+            .expect("attribute stored under its own type")
+    }
+
+    /// Insert a value, returning the previous value of the same type
+    pub fn insert<T>(&mut self, value: T) -> Option<T>
+    where
+        T: Any + Clone + Send + Sync,
+    {
+        self.values
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.into_any().downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    /// Remove and return the value of type `T`
+    pub fn remove<T: Any>(&mut self) -> Option<T> {
+        self.values
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.into_any().downcast().ok())
+            .map(|value| *value)
+    }
+
+    pub fn contains<T: Any>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+}
+
+impl Clone for Attributes {
+    fn clone(&self) -> Self {
+        Self {
+            values: self
+                .values
+                .iter()
+                .map(|(type_id, value)| (*type_id, (**value).clone_box()))
+                .collect(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Attributes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set()
+            .entries(self.values.values().map(|value| (**value).type_name()))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        test::{test_tree_node, TestNode},
+        TreeNode as _, TreeNodeRef as _,
+    };
+
+    use super::Attributes;
+
+    type ArcNodeRef =
+        crate::noderef::arc::NodeRef<crate::node::arc::Node<&'static str, crate::NodeId>>;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Layout {
+        width: u32,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Dirty(bool);
+
+    #[traced_test]
+    #[test]
+    fn attributes() {
+        let mut attrs = Attributes::new();
+        assert!(attrs.insert(Layout { width: 10 }).is_none());
+        assert_eq!(
+            attrs.insert(Layout { width: 20 }),
+            Some(Layout { width: 10 })
+        );
+        attrs.get_or_insert_with(|| Dirty(false)).0 = true;
+
+        assert_eq!(attrs.len(), 2);
+        assert_eq!(attrs.get::<Layout>(), Some(&Layout { width: 20 }));
+        assert_eq!(attrs.get::<Dirty>(), Some(&Dirty(true)));
+        assert!(!attrs.contains::<u32>());
+
+        let copy = attrs.clone();
+        assert_eq!(attrs.remove::<Dirty>(), Some(Dirty(true)));
+        assert!(attrs.get::<Dirty>().is_none());
+        assert_eq!(copy.get::<Dirty>(), Some(&Dirty(true)));
+    }
+
+    #[traced_test]
+    #[test]
+    fn node_attributes() {
+        let tree = test_tree_node(vec![TestNode("a", vec![])]);
+
+        let mut a = tree.find(|data| *data == "a").unwrap();
+        a.node_mut().attrs_mut().insert(Layout { width: 5 });
+
+        let a = tree.find(|data| *data == "a").unwrap();
+        assert_eq!(a.node().attrs().get::<Layout>().unwrap().width, 5);
+
+        // Attributes are not hashed, and are carried over to copies
+        assert_eq!(tree, test_tree_node(vec![TestNode("a", vec![])]));
+
+        let copy = tree.map::<ArcNodeRef, _>(Clone::clone).index();
+        let copied = copy.find(|data| *data == "a").unwrap();
+        assert_eq!(
+            copied.node().attrs().get::<Layout>(),
+            Some(&Layout { width: 5 })
+        );
+    }
+}
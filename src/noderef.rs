@@ -1,9 +1,24 @@
+//! Shared references to tree nodes.
+//!
+//! A [`TreeNodeRef`] is a cloneable handle to a node, and every clone refers to the same node.
+//! Nodes are mutated through shared handles, and each node holds a handle to its parent, so
+//! implementations need shared ownership with interior mutability.
+//!
+//! - [`rc`]: `Rc<RefCell<_>>`, for single threaded trees
+//! - [`arc`]: `Arc<RwLock<_>>`, for trees shared between threads
+//! - [`arena`]: nodes stored in slabs, with reference counted slot handles
+//!
+//! Trees which never need shared handles can be held as owned nodes from [`boxed`], which
+//! exclusively own their children and are converted to a [`Tree`](crate::Tree) when needed.
+
 use std::{
     cell::{BorrowError, BorrowMutError},
     ops::{Deref, DerefMut},
 };
 
 pub mod arc;
+pub mod arena;
+pub mod boxed;
 pub mod rc;
 
 /// Type alias to get associated type of Id from the Inner node of a NodeRef
@@ -38,22 +53,47 @@ pub trait TreeNodeRef:
     // The Data type contained within the Inner Node
     type Data;
 
+    // Non-owning handle to the Inner Node
+    type Weak: TreeWeakNodeRef<NodeRef = Self>;
+
+    /// Storage which the nodes of a [`Tree`](crate::Tree) or builder are allocated from, owned
+    /// by the tree or builder. Backends which allocate each node separately use `()`.
+    type Allocator: Default + Clone + std::fmt::Debug;
+
     // Create a new NodeRef with the supplied Inner node
     fn new<T>(node: T) -> Self
     where
         T: Into<Self::Inner>;
 
+    /// Create a new NodeRef with the supplied Inner node, allocated from `allocator`
+    fn new_in<T>(node: T, _allocator: &Self::Allocator) -> Self
+    where
+        T: Into<Self::Inner>,
+    {
+        Self::new(node)
+    }
+
     /// Get a reference to the inner node
     fn node<'b>(&'b self) -> Self::InnerRef<'b>;
 
-    /// Try to get a reference to the inner node
-    fn try_node<'b>(&'b self) -> Result<Self::InnerRef<'b>, BorrowError>;
+    /// Try to get a reference to the inner node without blocking
+    fn try_node<'b>(&'b self) -> Result<Self::InnerRef<'b>, AccessError>;
 
     /// Get a reference to the inner node
     fn node_mut<'b>(&'b mut self) -> Self::InnerRefMut<'b>;
 
-    /// Try to get a mutable reference to the inner node
-    fn try_node_mut<'b>(&'b self) -> Result<Self::InnerRefMut<'b>, BorrowMutError>;
+    /// Try to get a mutable reference to the inner node without blocking
+    fn try_node_mut<'b>(&'b self) -> Result<Self::InnerRefMut<'b>, AccessError>;
+
+    /// Create a non-owning handle to the node, which does not keep it alive
+    fn downgrade(&self) -> Self::Weak;
+
+    /// Reserve storage in `allocator` for at least `additional` more nodes created with
+    /// [`TreeNodeRef::new_in`]. Backends which allocate each node separately ignore the hint.
+    fn reserve(_allocator: &Self::Allocator, _additional: usize) {}
+
+    /// Check if both references point to the same node instance, without accessing the nodes
+    fn ptr_eq(&self, other: &Self) -> bool;
 
     /// Calls the provided closure with a reference to the Node's data
     fn with_data<'b, R, E, F>(&'b self, f: F) -> Result<R, E>
@@ -65,7 +105,9 @@ pub trait TreeNodeRef:
         f(data)
     }
 
-    /// Calls the provided closure with a mutable reference to the Node's data
+    /// Calls the provided closure with a mutable reference to the Node's data. Changes made
+    /// through the node directly don't send tree events or update ancestor hashes, see
+    /// [`IndexedTree::update_data`](crate::IndexedTree::update_data).
     fn with_data_mut<'b, R, E, F>(&'b mut self, f: F) -> Result<R, E>
     where
         F: FnOnce(<Self::Inner as TreeNode>::DataRefMut<'_>) -> Result<R, E> + 'b,
@@ -84,24 +126,15 @@ pub trait TreeNodeRef:
         // Create a stack with depth 0, and the initial node
         let mut stack: Vec<(usize, Self)> = Vec::from([(0, self.clone())]);
 
-        loop {
-            let current = stack.pop();
-            if let None = current {
-                break;
-            };
-            let node = current.map(|node| {
-                node.1.node().children().map(|children| {
-                    children
-                        .iter()
-                        .rev()
-                        .for_each(|child| stack.push((node.0 + 1, child.clone())))
-                });
-                node
-            });
-
-            if let Some(node) = node {
-                f(node.0, node.1)?
+        while let Some((depth, node)) = stack.pop() {
+            if let Some(children) = node.node().children() {
+                children
+                    .iter()
+                    .rev()
+                    .for_each(|child| stack.push((depth + 1, child.clone())))
             }
+
+            f(depth, node)?
         }
         Ok(())
     }
@@ -114,46 +147,188 @@ pub trait TreeNodeRef:
     {
         let mut stack: Vec<Self> = Vec::from([self.clone()]);
 
-        loop {
-            let current = stack.pop();
-            if let None = current {
-                break;
-            };
-            let node = current.map(|mut node| {
-                node.node_mut().children_mut().map(|mut children| {
-                    children
-                        .iter_mut()
-                        .rev()
-                        .for_each(|child| stack.push(child.clone()))
-                });
-                node
-            });
-
-            if let Some(mut node) = node {
-                f(&mut node)?
+        while let Some(mut node) = stack.pop() {
+            // Borrow immutably, so visiting a node doesn't bump its generation
+            if let Some(children) = node.node().children() {
+                children
+                    .iter()
+                    .rev()
+                    .for_each(|child| stack.push(child.clone()))
             }
+
+            f(&mut node)?
         }
         Ok(())
     }
+
+    /// Find the first node in depth first order whose data matches the predicate,
+    /// stopping the traversal at the first match
+    fn find<F>(&self, mut f: F) -> Option<Self>
+    where
+        F: FnMut(&<Self::Inner as TreeNode>::Data) -> bool,
+    {
+        self.find_map(|data| f(data).then_some(()))
+            .map(|(node, _)| node)
+    }
+
+    /// Call the closure with the data of each node in depth first order, returning the first
+    /// node for which it returns `Some`, along with the returned value
+    fn find_map<T, F>(&self, mut f: F) -> Option<(Self, T)>
+    where
+        F: FnMut(&<Self::Inner as TreeNode>::Data) -> Option<T>,
+    {
+        let mut stack: Vec<Self> = Vec::from([self.clone()]);
+
+        while let Some(node) = stack.pop() {
+            let value = f(&node.node().data());
+            if let Some(value) = value {
+                return Some((node, value));
+            }
+
+            if let Some(children) = node.node().children() {
+                stack.extend(children.iter().rev().cloned());
+            }
+        }
+
+        None
+    }
+}
+
+/// Error returned when a node can't be accessed without blocking, because it is in use through
+/// another reference
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AccessError {
+    /// The node could not be read, as it is being mutated
+    Read,
+
+    /// The node could not be mutated, as it is being read or mutated
+    Write,
+}
+
+impl std::fmt::Display for AccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccessError::Read => write!(f, "node is being mutated"),
+            AccessError::Write => write!(f, "node is being read or mutated"),
+        }
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+impl From<BorrowError> for AccessError {
+    fn from(_: BorrowError) -> Self {
+        AccessError::Read
+    }
+}
+
+impl From<BorrowMutError> for AccessError {
+    fn from(_: BorrowMutError) -> Self {
+        AccessError::Write
+    }
+}
+
+/// Non-owning handle to a node, created with [`TreeNodeRef::downgrade`]
+pub trait TreeWeakNodeRef: Clone {
+    type NodeRef: TreeNodeRef;
+
+    /// Get a reference to the node, or `None` if it has been dropped
+    fn upgrade(&self) -> Option<Self::NodeRef>;
 }
 
 trait TreeFormat {
-    fn tree_format_display(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result;
     fn tree_format_debug(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result;
 }
 
-// Implement TreeFormat on anything implementing NodeRef
-impl<T: TreeNodeRef> TreeFormat for T
+trait TreeFormatDisplay {
+    fn tree_format_display(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result;
+}
+
+// Implement TreeFormatDisplay on any NodeRef with displayable data
+impl<T> TreeFormatDisplay for T
 where
     T: TreeNodeRef,
-    T::Inner: std::fmt::Debug,
+    <T::Inner as TreeNode>::Data: std::fmt::Display,
 {
     fn tree_format_display(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         TreeDisplay::format(self, f, |data, f| write!(f, "{}", *data))
     }
+}
+
+// Implement TreeFormat on anything implementing NodeRef
+impl<T: TreeNodeRef> TreeFormat for T
+where
+    T: TreeNodeRef,
+    T::Inner: std::fmt::Debug,
+{
     fn tree_format_debug(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("NodeRef")
             //.field("node", &*self.try_node().unwrap())
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{NodeId, TreeNode as _};
+
+    use super::{AccessError, TreeNodeRef};
+
+    fn conflicts<R: TreeNodeRef>(mut node: R) {
+        let other = node.clone();
+
+        let guard = node.node_mut();
+        assert_eq!(other.try_node().err(), Some(AccessError::Read));
+        assert_eq!(other.try_node_mut().err(), Some(AccessError::Write));
+        drop(guard);
+
+        let guard = other.try_node().unwrap();
+        assert!(other.try_node().is_ok());
+        assert_eq!(other.try_node_mut().err(), Some(AccessError::Write));
+        drop(guard);
+
+        assert!(other.try_node_mut().is_ok());
+    }
+
+    fn identity<R: TreeNodeRef>(node: R, equal: R) {
+        assert!(node.ptr_eq(&node.clone()));
+        assert!(!node.ptr_eq(&equal));
+        assert_eq!(node.node().xxhash(), equal.node().xxhash());
+    }
+
+    #[traced_test]
+    #[test]
+    fn access_error() {
+        type RcNode = crate::node::rc::Node<&'static str, NodeId>;
+        type ArcNode = crate::node::arc::Node<&'static str, NodeId>;
+        type ArenaNode = crate::node::arena::Node<&'static str, NodeId>;
+
+        conflicts(super::rc::NodeRef::new(RcNode::new(0, "rc", None)));
+        conflicts(super::arc::NodeRef::new(ArcNode::new(0, "arc", None)));
+        conflicts(super::arena::NodeRef::new(ArenaNode::new(0, "arena", None)));
+    }
+
+    #[traced_test]
+    #[test]
+    fn ptr_eq() {
+        type RcNode = crate::node::rc::Node<&'static str, NodeId>;
+        type ArcNode = crate::node::arc::Node<&'static str, NodeId>;
+        type ArenaNode = crate::node::arena::Node<&'static str, NodeId>;
+
+        identity(
+            super::rc::NodeRef::new(RcNode::new(0, "a", None)),
+            super::rc::NodeRef::new(RcNode::new(0, "a", None)),
+        );
+        identity(
+            super::arc::NodeRef::new(ArcNode::new(0, "a", None)),
+            super::arc::NodeRef::new(ArcNode::new(0, "a", None)),
+        );
+        identity(
+            super::arena::NodeRef::new(ArenaNode::new(0, "a", None)),
+            super::arena::NodeRef::new(ArenaNode::new(0, "a", None)),
+        );
+    }
+}
@@ -1,4 +1,7 @@
-use std::{cell::BorrowError, collections::VecDeque, sync::Arc};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Weak},
+};
 
 use parking_lot::{ArcRwLockReadGuard, ArcRwLockWriteGuard, RawRwLock, RwLock};
 
@@ -7,7 +10,10 @@ use crate::{
     TreeNode,
 };
 
-use super::{internal::NodeRefInternal, TreeFormat as _, TreeNodeRef};
+use super::{
+    internal::NodeRefInternal, AccessError, TreeFormat as _, TreeFormatDisplay as _, TreeNodeRef,
+    TreeWeakNodeRef,
+};
 
 pub struct NodeRef<T>
 where
@@ -16,6 +22,45 @@ where
     node_ref: Arc<RwLock<T>>,
 }
 
+/// Non-owning handle to a [`NodeRef`]
+pub struct WeakNodeRef<T>
+where
+    T: TreeNode<NodeRef = NodeRef<T>>,
+{
+    node_ref: Weak<RwLock<T>>,
+}
+
+impl<T> Clone for WeakNodeRef<T>
+where
+    T: TreeNode<NodeRef = NodeRef<T>>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            node_ref: self.node_ref.clone(),
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for WeakNodeRef<T>
+where
+    T: TreeNode<NodeRef = NodeRef<T>>,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WeakNodeRef").finish()
+    }
+}
+
+impl<T> TreeWeakNodeRef for WeakNodeRef<T>
+where
+    T: TreeNode<NodeRef = NodeRef<T>> + std::fmt::Debug + 'static,
+{
+    type NodeRef = NodeRef<T>;
+
+    fn upgrade(&self) -> Option<NodeRef<T>> {
+        self.node_ref.upgrade().map(|node_ref| NodeRef { node_ref })
+    }
+}
+
 impl<T> std::hash::Hash for NodeRef<T>
 where
     T: TreeNode<NodeRef = Self> + std::fmt::Debug + 'static,
@@ -50,6 +95,7 @@ where
 impl<T> std::fmt::Display for NodeRef<T>
 where
     T: TreeNode<NodeRef = Self> + 'static,
+    T::Data: std::fmt::Display,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.tree_format_display(f)
@@ -75,6 +121,8 @@ where
     type InnerRef<'b> = ArcRwLockReadGuard<RawRwLock, Self::Inner>;
     type InnerRefMut<'b> = ArcRwLockWriteGuard<RawRwLock, Self::Inner>;
     type Data = T::Data;
+    type Weak = WeakNodeRef<T>;
+    type Allocator = ();
 
     fn new<N>(node: N) -> Self
     where
@@ -89,18 +137,26 @@ where
         self.node_ref.read_arc()
     }
 
-    fn try_node<'b>(&'b self) -> Result<Self::InnerRef<'b>, BorrowError> {
-        // TODO: change error type of Result to handle other impls
-        Ok(self.node_ref.try_read_arc().unwrap())
+    fn try_node<'b>(&'b self) -> Result<Self::InnerRef<'b>, AccessError> {
+        self.node_ref.try_read_arc().ok_or(AccessError::Read)
     }
 
     fn node_mut<'b>(&'b mut self) -> Self::InnerRefMut<'b> {
         self.node_ref.write_arc()
     }
 
-    fn try_node_mut<'b>(&'b self) -> Result<Self::InnerRefMut<'b>, std::cell::BorrowMutError> {
-        // TODO: change error type of Result to handle other impls
-        Ok(self.node_ref.try_write_arc().unwrap())
+    fn try_node_mut<'b>(&'b self) -> Result<Self::InnerRefMut<'b>, AccessError> {
+        self.node_ref.try_write_arc().ok_or(AccessError::Write)
+    }
+
+    fn downgrade(&self) -> Self::Weak {
+        WeakNodeRef {
+            node_ref: Arc::downgrade(&self.node_ref),
+        }
+    }
+
+    fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.node_ref, &other.node_ref)
     }
 
     fn for_each<E, F>(&self, f: F) -> Result<(), E>
@@ -110,24 +166,15 @@ where
         // Create a stack with depth 0, and the initial node
         let mut stack: VecDeque<(usize, Self)> = VecDeque::from([(0, self.clone())]);
 
-        loop {
-            let current = stack.pop_front();
-            if let None = current {
-                break;
-            };
-            let node = current.map(|node| {
-                node.1.node().children().map(|children| {
-                    children
-                        .iter()
-                        .rev()
-                        .for_each(|child| stack.push_front((node.0 + 1, child.clone())))
-                });
-                node
-            });
-
-            if let Some(node) = node {
-                f(node.0, node.1)?
+        while let Some((depth, node)) = stack.pop_front() {
+            if let Some(children) = node.node().children() {
+                children
+                    .iter()
+                    .rev()
+                    .for_each(|child| stack.push_front((depth + 1, child.clone())))
             }
+
+            f(depth, node)?
         }
         Ok(())
     }
@@ -159,7 +206,7 @@ where
     }
 }
 
-impl<'a, N> IntoIterator for &'a NodeRef<N>
+impl<N> IntoIterator for &NodeRef<N>
 where
     N: TreeNode<NodeRef = NodeRef<N>> + 'static,
 {
@@ -0,0 +1,543 @@
+//! Arena backed [`TreeNodeRef`], storing nodes in contiguous slabs rather than individual
+//! allocations.
+//!
+//! Nodes are allocated from an [`Arena`] of chunks, each holding a fixed number of node slots.
+//! A [`NodeRef`] is a handle to the chunk and the index of a slot within it, which is reference
+//! counted like an `Rc`. When the last handle to a node is dropped, the node is dropped and its
+//! slot is reused by later allocations.
+//!
+//! Each [`Tree`](crate::Tree) and builder owns the arena its nodes are allocated from, which can
+//! be provided with [`Tree::with_allocator`](crate::Tree::with_allocator) or
+//! [`TreeBuilder::with_allocator`](crate::TreeBuilder::with_allocator). A chunk is freed once the
+//! arena and every handle to its nodes are dropped, and chunks left without nodes are released
+//! by the arena as it allocates. Nodes created with [`TreeNodeRef::new`], outside of a tree or
+//! builder, are allocated in a chunk of their own.
+
+use std::{
+    cell::{Cell, Ref, RefCell, RefMut},
+    rc::Rc,
+};
+
+use crate::{
+    iterator::{IterNode, NodeRefIter},
+    TreeNode,
+};
+
+use super::{
+    internal::NodeRefInternal, AccessError, TreeFormat as _, TreeFormatDisplay as _, TreeNodeRef,
+    TreeWeakNodeRef,
+};
+
+// Number of slots in the first chunk of an arena. Each further chunk doubles in size, up to
+// the maximum.
+const MIN_CHUNK_SLOTS: usize = 64;
+const MAX_CHUNK_SLOTS: usize = 64 * 1024;
+
+struct Slot<T> {
+    node: RefCell<Option<T>>,
+    refs: Cell<usize>,
+
+    // Incremented when the node in the slot is dropped, so weak references to it are not
+    // upgraded to a later node reusing the slot
+    generation: Cell<u64>,
+}
+
+/// Contiguous block of node slots
+struct Chunk<T> {
+    slots: Box<[Slot<T>]>,
+
+    // Slots released by dropped nodes
+    free: RefCell<Vec<usize>>,
+
+    // Number of slots which have been used at least once
+    used: Cell<usize>,
+}
+
+impl<T> Chunk<T> {
+    fn new(len: usize) -> Self {
+        Self {
+            slots: (0..len)
+                .map(|_| Slot {
+                    node: RefCell::new(None),
+                    refs: Cell::new(0),
+                    generation: Cell::new(0),
+                })
+                .collect(),
+            free: RefCell::new(Vec::new()),
+            used: Cell::new(0),
+        }
+    }
+
+    /// Store a node in a vacant slot, returning its index
+    fn alloc(&self, node: T) -> Result<usize, T> {
+        let slot = match self.free.borrow_mut().pop() {
+            Some(slot) => slot,
+            None if self.used.get() < self.slots.len() => {
+                self.used.set(self.used.get() + 1);
+                self.used.get() - 1
+            }
+            None => return Err(node),
+        };
+
+        *self.slots[slot].node.borrow_mut() = Some(node);
+        self.slots[slot].refs.set(1);
+        Ok(slot)
+    }
+
+    fn live(&self) -> usize {
+        self.used.get() - self.free.borrow().len()
+    }
+}
+
+/// Slab storage of the nodes referenced by arena [`NodeRef`]s. Clones share the same storage.
+pub struct Arena<T> {
+    chunks: Rc<RefCell<Vec<Rc<Chunk<T>>>>>,
+}
+
+impl<T> std::fmt::Debug for Arena<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let chunks = self.chunks.borrow();
+        f.debug_struct("Arena")
+            .field("chunks", &chunks.len())
+            .field(
+                "live",
+                &chunks.iter().map(|chunk| chunk.live()).sum::<usize>(),
+            )
+            .finish()
+    }
+}
+
+impl<T> Clone for Arena<T> {
+    fn clone(&self) -> Self {
+        Self {
+            chunks: self.chunks.clone(),
+        }
+    }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self {
+            chunks: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+}
+
+impl<T> Arena<T>
+where
+    T: TreeNode<NodeRef = NodeRef<T>>,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an arena with a single chunk of `nodes` contiguous slots
+    pub fn with_capacity(nodes: usize) -> Self {
+        let arena = Self::new();
+        arena.reserve(nodes);
+        arena
+    }
+
+    /// Move a node into the arena, returning a reference to it
+    pub fn alloc(&self, mut node: T) -> NodeRef<T> {
+        let mut chunks = self.chunks.borrow_mut();
+        Self::release_empty(&mut chunks);
+
+        // Recently allocated chunks are the most likely to have vacant slots
+        for chunk in chunks.iter().rev() {
+            match chunk.alloc(node) {
+                Ok(slot) => {
+                    return NodeRef {
+                        chunk: chunk.clone(),
+                        slot,
+                    }
+                }
+                Err(returned) => node = returned,
+            }
+        }
+
+        let len = chunks
+            .last()
+            .map_or(MIN_CHUNK_SLOTS, |chunk| chunk.slots.len() * 2)
+            .min(MAX_CHUNK_SLOTS);
+        let chunk = Rc::new(Chunk::new(len));
+        let Ok(slot) = chunk.alloc(node) else {
+            unreachable!("new chunk has vacant slots");
+        };
+        chunks.push(chunk.clone());
+
+        NodeRef { chunk, slot }
+    }
+
+    /// Ensure at least `additional` more nodes can be allocated without adding a chunk. Missing
+    /// slots are added as a single contiguous chunk.
+    pub fn reserve(&self, additional: usize) {
+        let mut chunks = self.chunks.borrow_mut();
+        Self::release_empty(&mut chunks);
+        let vacant: usize = chunks
+            .iter()
+            .map(|chunk| chunk.slots.len() - chunk.live())
+            .sum();
+
+        if additional > vacant {
+            chunks.push(Rc::new(Chunk::new(additional - vacant)));
+        }
+    }
+
+    /// Release chunks left without nodes, keeping the most recent for reuse. A released chunk is
+    /// freed once the weak handles to its former nodes are dropped.
+    fn release_empty(chunks: &mut Vec<Rc<Chunk<T>>>) {
+        let last = chunks.pop();
+        chunks.retain(|chunk| chunk.live() > 0);
+        chunks.extend(last);
+    }
+
+    /// Number of live nodes in the arena
+    pub fn len(&self) -> usize {
+        self.chunks.borrow().iter().map(|chunk| chunk.live()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of node slots allocated by the arena
+    pub fn capacity(&self) -> usize {
+        self.chunks
+            .borrow()
+            .iter()
+            .map(|chunk| chunk.slots.len())
+            .sum()
+    }
+}
+
+pub struct NodeRef<T>
+where
+    T: TreeNode<NodeRef = Self>,
+{
+    chunk: Rc<Chunk<T>>,
+    slot: usize,
+}
+
+impl<T> NodeRef<T>
+where
+    T: TreeNode<NodeRef = Self> + 'static,
+{
+    /// Allocate a node in a chunk of its own. Nodes of a tree are allocated from the arena of
+    /// the tree with [`TreeNodeRef::new_in`].
+    pub fn new(node: T) -> Self {
+        Arena::with_capacity(1).alloc(node)
+    }
+
+    fn cell(&self) -> &RefCell<Option<T>> {
+        &self.chunk.slots[self.slot].node
+    }
+}
+
+impl<T> Drop for NodeRef<T>
+where
+    T: TreeNode<NodeRef = Self>,
+{
+    fn drop(&mut self) {
+        let slot = &self.chunk.slots[self.slot];
+        slot.refs.set(slot.refs.get() - 1);
+        if slot.refs.get() > 0 {
+            return;
+        }
+
+        // Release the borrow before dropping the node, which may drop the last
+        // references to other nodes in the same chunk
+        let node = slot.node.borrow_mut().take();
+        slot.generation.set(slot.generation.get() + 1);
+        drop(node);
+
+        self.chunk.free.borrow_mut().push(self.slot);
+    }
+}
+
+/// Non-owning handle to a [`NodeRef`]. Keeps the chunk of the node allocated, but not the
+/// node itself.
+pub struct WeakNodeRef<T>
+where
+    T: TreeNode<NodeRef = NodeRef<T>>,
+{
+    chunk: Rc<Chunk<T>>,
+    slot: usize,
+    generation: u64,
+}
+
+impl<T> Clone for WeakNodeRef<T>
+where
+    T: TreeNode<NodeRef = NodeRef<T>>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            chunk: self.chunk.clone(),
+            slot: self.slot,
+            generation: self.generation,
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for WeakNodeRef<T>
+where
+    T: TreeNode<NodeRef = NodeRef<T>>,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WeakNodeRef").finish()
+    }
+}
+
+impl<T> TreeWeakNodeRef for WeakNodeRef<T>
+where
+    T: TreeNode<NodeRef = NodeRef<T>> + std::fmt::Debug + 'static,
+{
+    type NodeRef = NodeRef<T>;
+
+    fn upgrade(&self) -> Option<NodeRef<T>> {
+        let slot = &self.chunk.slots[self.slot];
+        if slot.generation.get() != self.generation || slot.refs.get() == 0 {
+            return None;
+        }
+
+        slot.refs.set(slot.refs.get() + 1);
+        Some(NodeRef {
+            chunk: self.chunk.clone(),
+            slot: self.slot,
+        })
+    }
+}
+
+impl<T> std::hash::Hash for NodeRef<T>
+where
+    T: TreeNode<NodeRef = Self> + std::fmt::Debug + 'static,
+{
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.node().hash(state)
+    }
+}
+
+impl<T> Clone for NodeRef<T>
+where
+    T: TreeNode<NodeRef = Self>,
+{
+    fn clone(&self) -> Self {
+        let refs = &self.chunk.slots[self.slot].refs;
+        refs.set(refs.get() + 1);
+
+        Self {
+            chunk: self.chunk.clone(),
+            slot: self.slot,
+        }
+    }
+}
+
+impl<T> std::fmt::Display for NodeRef<T>
+where
+    T: TreeNode<NodeRef = Self> + 'static,
+    T::Data: std::fmt::Display,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.tree_format_display(f)
+    }
+}
+
+impl<T> std::fmt::Debug for NodeRef<T>
+where
+    T: TreeNode<NodeRef = Self> + std::fmt::Debug + 'static,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.tree_format_debug(f)
+    }
+}
+
+impl<T> NodeRefInternal<T> for NodeRef<T> where T: TreeNode<NodeRef = Self> + 'static {}
+
+impl<T> TreeNodeRef for NodeRef<T>
+where
+    T: TreeNode<NodeRef = Self> + std::fmt::Debug + 'static,
+{
+    type Inner = T;
+    type InnerRef<'b> = Ref<'b, Self::Inner>;
+    type InnerRefMut<'b> = RefMut<'b, Self::Inner>;
+    type Data = T::Data;
+    type Weak = WeakNodeRef<T>;
+    type Allocator = Arena<T>;
+
+    fn new<N>(node: N) -> Self
+    where
+        N: Into<Self::Inner>,
+    {
+        NodeRef::new(node.into())
+    }
+
+    fn new_in<N>(node: N, allocator: &Self::Allocator) -> Self
+    where
+        N: Into<Self::Inner>,
+    {
+        allocator.alloc(node.into())
+    }
+
+    fn node<'b>(&'b self) -> Self::InnerRef<'b> {
+        Ref::map(self.cell().borrow(), |node| {
+            node.as_ref().expect("live node in arena slot")
+        })
+    }
+
+    fn try_node<'b>(&'b self) -> Result<Self::InnerRef<'b>, AccessError> {
+        let node = self.cell().try_borrow()?;
+        Ok(Ref::map(node, |node| {
+            node.as_ref().expect("live node in arena slot")
+        }))
+    }
+
+    fn node_mut<'b>(&'b mut self) -> Self::InnerRefMut<'b> {
+        RefMut::map(self.cell().borrow_mut(), |node| {
+            node.as_mut().expect("live node in arena slot")
+        })
+    }
+
+    fn try_node_mut<'b>(&'b self) -> Result<Self::InnerRefMut<'b>, AccessError> {
+        let node = self.cell().try_borrow_mut()?;
+        Ok(RefMut::map(node, |node| {
+            node.as_mut().expect("live node in arena slot")
+        }))
+    }
+
+    fn downgrade(&self) -> Self::Weak {
+        WeakNodeRef {
+            chunk: self.chunk.clone(),
+            slot: self.slot,
+            generation: self.chunk.slots[self.slot].generation.get(),
+        }
+    }
+
+    fn ptr_eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.chunk, &other.chunk) && self.slot == other.slot
+    }
+
+    fn reserve(allocator: &Self::Allocator, additional: usize) {
+        allocator.reserve(additional);
+    }
+}
+
+impl<N> IntoIterator for NodeRef<N>
+where
+    N: TreeNode<NodeRef = Self> + 'static,
+{
+    type Item = IterNode<Self>;
+    type IntoIter = NodeRefIter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        // Create an iterator starting with the root node in the stack
+        NodeRefIter::new(self)
+    }
+}
+
+impl<N> IntoIterator for &NodeRef<N>
+where
+    N: TreeNode<NodeRef = NodeRef<N>> + 'static,
+{
+    type Item = IterNode<NodeRef<N>>;
+    type IntoIter = NodeRefIter<NodeRef<N>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        // Create an iterator starting with the root node in the stack
+        NodeRefIter::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        node::arena::Node, IdGenerator, NodeId, Tree, TreeBuilder, TreeNode as _, TreeNodeRef as _,
+        TreeWeakNodeRef as _,
+    };
+
+    use super::{Arena, NodeRef};
+
+    type ArenaNode = Node<&'static str, NodeId>;
+
+    #[traced_test]
+    #[test]
+    fn arena() {
+        let arena = Arena::<ArenaNode>::with_capacity(16);
+
+        let mut tree: Tree<NodeRef<ArenaNode>> =
+            TreeBuilder::<&str, (), IdGenerator, ArenaNode, NodeRef<ArenaNode>>::new()
+                .with_allocator(arena.clone())
+                .root("root", |root| {
+                    for data in ["a", "b", "c"] {
+                        root.child(data, |_| Ok(()))?;
+                    }
+                    Ok(())
+                })
+                .unwrap()
+                .done()
+                .unwrap()
+                .unwrap();
+
+        // The provided arena backs the builder, and is handed to the tree
+        assert_eq!(arena.len(), 4);
+        assert_eq!(arena.capacity(), 16);
+        assert_eq!(tree.allocator().len(), 4);
+        assert_eq!(tree.root_ref().node().num_children(), 3);
+
+        let mut root = tree.root();
+        let removed = tree.remove_child(&mut root, 0).unwrap();
+        drop(removed);
+        assert_eq!(arena.len(), 3);
+
+        // Released slots are reused by nodes created by the tree
+        tree.append_child(&mut root, "d").unwrap();
+        assert_eq!(arena.capacity(), 16);
+        assert_eq!(arena.len(), 4);
+
+        // Nodes created outside of a tree don't use its arena
+        let node = NodeRef::new(ArenaNode::new(10, "e", None));
+        assert_eq!(arena.len(), 4);
+        drop(node);
+    }
+
+    #[traced_test]
+    #[test]
+    fn chunks() {
+        let arena = Arena::<ArenaNode>::new();
+        let nodes: Vec<_> = (0..200)
+            .map(|id| arena.alloc(ArenaNode::new(id, "n", None)))
+            .collect();
+
+        // Chunks double in size as the arena grows
+        assert_eq!(arena.len(), 200);
+        assert_eq!(arena.capacity(), 64 + 128 + 256);
+        assert_eq!(nodes[150].node().id(), 150);
+
+        // Chunks left without nodes are released, except the most recent
+        let mut nodes: Vec<_> = nodes.into_iter().skip(64).collect();
+        nodes.push(arena.alloc(ArenaNode::new(200, "n", None)));
+        assert_eq!(arena.capacity(), 128 + 256);
+
+        drop(nodes);
+        assert!(arena.is_empty());
+        arena.reserve(1);
+        assert_eq!(arena.capacity(), 256);
+    }
+
+    #[traced_test]
+    #[test]
+    fn weak() {
+        let arena = Arena::<ArenaNode>::new();
+        let node = arena.alloc(ArenaNode::new(1, "a", None));
+        let weak = node.downgrade();
+        assert_eq!(weak.upgrade().unwrap().node().id(), 1);
+
+        // A weak reference is not upgraded to a node reusing the slot
+        drop(node);
+        let reused = arena.alloc(ArenaNode::new(2, "b", None));
+        assert_eq!(arena.capacity(), 64);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(reused.downgrade().upgrade().unwrap().node().id(), 2);
+    }
+}
@@ -0,0 +1,287 @@
+//! Owned nodes, where each node exclusively owns its children, without reference counting or
+//! interior mutability.
+//!
+//! Owned nodes have no handle to their parent and can't be cloned into a second handle to the
+//! same node, so they don't implement [`TreeNodeRef`]. They implement the smaller
+//! [`OwnedTreeNode`] trait instead, and are converted to and from a [`Tree`] with
+//! [`Tree::from_boxed`] and [`Tree::to_boxed`] when shared handles, hashes or indexes are needed.
+//! A [`Node`] is `Send` and `Sync` whenever its ID and data are.
+
+use crate::{
+    hash::rehash_subtree,
+    node::TreeNode,
+    noderef::{NodeRefId, TreeNodeRef},
+    NodeId, Tree, UniqueGenerator,
+};
+
+/// A node which exclusively owns its children
+pub trait OwnedTreeNode: Sized {
+    type Id: Copy;
+    type Data;
+
+    /// Create a node without children
+    fn new(id: Self::Id, data: Self::Data) -> Self;
+
+    /// Get the ID of the node
+    fn id(&self) -> Self::Id;
+
+    /// Get a reference to the data of the node
+    fn data(&self) -> &Self::Data;
+
+    /// Get a mutable reference to the data of the node
+    fn data_mut(&mut self) -> &mut Self::Data;
+
+    /// Get the children of the node
+    fn children(&self) -> &[Self];
+
+    /// Get the children of the node for modification
+    fn children_mut(&mut self) -> &mut Vec<Self>;
+
+    /// Split the node into its ID, data and children
+    fn into_parts(self) -> (Self::Id, Self::Data, Vec<Self>);
+
+    /// Add a child as the last child of the node
+    fn push_child(&mut self, child: Self) {
+        self.children_mut().push(child);
+    }
+
+    /// Iterate through the node and its descendants in depth first order
+    fn iter(&self) -> Iter<'_, Self> {
+        Iter { stack: vec![self] }
+    }
+}
+
+/// Depth first iterator over an [`OwnedTreeNode`] and its descendants
+pub struct Iter<'a, N> {
+    stack: Vec<&'a N>,
+}
+
+impl<'a, N> Iterator for Iter<'a, N>
+where
+    N: OwnedTreeNode,
+{
+    type Item = &'a N;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        // Reversed so children are visited in order
+        self.stack.extend(node.children().iter().rev());
+        Some(node)
+    }
+}
+
+/// Node which owns its data and children directly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node<D, Id = NodeId> {
+    id: Id,
+    data: D,
+    children: Vec<Node<D, Id>>,
+}
+
+impl<D, Id> OwnedTreeNode for Node<D, Id>
+where
+    Id: Copy,
+{
+    type Id = Id;
+    type Data = D;
+
+    fn new(id: Id, data: D) -> Self {
+        Self {
+            id,
+            data,
+            children: Vec::new(),
+        }
+    }
+
+    fn id(&self) -> Id {
+        self.id
+    }
+
+    fn data(&self) -> &D {
+        &self.data
+    }
+
+    fn data_mut(&mut self) -> &mut D {
+        &mut self.data
+    }
+
+    fn children(&self) -> &[Self] {
+        &self.children
+    }
+
+    fn children_mut(&mut self) -> &mut Vec<Self> {
+        &mut self.children
+    }
+
+    fn into_parts(self) -> (Id, D, Vec<Self>) {
+        (self.id, self.data, self.children)
+    }
+}
+
+impl<D, Id> Node<D, Id>
+where
+    D: Clone,
+    Id: Copy,
+{
+    /// Copy the subtree rooted at `root` into owned nodes with the same IDs and data
+    pub(crate) fn from_subtree<R>(root: R) -> Self
+    where
+        R: TreeNodeRef,
+        R::Inner: TreeNode<Id = Id, Data = D>,
+    {
+        // Stack of nodes being copied, with the index of the next child to visit and the copies
+        // of the children visited so far
+        let mut stack: Vec<(R, usize, Vec<Self>)> = vec![(root, 0, Vec::new())];
+
+        loop {
+            let (node, next, _) = stack.last_mut().expect("stack holds the root");
+            let child = node
+                .node()
+                .children()
+                .and_then(|children| children.get(*next).cloned());
+
+            if let Some(child) = child {
+                *next += 1;
+                stack.push((child, 0, Vec::new()));
+                continue;
+            }
+
+            let (node, _, children) = stack.pop().expect("stack holds the node");
+            let inner = node.node();
+            let copy = Node {
+                id: inner.id(),
+                data: inner.data().clone(),
+                children,
+            };
+
+            match stack.last_mut() {
+                Some((_, _, siblings)) => siblings.push(copy),
+                None => return copy,
+            }
+        }
+    }
+}
+
+impl<R, G> Tree<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    /// Build a tree from owned nodes, keeping their IDs, and computing subtree hashes and
+    /// positions. As with [`Tree::from_node`], the ID generator must not produce the IDs of the
+    /// converted nodes.
+    pub fn from_boxed<N>(root: N, idgen: Option<G>) -> Self
+    where
+        N: OwnedTreeNode<Id = NodeRefId<R>, Data = <R::Inner as TreeNode>::Data>,
+    {
+        let mut converted_root = None;
+
+        // Stack of owned nodes to convert, with the converted parent to attach them to
+        let mut stack: Vec<(N, Option<R>)> = vec![(root, None)];
+        let allocator = R::Allocator::default();
+
+        while let Some((source, parent)) = stack.pop() {
+            let (id, data, children) = source.into_parts();
+
+            let mut node = R::Inner::new(id, data, None);
+            if let Some(parent) = &parent {
+                node = node.with_parent(parent.clone());
+            }
+
+            let node_ref = R::new_in(node, &allocator);
+            match parent {
+                Some(mut parent) => parent.node_mut().push_child(node_ref.clone()),
+                None => converted_root = Some(node_ref.clone()),
+            }
+
+            // Reversed so children are popped, and attached to the parent, in order
+            for child in children.into_iter().rev() {
+                stack.push((child, Some(node_ref.clone())));
+            }
+        }
+
+        let root = converted_root.expect("root is converted first");
+        let mut tree = Tree::from_node(root.clone(), idgen).with_allocator(allocator);
+        tree.update_positions();
+        rehash_subtree(root, tree.hash_context());
+        tree
+    }
+
+    /// Copy the tree into owned nodes with the same IDs and data. Returns `None` if the tree is
+    /// empty. Hashes, positions and attributes are not copied.
+    pub fn to_boxed(&self) -> Option<Node<<R::Inner as TreeNode>::Data, NodeRefId<R>>>
+    where
+        <R::Inner as TreeNode>::Data: Clone,
+    {
+        let root = self.try_root_ref().ok()?.clone();
+        Some(Node::from_subtree(root))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        test::{test_tree_node, TestNode},
+        Tree, TreeNode as _, TreeNodeRef as _,
+    };
+
+    use super::{Node, OwnedTreeNode as _};
+
+    type ArcTree =
+        Tree<crate::noderef::arc::NodeRef<crate::node::arc::Node<&'static str, crate::NodeId>>>;
+
+    #[traced_test]
+    #[test]
+    fn roundtrip() {
+        let tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+
+        let mut boxed = tree.to_boxed().unwrap();
+        assert_eq!(boxed.id(), tree.root().node().id());
+        assert_eq!(
+            boxed.iter().map(|node| *node.data()).collect::<Vec<_>>(),
+            ["root", "a", "1", "2", "b"]
+        );
+
+        let loaded = ArcTree::from_boxed(boxed.clone(), None);
+        assert_eq!(
+            loaded.root().node().get_subtree_hash(),
+            tree.root().node().get_subtree_hash()
+        );
+        assert_eq!(loaded.to_boxed().as_ref(), Some(&boxed));
+
+        // Owned nodes are edited without borrowing through a handle
+        *boxed.children_mut()[1].data_mut() = "c";
+        boxed.children_mut()[1].push_child(Node::new(100, "3"));
+
+        let edited = ArcTree::from_boxed(boxed, None);
+        let expected = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("c", vec![TestNode("3", vec![])]),
+        ]);
+        assert_eq!(
+            edited.root().node().get_subtree_hash(),
+            expected.root().node().get_subtree_hash()
+        );
+        let three = edited.find(|data| *data == "3").unwrap();
+        assert_eq!(three.node().id(), 100);
+        assert_eq!(three.node().get_position().unwrap().depth, 2);
+
+        assert!(
+            Tree::<crate::noderef::rc::NodeRef<crate::node::rc::Node<&str>>>::new()
+                .to_boxed()
+                .is_none()
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn send() {
+        fn assert_send<T: Send + Sync>() {}
+        assert_send::<Node<String>>();
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
-    cell::{BorrowError, Ref, RefCell, RefMut},
-    rc::Rc,
+    cell::{Ref, RefCell, RefMut},
+    rc::{Rc, Weak},
 };
 
 use crate::{
@@ -8,7 +8,10 @@ use crate::{
     TreeNode,
 };
 
-use super::{internal::NodeRefInternal, TreeFormat as _, TreeNodeRef};
+use super::{
+    internal::NodeRefInternal, AccessError, TreeFormat as _, TreeFormatDisplay as _, TreeNodeRef,
+    TreeWeakNodeRef,
+};
 
 pub struct NodeRef<T>
 where
@@ -17,6 +20,45 @@ where
     node_ref: Rc<RefCell<T>>,
 }
 
+/// Non-owning handle to a [`NodeRef`]
+pub struct WeakNodeRef<T>
+where
+    T: TreeNode<NodeRef = NodeRef<T>>,
+{
+    node_ref: Weak<RefCell<T>>,
+}
+
+impl<T> Clone for WeakNodeRef<T>
+where
+    T: TreeNode<NodeRef = NodeRef<T>>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            node_ref: self.node_ref.clone(),
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for WeakNodeRef<T>
+where
+    T: TreeNode<NodeRef = NodeRef<T>>,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WeakNodeRef").finish()
+    }
+}
+
+impl<T> TreeWeakNodeRef for WeakNodeRef<T>
+where
+    T: TreeNode<NodeRef = NodeRef<T>> + std::fmt::Debug + 'static,
+{
+    type NodeRef = NodeRef<T>;
+
+    fn upgrade(&self) -> Option<NodeRef<T>> {
+        self.node_ref.upgrade().map(|node_ref| NodeRef { node_ref })
+    }
+}
+
 impl<T> std::hash::Hash for NodeRef<T>
 where
     T: TreeNode<NodeRef = Self> + std::fmt::Debug + 'static,
@@ -51,6 +93,7 @@ where
 impl<T> std::fmt::Display for NodeRef<T>
 where
     T: TreeNode<NodeRef = Self> + 'static,
+    T::Data: std::fmt::Display,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.tree_format_display(f)
@@ -76,6 +119,8 @@ where
     type InnerRef<'b> = Ref<'b, Self::Inner>;
     type InnerRefMut<'b> = RefMut<'b, Self::Inner>;
     type Data = T::Data;
+    type Weak = WeakNodeRef<T>;
+    type Allocator = ();
 
     fn new<N>(node: N) -> Self
     where
@@ -91,17 +136,27 @@ where
         r.borrow()
     }
 
-    fn try_node<'b>(&'b self) -> Result<Self::InnerRef<'b>, BorrowError> {
+    fn try_node<'b>(&'b self) -> Result<Self::InnerRef<'b>, AccessError> {
         let r: &'b RefCell<T> = &self.node_ref;
-        r.try_borrow()
+        Ok(r.try_borrow()?)
     }
 
     fn node_mut<'b>(&'b mut self) -> Self::InnerRefMut<'b> {
-        (&*self.node_ref).borrow_mut()
+        self.node_ref.borrow_mut()
+    }
+
+    fn try_node_mut<'b>(&'b self) -> Result<Self::InnerRefMut<'b>, AccessError> {
+        Ok(self.node_ref.try_borrow_mut()?)
+    }
+
+    fn downgrade(&self) -> Self::Weak {
+        WeakNodeRef {
+            node_ref: Rc::downgrade(&self.node_ref),
+        }
     }
 
-    fn try_node_mut<'b>(&'b self) -> Result<Self::InnerRefMut<'b>, std::cell::BorrowMutError> {
-        (&*self.node_ref).try_borrow_mut()
+    fn ptr_eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.node_ref, &other.node_ref)
     }
 }
 
@@ -118,7 +173,7 @@ where
     }
 }
 
-impl<'a, N> IntoIterator for &'a NodeRef<N>
+impl<N> IntoIterator for &NodeRef<N>
 where
     N: TreeNode<NodeRef = NodeRef<N>> + 'static,
 {
@@ -0,0 +1,187 @@
+//! Merkle proofs that a subtree is part of a tree.
+//!
+//! The subtree hash of a node is computed from the subtree hashes of its children and its own
+//! data, so the subtree hash of any node can be chained up to the root hash given the hashes of
+//! its siblings and the data of its ancestors. A peer holding only the root hash of a tree can
+//! then check that a received subtree belongs to it, without the rest of the tree.
+//!
+//! Subtree hashes are 64 bit XXH64 digests, which are fast but not cryptographic, so a proof only
+//! detects accidental corruption or a mismatched subtree. An adversary can construct data with a
+//! colliding hash, so proofs must not be used to authenticate subtrees from an untrusted source.
+
+use std::hash::{Hash as _, Hasher as _};
+
+use xxhash_rust::xxh64::Xxh64;
+
+use crate::{
+    index::TreeIndex, noderef::NodeRefId, Fingerprint, IndexedTree, TreeNode, TreeNodeRef,
+    UniqueGenerator,
+};
+
+/// One level of a [`MerkleProof`], describing the parent of the node proven by the level below
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProofStep<D> {
+    /// Subtree hashes of the siblings before the child on the path
+    pub before: Vec<u64>,
+
+    /// Subtree hashes of the siblings after the child on the path
+    pub after: Vec<u64>,
+
+    /// Data of the parent
+    pub data: D,
+}
+
+/// Path of sibling hashes and ancestor data from a node up to the root, returned by
+/// [`IndexedTree::merkle_proof`] and checked with [`verify_proof`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleProof<D> {
+    /// Hash context of the tree, see [`Tree::set_hash_context`](crate::Tree::set_hash_context)
+    pub context: u64,
+
+    /// Steps from the parent of the proven node up to the root
+    pub steps: Vec<ProofStep<D>>,
+}
+
+impl<D> MerkleProof<D>
+where
+    D: Fingerprint,
+{
+    /// Compute the root hash implied by the proof, for a proven node with the provided subtree
+    /// hash
+    pub fn root_hash(&self, subtree_hash: u64) -> u64 {
+        self.steps.iter().fold(subtree_hash, |hash, step| {
+            let mut hasher = Xxh64::new(self.context);
+
+            for sibling in &step.before {
+                hasher.write_u64(*sibling);
+            }
+            hasher.write_u64(hash);
+            for sibling in &step.after {
+                hasher.write_u64(*sibling);
+            }
+
+            // Must match the node hash of the node implementations
+            (step.before.len() + 1 + step.after.len()).hash(&mut hasher);
+            step.data.fingerprint(&mut hasher);
+
+            hasher.finish()
+        })
+    }
+}
+
+/// Check that a node with the provided subtree hash is part of the tree with the provided root
+/// hash. The subtree hash should be computed by the verifier from the received subtree.
+///
+/// Subtree hashes are not cryptographic, so this only detects accidental corruption and must not
+/// be used to authenticate subtrees from an untrusted source.
+pub fn verify_proof<D>(proof: &MerkleProof<D>, subtree_hash: u64, root_hash: u64) -> bool
+where
+    D: Fingerprint,
+{
+    proof.root_hash(subtree_hash) == root_hash
+}
+
+impl<R, G, I> IndexedTree<R, G, I>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+{
+    /// Build a [`MerkleProof`] that the node with the provided ID is part of this tree.
+    /// Returns `None` if the node is not in the tree, or was removed. Hashes deferred in
+    /// [`HashMode::Deferred`](crate::HashMode::Deferred) must be flushed first.
+    pub fn merkle_proof(
+        &self,
+        id: NodeRefId<R>,
+    ) -> Option<MerkleProof<<R::Inner as TreeNode>::Data>> {
+        let mut node = self
+            .get_node(&id)
+            .filter(|node| !node.node().is_tombstoned())?
+            .clone();
+        let mut steps = Vec::new();
+
+        loop {
+            let Some(parent) = node.node().parent().cloned() else {
+                break;
+            };
+
+            let step = {
+                let inner = parent.node();
+                let children = inner.children()?;
+                let index = children.iter().position(|child| child.ptr_eq(&node))?;
+                let hashes = |children: &[R]| -> Vec<u64> {
+                    children
+                        .iter()
+                        .map(|child| child.node().get_subtree_hash())
+                        .collect()
+                };
+
+                let data = inner.data().clone();
+                ProofStep {
+                    before: hashes(&children[..index]),
+                    after: hashes(&children[index + 1..]),
+                    data,
+                }
+            };
+
+            steps.push(step);
+            node = parent;
+        }
+
+        Some(MerkleProof {
+            context: self.hash_context(),
+            steps,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        test::{test_tree_node, TestNode},
+        TreeNode as _, TreeNodeRef as _,
+    };
+
+    use super::verify_proof;
+
+    #[traced_test]
+    #[test]
+    fn merkle_proof() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![TestNode("3", vec![TestNode("4", vec![])])]),
+            TestNode("c", vec![]),
+        ]);
+        let root_hash = tree.root().node().get_subtree_hash();
+
+        for node in tree.root() {
+            let (id, hash) = (node.node().id(), node.node().get_subtree_hash());
+            let proof = tree.merkle_proof(id).unwrap();
+            assert!(verify_proof(&proof, hash, root_hash));
+            assert!(!verify_proof(&proof, hash ^ 1, root_hash));
+        }
+
+        let root_id = tree.root().node().id();
+        assert!(tree.merkle_proof(root_id).unwrap().steps.is_empty());
+
+        // Proofs don't verify against a tree whose data changed on the path
+        let four = tree.find(|data| *data == "4").unwrap().clone();
+        let four_hash = four.node().get_subtree_hash();
+        let proof = tree.merkle_proof(four.node().id()).unwrap();
+        let b = tree.find(|data| *data == "b").unwrap().node().id();
+        tree.set_data(b, "B").unwrap();
+        assert!(!verify_proof(
+            &proof,
+            four_hash,
+            tree.root().node().get_subtree_hash()
+        ));
+
+        // Removed nodes have no proof
+        let c = tree.find(|data| *data == "c").unwrap().clone();
+        let c_id = c.node().id();
+        tree.remove_node(&c).unwrap();
+        assert!(tree.merkle_proof(c_id).is_none());
+    }
+}
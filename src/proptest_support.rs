@@ -0,0 +1,129 @@
+//! Random tree generation for property tests with [`proptest`], enabled with the `proptest`
+//! feature.
+//!
+//! [`arb_tree`] generates trees with node data from any strategy, and [`Tree`] implements
+//! [`Arbitrary`] for data which is itself [`Arbitrary`], so `any::<Tree<_>>()` can be used
+//! directly. Generated trees shrink towards fewer and smaller nodes.
+
+use proptest::{
+    arbitrary::{any, Arbitrary},
+    collection::vec,
+    strategy::{BoxedStrategy, Strategy},
+};
+
+use crate::{node::TreeNode, noderef::NodeRefId, Tree, TreeNodeRef, UniqueGenerator};
+
+/// Shape limits of generated trees
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TreeParams {
+    /// Maximum depth of a generated tree, where a tree of only a root has depth 0
+    pub max_depth: u32,
+
+    /// Maximum number of children of each node
+    pub max_children: usize,
+
+    /// Number of nodes generation aims to stay below. Trees may exceed it slightly, as the
+    /// limit is only checked between levels.
+    pub max_nodes: u32,
+}
+
+impl Default for TreeParams {
+    fn default() -> Self {
+        Self {
+            max_depth: 4,
+            max_children: 4,
+            max_nodes: 64,
+        }
+    }
+}
+
+/// Generated node with its subtree, before it is converted to a [`Tree`]
+#[derive(Debug, Clone)]
+struct GeneratedNode<D> {
+    data: D,
+    children: Vec<GeneratedNode<D>>,
+}
+
+/// Strategy generating trees within the limits of `params`, with the data of each node
+/// generated by `data`. Nodes are assigned IDs from a default generator.
+pub fn arb_tree<R, G, S>(data: S, params: TreeParams) -> impl Strategy<Value = Tree<R, G>>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    S: Strategy<Value = <R::Inner as TreeNode>::Data> + Clone + 'static,
+    <R::Inner as TreeNode>::Data: std::fmt::Debug,
+{
+    let leaf = data.clone().prop_map(|data| GeneratedNode {
+        data,
+        children: Vec::new(),
+    });
+
+    let max_children = params.max_children;
+    leaf.prop_recursive(
+        params.max_depth,
+        params.max_nodes,
+        max_children as u32,
+        move |inner| {
+            (data.clone(), vec(inner, 0..=max_children))
+                .prop_map(|(data, children)| GeneratedNode { data, children })
+        },
+    )
+    .prop_map(|root| {
+        Tree::from_foreign(
+            &root,
+            |node| node.data.clone(),
+            |node| node.children.iter().collect(),
+        )
+    })
+}
+
+impl<R, G> Arbitrary for Tree<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    <R::Inner as TreeNode>::Data: Arbitrary + 'static,
+{
+    type Parameters = TreeParams;
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(params: TreeParams) -> Self::Strategy {
+        arb_tree(any::<<R::Inner as TreeNode>::Data>().boxed(), params).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::{arbitrary::any_with, proptest, strategy::Strategy as _};
+
+    use crate::{noderef::arc::NodeRef, Tree, TreeNode as _, TreeNodeRef as _};
+
+    use super::{arb_tree, TreeParams};
+
+    type NumberTree = Tree<NodeRef<crate::node::arc::Node<u32, crate::NodeId>>>;
+
+    const PARAMS: TreeParams = TreeParams {
+        max_depth: 3,
+        max_children: 3,
+        max_nodes: 32,
+    };
+
+    proptest! {
+        #[test]
+        fn generated_shape(tree in any_with::<NumberTree>(PARAMS)) {
+            assert!(tree.depth() <= PARAMS.max_depth as usize);
+            for node in tree.root() {
+                assert!(node.node().num_children() <= PARAMS.max_children);
+            }
+
+            let indexed = tree.index();
+            assert_eq!(indexed.validate(), Ok(()));
+        }
+
+        #[test]
+        fn generated_data(tree in arb_tree::<_, _, _>((0u32..10).prop_map(|n| n * 2), PARAMS)) {
+            let tree: NumberTree = tree;
+            assert!(tree.root().into_iter().all(|node| *node.node().data() % 2 == 0));
+            assert!(NumberTree::from_sexpr(&tree.to_sexpr()).unwrap() == tree);
+        }
+    }
+}
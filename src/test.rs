@@ -8,7 +8,7 @@ pub enum MyError {
     Fail(String),
 }
 
-#[derive(Debug, Clone, Hash)]
+#[derive(Debug, Clone, Hash, PartialEq)]
 #[allow(unused)]
 pub enum TestData {
     Root,
@@ -22,6 +22,21 @@ impl std::fmt::Display for TestData {
     }
 }
 
+/// Data hashing to the same value regardless of content, so subtrees of the same shape have
+/// colliding hashes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Colliding(pub &'static str);
+
+impl std::hash::Hash for Colliding {
+    fn hash<H: std::hash::Hasher>(&self, _state: &mut H) {}
+}
+
+impl std::fmt::Display for Colliding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 pub struct TestNode(pub &'static str, pub Vec<Self>);
 
 pub fn test_tree_node(data: Vec<TestNode>) -> IndexedTree<NodeRef<Node<&'static str, NodeId>>> {
@@ -36,7 +51,7 @@ pub fn test_tree_node(data: Vec<TestNode>) -> IndexedTree<NodeRef<Node<&'static
         }
     }
 
-    let tree = TreeBuilder::<&'static str, ()>::new()
+    TreeBuilder::<&'static str, ()>::new()
         .root("root", |root| {
             for node in data {
                 root.child(node.0, |parent| {
@@ -50,16 +65,14 @@ pub fn test_tree_node(data: Vec<TestNode>) -> IndexedTree<NodeRef<Node<&'static
         .done()
         .unwrap()
         .unwrap()
-        .index();
-
-    tree
+        .index()
 }
 
 /// Construct a tree from a Vec of tuples of (&str, Vec of children)
 pub fn test_tree_vec(
     data: Vec<(&'static str, Vec<&'static str>)>,
 ) -> IndexedTree<NodeRef<Node<&'static str, NodeId>>> {
-    let tree = TreeBuilder::<&'static str, ()>::new()
+    TreeBuilder::<&'static str, ()>::new()
         .root("root", |root| {
             for (data, children) in data {
                 root.child(data, |node| {
@@ -76,9 +89,7 @@ pub fn test_tree_vec(
         .done()
         .unwrap()
         .unwrap()
-        .index();
-
-    tree
+        .index()
 }
 
 pub fn test_tree_deep(
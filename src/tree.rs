@@ -1,7 +1,7 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
     hash::{Hash as _, Hasher},
-    ops::{Deref, DerefMut},
+    ops::Deref,
     sync::{atomic::AtomicU64, Arc, Mutex},
 };
 
@@ -9,29 +9,107 @@ use tracing::{debug, error, warn};
 use xxhash_rust::xxh64::Xxh64;
 
 use crate::{
-    index::{BTreeIndex, TreeIndex},
+    compare::subtree_eq,
+    hash::{rehash_subtree, subtree_size, update_subtree_hash_with, update_subtree_hashes_with},
+    index::{BTreeIndex, KeyIndex, PathIndex, SecondaryIndex, SubtreeHashIndex, TreeIndex},
     leaf::LeafIter,
     node::TreeNode,
     noderef::{NodeRefId, TreeNodeRef},
-    TreeEvent, UniqueGenerator,
+    Error, EventFilter, NodePosition, TreeEvent, UniqueGenerator,
 };
 
 use crate::node::internal::NodeInternal as _;
 
+#[cfg(any(
+    feature = "ego-tree",
+    feature = "indextree",
+    feature = "proptest",
+    feature = "fs"
+))]
+mod convert;
+#[cfg(feature = "fs")]
+mod dir;
+mod flat;
+#[cfg(feature = "rayon")]
+mod parallel;
+#[cfg(feature = "serde")]
+mod serialize;
+mod sexpr;
+#[cfg(feature = "snapshot")]
+mod snapshot;
+mod stats;
+#[cfg(feature = "sync")]
+mod sync;
+mod transaction;
+mod validate;
+
+#[cfg(feature = "fs")]
+pub use dir::{DirOptions, FileEntry, FileKind};
+#[cfg(feature = "sync")]
+pub(crate) use flat::flat_records;
+pub use flat::{FlatRecord, FlatRecordError};
+pub use sexpr::SexprError;
+#[cfg(feature = "snapshot")]
+pub use snapshot::SnapshotReader;
+pub use stats::TreeStats;
+#[cfg(feature = "sync")]
+pub use sync::{SyncMessage, SyncSession, WireOperation, MAX_PAYLOAD_LEN, SYNC_PROTOCOL_VERSION};
+pub use transaction::TreeTransaction;
+pub use validate::Invariant;
+
+/// Event listener callback, called with the sequence number of each event. Returns false once
+/// the callback should be removed.
+type EventCallback<R> = Box<dyn for<'a> FnMut(u64, &'a TreeEvent<R>) -> bool + Send>;
+
+/// Shared registry of event listener callbacks, keyed by listener ID
+type EventListeners<R> = Arc<Mutex<HashMap<u64, EventCallback<R>>>>;
+
+/// Registration of an event listener callback, returned by [`Tree::on_event`]. The callback is
+/// removed from the tree when this is dropped.
 pub struct TreeEventListener<R>
 where
     R: TreeNodeRef + 'static,
 {
     id: u64,
     // Event listener registry that we can deregister ourselves from when dropped
-    event_listeners: Arc<Mutex<HashMap<u64, Box<dyn for<'a> FnMut(&'a TreeEvent<R>) + Send>>>>,
+    event_listeners: EventListeners<R>,
+    // Whether dropping this handle removes the callback
+    owned: bool,
+}
+
+impl<R> TreeEventListener<R>
+where
+    R: TreeNodeRef + 'static,
+{
+    /// Keep the callback registered for as long as `owner` is alive, rather than until this
+    /// handle is dropped, such as to tie a listener to the widget it updates. The callback is
+    /// removed at the first event sent after the owner is dropped.
+    pub fn downgrade<O>(mut self, owner: &Arc<O>)
+    where
+        O: Send + Sync + 'static,
+    {
+        let owner = Arc::downgrade(owner);
+        if let Ok(mut guard) = self.event_listeners.lock() {
+            if let Some(mut callback) = guard.remove(&self.id) {
+                guard.insert(
+                    self.id,
+                    Box::new(move |seq, event| owner.strong_count() > 0 && callback(seq, event)),
+                );
+            }
+        }
+        self.owned = false;
+    }
 }
 
-impl<'a, R> Drop for TreeEventListener<R>
+impl<R> Drop for TreeEventListener<R>
 where
     R: TreeNodeRef + 'static,
 {
     fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+
         debug!("Listener {} dropped. Deregistering.", self.id);
         if let Ok(mut guard) = self.event_listeners.lock() {
             guard.remove(&self.id);
@@ -39,6 +117,55 @@ where
     }
 }
 
+/// Determines what [`Tree::swap`] exchanges between two nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapMode {
+    /// Exchange the data of the nodes, leaving their children in place
+    Data,
+
+    /// Exchange the positions of the nodes along with their subtrees
+    Subtree,
+}
+
+/// Determines how nodes removed from a [`Tree`] are disposed of
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalMode {
+    /// Removed nodes are detached from the tree and dropped
+    #[default]
+    Delete,
+
+    /// Removed nodes are detached from the tree, but are marked with a removal sequence number
+    /// and retained as tombstones until collected with [`Tree::gc`]. Tombstoned nodes are excluded
+    /// from iteration, display and hashing, but remain resolvable by late references.
+    Tombstone,
+}
+
+/// Determines which IDs are assigned to nodes attached by [`Tree::set_children`] and
+/// [`Tree::replace_child`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IdMode {
+    /// Every attached node is assigned a new ID
+    #[default]
+    Regenerate,
+
+    /// Nodes detached by the same operation and reattached elsewhere in the new children keep
+    /// their IDs. New IDs are only assigned to nodes which were not already part of the tree.
+    Preserve,
+}
+
+/// Determines when the subtree hashes of ancestors are updated after a mutation of a [`Tree`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HashMode {
+    /// Ancestor hashes are recomputed by each mutation
+    #[default]
+    Eager,
+
+    /// Mutations record the changed node, and ancestor hashes are recomputed once by
+    /// [`Tree::flush_hashes`]. Until then, subtree hashes, and comparisons and diffs based
+    /// on them, may be stale.
+    Deferred,
+}
+
 pub struct Tree<R, G = crate::IdGenerator>
 where
     R: TreeNodeRef + 'static,
@@ -54,7 +181,53 @@ where
     next_listener_id: AtomicU64,
 
     // Registry of event listener callbacks
-    event_listeners: Arc<Mutex<HashMap<u64, Box<dyn for<'c> FnMut(&'c TreeEvent<R>) + Send>>>>,
+    event_listeners: EventListeners<R>,
+
+    // Events collected for delivery at the end of the current batch
+    batch: Option<Vec<TreeEvent<R>>>,
+
+    // Events recorded for an IndexedTree to update its indexes from, regardless of batching
+    // and silent scopes
+    journal: Option<Vec<TreeEvent<R>>>,
+
+    // Delivered events retained for replay, with their sequence numbers
+    history: VecDeque<(u64, TreeEvent<R>)>,
+
+    // Maximum number of events retained in the history
+    history_len: usize,
+
+    // Number of events suppressed within the current silent scope
+    suppressed: Option<usize>,
+
+    // Sequence number of the last event delivered to listeners
+    event_seq: u64,
+
+    // Disposal of removed nodes
+    removal_mode: RemovalMode,
+
+    // Assignment of IDs to reattached nodes
+    id_mode: IdMode,
+
+    // Timing of ancestor hash updates
+    hash_mode: HashMode,
+
+    // Seed of every subtree hash, derived from the external hash context
+    hash_context: u64,
+
+    // Changed nodes whose ancestor hashes are pending in deferred hash mode
+    stale_hashes: Vec<R>,
+
+    // Sequence number assigned to the next removal
+    removal_seq: u64,
+
+    // Roots of removed subtrees retained in tombstone mode, in order of removal sequence
+    tombstones: Vec<(u64, R)>,
+
+    // Subtree hashes of the nodes when they were last marked clean
+    clean_hashes: HashMap<NodeRefId<R>, u64>,
+
+    // Storage new nodes are allocated from
+    allocator: R::Allocator,
 }
 
 impl<R, G> std::fmt::Debug for Tree<R, G>
@@ -63,6 +236,10 @@ where
     G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return f.write_str("Tree(empty)");
+        }
+
         f.debug_struct("Tree")
             .field(
                 "subtree_hash",
@@ -84,15 +261,38 @@ where
             root: None,
             node_id_generator: None,
             event_listeners: Arc::new(Mutex::new(HashMap::new())),
+            batch: None,
+            journal: None,
+            history: VecDeque::new(),
+            history_len: 0,
+            suppressed: None,
+            event_seq: 0,
             next_listener_id: AtomicU64::new(0),
+            removal_mode: RemovalMode::default(),
+            id_mode: IdMode::default(),
+            hash_mode: HashMode::default(),
+            hash_context: 0,
+            stale_hashes: Vec::new(),
+            removal_seq: 0,
+            tombstones: Vec::new(),
+            clean_hashes: HashMap::new(),
+            allocator: R::Allocator::default(),
         }
     }
 
     /// Register an event listener
-    fn listen<'b, F>(&mut self, callback: F) -> Result<TreeEventListener<R>, ()>
+    fn listen<F>(&mut self, mut callback: F) -> Result<TreeEventListener<R>, Error>
     where
-        F: for<'c> FnMut(&'c TreeEvent<R>) + Send + 'static,
+        F: for<'c> FnMut(u64, &'c TreeEvent<R>) + Send + 'static,
     {
+        self.register(Box::new(move |seq, event| {
+            callback(seq, event);
+            true
+        }))
+    }
+
+    /// Add a callback to the listener registry
+    fn register(&mut self, callback: EventCallback<R>) -> Result<TreeEventListener<R>, Error> {
         // Get an ID for a new listener
         let id = self
             .next_listener_id
@@ -101,45 +301,313 @@ where
         let listener = TreeEventListener {
             id,
             event_listeners: self.event_listeners.clone(),
+            owned: true,
         };
 
         if let Ok(mut guard) = self.event_listeners.lock() {
-            guard.insert(id, Box::new(callback));
+            guard.insert(id, callback);
             debug!("Event listener {id} added to Tree");
             Ok(listener)
         } else {
             error!("Failed to lock mutex trying to register an Event Listener");
-            Err(())
+            Err(Error::ListenersPoisoned)
         }
     }
 
-    pub fn on_event<F>(&mut self, f: F) -> Result<TreeEventListener<R>, ()>
+    /// Register a callback called with every [`TreeEvent`] sent by this tree. The callback is
+    /// removed when the returned [`TreeEventListener`] is dropped. Returns
+    /// [`Error::ListenersPoisoned`] if a listener previously panicked.
+    ///
+    /// Events are delivered in the order of the mutations which sent them. Each event is
+    /// delivered to every listener before the next is sent, and callbacks are never called
+    /// concurrently.
+    ///
+    /// Callbacks must be `Send`, so a tree of thread safe nodes, such as
+    /// [`noderef::arc::NodeRef`](crate::noderef::arc::NodeRef), remains `Send` and `Sync` with
+    /// listeners registered. Events are dispatched on the thread performing the mutation.
+    pub fn on_event<F>(&mut self, mut f: F) -> Result<TreeEventListener<R>, Error>
     where
         F: for<'c> FnMut(&'c TreeEvent<R>) + Send + 'static,
     {
+        self.listen(move |_, event| f(event))
+    }
+
+    /// Register a callback called with every [`TreeEvent`] sent by this tree, along with its
+    /// sequence number. Sequence numbers start at 1 and increase by one with each event
+    /// delivered, so a listener can detect events it missed, such as while it was not
+    /// registered. A [`TreeEvent::Batch`] takes a single sequence number. See
+    /// [`Tree::on_event`].
+    pub fn on_sequenced_event<F>(&mut self, f: F) -> Result<TreeEventListener<R>, Error>
+    where
+        F: for<'c> FnMut(u64, &'c TreeEvent<R>) + Send + 'static,
+    {
+        self.listen(f)
+    }
+
+    /// Retain up to `len` of the most recently delivered events, so listeners registered later
+    /// can catch up with [`Tree::replay_since`] instead of resynchronizing the whole tree. The
+    /// history is disabled with a length of 0, which is the default.
+    pub fn set_event_history(&mut self, len: usize) {
+        self.history_len = len;
+        while self.history.len() > len {
+            self.history.pop_front();
+        }
+    }
+
+    /// Iterate the retained events delivered after the event with sequence number `seq`, along
+    /// with their sequence numbers. Returns [`Error::HistoryTruncated`] if any of those events
+    /// are no longer retained. See [`Tree::set_event_history`].
+    pub fn replay_since(
+        &self,
+        seq: u64,
+    ) -> Result<impl Iterator<Item = (u64, &TreeEvent<R>)> + '_, Error> {
+        let oldest = self
+            .history
+            .front()
+            .map_or(self.event_seq + 1, |(oldest, _)| *oldest);
+        if seq + 1 < oldest && seq < self.event_seq {
+            return Err(Error::HistoryTruncated { oldest });
+        }
+
+        Ok(self
+            .history
+            .iter()
+            .filter(move |(event_seq, _)| *event_seq > seq)
+            .map(|(event_seq, event)| (*event_seq, event)))
+    }
+
+    /// Call `f` with the retained events delivered after the event with sequence number `seq`,
+    /// then register it like [`Tree::on_sequenced_event`], so it receives every event after
+    /// `seq` exactly once. Returns [`Error::HistoryTruncated`] without registering the callback
+    /// if any of the events to replay are no longer retained.
+    pub fn on_sequenced_event_since<F>(
+        &mut self,
+        seq: u64,
+        mut f: F,
+    ) -> Result<TreeEventListener<R>, Error>
+    where
+        F: for<'c> FnMut(u64, &'c TreeEvent<R>) + Send + 'static,
+    {
+        for (event_seq, event) in self.replay_since(seq)? {
+            f(event_seq, event);
+        }
         self.listen(f)
     }
 
-    /// Send an event to all registered listeners
+    /// Register a callback called with `owner` and every [`TreeEvent`] sent by this tree, for as
+    /// long as `owner` is alive. No handle is returned, and the callback is removed at the first
+    /// event sent after the owner is dropped. See [`TreeEventListener::downgrade`].
+    pub fn on_event_weak<O, F>(&mut self, owner: &Arc<O>, mut f: F) -> Result<(), Error>
+    where
+        O: Send + Sync + 'static,
+        F: for<'c> FnMut(&O, &'c TreeEvent<R>) + Send + 'static,
+    {
+        let owner = Arc::downgrade(owner);
+        let mut listener = self.register(Box::new(move |_, event| match owner.upgrade() {
+            Some(owner) => {
+                f(&owner, event);
+                true
+            }
+            None => false,
+        }))?;
+        listener.owned = false;
+        Ok(())
+    }
+
+    /// Get the sequence number of the last event delivered to listeners, or 0 if no event was
+    /// delivered yet
+    pub fn event_sequence(&self) -> u64 {
+        self.event_seq
+    }
+
+    /// Register a callback called with the [`TreeEvent`]s sent by this tree which pass the
+    /// filter. The filter is checked before the callback is invoked. See [`Tree::on_event`].
+    pub fn on_event_filtered<F>(
+        &mut self,
+        filter: EventFilter<NodeRefId<R>>,
+        mut f: F,
+    ) -> Result<TreeEventListener<R>, Error>
+    where
+        F: for<'c> FnMut(&'c TreeEvent<R>) + Send + 'static,
+        NodeRefId<R>: Send,
+    {
+        self.listen(move |_, event| {
+            if filter.matches(event) {
+                f(event)
+            }
+        })
+    }
+
+    /// Run `f` with event delivery batched. Events sent by mutations within `f` are collected,
+    /// and delivered to listeners as a single [`TreeEvent::Batch`] once `f` returns. Nested
+    /// batches join the outermost batch.
+    pub fn batch<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let started = self.begin_batch();
+        let result = f(self);
+        if started {
+            self.end_batch(false);
+        }
+        result
+    }
+
+    /// Run `f` with event delivery batched like [`Tree::batch`], dropping events made
+    /// redundant by a later event for the same node, such as repeated replacements of its data.
+    pub fn batch_coalesced<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let started = self.begin_batch();
+        let result = f(self);
+        if started {
+            self.end_batch(true);
+        }
+        result
+    }
+
+    /// Run `f` without sending events. Events sent by mutations within `f` are dropped, such as
+    /// for bulk imports which listeners don't need to follow event by event. Nested silent
+    /// scopes join the outermost scope.
+    pub fn silent<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let started = self.begin_silent();
+        let result = f(self);
+        if started {
+            self.end_silent(false);
+        }
+        result
+    }
+
+    /// Run `f` without sending events like [`Tree::silent`], then send a single
+    /// [`TreeEvent::Suppressed`] summary if any events were dropped
+    pub fn silent_summarized<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let started = self.begin_silent();
+        let result = f(self);
+        if started {
+            self.end_silent(true);
+        }
+        result
+    }
+
+    /// Start dropping events. Returns false if a silent scope is already in progress.
+    pub(crate) fn begin_silent(&mut self) -> bool {
+        if self.suppressed.is_some() {
+            return false;
+        }
+        self.suppressed = Some(0);
+        true
+    }
+
+    /// Resume sending events, sending a summary of the events dropped since
+    /// [`Tree::begin_silent`] if requested
+    pub(crate) fn end_silent(&mut self, summarize: bool) {
+        let Some(count) = self.suppressed.take() else {
+            return;
+        };
+
+        if summarize && count > 0 {
+            self.dispatch(TreeEvent::Suppressed { count });
+        }
+    }
+
+    /// Start collecting events into a batch. Returns false if a batch is already in progress.
+    pub(crate) fn begin_batch(&mut self) -> bool {
+        if self.batch.is_some() {
+            return false;
+        }
+        self.batch = Some(Vec::new());
+        true
+    }
+
+    /// Record the events sent by mutations within `f`, regardless of batching and silent
+    /// scopes, and return them along with the result of `f`
+    pub(crate) fn record<T, F>(&mut self, f: F) -> (T, Vec<TreeEvent<R>>)
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let outer = self.journal.replace(Vec::new());
+        let result = f(self);
+        let events = std::mem::replace(&mut self.journal, outer).unwrap_or_default();
+        (result, events)
+    }
+
+    /// Deliver the events collected since [`Tree::begin_batch`] as a single batch
+    pub(crate) fn end_batch(&mut self, coalesce: bool) {
+        let Some(mut events) = self.batch.take() else {
+            return;
+        };
+
+        if coalesce {
+            events = TreeEvent::coalesce(events);
+        }
+        if !events.is_empty() {
+            self.dispatch(TreeEvent::Batch(events));
+        }
+    }
+
+    /// Send an event describing a mutation to all registered listeners
     fn send_event(&mut self, event: TreeEvent<R>) {
+        if let Some(journal) = &mut self.journal {
+            journal.push(event.clone());
+        }
+        self.dispatch(event);
+    }
+
+    /// Deliver an event to all registered listeners, unless it is suppressed or batched
+    fn dispatch(&mut self, event: TreeEvent<R>) {
+        if let Some(count) = &mut self.suppressed {
+            *count += 1;
+            return;
+        }
+
+        if let Some(batch) = &mut self.batch {
+            batch.push(event);
+            return;
+        }
+
+        // Events are numbered even when no listener is registered
+        self.event_seq += 1;
+
         if let Ok(mut guard) = self.event_listeners.lock() {
-            for (_id, callback) in &mut *guard {
+            let seq = self.event_seq;
+            guard.retain(|_id, callback| {
                 debug!("Sending Event {event:?} to Listener ID {_id}");
-                callback(&event)
+                callback(seq, &event)
+            });
+        }
+
+        if self.history_len > 0 {
+            if self.history.len() == self.history_len {
+                self.history.pop_front();
             }
+            self.history.push_back((self.event_seq, event));
         }
     }
 
     pub fn generator(&self) -> &G {
-        self.node_id_generator.as_ref().unwrap()
+        self.try_generator().expect("ID Generator is not defined")
+    }
+
+    /// Get the ID generator of the tree, or [`Error::NoGenerator`] if it was created without one
+    pub fn try_generator(&self) -> Result<&G, Error> {
+        self.node_id_generator.as_ref().ok_or(Error::NoGenerator)
     }
 
     /// Allocate a new node ID
     pub fn generate_id(&self) -> G::Output {
-        self.node_id_generator
-            .as_ref()
-            .expect("ID Generator is not defined")
-            .generate()
+        self.generator().generate()
+    }
+
+    /// Allocate a new node ID, or return [`Error::NoGenerator`] if the tree has no generator
+    pub fn try_generate_id(&self) -> Result<G::Output, Error> {
+        Ok(self.try_generator()?.generate())
     }
 
     /// Convert this tree into an [`IndexedTree`]
@@ -149,15 +617,33 @@ where
 
     /// Get the maximum depth of the tree
     pub fn depth(&self) -> usize {
+        self.try_depth().expect("Tree is empty")
+    }
+
+    /// Get the maximum depth of the tree, or [`Error::EmptyTree`] if the tree has no root
+    pub fn try_depth(&self) -> Result<usize, Error> {
         // The iterator yields IterNode's which have a depth() method,
         // so we .map() to yield the depth as usize, and .max()
         // to get the maximum depth.
-        self.root().into_iter().map(|f| f.depth()).max().unwrap()
+        self.try_root()?
+            .into_iter()
+            .map(|f| f.depth())
+            .max()
+            .ok_or(Error::EmptyTree)
     }
 
     /// Get the maximum width of the tree (iterator index())
     pub fn width(&self) -> usize {
-        self.root().into_iter().map(|f| f.index()).max().unwrap()
+        self.try_width().expect("Tree is empty")
+    }
+
+    /// Get the maximum width of the tree, or [`Error::EmptyTree`] if the tree has no root
+    pub fn try_width(&self) -> Result<usize, Error> {
+        self.try_root()?
+            .into_iter()
+            .map(|f| f.index())
+            .max()
+            .ok_or(Error::EmptyTree)
     }
 
     /// Get the positional xxh64 hash of the tree. This includes the index, depth, and data of each node
@@ -172,398 +658,4791 @@ where
         hasher.finish()
     }
 
-    /// Create a [`Tree`] container from a root [`NodeRef`]
-    pub fn from_node(root: R, idgen: Option<G>) -> Self {
-        Self {
-            root: Some(root),
-            node_id_generator: idgen,
-            event_listeners: Arc::new(Mutex::new(HashMap::new())),
-            next_listener_id: AtomicU64::new(0),
-        }
+    /// Create a structurally identical tree with each node's data transformed by `f`.
+    /// Node IDs, positions and attributes are preserved, and the ID generator is shared with
+    /// this tree.
+    ///
+    /// Mapping with `Clone::clone` gives a copy which can be modified and compared with this tree
+    /// using [`TreeDiff`](crate::TreeDiff). Every node is copied, as nodes hold a reference to
+    /// their parent and so can't be shared copy-on-write between trees.
+    pub fn map<R2, F>(&self, mut f: F) -> Tree<R2, G>
+    where
+        R2: TreeNodeRef + std::fmt::Debug + 'static,
+        R2::Inner: TreeNode<Id = NodeRefId<R>>,
+        G: UniqueGenerator<Output = NodeRefId<R2>>,
+        F: FnMut(
+            &<<R as TreeNodeRef>::Inner as TreeNode>::Data,
+        ) -> <<R2 as TreeNodeRef>::Inner as TreeNode>::Data,
+    {
+        let Ok(tree) = self.try_map(|data| Ok::<_, std::convert::Infallible>(f(data)));
+        tree
     }
 
-    /// Get the root [`NodeRef`] of the tree
-    pub fn root(&self) -> R {
-        self.root.as_ref().unwrap().clone()
-    }
+    /// Create a structurally identical tree with each node's data transformed by the fallible
+    /// function `f`, returning the first error produced. See [`Tree::map`].
+    pub fn try_map<R2, E, F>(&self, mut f: F) -> Result<Tree<R2, G>, E>
+    where
+        R2: TreeNodeRef + std::fmt::Debug + 'static,
+        R2::Inner: TreeNode<Id = NodeRefId<R>>,
+        G: UniqueGenerator<Output = NodeRefId<R2>>,
+        F: FnMut(
+            &<<R as TreeNodeRef>::Inner as TreeNode>::Data,
+        ) -> Result<<<R2 as TreeNodeRef>::Inner as TreeNode>::Data, E>,
+    {
+        let Some(root) = &self.root else {
+            return Ok(Tree::new());
+        };
 
-    /// Get a reference to the root [`NodeRef`] of the tree
-    pub fn root_ref<'a>(&'a self) -> &'a R {
-        self.root.as_ref().unwrap()
-    }
+        let allocator = R2::Allocator::default();
+        let mut mapped_root = None;
 
-    /// Get a mutable reference to the root [`NodeRef`] of the tree
-    pub fn root_ref_mut<'a>(&'a mut self) -> &'a mut R {
-        self.root.as_mut().unwrap()
-    }
+        // Stack of source nodes to map, with the mapped parent to attach them to
+        let mut stack: Vec<(R, Option<R2>)> = Vec::from([(root.clone(), None)]);
 
-    /// Remove the provided [`NodeRef`] from the tree
-    pub fn remove_node(&mut self, node: &R) {
-        let node_id = node.node().id().clone();
-        debug!("Removing node id {node_id}");
+        while let Some((source_ref, parent)) = stack.pop() {
+            let source = source_ref.node();
+
+            let mut node = R2::Inner::new(source.id(), f(&source.data())?, None);
+            if let Some(position) = source.get_position() {
+                node = node.with_position(*position);
+            }
+            *node.attrs_mut() = source.attrs().clone();
+            if let Some(parent) = &parent {
+                node = node.with_parent(parent.clone());
+            }
 
-        let mut index = None;
+            let node_ref = R2::new_in(node, &allocator);
 
-        // Remove the node from the parent children vec
-        if let Some(parent) = node.clone().node().parent() {
-            if let Some(children) = parent.node().children() {
-                for child in (&*children).iter().enumerate() {
-                    if child.1.node().id() == node_id {
-                        debug!("Found child node at index {}", child.0);
-                        // Found index of node to remove
-                        index = Some(child.0);
-                    }
+            match parent {
+                Some(mut parent) => parent.node_mut().push_child(node_ref.clone()),
+                None => mapped_root = Some(node_ref.clone()),
+            }
+
+            if let Some(children) = source.children() {
+                // Reversed so children are popped, and attached to the parent, in order
+                for child in children.iter().rev() {
+                    stack.push((child.clone(), Some(node_ref.clone())));
                 }
             }
+            drop(source);
         }
 
-        if let Some(index) = index {
-            node.clone()
-                .node_mut()
-                .parent_mut()
-                .unwrap()
-                .node_mut()
-                .remove_child_index(index);
+        let Some(mapped_root) = mapped_root else {
+            return Ok(Tree::new());
+        };
+
+        rehash_subtree(mapped_root.clone(), self.hash_context);
+
+        let mut tree = Tree::from_node(mapped_root, self.node_id_generator.clone());
+        tree.hash_context = self.hash_context;
+        tree.allocator = allocator;
+        Ok(tree)
+    }
+
+    /// Replace the ID of every node, including retained tombstones, with the result of `map`.
+    /// This allows a tree imported from another process to be moved into an ID namespace which
+    /// doesn't collide with local IDs. `map` must not map two IDs to the same value.
+    pub fn remap_ids<F>(&mut self, mut map: F)
+    where
+        F: FnMut(NodeRefId<R>) -> NodeRefId<R>,
+    {
+        let mut ids = HashMap::new();
+
+        let subtrees = self.root.iter().chain(self.tombstones());
+        for subtree in subtrees {
+            subtree
+                .clone()
+                .for_each_mut(|node| {
+                    let old = node.node().id();
+                    let new = map(old);
+                    node.node_mut().set_id(new);
+                    ids.insert(old, new);
+                    Ok::<(), ()>(())
+                })
+                .ok();
         }
 
-        self.send_event(TreeEvent::NodeRemoved { node: node.clone() });
+        self.clean_hashes = self
+            .clean_hashes
+            .drain()
+            .filter_map(|(id, hash)| Some((*ids.get(&id)?, hash)))
+            .collect();
+
+        debug!("Remapped {} node IDs", ids.len());
     }
 
-    /// Remove a child from a node at the given index
-    pub fn remove_child(&mut self, parent: &mut R, index: usize) -> Option<R> {
-        let parent_id = parent.node().id();
-        let ret = if let Some(removed) = parent.clone().node_mut().remove_child_index(index) {
-            debug!("Child {index} removed from {parent_id}");
-            Some(removed)
-        } else {
-            warn!("Child not found attempting to remove child at index {index}");
-            None
+    /// Replace repeated subtrees with a single shared [`TreeNodeRef`], returning the number of
+    /// subtrees replaced. Subtrees are considered identical if their subtree hashes match and
+    /// they are equal node by node, so subtrees with colliding hashes are not shared.
+    ///
+    /// As with [`TreeBuilder::with_dedup`](crate::TreeBuilder::with_dedup), shared nodes keep
+    /// the ID, parent and position of their first occurrence in breadth first order, and
+    /// mutating a shared node affects every occurrence, as sharing is not copy-on-write.
+    /// Interning is intended for trees which are treated as immutable afterwards. Indexing the
+    /// tree copies shared subtrees back into distinct nodes, see [`Tree::unshare`].
+    pub fn intern(&mut self) -> usize
+    where
+        <<R as TreeNodeRef>::Inner as TreeNode>::Data: PartialEq,
+    {
+        let Some(root) = self.root.clone() else {
+            return 0;
         };
 
-        self.send_event(TreeEvent::ChildRemoved {
-            parent: parent.clone(),
-            index,
-        });
+        // Distinct subtrees with each hash, as different subtrees may have colliding hashes
+        let mut shared: HashMap<u64, Vec<R>> = HashMap::new();
+        let mut replaced = 0;
 
-        ret
-    }
+        // Visit parents before children, so the largest repeated subtrees are replaced first
+        let mut queue = VecDeque::from([root]);
+        while let Some(mut parent) = queue.pop_front() {
+            let children = parent.node().children().map(|children| children.clone());
+            for (index, mut child) in children.into_iter().flatten().enumerate() {
+                let hash = child.node().get_subtree_hash();
+                let candidates = shared.entry(hash).or_default();
+                let Some(existing) = candidates
+                    .iter()
+                    .find(|existing| subtree_eq(*existing, &child))
+                    .cloned()
+                else {
+                    candidates.push(child.clone());
+                    queue.push_back(child);
+                    continue;
+                };
 
-    /// Remove all children from the specified parent node
-    pub fn remove_children(&mut self, parent: &mut R) {
-        let parent_id = parent.node().id();
+                if existing.ptr_eq(&child) {
+                    continue;
+                }
 
-        if let Some(children) = (*parent).clone().node_mut().take_children() {
-            let p = parent.clone();
-            self.send_event(TreeEvent::ChildrenRemoved {
-                parent: p,
-                children,
-            });
+                debug!("Interning subtree with hash 0x{hash:X}");
+                parent.node_mut().replace_child(existing, index);
+                child.node_mut().take_parent();
+                replaced += 1;
+
+                self.send_event(TreeEvent::ChildReplaced {
+                    parent: parent.clone(),
+                    index,
+                    previous: Some(child.clone()),
+                });
+            }
         }
 
-        debug!("All children removed from {parent_id}");
+        replaced
     }
 
-    pub fn set_children(&mut self, parent: &mut R, mut children: Vec<R>) {
-        let mut added_children = Vec::new();
+    /// Replace every repeated occurrence of a shared node, as produced by [`Tree::intern`] or
+    /// [`TreeBuilder::with_dedup`](crate::TreeBuilder::with_dedup), with a copy of its subtree
+    /// under new IDs, so each node has a single parent and a unique ID. The first occurrence in
+    /// depth first order keeps the shared node. Returns the number of subtrees copied, or
+    /// [`Error::NoGenerator`] if a subtree must be copied and the tree has no generator.
+    pub fn unshare(&mut self) -> Result<usize, Error> {
+        let Some(root) = self.root.clone() else {
+            return Ok(0);
+        };
 
-        // For each child being added, set its parent to the new parent
-        for child in &mut children {
-            let new_id = self.generate_id();
-            child.node_mut().set_id(new_id);
-            child.node_mut().set_parent(parent.clone());
+        let mut seen = HashSet::from([root.node().id()]);
+        let mut copied = 0;
 
-            added_children.push(child.clone())
+        let mut stack = vec![root];
+        while let Some(mut parent) = stack.pop() {
+            let children = parent.node().children().map(|children| children.clone());
+            for (index, mut child) in children.into_iter().flatten().enumerate() {
+                if seen.insert(child.node().id()) {
+                    // A shared node may refer to the parent of another occurrence
+                    let linked = child
+                        .node()
+                        .parent()
+                        .is_some_and(|linked| linked.ptr_eq(&parent));
+                    if !linked {
+                        child.node_mut().set_parent(parent.clone());
+                    }
+                    stack.push(child);
+                    continue;
+                }
+
+                let copy = self.copy_subtree(&child, Some(&parent))?;
+                parent.node_mut().replace_child(copy, index);
+                copied += 1;
+            }
         }
 
-        // Take the existing children from the parent, and notify any listeners of their removal
-        if let Some(children) = parent.clone().node_mut().take_children() {
-            self.send_event(TreeEvent::ChildrenRemoved {
-                parent: parent.clone(),
-                children,
-            });
+        if copied > 0 {
+            debug!("Copied {copied} shared subtrees");
+            self.update_positions();
         }
+        Ok(copied)
+    }
 
-        parent.node_mut().set_children(Some(children));
+    /// Copy a subtree under new IDs, as a child of `parent` or as a root. The copy is not
+    /// attached.
+    fn copy_subtree(&self, source: &R, parent: Option<&R>) -> Result<R, Error> {
+        let mut copied_root: Option<R> = None;
 
-        self.send_event(TreeEvent::ChildrenAdded {
-            parent: parent.clone(),
-            children: added_children,
-        });
-    }
+        // Stack of source nodes to copy, with the copied parent to attach them to
+        let mut stack: Vec<(R, Option<R>)> = vec![(source.clone(), None)];
 
-    /// Replace a child in a node with a new child at the given index
-    pub fn replace_child(&mut self, parent: &mut R, index: usize, mut new: R) {
-        new.node_mut().set_id(self.generate_id());
+        while let Some((source_ref, copied_parent)) = stack.pop() {
+            let source = source_ref.node();
 
-        if let Some(mut children) = new.node_mut().children_mut() {
-            for child in children.iter_mut() {
-                let new_id = self.generate_id();
-                child.node_mut().set_id(new_id);
+            let mut node = R::Inner::new(self.try_generate_id()?, source.data().clone(), None);
+            if let Some(parent) = copied_parent.clone().or_else(|| parent.cloned()) {
+                node = node.with_parent(parent);
             }
-        }
+            *node.attrs_mut() = source.attrs().clone();
 
-        new.node_mut().set_parent(parent.clone());
-        parent.node_mut().replace_child(new, index);
+            let node_ref = R::new_in(node, &self.allocator);
+            match copied_parent {
+                Some(mut copied_parent) => copied_parent.node_mut().push_child(node_ref.clone()),
+                None => copied_root = Some(node_ref.clone()),
+            }
 
-        self.send_event(TreeEvent::ChildReplaced {
-            parent: parent.clone(),
-            index,
-        });
+            if let Some(children) = source.children() {
+                // Reversed so children are popped, and attached to the parent, in order
+                for child in children.iter().rev() {
+                    stack.push((child.clone(), Some(node_ref.clone())));
+                }
+            }
+            drop(source);
+        }
+
+        let copied_root = copied_root.expect("source is copied first");
+        rehash_subtree(copied_root.clone(), self.hash_context);
+        Ok(copied_root)
     }
 
-    /// Insert a child into a parent at the given index
-    pub fn insert_child(&mut self, parent: &mut R, index: usize, mut new: R) -> Option<()> {
-        new.node_mut().set_parent(parent.clone());
-        let ret = parent.node_mut().insert_child(new, index);
-        self.send_event(TreeEvent::ChildInserted {
-            parent: parent.clone(),
-            index,
-        });
+    /// Mark every node as clean, recording the subtree hashes used by [`Tree::iter_dirty`]
+    /// to skip unchanged subtrees
+    pub fn clear_dirty(&mut self) {
+        self.clean_hashes.clear();
+        let Some(root) = self.root.clone() else {
+            return;
+        };
 
-        ret
-    }
-
-    pub fn replace_node(&mut self, dest: &mut R, source: &R) {
-        *dest.node_mut().data_mut() = source.node().data().clone();
-        self.send_event(TreeEvent::NodeReplaced { node: dest.clone() });
+        for mut node in root {
+            let mut inner = node.node_mut();
+            inner.clear_dirty();
+            self.clean_hashes
+                .insert(inner.id(), inner.get_subtree_hash());
+        }
     }
 
-    /// Create a new node from the provided data. Does not insert into the tree, but allocates a new ID
-    pub fn create_node(&self, data: <<R as TreeNodeRef>::Inner as TreeNode>::Data) -> Option<R> {
-        // Generate a new Node ID
-        if let Some(gen) = &self.node_id_generator {
-            let id = gen.generate();
-            debug!("Allocated new node ID {id}");
+    /// Iterate through the nodes mutated since [`Tree::clear_dirty`] in depth first order.
+    /// Subtrees whose hash is unchanged since they were marked clean are not visited.
+    pub fn iter_dirty(&self) -> impl Iterator<Item = R> + '_ {
+        let mut stack: Vec<R> = self.root.iter().cloned().collect();
 
-            // Create a new Inner Node
-            let node = <R as TreeNodeRef>::Inner::new(id, data, None);
+        std::iter::from_fn(move || {
+            while let Some(node) = stack.pop() {
+                let inner = node.node();
+                let dirty = inner.is_dirty();
+                let unchanged =
+                    self.clean_hashes.get(&inner.id()) == Some(&inner.get_subtree_hash());
+                if !unchanged {
+                    if let Some(children) = inner.children() {
+                        stack.extend(children.iter().rev().cloned());
+                    }
+                }
+                drop(inner);
 
-            // Create and return a new NodeRef wrapping this node
-            Some(R::new(node))
-        } else {
-            error!("ID generator not available attempting to create new node from Tree");
+                if dirty {
+                    return Some(node);
+                }
+            }
             None
-        }
+        })
     }
 
-    /// Insert a subtree as a child of the specified parent at a given child index
-    pub fn insert_subtree(&mut self, parent: &mut R, index: usize, mut subtree: R) -> Option<()>
-    where
-        R::Data: Clone,
-        <<R as TreeNodeRef>::Inner as TreeNode>::Data: Clone,
-    {
-        subtree
-            .for_each_mut(|r| {
-                let new_id = self.generate_id();
-                r.node_mut().set_id(new_id);
+    /// Create a [`Tree`] container from a root [`NodeRef`]
+    pub fn from_node(root: R, idgen: Option<G>) -> Self {
+        Self {
+            root: Some(root),
+            node_id_generator: idgen,
+            event_listeners: Arc::new(Mutex::new(HashMap::new())),
+            batch: None,
+            journal: None,
+            history: VecDeque::new(),
+            history_len: 0,
+            suppressed: None,
+            event_seq: 0,
+            next_listener_id: AtomicU64::new(0),
+            removal_mode: RemovalMode::default(),
+            id_mode: IdMode::default(),
+            hash_mode: HashMode::default(),
+            hash_context: 0,
+            stale_hashes: Vec::new(),
+            removal_seq: 0,
+            tombstones: Vec::new(),
+            clean_hashes: HashMap::new(),
+            allocator: R::Allocator::default(),
+        }
+    }
 
-                Ok::<(), ()>(())
-            })
-            .unwrap();
+    /// Allocate the nodes created by this tree from the provided storage, such as an
+    /// [`Arena`](crate::noderef::arena::Arena) shared with a builder or another tree
+    pub fn with_allocator(mut self, allocator: R::Allocator) -> Self {
+        self.allocator = allocator;
+        self
+    }
 
-        // Set the parent of the subtree
-        subtree.node_mut().set_parent(parent.clone());
+    /// Get the storage the nodes created by this tree are allocated from
+    pub fn allocator(&self) -> &R::Allocator {
+        &self.allocator
+    }
 
-        // Insert the root of the cloned subtree into the parent node at the provided index
-        parent.node_mut().insert_child(subtree.clone(), index);
+    /// Returns true if the tree has no root node
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
 
-        self.send_event(TreeEvent::SubtreeInserted {
-            node: subtree.clone(),
-        });
+    /// Get the root [`NodeRef`] of the tree
+    pub fn root(&self) -> R {
+        self.root.as_ref().unwrap().clone()
+    }
 
-        Some(())
+    /// Get the root [`NodeRef`] of the tree, or [`Error::EmptyTree`] if the tree has no root
+    pub fn try_root(&self) -> Result<R, Error> {
+        self.try_root_ref().cloned()
     }
-}
 
-impl<R, G> Deref for Tree<R, G>
-where
-    R: TreeNodeRef + 'static,
-    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
-{
-    type Target = R;
+    /// Get a reference to the root [`NodeRef`] of the tree, or [`Error::EmptyTree`]
+    /// if the tree has no root
+    pub fn try_root_ref(&self) -> Result<&R, Error> {
+        self.root.as_ref().ok_or(Error::EmptyTree)
+    }
 
-    fn deref(&self) -> &Self::Target {
+    /// Get a reference to the root [`NodeRef`] of the tree
+    pub fn root_ref(&self) -> &R {
         self.root.as_ref().unwrap()
     }
-}
 
-pub struct IndexedTree<R, G = crate::IdGenerator>
-where
-    R: TreeNodeRef + 'static,
-    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
-{
-    tree: Tree<R, G>,
-    leaves: Vec<R>,
-    index: BTreeIndex<R>,
-}
+    /// Get a mutable reference to the root [`NodeRef`] of the tree
+    pub fn root_ref_mut(&mut self) -> &mut R {
+        self.root.as_mut().unwrap()
+    }
 
-impl<R, G> std::fmt::Debug for IndexedTree<R, G>
-where
-    R: TreeNodeRef + std::fmt::Debug + 'static,
-    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
-{
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let leaf_ids: Vec<<<R as TreeNodeRef>::Inner as TreeNode>::Id> =
-            self.leaves.iter().map(|leaf| leaf.node().id()).collect();
-        let ids = self.index.get_ids();
+    /// Find the first node in depth first order whose data matches the predicate.
+    /// See [`TreeNodeRef::find`].
+    pub fn find<F>(&self, f: F) -> Option<R>
+    where
+        F: FnMut(&<<R as TreeNodeRef>::Inner as TreeNode>::Data) -> bool,
+    {
+        self.root.as_ref()?.find(f)
+    }
 
-        f.debug_struct("IndexedTree")
-            .field("tree", &self.tree)
-            .field("leaf_ids", &leaf_ids)
-            .field("index_ids", &ids)
-            .finish()
+    /// Find the first node in depth first order for which the closure returns `Some`.
+    /// See [`TreeNodeRef::find_map`].
+    pub fn find_map<T, F>(&self, f: F) -> Option<(R, T)>
+    where
+        F: FnMut(&<<R as TreeNodeRef>::Inner as TreeNode>::Data) -> Option<T>,
+    {
+        self.root.as_ref()?.find_map(f)
     }
-}
 
-impl<R, G> IndexedTree<R, G>
-where
-    R: TreeNodeRef + std::fmt::Debug + 'static,
-    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
-{
-    // Create a new empty indexed tree
-    pub fn new() -> Self {
-        Self {
-            tree: Tree::new(),
-            leaves: Vec::new(),
-            index: BTreeIndex::new(),
-        }
+    /// Get the [`RemovalMode`] of this tree
+    pub fn removal_mode(&self) -> RemovalMode {
+        self.removal_mode
     }
 
-    pub fn from_tree(tree: Tree<R, G>) -> Self {
-        let index = BTreeIndex::from_tree(&tree);
+    /// Set the [`RemovalMode`] used by subsequent removals
+    pub fn set_removal_mode(&mut self, mode: RemovalMode) {
+        self.removal_mode = mode;
+    }
 
-        let mut leaves = Vec::new();
+    /// Get the [`IdMode`] of this tree
+    pub fn id_mode(&self) -> IdMode {
+        self.id_mode
+    }
 
-        // Find all leaves
-        for node in tree.root() {
-            if node.node().children().is_none() {
-                leaves.push(node.clone())
-            }
+    /// Set the [`IdMode`] used by subsequent calls to [`Tree::set_children`] and
+    /// [`Tree::replace_child`]
+    pub fn set_id_mode(&mut self, mode: IdMode) {
+        self.id_mode = mode;
+    }
+
+    /// Get the [`HashMode`] of this tree
+    pub fn hash_mode(&self) -> HashMode {
+        self.hash_mode
+    }
+
+    /// Set the [`HashMode`] used by subsequent mutations. Switching to [`HashMode::Eager`]
+    /// flushes any pending hash updates.
+    pub fn set_hash_mode(&mut self, mode: HashMode) {
+        self.hash_mode = mode;
+        if mode == HashMode::Eager {
+            self.flush_hashes();
         }
+    }
 
-        Self {
-            tree,
-            index,
-            leaves,
+    /// Recompute the subtree hashes of the ancestors of nodes changed since the last flush in
+    /// [`HashMode::Deferred`], visiting each ancestor once. Returns true if any hashes were
+    /// pending.
+    pub fn flush_hashes(&mut self) -> bool {
+        if self.stale_hashes.is_empty() {
+            return false;
         }
+
+        debug!("Flushing {} stale subtree hashes", self.stale_hashes.len());
+        update_subtree_hashes_with(self.stale_hashes.drain(..), self.hash_context);
+        true
     }
 
-    pub fn tree(&self) -> &Tree<R, G> {
-        &self.tree
+    /// Get the seed of every subtree hash in this tree, derived from the external context set
+    /// with [`Tree::set_hash_context`]. Zero if no context is set.
+    pub fn hash_context(&self) -> u64 {
+        self.hash_context
     }
 
-    pub fn index(&self) -> &BTreeIndex<R> {
-        &self.index
+    /// Make external state, such as a theme, locale or viewport, participate in every subtree
+    /// hash of this tree. The hash of `context` seeds the hash of every node, so trees hashed
+    /// with different contexts differ at every node, and diffs between them replace every node.
+    /// Every subtree hash is recomputed if the context changed.
+    pub fn set_hash_context<C>(&mut self, context: &C)
+    where
+        C: std::hash::Hash + ?Sized,
+    {
+        let mut hasher = Xxh64::new(0);
+        context.hash(&mut hasher);
+        self.rehash_with_context(hasher.finish());
     }
 
-    pub fn get_node(&self, id: &<<R as TreeNodeRef>::Inner as TreeNode>::Id) -> Option<&R> {
-        self.index.get(id)
+    /// Remove the external hash context, so subtree hashes are computed from the tree alone.
+    /// See [`Tree::set_hash_context`].
+    pub fn clear_hash_context(&mut self) {
+        self.rehash_with_context(0);
     }
 
-    pub fn get_node_mut(
-        &mut self,
-        id: &<<R as TreeNodeRef>::Inner as TreeNode>::Id,
-    ) -> Option<&mut R> {
-        self.index.get_mut(id)
+    /// Recompute every subtree hash, including those of tombstones, with a new hash context.
+    /// Returns false if the context was unchanged.
+    fn rehash_with_context(&mut self, context: u64) -> bool {
+        if context == self.hash_context {
+            return false;
+        }
+
+        self.hash_context = context;
+        self.stale_hashes.clear();
+
+        let subtrees: Vec<R> = self.root.iter().chain(self.tombstones()).cloned().collect();
+        for subtree in subtrees {
+            rehash_subtree(subtree, context);
+        }
+        true
     }
 
-    pub fn remove_node(&mut self, node: &R) -> Option<()> {
-        let node_id = node.node().id().clone();
+    /// Update the subtree hashes of a changed node and its ancestors, or defer the update in
+    /// [`HashMode::Deferred`]
+    pub(crate) fn update_hashes_from(&mut self, node: R) {
+        match self.hash_mode {
+            HashMode::Eager => update_subtree_hash_with(node, self.hash_context),
+            HashMode::Deferred => self.stale_hashes.push(node),
+        }
+    }
+
+    /// Get the sequence number which will be assigned to the next removal
+    pub fn removal_seq(&self) -> u64 {
+        self.removal_seq
+    }
 
-        // Remove the node from the tree
-        self.tree.remove_node(node);
+    /// Iterate over the roots of removed subtrees retained as tombstones
+    pub fn tombstones(&self) -> impl Iterator<Item = &R> {
+        self.tombstones.iter().map(|(_, node)| node)
+    }
 
-        let mut remove_ids: HashSet<<<R as TreeNodeRef>::Inner as TreeNode>::Id> =
-            HashSet::from([node_id]);
+    /// Return the IDs of every node of the removed subtrees to the generator
+    fn release_ids(&self, removed: &[R]) {
+        let Some(generator) = &self.node_id_generator else {
+            return;
+        };
 
-        // Remove node and descendents from the index
-        for node in node.clone().into_iter() {
-            remove_ids.insert(node.node().id().clone());
+        for subtree in removed {
+            subtree
+                .for_each(|_, node| {
+                    generator.release(node.node().id());
+                    Ok::<(), ()>(())
+                })
+                .ok();
         }
+    }
 
-        for id in remove_ids {
-            // Remove from the index
-            let _removed = self.index.remove(&id)?;
+    /// Dispose of removed subtrees according to the [`RemovalMode`]. In tombstone mode,
+    /// every node of the removed subtrees is marked with the next removal sequence number.
+    fn bury(&mut self, removed: &[R]) {
+        let seq = self.removal_seq;
+        self.removal_seq += 1;
 
-            // Remove from leaves
-            self.leaves.retain(|node| node.node().id() != id);
+        if self.removal_mode != RemovalMode::Tombstone {
+            self.release_ids(removed);
+            return;
         }
 
-        Some(())
+        for node in removed {
+            node.clone()
+                .for_each_mut(|n| {
+                    n.node_mut().set_tombstone(Some(seq));
+                    Ok::<(), ()>(())
+                })
+                .ok();
+
+            debug!("Tombstoned node {} at seq {seq}", node.node().id());
+            self.tombstones.push((seq, node.clone()));
+        }
     }
 
-    pub fn insert_child(
-        &mut self,
-        parent_id: NodeRefId<R>,
-        index: usize,
-        data: <<R as TreeNodeRef>::Inner as TreeNode>::Data,
-    ) -> Option<()> {
-        let mut parent = self.get_node_mut(&parent_id)?.clone();
+    /// Collect tombstones removed before the provided removal sequence number,
+    /// returning the roots of the collected subtrees
+    pub fn gc(&mut self, before_seq: u64) -> Vec<R> {
+        let split = self
+            .tombstones
+            .partition_point(|(seq, _)| *seq < before_seq);
 
-        let node = self.tree.create_node(data)?;
+        let collected: Vec<R> = self
+            .tombstones
+            .drain(..split)
+            .map(|(_, node)| node)
+            .collect();
 
-        self.tree.insert_child(&mut parent, index, node.clone())?;
+        debug!("Collected {} tombstones", collected.len());
+        self.release_ids(&collected);
 
-        for node in node.into_iter() {
-            let id = node.node().id().clone();
-            self.index.insert(id, node.clone());
-            if node.node().num_children() == 0 {
-                self.leaves.push(node.clone());
-            }
+        collected
+    }
+
+    /// Remove every node whose data fails the predicate, along with its subtree. The root node
+    /// is always retained. Subtree hashes are updated, and the number of removed nodes is returned.
+    pub fn retain<F>(&mut self, f: F) -> usize
+    where
+        F: FnMut(&<<R as TreeNodeRef>::Inner as TreeNode>::Data) -> bool,
+    {
+        self.retain_subtrees(f)
+            .iter()
+            .map(|removed| removed.clone().into_iter().count())
+            .sum()
+    }
+
+    /// Remove the subtrees of nodes failing the predicate, returning the roots of the removed subtrees
+    fn retain_subtrees<F>(&mut self, mut f: F) -> Vec<R>
+    where
+        F: FnMut(&<<R as TreeNodeRef>::Inner as TreeNode>::Data) -> bool,
+    {
+        let Some(root) = self.root.clone() else {
+            return Vec::new();
+        };
+
+        let mut removed = Vec::new();
+        let mut stack = Vec::from([root.clone()]);
+
+        while let Some(mut parent) = stack.pop() {
+            let Some(children) = parent.node_mut().take_children() else {
+                continue;
+            };
+
+            let (kept, dropped): (Vec<R>, Vec<R>) = children
+                .into_iter()
+                .partition(|child| f(&child.node().data()));
+
+            stack.extend(kept.iter().cloned());
+            parent.node_mut().set_children(Some(kept));
+            removed.extend(dropped);
         }
 
-        Some(())
+        if removed.is_empty() {
+            return removed;
+        }
+
+        debug!("Retain removed {} subtrees", removed.len());
+
+        rehash_subtree(root, self.hash_context);
+        self.update_positions();
+        self.bury(&removed);
+
+        for node in &removed {
+            self.send_event(TreeEvent::NodeRemoved { node: node.clone() });
+        }
+
+        removed
     }
 
-    pub fn leaves<'b>(&'b self) -> &'b Vec<R> {
-        &self.leaves
+    /// Remove the provided [`NodeRef`] and its subtree from the tree, returning the removed subtree
+    /// with its parent link cleared. Returns None if the node is not a child of a parent.
+    pub fn remove_node(&mut self, node: &R) -> Option<R> {
+        let node_id = node.node().id();
+        debug!("Removing node id {node_id}");
+
+        let (mut parent, index) = match Self::child_index(node) {
+            Ok(found) => found,
+            Err(e) => {
+                warn!("Cannot remove node {node_id}: {e}");
+                return None;
+            }
+        };
+        debug!("Found child node at index {index}");
+
+        // Remove the node from the parent children vec, and detach it from the parent
+        parent.node_mut().remove_child_index(index);
+        node.clone().node_mut().take_parent();
+
+        Self::reposition_children(&parent);
+        self.update_hashes_from(parent);
+
+        self.bury(std::slice::from_ref(node));
+
+        self.send_event(TreeEvent::NodeRemoved { node: node.clone() });
+
+        Some(node.clone())
     }
 
-    pub fn reindex(&mut self) {
-        if let Some(root) = &self.root {
-            self.index = BTreeIndex::from_node(root);
+    /// Remove a child from a node at the given index
+    pub fn remove_child(&mut self, parent: &mut R, index: usize) -> Option<R> {
+        let parent_id = parent.node().id();
+        let ret = if let Some(mut removed) = parent.clone().node_mut().remove_child_index(index) {
+            debug!("Child {index} removed from {parent_id}");
+            removed.node_mut().take_parent();
+            self.bury(std::slice::from_ref(&removed));
+            Some(removed)
+        } else {
+            warn!("Child not found attempting to remove child at index {index}");
+            None
+        };
+
+        Self::reposition_children(parent);
+        Self::resize_ancestors(parent);
+
+        if let Some(node) = &ret {
+            self.send_event(TreeEvent::ChildRemoved {
+                parent: parent.clone(),
+                index,
+                node: node.clone(),
+            });
         }
 
-        let mut leaves = Vec::new();
-        // Find all leaves
-        for node in self.root() {
-            if node.node().children().is_none() {
-                leaves.push(node.clone())
+        ret
+    }
+
+    /// Remove all children from the specified parent node
+    pub fn remove_children(&mut self, parent: &mut R) {
+        let parent_id = parent.node().id();
+
+        let removed = (*parent).clone().node_mut().take_children();
+        if let Some(mut children) = removed {
+            for child in &mut children {
+                child.node_mut().take_parent();
             }
+            Self::resize_ancestors(parent);
+            self.bury(&children);
+            let p = parent.clone();
+            self.send_event(TreeEvent::ChildrenRemoved {
+                parent: p,
+                children: children.into_iter().collect(),
+            });
         }
-        self.leaves = leaves;
+
+        debug!("All children removed from {parent_id}");
     }
 
-    /// Get a [`LeafIter`] instance for this tree, providing an iterator which
-    /// traverses backwards through the tree starting from the leaves
-    pub fn leaf_iter(&self) -> LeafIter<R>
-    where
-        R: std::fmt::Debug,
-    {
-        LeafIter::new(self.leaves())
+    /// Replace the children of a node. Each new child is assigned a new ID, unless the tree
+    /// is in [`IdMode::Preserve`] and the child was already part of the replaced subtrees.
+    pub fn set_children(&mut self, parent: &mut R, children: Vec<R>) {
+        let existing = parent
+            .node()
+            .children()
+            .map(|children| children.to_vec())
+            .unwrap_or_default();
+        let retained = self.reattached(&existing, &children);
+        self.set_children_retaining(parent, children, &retained);
     }
-}
 
-/// Deref IndexedTree into Tree
-impl<R, G> Deref for IndexedTree<R, G>
-where
-    R: TreeNodeRef + 'static,
-    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
-{
-    type Target = Tree<R, G>;
+    fn set_children_retaining(
+        &mut self,
+        parent: &mut R,
+        mut children: Vec<R>,
+        retained: &HashSet<NodeRefId<R>>,
+    ) {
+        let mut added_children = Vec::new();
 
-    fn deref(&self) -> &Self::Target {
-        &self.tree
-    }
-}
+        // For each child being added, set its parent to the new parent
+        for child in &mut children {
+            if !retained.contains(&child.node().id()) {
+                let new_id = self.generate_id();
+                child.node_mut().set_id(new_id);
+            }
+            child.node_mut().set_parent(parent.clone());
 
-/// DerefMut IndexedTree into Tree
-impl<R, G> DerefMut for IndexedTree<R, G>
-where
-    R: TreeNodeRef + 'static,
-    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
-{
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.tree
+            added_children.push(child.clone())
+        }
+
+        // Take the existing children from the parent, and notify any listeners of their removal
+        if let Some(mut children) = parent.clone().node_mut().take_children() {
+            children.retain(|child| !retained.contains(&child.node().id()));
+            self.bury(&children);
+            self.send_event(TreeEvent::ChildrenRemoved {
+                parent: parent.clone(),
+                children: children.into_iter().collect(),
+            });
+        }
+
+        parent.node_mut().set_children(Some(children));
+        Self::reposition_children(parent);
+        Self::resize_ancestors(parent);
+
+        self.send_event(TreeEvent::ChildrenAdded {
+            parent: parent.clone(),
+            children: added_children,
+        });
+    }
+
+    /// Replace a child in a node with a new child at the given index. The new child and its
+    /// children are assigned new IDs, except for nodes of the replaced subtree which are
+    /// reattached in [`IdMode::Preserve`].
+    pub fn replace_child(&mut self, parent: &mut R, index: usize, new: R) {
+        let replaced = parent
+            .node()
+            .children()
+            .and_then(|children| children.get(index).cloned());
+        let retained = self.reattached(replaced.as_slice(), std::slice::from_ref(&new));
+        self.replace_child_retaining(parent, index, new, &retained);
+    }
+
+    fn replace_child_retaining(
+        &mut self,
+        parent: &mut R,
+        index: usize,
+        mut new: R,
+        retained: &HashSet<NodeRefId<R>>,
+    ) {
+        if !retained.contains(&new.node().id()) {
+            new.node_mut().set_id(self.generate_id());
+        }
+
+        if let Some(mut children) = new.node_mut().children_mut() {
+            for child in children.iter_mut() {
+                if !retained.contains(&child.node().id()) {
+                    let new_id = self.generate_id();
+                    child.node_mut().set_id(new_id);
+                }
+            }
+        }
+
+        let replaced = parent
+            .node()
+            .children()
+            .and_then(|children| children.get(index).cloned())
+            .filter(|replaced| !replaced.ptr_eq(&new));
+
+        new.node_mut().set_parent(parent.clone());
+        parent.node_mut().replace_child(new, index);
+        Self::reposition_children(parent);
+        Self::resize_ancestors(parent);
+
+        if let Some(replaced) = &replaced {
+            self.bury(std::slice::from_ref(replaced));
+        }
+
+        self.send_event(TreeEvent::ChildReplaced {
+            parent: parent.clone(),
+            index,
+            previous: replaced,
+        });
+    }
+
+    /// Insert a child into a parent at the given index
+    pub fn insert_child(&mut self, parent: &mut R, index: usize, mut new: R) -> Option<()> {
+        new.node_mut().set_parent(parent.clone());
+        let ret = parent.node_mut().insert_child(new, index);
+        Self::reposition_children(parent);
+        Self::resize_ancestors(parent);
+        self.send_event(TreeEvent::ChildInserted {
+            parent: parent.clone(),
+            index,
+        });
+
+        ret
+    }
+
+    /// Insert multiple subtrees as children of `parent`, starting at child `index`. Each inserted
+    /// subtree is assigned new IDs, and the subtree hashes of the parent and its ancestors are
+    /// updated once, followed by a single [`TreeEvent::ChildrenAdded`] event.
+    pub fn insert_children(
+        &mut self,
+        parent: &mut R,
+        index: usize,
+        mut children: Vec<R>,
+    ) -> Result<(), Error> {
+        let len = parent.node().num_children();
+        if index > len {
+            return Err(Error::IndexOutOfBounds { index, len });
+        }
+
+        for child in &mut children {
+            self.assign_ids(child)?;
+            child.node_mut().set_parent(parent.clone());
+            rehash_subtree(child.clone(), self.hash_context);
+        }
+
+        {
+            let mut inner = parent.node_mut();
+            if inner.children().is_none() {
+                inner.set_children(Some(Vec::new()));
+            }
+            if let Some(mut existing) = inner.children_mut() {
+                let following: Vec<R> = existing.drain(index..).collect();
+                existing.extend(children.iter().cloned());
+                existing.extend(following);
+            };
+        }
+
+        debug!(
+            "Inserted {} children into {} at index {index}",
+            children.len(),
+            parent.node().id()
+        );
+
+        Self::reposition_children(parent);
+        self.update_hashes_from(parent.clone());
+
+        self.send_event(TreeEvent::ChildrenAdded {
+            parent: parent.clone(),
+            children,
+        });
+
+        Ok(())
+    }
+
+    pub fn replace_node(&mut self, dest: &mut R, source: &R) {
+        let data = source.node().data().clone();
+        let previous = std::mem::replace(&mut *dest.node_mut().data_mut(), data);
+        self.send_event(TreeEvent::NodeReplaced {
+            node: dest.clone(),
+            previous,
+        });
+    }
+
+    /// Create a node from the provided data and add it as the last child of `parent`,
+    /// updating the subtree hashes of its ancestors. Returns the new node.
+    pub fn append_child(
+        &mut self,
+        parent: &mut R,
+        data: <<R as TreeNodeRef>::Inner as TreeNode>::Data,
+    ) -> Result<R, Error> {
+        let index = parent.node().num_children();
+        self.add_child(parent, index, data)
+    }
+
+    /// Create a node from the provided data and add it as the first child of `parent`,
+    /// updating the subtree hashes of its ancestors. Returns the new node.
+    pub fn prepend_child(
+        &mut self,
+        parent: &mut R,
+        data: <<R as TreeNodeRef>::Inner as TreeNode>::Data,
+    ) -> Result<R, Error> {
+        self.add_child(parent, 0, data)
+    }
+
+    fn add_child(
+        &mut self,
+        parent: &mut R,
+        index: usize,
+        data: <<R as TreeNodeRef>::Inner as TreeNode>::Data,
+    ) -> Result<R, Error> {
+        let node = self.create_node(data).ok_or(Error::NoGenerator)?;
+        self.insert_child(parent, index, node.clone());
+
+        // Hash the new node, and propagate up through its ancestors
+        self.update_hashes_from(node.clone());
+
+        Ok(node)
+    }
+
+    /// Assign new IDs from the generator of this tree to every node of a subtree
+    fn assign_ids(&self, subtree: &mut R) -> Result<(), Error> {
+        self.assign_ids_retaining(subtree, &HashSet::new())
+    }
+
+    /// Assign new IDs to the nodes of a subtree, skipping nodes with a retained ID
+    fn assign_ids_retaining(
+        &self,
+        subtree: &mut R,
+        retained: &HashSet<NodeRefId<R>>,
+    ) -> Result<(), Error> {
+        let generator = self.try_generator()?;
+        subtree
+            .for_each_mut(|node| {
+                if !retained.contains(&node.node().id()) {
+                    node.node_mut().set_id(generator.generate());
+                }
+                Ok::<(), ()>(())
+            })
+            .ok();
+        Ok(())
+    }
+
+    /// Find the nodes of the `removed` subtrees which are attached again as part of the `added`
+    /// subtrees, returning their IDs. Always empty unless the tree is in [`IdMode::Preserve`].
+    ///
+    /// Reattached nodes are detached from any removed parent, so that disposing of the removed
+    /// nodes leaves them untouched.
+    fn reattached(&self, removed: &[R], added: &[R]) -> HashSet<NodeRefId<R>> {
+        if self.id_mode != IdMode::Preserve {
+            return HashSet::new();
+        }
+
+        let removed: HashMap<NodeRefId<R>, R> = removed
+            .iter()
+            .flat_map(|subtree| subtree.clone().into_iter())
+            .map(|node| (node.node().id(), (*node).clone()))
+            .collect();
+
+        let reattached: Vec<R> = added
+            .iter()
+            .flat_map(|subtree| subtree.clone().into_iter())
+            .filter(|node| {
+                removed
+                    .get(&node.node().id())
+                    .is_some_and(|existing| existing.ptr_eq(node))
+            })
+            .map(|node| (*node).clone())
+            .collect();
+
+        let retained: HashSet<NodeRefId<R>> =
+            reattached.iter().map(|node| node.node().id()).collect();
+
+        for node in &reattached {
+            let former = node.node().parent().cloned();
+            if let Some(mut former) = former {
+                let former_id = former.node().id();
+                if removed.contains_key(&former_id) && !retained.contains(&former_id) {
+                    if let Some(mut children) = former.node_mut().children_mut() {
+                        children.retain(|child| !child.ptr_eq(node));
+                    }
+                }
+            }
+        }
+
+        retained
+    }
+
+    /// Find the parent of a node and the index of the node within the children of the parent
+    fn child_index(node: &R) -> Result<(R, usize), Error> {
+        let node_id = node.node().id();
+        let parent = node.node().parent().cloned().ok_or(Error::NoParent)?;
+
+        let index = parent
+            .node()
+            .children()
+            .and_then(|children| {
+                children
+                    .iter()
+                    .position(|child| child.node().id() == node_id)
+            })
+            .ok_or(Error::NodeNotFound)?;
+
+        Ok((parent, index))
+    }
+
+    /// Check if the node with the provided ID is `node` or one of its ancestors
+    fn has_ancestor(node: &R, id: NodeRefId<R>) -> bool {
+        let mut current = Some(node.clone());
+        while let Some(node) = current {
+            if node.node().id() == id {
+                return true;
+            }
+            current = node.node().parent().cloned();
+        }
+        false
+    }
+
+    /// Recompute the stored [`NodePosition`] of `node` and its descendants. The horizontal index
+    /// of a node depends on the nodes preceding it at the same depth, so the tree is traversed
+    /// from the root up to the end of the subtree.
+    pub fn reposition_subtree(&mut self, node: &R) {
+        let Some(root) = self.root.clone() else {
+            return;
+        };
+
+        let node_id = node.node().id();
+        let depth = Self::node_depth(node);
+        let mut inside = false;
+
+        for mut current in root {
+            if current.node().id() == node_id {
+                inside = true;
+            } else if inside && current.position().depth <= depth {
+                // Left the subtree
+                break;
+            }
+
+            if inside {
+                let position = *current.position();
+                current.node_mut().set_position(position);
+            }
+        }
+    }
+
+    /// Number of ancestors of a node
+    fn node_depth(node: &R) -> usize {
+        let mut depth = 0;
+        let mut current = node.node().parent().cloned();
+        while let Some(node) = current {
+            depth += 1;
+            current = node.node().parent().cloned();
+        }
+        depth
+    }
+
+    /// Update the depth and child index of the children of `parent` after a structural edit,
+    /// along with the depths of their descendants where they have changed. Horizontal indices
+    /// are retained, and can be recomputed with [`Tree::update_positions`].
+    fn reposition_children(parent: &R) {
+        let depth = Self::node_depth(parent) + 1;
+
+        let mut stack: Vec<(R, usize, usize)> = match parent.node().children() {
+            Some(children) => children
+                .iter()
+                .enumerate()
+                .map(|(child_index, child)| (child.clone(), depth, child_index))
+                .collect(),
+            None => return,
+        };
+
+        while let Some((mut node, depth, child_index)) = stack.pop() {
+            let old = node.node().get_position().copied();
+
+            node.node_mut().set_position(NodePosition {
+                depth,
+                index: old.map_or(0, |position| position.index),
+                child_index,
+            });
+
+            // Descendants only need updating when the depth of this node changed
+            if old.map(|position| position.depth) != Some(depth) {
+                if let Some(children) = node.node().children() {
+                    for (child_index, child) in children.iter().enumerate() {
+                        stack.push((child.clone(), depth + 1, child_index));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Recompute the subtree size of a node and each of its ancestors,
+    /// after the children of the node have changed
+    fn resize_ancestors(node: &R) {
+        let mut current = Some(node.clone());
+
+        while let Some(mut node) = current {
+            let size = subtree_size(&*node.node());
+            node.node_mut().set_subtree_size(size);
+            current = node.node().parent().cloned();
+        }
+    }
+
+    /// Recompute the stored [`NodePosition`] of every node
+    /// from the current structure of the tree
+    pub fn update_positions(&mut self) {
+        let Some(root) = self.root.clone() else {
+            return;
+        };
+
+        for mut node in root {
+            let position = *node.position();
+            node.node_mut().set_position(position);
+        }
+    }
+
+    /// Swap two nodes according to the [`SwapMode`], updating subtree hashes.
+    ///
+    /// Swapping subtrees exchanges the positions of the nodes within their parents, which may
+    /// differ, and updates the stored positions of the tree. Neither node may be an ancestor
+    /// of the other.
+    pub fn swap(&mut self, a: &R, b: &R, mode: SwapMode) -> Result<(), Error> {
+        let (a_id, b_id) = (a.node().id(), b.node().id());
+        if a_id == b_id {
+            return Ok(());
+        }
+
+        match mode {
+            SwapMode::Data => {
+                std::mem::swap(
+                    &mut *a.clone().node_mut().data_mut(),
+                    &mut *b.clone().node_mut().data_mut(),
+                );
+
+                self.update_hashes_from(a.clone());
+                self.update_hashes_from(b.clone());
+
+                // Each node previously held the data now held by the other
+                let (a_data, b_data) = (a.node().data().clone(), b.node().data().clone());
+                self.send_event(TreeEvent::NodeReplaced {
+                    node: a.clone(),
+                    previous: b_data,
+                });
+                self.send_event(TreeEvent::NodeReplaced {
+                    node: b.clone(),
+                    previous: a_data,
+                });
+            }
+            SwapMode::Subtree => {
+                if Self::has_ancestor(a, b_id) || Self::has_ancestor(b, a_id) {
+                    return Err(Error::CyclicMove);
+                }
+
+                let (mut a_parent, a_index) = Self::child_index(a)?;
+                let (mut b_parent, b_index) = Self::child_index(b)?;
+
+                a_parent.node_mut().replace_child(b.clone(), a_index);
+                b_parent.node_mut().replace_child(a.clone(), b_index);
+                a.clone().node_mut().set_parent(b_parent.clone());
+                b.clone().node_mut().set_parent(a_parent.clone());
+
+                debug!("Swapped subtrees {a_id} and {b_id}");
+
+                self.update_hashes_from(a.clone());
+                self.update_hashes_from(b.clone());
+                self.update_positions();
+
+                self.send_event(TreeEvent::NodeMoved {
+                    node: a.clone(),
+                    old_parent: a_parent.clone(),
+                    new_parent: b_parent.clone(),
+                    index: b_index,
+                });
+                self.send_event(TreeEvent::NodeMoved {
+                    node: b.clone(),
+                    old_parent: b_parent,
+                    new_parent: a_parent,
+                    index: a_index,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sort the children of `parent` with a comparator on their data, updating subtree hashes
+    /// and positions. A single [`TreeEvent::ChildrenReordered`] event is sent.
+    pub fn sort_children_by<F>(&mut self, parent: &mut R, mut cmp: F)
+    where
+        F: FnMut(
+            &<<R as TreeNodeRef>::Inner as TreeNode>::Data,
+            &<<R as TreeNodeRef>::Inner as TreeNode>::Data,
+        ) -> std::cmp::Ordering,
+    {
+        Self::sort_children(parent, &mut cmp);
+
+        self.update_hashes_from(parent.clone());
+        self.update_positions();
+
+        self.send_event(TreeEvent::ChildrenReordered {
+            parent: parent.clone(),
+        });
+    }
+
+    /// Sort the children of every node in the subtree of `node` with a comparator on their data,
+    /// updating subtree hashes and positions. A [`TreeEvent::ChildrenReordered`] event is sent
+    /// for each node whose children changed order.
+    pub fn sort_all_by<F>(&mut self, node: &R, mut cmp: F)
+    where
+        F: FnMut(
+            &<<R as TreeNodeRef>::Inner as TreeNode>::Data,
+            &<<R as TreeNodeRef>::Inner as TreeNode>::Data,
+        ) -> std::cmp::Ordering,
+    {
+        let nodes: Vec<R> = node.clone().into_iter().map(|n| (*n).clone()).collect();
+
+        let mut reordered = Vec::new();
+        for mut parent in nodes {
+            if Self::sort_children(&mut parent, &mut cmp) {
+                reordered.push(parent);
+            }
+        }
+
+        rehash_subtree(node.clone(), self.hash_context);
+        if let Some(parent) = node.node().parent() {
+            self.update_hashes_from(parent.clone());
+        }
+        self.update_positions();
+
+        for parent in reordered {
+            self.send_event(TreeEvent::ChildrenReordered { parent });
+        }
+    }
+
+    /// Sort the children of a node, returning true if their order changed
+    fn sort_children<F>(parent: &mut R, cmp: &mut F) -> bool
+    where
+        F: FnMut(
+            &<<R as TreeNodeRef>::Inner as TreeNode>::Data,
+            &<<R as TreeNodeRef>::Inner as TreeNode>::Data,
+        ) -> std::cmp::Ordering,
+    {
+        let mut node = parent.node_mut();
+        let Some(mut children) = node.children_mut() else {
+            return false;
+        };
+
+        let before: Vec<NodeRefId<R>> = children.iter().map(|c| c.node().id()).collect();
+        children.sort_by(|a, b| cmp(&a.node().data(), &b.node().data()));
+
+        children
+            .iter()
+            .zip(before)
+            .any(|(child, id)| child.node().id() != id)
+    }
+
+    /// Insert a new node as the sibling immediately before `node`, updating the subtree hashes
+    /// of its ancestors
+    pub fn insert_before(&mut self, node: &R, new: R) -> Result<(), Error> {
+        let (mut parent, index) = Self::child_index(node)?;
+        self.insert_child(&mut parent, index, new.clone());
+        self.update_hashes_from(new);
+        Ok(())
+    }
+
+    /// Insert a new node as the sibling immediately after `node`, updating the subtree hashes
+    /// of its ancestors
+    pub fn insert_after(&mut self, node: &R, new: R) -> Result<(), Error> {
+        let (mut parent, index) = Self::child_index(node)?;
+        self.insert_child(&mut parent, index + 1, new.clone());
+        self.update_hashes_from(new);
+        Ok(())
+    }
+
+    /// Move a node and its subtree to the child `index` of `new_parent`. The index refers to the
+    /// children of the new parent after the node has been removed from its old parent.
+    ///
+    /// When `preserve_ids` is false, new IDs are allocated for every node of the moved subtree.
+    /// Subtree hashes are updated along both the old and new ancestor chains.
+    pub fn move_node(
+        &mut self,
+        node: &R,
+        new_parent: &mut R,
+        index: usize,
+        preserve_ids: bool,
+    ) -> Result<(), Error> {
+        let node_id = node.node().id();
+        let (mut old_parent, old_index) = Self::child_index(node)?;
+
+        // Refuse to move a node into its own subtree
+        if Self::has_ancestor(new_parent, node_id) {
+            return Err(Error::CyclicMove);
+        }
+
+        let same_parent = old_parent.node().id() == new_parent.node().id();
+        let len = new_parent.node().num_children() - usize::from(same_parent);
+        if index > len {
+            return Err(Error::IndexOutOfBounds { index, len });
+        }
+
+        if !preserve_ids {
+            // Check for a generator before modifying the tree
+            self.try_generator()?;
+        }
+
+        old_parent.node_mut().remove_child_index(old_index);
+
+        if !preserve_ids {
+            node.clone()
+                .for_each_mut(|n| {
+                    let new_id = self.generate_id();
+                    n.node_mut().set_id(new_id);
+                    Ok::<(), ()>(())
+                })
+                .ok();
+        }
+
+        node.clone().node_mut().set_parent(new_parent.clone());
+        new_parent.node_mut().insert_child(node.clone(), index);
+
+        Self::reposition_children(&old_parent);
+        if !same_parent {
+            Self::reposition_children(new_parent);
+        }
+
+        debug!(
+            "Moved node {node_id} from {} to {} at index {index}",
+            old_parent.node().id(),
+            new_parent.node().id()
+        );
+
+        self.update_hashes_from(old_parent.clone());
+        if !same_parent {
+            self.update_hashes_from(new_parent.clone());
+        }
+
+        self.send_event(TreeEvent::NodeMoved {
+            node: node.clone(),
+            old_parent,
+            new_parent: new_parent.clone(),
+            index,
+        });
+
+        Ok(())
+    }
+
+    /// Remove a node and its subtree from the tree, returning a copy of it as an independent
+    /// [`Tree`].
+    ///
+    /// The detached tree is given a new generator, and the copied nodes are assigned new IDs from
+    /// it so they remain unique within the detached tree. The removed nodes keep their IDs, and
+    /// are disposed of according to the [`RemovalMode`] like any other removal. Detaching the
+    /// root node leaves this tree empty.
+    pub fn detach(&mut self, node: &R) -> Result<Tree<R, G>, Error> {
+        let node_id = node.node().id();
+
+        let is_root = self
+            .root
+            .as_ref()
+            .is_some_and(|root| root.node().id() == node_id);
+        if node.node().parent().is_none() && !is_root {
+            return Err(Error::NodeNotFound);
+        }
+
+        // The subtree hashes of the copied nodes are computed in the context of this tree
+        let mut detached = Self::new();
+        detached.node_id_generator = Some(G::default());
+        detached.hash_context = self.hash_context;
+        detached.root = Some(detached.copy_subtree(node, None)?);
+        detached.update_positions();
+
+        if node.node().parent().is_some() {
+            let (mut parent, index) = Self::child_index(node)?;
+            parent.node_mut().remove_child_index(index);
+            node.clone().node_mut().take_parent();
+            Self::reposition_children(&parent);
+            self.update_hashes_from(parent);
+        } else {
+            self.root = None;
+        }
+
+        debug!("Detached node {node_id}");
+
+        self.bury(std::slice::from_ref(node));
+        self.send_event(TreeEvent::NodeRemoved { node: node.clone() });
+
+        Ok(detached)
+    }
+
+    /// Create a new node from the provided data. Does not insert into the tree, but allocates a new ID
+    pub fn create_node(&self, data: <<R as TreeNodeRef>::Inner as TreeNode>::Data) -> Option<R> {
+        // Generate a new Node ID
+        if let Some(gen) = &self.node_id_generator {
+            let id = gen.generate();
+            debug!("Allocated new node ID {id}");
+
+            // Create a new Inner Node
+            let node = <R as TreeNodeRef>::Inner::new(id, data, None);
+
+            // Create and return a new NodeRef wrapping this node
+            Some(R::new_in(node, &self.allocator))
+        } else {
+            error!("ID generator not available attempting to create new node from Tree");
+            None
+        }
+    }
+
+    /// Insert a subtree as a child of the specified parent at a given child index
+    pub fn insert_subtree(&mut self, parent: &mut R, index: usize, mut subtree: R) -> Option<()>
+    where
+        R::Data: Clone,
+        <<R as TreeNodeRef>::Inner as TreeNode>::Data: Clone,
+    {
+        subtree
+            .for_each_mut(|r| {
+                let new_id = self.generate_id();
+                r.node_mut().set_id(new_id);
+
+                Ok::<(), ()>(())
+            })
+            .unwrap();
+
+        // Set the parent of the subtree
+        subtree.node_mut().set_parent(parent.clone());
+
+        // Insert the root of the cloned subtree into the parent node at the provided index
+        parent.node_mut().insert_child(subtree.clone(), index);
+        Self::reposition_children(parent);
+        Self::resize_ancestors(parent);
+
+        self.send_event(TreeEvent::SubtreeInserted {
+            node: subtree.clone(),
+        });
+
+        Some(())
+    }
+}
+
+impl<R, G> Default for Tree<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R, G> Deref for Tree<R, G>
+where
+    R: TreeNodeRef + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    type Target = R;
+
+    fn deref(&self) -> &Self::Target {
+        self.root.as_ref().unwrap()
+    }
+}
+
+/// IDs of the nodes of an [`IndexedTree`] at each depth, maintained incrementally on mutation
+#[derive(Debug, Clone)]
+struct Shape<Id> {
+    // Nodes at each depth, without trailing empty levels
+    levels: Vec<BTreeSet<Id>>,
+    nodes: usize,
+    max_width: usize,
+}
+
+impl<Id> Default for Shape<Id> {
+    fn default() -> Self {
+        Self {
+            levels: Vec::new(),
+            nodes: 0,
+            max_width: 0,
+        }
+    }
+}
+
+impl<Id: Ord> Shape<Id> {
+    /// Add a node at the provided depth
+    fn insert(&mut self, id: Id, depth: usize) {
+        if depth >= self.levels.len() {
+            self.levels.resize_with(depth + 1, BTreeSet::new);
+        }
+        if self.levels[depth].insert(id) {
+            self.nodes += 1;
+        }
+    }
+
+    /// Add the nodes of a subtree whose root is at the provided depth
+    fn add<R>(&mut self, subtree: &R, depth: usize)
+    where
+        R: TreeNodeRef<Inner: TreeNode<Id = Id>>,
+    {
+        for node in subtree.clone() {
+            self.insert(node.node().id(), depth + node.position().depth);
+        }
+        self.update();
+    }
+
+    /// Remove a node from the provided depth
+    fn remove_id(&mut self, id: &Id, depth: usize) {
+        if let Some(level) = self.levels.get_mut(depth) {
+            if level.remove(id) {
+                self.nodes -= 1;
+            }
+        }
+    }
+
+    /// Remove the nodes of a subtree whose root was at the provided depth
+    fn remove<R>(&mut self, subtree: &R, depth: usize)
+    where
+        R: TreeNodeRef<Inner: TreeNode<Id = Id>>,
+    {
+        for node in subtree.clone() {
+            let id = node.node().id();
+            self.remove_id(&id, depth + node.position().depth);
+        }
+        self.update();
+    }
+
+    fn update(&mut self) {
+        while self.levels.last().is_some_and(|level| level.is_empty()) {
+            self.levels.pop();
+        }
+        self.max_width = self
+            .levels
+            .iter()
+            .map(|level| level.len())
+            .max()
+            .unwrap_or(0);
+    }
+}
+
+pub struct IndexedTree<R, G = crate::IdGenerator, I = BTreeIndex<R>>
+where
+    R: TreeNodeRef + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+{
+    tree: Tree<R, G>,
+    leaves: Vec<R>,
+    index: I,
+    shape: Shape<NodeRefId<R>>,
+
+    // Parent ID of each live node other than the root
+    parents: HashMap<NodeRefId<R>, NodeRefId<R>>,
+
+    // Optional reverse index of the subtree hash of each live node
+    subtree_hashes: Option<SubtreeHashIndex<NodeRefId<R>>>,
+
+    // Optional index of the path of child indices to each live node
+    paths: Option<PathIndex<NodeRefId<R>>>,
+
+    // Attached secondary indexes, notified as nodes are indexed and removed
+    secondary_indexes: Vec<Box<dyn SecondaryIndex<R>>>,
+
+    // Positional hash of the tree, keyed by the root subtree hash it was computed at
+    positional: parking_lot::Mutex<Option<(u64, u64)>>,
+}
+
+impl<R, G, I> std::fmt::Debug for IndexedTree<R, G, I>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let leaf_ids: Vec<<<R as TreeNodeRef>::Inner as TreeNode>::Id> =
+            self.leaves.iter().map(|leaf| leaf.node().id()).collect();
+        let ids = self.index.get_ids();
+
+        f.debug_struct("IndexedTree")
+            .field("tree", &self.tree)
+            .field("leaf_ids", &leaf_ids)
+            .field("index_ids", &ids)
+            .finish()
+    }
+}
+
+impl<R, G, I> IndexedTree<R, G, I>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+{
+    // Create a new empty indexed tree
+    pub fn new() -> Self {
+        Self {
+            tree: Tree::new(),
+            leaves: Vec::new(),
+            index: I::new(),
+            shape: Shape::default(),
+            parents: HashMap::new(),
+            subtree_hashes: None,
+            paths: None,
+            secondary_indexes: Vec::new(),
+            positional: parking_lot::Mutex::new(None),
+        }
+    }
+
+    /// Index a tree. Shared subtrees, as produced by [`Tree::intern`], are first copied into
+    /// distinct nodes with [`Tree::unshare`], as the index requires unique IDs. Shared subtrees
+    /// remain if the tree has no ID generator.
+    pub fn from_tree(mut tree: Tree<R, G>) -> Self {
+        if let Err(e) = tree.unshare() {
+            warn!("Indexing a tree with shared subtrees: {e}");
+        }
+        let index = I::from_tree(&tree);
+
+        let mut leaves = Vec::new();
+        let mut shape = Shape::default();
+
+        // Find all leaves. An empty tree has an empty index.
+        if let Ok(root) = tree.try_root_ref() {
+            for node in root.clone() {
+                if node.node().num_children() == 0 {
+                    leaves.push(node.clone())
+                }
+            }
+            shape.add(root, 0);
+        }
+
+        let mut indexed = Self {
+            tree,
+            index,
+            leaves,
+            shape,
+            parents: HashMap::new(),
+            subtree_hashes: None,
+            paths: None,
+            secondary_indexes: Vec::new(),
+            positional: parking_lot::Mutex::new(None),
+        };
+        indexed.link_parents();
+        indexed
+    }
+
+    /// Create an indexed tree from an already populated index and leaves list
+    pub(crate) fn from_parts(tree: Tree<R, G>, index: I, leaves: Vec<R>) -> Self {
+        // Count nodes from the positions assigned by the builder, without traversing the tree
+        let mut shape = Shape::default();
+        for id in index.get_ids() {
+            let depth = index
+                .get(&id)
+                .and_then(|node| node.node().get_position().map(|p| p.depth))
+                .unwrap_or(0);
+            shape.insert(id, depth);
+        }
+        shape.update();
+
+        let mut indexed = Self {
+            tree,
+            index,
+            leaves,
+            shape,
+            parents: HashMap::new(),
+            subtree_hashes: None,
+            paths: None,
+            secondary_indexes: Vec::new(),
+            positional: parking_lot::Mutex::new(None),
+        };
+        indexed.link_parents();
+        indexed
+    }
+
+    /// Number of nodes in the tree
+    pub fn node_count(&self) -> usize {
+        self.shape.nodes
+    }
+
+    /// Get the maximum depth of the tree. Unlike [`Tree::depth`] this does not traverse the
+    /// tree, and returns 0 for an empty tree.
+    pub fn depth(&self) -> usize {
+        self.shape.levels.len().saturating_sub(1)
+    }
+
+    /// Get the maximum horizontal index of the tree, which is one less than the number of nodes
+    /// at the widest depth. Unlike [`Tree::width`] this does not traverse the tree.
+    pub fn width(&self) -> usize {
+        self.shape.max_width.saturating_sub(1)
+    }
+
+    /// Number of nodes at the provided depth
+    pub fn width_at(&self, depth: usize) -> usize {
+        self.shape.levels.get(depth).map_or(0, |level| level.len())
+    }
+
+    /// Get the IDs of the nodes at the provided depth in ascending order, without traversing
+    /// the tree
+    pub fn ids_at_depth(&self, depth: usize) -> impl Iterator<Item = NodeRefId<R>> + '_ {
+        self.shape.levels.get(depth).into_iter().flatten().copied()
+    }
+
+    /// Get the nodes at the provided depth in ascending order of ID, without traversing the tree
+    pub fn nodes_at_depth(&self, depth: usize) -> impl Iterator<Item = &R> + '_ {
+        self.ids_at_depth(depth)
+            .filter_map(|id| self.index.get(&id))
+    }
+
+    /// Add a secondary index of the nodes keyed by a function of their data, which is kept in
+    /// sync as the tree is mutated through this [`IndexedTree`]. Nodes are looked up by key
+    /// with [`IndexedTree::get_by_key`].
+    pub fn with_data_index<K, F>(self, key: F) -> Self
+    where
+        K: std::hash::Hash + Eq + Clone + Send + Sync + 'static,
+        F: Fn(&<<R as TreeNodeRef>::Inner as TreeNode>::Data) -> K + Send + Sync + 'static,
+        NodeRefId<R>: Send + Sync,
+    {
+        self.with_index(KeyIndex::<R, K>::new(key))
+    }
+
+    /// Attach a [`SecondaryIndex`], which is populated with the indexed nodes and then notified
+    /// of every node indexed, updated or removed through this [`IndexedTree`]
+    pub fn with_index<T>(mut self, mut index: T) -> Self
+    where
+        T: SecondaryIndex<R> + 'static,
+    {
+        for id in self.index.get_ids() {
+            if let Some(node) = self.index.get(&id) {
+                index.insert(node);
+            }
+        }
+
+        self.secondary_indexes.push(Box::new(index));
+        self
+    }
+
+    /// Get the first attached [`SecondaryIndex`] of type `T`
+    pub fn secondary_index<T>(&self) -> Option<&T>
+    where
+        T: 'static,
+    {
+        self.secondary_indexes
+            .iter()
+            .find_map(|index| index.as_any().downcast_ref::<T>())
+    }
+
+    /// Get the nodes whose data maps to the provided key in a data index added with
+    /// [`IndexedTree::with_data_index`], in ascending order of ID. Yields nothing if there is
+    /// no data index with keys of type `K`.
+    pub fn get_by_key<K>(&self, key: &K) -> impl Iterator<Item = &R> + '_
+    where
+        K: std::hash::Hash + Eq + Clone + 'static,
+    {
+        let ids: Vec<NodeRefId<R>> = self
+            .secondary_index::<KeyIndex<R, K>>()
+            .map(|index| index.get(key).copied().collect())
+            .unwrap_or_default();
+
+        ids.into_iter().filter_map(|id| self.live_node(id))
+    }
+
+    /// Maintain a reverse index of the subtree hashes of the nodes, so identical subtrees can be
+    /// found with [`IndexedTree::find_duplicate_subtrees`] and
+    /// [`IndexedTree::nodes_with_subtree_hash`] without traversing the tree. Every mutation
+    /// through this [`IndexedTree`] then also updates the entries of the ancestors of the
+    /// mutated nodes.
+    pub fn with_subtree_hash_index(mut self) -> Self {
+        self.subtree_hashes = Some(SubtreeHashIndex::new());
+        self.index_subtree_hashes();
+        self
+    }
+
+    /// Get the IDs of each group of nodes with identical subtrees, in ascending order of subtree
+    /// hash and ID. Returns nothing unless enabled by [`IndexedTree::with_subtree_hash_index`].
+    pub fn find_duplicate_subtrees(&self) -> Vec<Vec<NodeRefId<R>>> {
+        self.subtree_hashes
+            .iter()
+            .flat_map(|hashes| hashes.duplicates())
+            .map(|ids| ids.iter().copied().collect())
+            .collect()
+    }
+
+    /// Get the nodes with the provided subtree hash in ascending order of ID. Returns nothing
+    /// unless enabled by [`IndexedTree::with_subtree_hash_index`].
+    pub fn nodes_with_subtree_hash(&self, hash: u64) -> impl Iterator<Item = &R> + '_ {
+        self.subtree_hashes
+            .iter()
+            .flat_map(move |hashes| hashes.get(hash))
+            .filter_map(|id| self.index.get(id))
+    }
+
+    /// Check if a subtree with the provided subtree hash exists anywhere in the tree. Always
+    /// false unless enabled by [`IndexedTree::with_subtree_hash_index`].
+    pub fn contains_subtree_hash(&self, hash: u64) -> bool {
+        self.nodes_with_subtree_hash(hash).next().is_some()
+    }
+
+    /// Maintain an index of the nodes by their path of child indices from the root, so
+    /// [`IndexedTree::get_by_path`] and [`IndexedTree::path_of`] are answered without walking
+    /// the tree. Mutations through this [`IndexedTree`] then also rebuild the paths within the
+    /// subtree of the parent of the mutated nodes.
+    pub fn with_path_index(mut self) -> Self {
+        self.paths = Some(PathIndex::new());
+        self.index_paths();
+        self
+    }
+
+    /// Get the node at a path of child indices from the root. The empty path is the root.
+    /// Without [`IndexedTree::with_path_index`] the path is followed down from the root.
+    pub fn get_by_path(&self, path: &[usize]) -> Option<&R> {
+        if let Some(paths) = &self.paths {
+            return paths.get(path).and_then(|id| self.index.get(id));
+        }
+
+        let mut id = self.tree.root.as_ref()?.node().id();
+        for &index in path {
+            let node = self.index.get(&id)?;
+            let child = node.node().children()?.get(index)?.node().id();
+            id = child;
+        }
+        self.index.get(&id)
+    }
+
+    /// Get the path of child indices from the root to the node with the provided ID. Without
+    /// [`IndexedTree::with_path_index`] the path is found by following the parent links.
+    pub fn path_of(&self, id: NodeRefId<R>) -> Option<Vec<usize>> {
+        if let Some(paths) = &self.paths {
+            return paths.path_of(&id).map(<[usize]>::to_vec);
+        }
+
+        let mut node = self.live_node(id)?.clone();
+        let mut path = Vec::new();
+        while node.node().parent().is_some() {
+            let (parent, index) = Tree::<R, G>::child_index(&node).ok()?;
+            path.push(index);
+            node = parent;
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    pub fn tree(&self) -> &Tree<R, G> {
+        &self.tree
+    }
+
+    pub fn index(&self) -> &I {
+        &self.index
+    }
+
+    pub fn get_node(&self, id: &<<R as TreeNodeRef>::Inner as TreeNode>::Id) -> Option<&R> {
+        self.index.get(id)
+    }
+
+    /// Get a node, verifying it has not been mutated since `generation` was read from
+    /// [`TreeNode::generation`]. Returns [`Error::StaleNode`] if the generation differs.
+    pub fn get_node_checked(
+        &self,
+        id: &<<R as TreeNodeRef>::Inner as TreeNode>::Id,
+        generation: u64,
+    ) -> Result<&R, Error> {
+        let node = self.index.get(id).ok_or(Error::NodeNotFound)?;
+        let found = node.node().generation();
+        if found != generation {
+            return Err(Error::StaleNode {
+                expected: generation,
+                found,
+            });
+        }
+        Ok(node)
+    }
+
+    pub fn get_node_mut(
+        &mut self,
+        id: &<<R as TreeNodeRef>::Inner as TreeNode>::Id,
+    ) -> Option<&mut R> {
+        self.index.get_mut(id)
+    }
+
+    /// Check if the tree contains a node with the provided ID. Tombstoned nodes
+    /// are not considered part of the tree.
+    pub fn contains(&self, id: NodeRefId<R>) -> bool {
+        self.live_node(id).is_some()
+    }
+
+    /// Get the depth of the node with the provided ID, from its stored position
+    pub fn depth_of(&self, id: NodeRefId<R>) -> Option<usize> {
+        self.live_node(id).map(Self::stored_depth)
+    }
+
+    /// Get the ID of the parent of the node with the provided ID from the parent index, without
+    /// locking any nodes. Returns None for the root, and for nodes which are not in the tree.
+    pub fn parent_id(&self, id: NodeRefId<R>) -> Option<NodeRefId<R>> {
+        self.parents.get(&id).copied()
+    }
+
+    /// Iterate the IDs of the ancestors of the node with the provided ID from the parent index,
+    /// starting with its parent and ending with the root
+    pub fn ancestors_of(&self, id: NodeRefId<R>) -> impl Iterator<Item = NodeRefId<R>> + '_ {
+        std::iter::successors(self.parent_id(id), move |id| self.parent_id(*id))
+    }
+
+    /// Check if the node with ID `a` is a proper ancestor of the node with ID `b`, from the
+    /// parent index
+    pub fn is_ancestor_of(&self, a: NodeRefId<R>, b: NodeRefId<R>) -> bool {
+        self.ancestors_of(b).any(|id| id == a)
+    }
+
+    /// Check if the node with ID `a` is a proper descendant of the node with ID `b`, from the
+    /// parent index
+    pub fn is_descendant_of(&self, a: NodeRefId<R>, b: NodeRefId<R>) -> bool {
+        self.is_ancestor_of(b, a)
+    }
+
+    /// Get an indexed node which has not been tombstoned
+    fn live_node(&self, id: NodeRefId<R>) -> Option<&R> {
+        self.index
+            .get(&id)
+            .filter(|node| !node.node().is_tombstoned())
+    }
+
+    fn stored_depth(node: &R) -> usize {
+        let depth = node.node().get_position().map(|position| position.depth);
+        depth.unwrap_or_else(|| Tree::<R, G>::node_depth(node))
+    }
+
+    /// Remove a node and its subtree from the tree, the index and the leaves,
+    /// returning the removed subtree. See [`Tree::remove_node`].
+    pub fn remove_node(&mut self, node: &R) -> Option<R> {
+        self.sync(|tree| tree.remove_node(node))
+    }
+
+    /// Remove every node whose data fails the predicate, along with its subtree, from the tree,
+    /// the index and the leaves. See [`Tree::retain`].
+    pub fn retain<F>(&mut self, f: F) -> usize
+    where
+        F: FnMut(&<<R as TreeNodeRef>::Inner as TreeNode>::Data) -> bool,
+    {
+        let removed = self.sync(|tree| tree.retain_subtrees(f));
+        removed
+            .into_iter()
+            .map(|subtree| subtree.into_iter().count())
+            .sum()
+    }
+
+    /// Replace the data of the node with the provided ID, updating the subtree hashes of the node
+    /// and its ancestors, and sending a [`TreeEvent::NodeReplaced`] event. Returns the previous data.
+    pub fn set_data(
+        &mut self,
+        id: NodeRefId<R>,
+        data: <<R as TreeNodeRef>::Inner as TreeNode>::Data,
+    ) -> Result<<<R as TreeNodeRef>::Inner as TreeNode>::Data, Error> {
+        let mut node = self.get_node(&id).ok_or(Error::NodeNotFound)?.clone();
+
+        let old = std::mem::replace(&mut *node.node_mut().data_mut(), data);
+        self.sync(|tree| {
+            tree.update_hashes_from(node.clone());
+            tree.send_event(TreeEvent::NodeReplaced {
+                node,
+                previous: old.clone(),
+            });
+        });
+
+        Ok(old)
+    }
+
+    /// Modify the data of the node with the provided ID in place with `f`, then update hashes and
+    /// send a [`TreeEvent::NodeReplaced`] event like [`IndexedTree::set_data`]. Unlike editing
+    /// through [`TreeNodeRef::with_data_mut`], the change is visible to listeners. Returns the
+    /// result of `f`.
+    pub fn update_data<T, F>(&mut self, id: NodeRefId<R>, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut <<R as TreeNodeRef>::Inner as TreeNode>::Data) -> T,
+    {
+        let mut data = self
+            .get_node(&id)
+            .ok_or(Error::NodeNotFound)?
+            .node()
+            .data()
+            .clone();
+        let result = f(&mut data);
+        self.set_data(id, data)?;
+        Ok(result)
+    }
+
+    /// Split the tree at a node, keeping everything outside of the subtree of the node in this
+    /// tree, and returning the subtree as a separate [`IndexedTree`]. See [`Tree::detach`].
+    pub fn split_off(&mut self, node: &R) -> Result<IndexedTree<R, G, I>, Error> {
+        self.detach(node).map(IndexedTree::from_tree)
+    }
+
+    /// Remove a node and its subtree from the tree and the index, returning a copy of it as an
+    /// independent [`Tree`]. See [`Tree::detach`].
+    pub fn detach(&mut self, node: &R) -> Result<Tree<R, G>, Error> {
+        self.sync(|tree| tree.detach(node))
+    }
+
+    /// Collect tombstones removed before the provided removal sequence number, removing
+    /// the collected nodes from the index. Returns the roots of the collected subtrees.
+    pub fn gc(&mut self, before_seq: u64) -> Vec<R> {
+        let collected = self.tree.gc(before_seq);
+
+        for subtree in &collected {
+            for node in subtree.clone() {
+                let id = node.node().id();
+                self.index_remove(&id);
+            }
+        }
+
+        collected
+    }
+
+    pub fn insert_child(
+        &mut self,
+        parent_id: NodeRefId<R>,
+        index: usize,
+        data: <<R as TreeNodeRef>::Inner as TreeNode>::Data,
+    ) -> Option<()> {
+        let mut parent = self.get_node_mut(&parent_id)?.clone();
+
+        let node = self.tree.create_node(data)?;
+
+        self.sync(|tree| {
+            tree.insert_child(&mut parent, index, node.clone())?;
+            tree.update_hashes_from(node);
+            Some(())
+        })
+    }
+
+    /// Create a node from the provided data and insert it as the sibling immediately before
+    /// the node with the provided ID
+    pub fn insert_before(
+        &mut self,
+        node_id: NodeRefId<R>,
+        data: <<R as TreeNodeRef>::Inner as TreeNode>::Data,
+    ) -> Result<(), Error> {
+        let node = self.get_node(&node_id).ok_or(Error::NodeNotFound)?.clone();
+        let new = self.tree.create_node(data).ok_or(Error::NoGenerator)?;
+        self.sync(|tree| tree.insert_before(&node, new))
+    }
+
+    /// Create a node from the provided data and insert it as the sibling immediately after
+    /// the node with the provided ID
+    pub fn insert_after(
+        &mut self,
+        node_id: NodeRefId<R>,
+        data: <<R as TreeNodeRef>::Inner as TreeNode>::Data,
+    ) -> Result<(), Error> {
+        let node = self.get_node(&node_id).ok_or(Error::NodeNotFound)?.clone();
+        let new = self.tree.create_node(data).ok_or(Error::NoGenerator)?;
+        self.sync(|tree| tree.insert_after(&node, new))
+    }
+
+    /// Create a node from the provided data and add it as the last child of the node with the
+    /// provided ID. See [`Tree::append_child`].
+    pub fn append_child(
+        &mut self,
+        parent_id: NodeRefId<R>,
+        data: <<R as TreeNodeRef>::Inner as TreeNode>::Data,
+    ) -> Result<R, Error> {
+        let mut parent = self.node_by_id(parent_id)?;
+        self.sync(|tree| tree.append_child(&mut parent, data))
+    }
+
+    /// Create a node from the provided data and add it as the first child of the node with the
+    /// provided ID. See [`Tree::prepend_child`].
+    pub fn prepend_child(
+        &mut self,
+        parent_id: NodeRefId<R>,
+        data: <<R as TreeNodeRef>::Inner as TreeNode>::Data,
+    ) -> Result<R, Error> {
+        let mut parent = self.node_by_id(parent_id)?;
+        self.sync(|tree| tree.prepend_child(&mut parent, data))
+    }
+
+    /// Insert multiple subtrees as children of the node with the provided ID.
+    /// See [`Tree::insert_children`].
+    pub fn insert_children(
+        &mut self,
+        parent_id: NodeRefId<R>,
+        index: usize,
+        children: Vec<R>,
+    ) -> Result<(), Error> {
+        let mut parent = self.node_by_id(parent_id)?;
+        self.sync(|tree| tree.insert_children(&mut parent, index, children))
+    }
+
+    /// Insert a subtree as a child of the node with the provided ID, assigning new IDs to the
+    /// nodes of the subtree. See [`Tree::insert_subtree`].
+    pub fn insert_subtree(
+        &mut self,
+        parent_id: NodeRefId<R>,
+        index: usize,
+        subtree: R,
+    ) -> Result<(), Error>
+    where
+        R::Data: Clone,
+        <<R as TreeNodeRef>::Inner as TreeNode>::Data: Clone,
+    {
+        let mut parent = self.node_by_id(parent_id)?;
+        Self::check_index(&parent, index, 1)?;
+        self.tree.try_generator()?;
+
+        self.sync(|tree| {
+            tree.insert_subtree(&mut parent, index, subtree.clone());
+            rehash_subtree(subtree, tree.hash_context);
+            tree.update_hashes_from(parent);
+        });
+
+        Ok(())
+    }
+
+    /// Replace the child at `index` of the node with the provided ID with a new subtree,
+    /// assigning new IDs to the nodes of the subtree. See [`Tree::replace_child`].
+    pub fn replace_child(
+        &mut self,
+        parent_id: NodeRefId<R>,
+        index: usize,
+        mut new: R,
+    ) -> Result<(), Error> {
+        let mut parent = self.node_by_id(parent_id)?;
+        Self::check_index(&parent, index, 0)?;
+        self.tree.try_generator()?;
+
+        let depth = Self::stored_depth(&parent) + 1;
+        let replaced = parent.node().children().unwrap()[index].clone();
+
+        // Reattached nodes are removed from the index along with the replaced subtree,
+        // and indexed again at their new position
+        self.unindex(&replaced, depth);
+
+        let retained = self
+            .tree
+            .reattached(std::slice::from_ref(&replaced), std::slice::from_ref(&new));
+        self.tree.assign_ids_retaining(&mut new, &retained)?;
+        self.tree
+            .replace_child_retaining(&mut parent, index, new.clone(), &retained);
+
+        rehash_subtree(new.clone(), self.hash_context);
+        self.tree.update_hashes_from(parent);
+        self.index_inserted(new);
+
+        Ok(())
+    }
+
+    /// Replace the children of the node with the provided ID, assigning new IDs to the nodes of
+    /// each new subtree. Nodes of the replaced subtrees which are attached again keep their IDs
+    /// in [`IdMode::Preserve`]. See [`Tree::set_children`].
+    pub fn set_children(
+        &mut self,
+        parent_id: NodeRefId<R>,
+        mut children: Vec<R>,
+    ) -> Result<(), Error> {
+        if self.tree.id_mode() == IdMode::Regenerate {
+            self.remove_children(parent_id)?;
+            return self.insert_children(parent_id, 0, children);
+        }
+
+        let mut parent = self.node_by_id(parent_id)?;
+        self.tree.try_generator()?;
+
+        let depth = Self::stored_depth(&parent) + 1;
+        let removed = parent
+            .node()
+            .children()
+            .map(|children| children.to_vec())
+            .unwrap_or_default();
+
+        // Reattached nodes are removed from the index along with the replaced subtrees,
+        // and indexed again at their new position
+        for child in &removed {
+            self.unindex(child, depth);
+        }
+
+        let retained = self.tree.reattached(&removed, &children);
+        for child in &mut children {
+            self.tree.assign_ids_retaining(child, &retained)?;
+        }
+
+        self.tree
+            .set_children_retaining(&mut parent, children.clone(), &retained);
+
+        for child in &children {
+            rehash_subtree(child.clone(), self.hash_context);
+        }
+        self.tree.update_hashes_from(parent.clone());
+
+        for child in children {
+            self.index_inserted(child);
+        }
+        self.mark_leaf(parent);
+
+        Ok(())
+    }
+
+    /// Remove the child at `index` of the node with the provided ID, returning the removed
+    /// subtree. See [`Tree::remove_child`].
+    pub fn remove_child(&mut self, parent_id: NodeRefId<R>, index: usize) -> Result<R, Error> {
+        let mut parent = self.node_by_id(parent_id)?;
+        Self::check_index(&parent, index, 0)?;
+
+        self.sync(|tree| {
+            let removed = tree.remove_child(&mut parent, index);
+            tree.update_hashes_from(parent);
+            removed.ok_or(Error::NodeNotFound)
+        })
+    }
+
+    /// Remove all children of the node with the provided ID, returning the removed subtrees.
+    /// See [`Tree::remove_children`].
+    pub fn remove_children(&mut self, parent_id: NodeRefId<R>) -> Result<Vec<R>, Error> {
+        let mut parent = self.node_by_id(parent_id)?;
+
+        let removed = parent
+            .node()
+            .children()
+            .map(|children| children.to_vec())
+            .unwrap_or_default();
+
+        self.sync(|tree| {
+            tree.remove_children(&mut parent);
+            tree.update_hashes_from(parent);
+        });
+
+        Ok(removed)
+    }
+
+    /// Move the node with the provided ID and its subtree to child `index` of the node with ID
+    /// `parent_id`. See [`Tree::move_node`].
+    pub fn move_node(
+        &mut self,
+        node_id: NodeRefId<R>,
+        parent_id: NodeRefId<R>,
+        index: usize,
+        preserve_ids: bool,
+    ) -> Result<(), Error> {
+        let node = self.node_by_id(node_id)?;
+        let mut new_parent = self.node_by_id(parent_id)?;
+
+        // Collect the IDs before the moved nodes are renumbered
+        let ids: HashSet<NodeRefId<R>> = node
+            .clone()
+            .into_iter()
+            .map(|node| node.node().id())
+            .collect();
+        let old_parent = node.node().parent().cloned();
+        let old_parent_id = old_parent.as_ref().map(|parent| parent.node().id());
+        let depth = Self::stored_depth(&node);
+
+        // Leaves are matched before the moved nodes are renumbered
+        let leaves: Vec<R> = self
+            .leaves
+            .iter()
+            .filter(|leaf| !ids.contains(&leaf.node().id()))
+            .cloned()
+            .collect();
+
+        // The depth index is keyed by the IDs before renumbering
+        self.shape.remove(&node, depth);
+        if let Err(e) = self
+            .tree
+            .move_node(&node, &mut new_parent, index, preserve_ids)
+        {
+            self.shape.add(&node, depth);
+            return Err(e);
+        }
+
+        for id in &ids {
+            self.index_remove(id);
+        }
+        self.leaves = leaves;
+
+        if let Some(old_parent) = old_parent {
+            self.mark_leaf(old_parent);
+        }
+        self.children_changed(old_parent_id);
+        self.index_inserted(node);
+
+        Ok(())
+    }
+
+    /// Swap two nodes with the provided IDs. See [`Tree::swap`].
+    pub fn swap(&mut self, a: NodeRefId<R>, b: NodeRefId<R>, mode: SwapMode) -> Result<(), Error> {
+        let (a_id, b_id) = (a, b);
+        let (a, b) = (self.node_by_id(a)?, self.node_by_id(b)?);
+        let (depth_a, depth_b) = (Self::stored_depth(&a), Self::stored_depth(&b));
+
+        self.tree.swap(&a, &b, mode)?;
+
+        // Refresh the parent and data indexes of the swapped nodes
+        self.index_insert(&a);
+        self.index_insert(&b);
+        match mode {
+            SwapMode::Data => {
+                self.refresh_subtree_hashes(Some(a_id));
+                self.refresh_subtree_hashes(Some(b_id));
+            }
+            SwapMode::Subtree => {
+                self.children_changed(self.parent_id(a_id));
+                self.children_changed(self.parent_id(b_id));
+            }
+        }
+
+        // Swapped subtrees may change depth
+        if mode == SwapMode::Subtree {
+            self.shape.remove(&a, depth_a);
+            self.shape.remove(&b, depth_b);
+            self.shape.add(&a, depth_b);
+            self.shape.add(&b, depth_a);
+        }
+
+        Ok(())
+    }
+
+    /// Sort the children of the node with the provided ID with a comparator on their data.
+    /// See [`Tree::sort_children_by`].
+    pub fn sort_children_by<F>(&mut self, parent_id: NodeRefId<R>, cmp: F) -> Result<(), Error>
+    where
+        F: FnMut(
+            &<<R as TreeNodeRef>::Inner as TreeNode>::Data,
+            &<<R as TreeNodeRef>::Inner as TreeNode>::Data,
+        ) -> std::cmp::Ordering,
+    {
+        let mut parent = self.node_by_id(parent_id)?;
+        self.sync(|tree| tree.sort_children_by(&mut parent, cmp));
+        Ok(())
+    }
+
+    /// Sort the children of every node in the subtree of the node with the provided ID with a
+    /// comparator on their data. See [`Tree::sort_all_by`].
+    pub fn sort_all_by<F>(&mut self, node_id: NodeRefId<R>, cmp: F) -> Result<(), Error>
+    where
+        F: FnMut(
+            &<<R as TreeNodeRef>::Inner as TreeNode>::Data,
+            &<<R as TreeNodeRef>::Inner as TreeNode>::Data,
+        ) -> std::cmp::Ordering,
+    {
+        let node = self.node_by_id(node_id)?;
+        self.sync(|tree| tree.sort_all_by(&node, cmp));
+        Ok(())
+    }
+
+    /// Get an indexed node which has not been tombstoned, or [`Error::NodeNotFound`]
+    fn node_by_id(&self, id: NodeRefId<R>) -> Result<R, Error> {
+        self.live_node(id).cloned().ok_or(Error::NodeNotFound)
+    }
+
+    /// Check that `index` is within the children of `parent`, allowing `extra` positions past
+    /// the last child for insertion
+    fn check_index(parent: &R, index: usize, extra: usize) -> Result<(), Error> {
+        let len = parent.node().num_children();
+        if index >= len + extra {
+            return Err(Error::IndexOutOfBounds { index, len });
+        }
+        Ok(())
+    }
+
+    /// Run a mutation of the inner tree, then update the index, leaves and shape from the events
+    /// it sent. Mutations which renumber or reattach nodes maintain the indexes themselves, as
+    /// their events don't describe the nodes as they were indexed.
+    fn sync<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut Tree<R, G>) -> T,
+    {
+        let (result, events) = self.tree.record(f);
+        for event in events {
+            self.apply_event(event);
+        }
+        result
+    }
+
+    /// Update the indexes from an event sent by a mutation. Subtree hashes must be up to date,
+    /// and the parent index must not yet reflect removals, as it locates removed nodes.
+    fn apply_event(&mut self, event: TreeEvent<R>) {
+        match event {
+            TreeEvent::NodeReplaced { node, .. } => {
+                self.index_insert(&node);
+                self.refresh_subtree_hashes(Some(node.node().id()));
+            }
+            TreeEvent::SubtreeInserted { node } => self.index_inserted(node),
+            TreeEvent::ChildInserted { parent, index } => {
+                let child = parent
+                    .node()
+                    .children()
+                    .and_then(|children| children.get(index).cloned());
+                if let Some(child) = child {
+                    self.index_inserted(child);
+                }
+            }
+            TreeEvent::ChildrenAdded { children, .. } => {
+                for child in children {
+                    self.index_inserted(child);
+                }
+            }
+            TreeEvent::NodeRemoved { node } => {
+                let parent = self
+                    .parent_id(node.node().id())
+                    .and_then(|id| self.index.get(&id))
+                    .cloned();
+                self.unindex_removed(&node, parent);
+            }
+            TreeEvent::ChildRemoved { parent, node, .. } => {
+                self.unindex_removed(&node, Some(parent));
+            }
+            TreeEvent::ChildrenRemoved { parent, children } => {
+                for child in &children {
+                    self.unindex_removed(child, Some(parent.clone()));
+                }
+            }
+            TreeEvent::ChildrenReordered { parent } => {
+                self.children_changed(Some(parent.node().id()));
+            }
+            TreeEvent::Batch(events) => {
+                for event in events {
+                    self.apply_event(event);
+                }
+            }
+            // Only sent by IndexedTree::replace_child, move_node and swap, which renumber or
+            // reattach nodes and maintain the indexes themselves. The inner tree is not exposed
+            // mutably, so these events can't come from unindexed mutations.
+            TreeEvent::ChildReplaced { .. }
+            | TreeEvent::NodeMoved { .. }
+            | TreeEvent::Suppressed { .. } => {}
+        }
+    }
+
+    /// Remove a subtree detached from `parent` from the indexes, locating it with the parent
+    /// index
+    fn unindex_removed(&mut self, subtree: &R, parent: Option<R>) {
+        let depth = self.ancestors_of(subtree.node().id()).count();
+        self.unindex(subtree, depth);
+        if let Some(parent) = parent {
+            self.mark_leaf(parent);
+        }
+    }
+
+    /// Remove the nodes of a removed subtree from the index and the leaves. Tombstoned nodes
+    /// remain in the index until they are collected.
+    fn unindex(&mut self, subtree: &R, depth: usize) {
+        self.shape.remove(subtree, depth);
+
+        let id = subtree.node().id();
+        let parent_id = self.parent_id(id);
+
+        let ids: HashSet<NodeRefId<R>> = subtree
+            .clone()
+            .into_iter()
+            .map(|node| node.node().id())
+            .collect();
+
+        let tombstoned = self.tree.removal_mode() == RemovalMode::Tombstone;
+        for id in &ids {
+            self.unlink(id);
+            if !tombstoned {
+                self.index_remove(id);
+            }
+        }
+
+        self.leaves.retain(|leaf| !ids.contains(&leaf.node().id()));
+        self.children_changed(parent_id);
+    }
+
+    /// Add a node to the leaves if it was left without children
+    fn mark_leaf(&mut self, node: R) {
+        let id = node.node().id();
+        if node.node().num_children() == 0 && !self.leaves.iter().any(|l| l.node().id() == id) {
+            self.leaves.push(node);
+        }
+    }
+
+    /// Add the nodes of an inserted subtree to the index and leaves
+    fn index_inserted(&mut self, subtree: R) {
+        self.shape.add(&subtree, Tree::<R, G>::node_depth(&subtree));
+        let id = subtree.node().id();
+
+        // The parent of the subtree is no longer a leaf
+        let parent_id = subtree.node().parent().map(|parent| parent.node().id());
+        if let Some(parent_id) = parent_id {
+            self.leaves.retain(|leaf| leaf.node().id() != parent_id);
+        }
+
+        for node in subtree.into_iter() {
+            self.index_insert(&node);
+            if node.node().num_children() == 0 {
+                self.leaves.push(node.clone());
+            }
+        }
+
+        match parent_id {
+            Some(parent_id) => self.children_changed(Some(parent_id)),
+            // A subtree inserted as the root has no parent
+            None => self.refresh_paths(Some(id)),
+        }
+    }
+
+    /// Add a node to the index, the parent index and the data indexes
+    fn index_insert(&mut self, node: &R) {
+        let id = node.node().id();
+        let parent_id = node.node().parent().map(|parent| parent.node().id());
+        match parent_id {
+            Some(parent_id) => self.parents.insert(id, parent_id),
+            None => self.parents.remove(&id),
+        };
+
+        if let Some(hashes) = &mut self.subtree_hashes {
+            let hash = node.node().get_subtree_hash();
+            hashes.insert(id, hash);
+        }
+
+        self.index.insert(id, node.clone());
+        for index in self.secondary_indexes.iter_mut() {
+            index.insert(node);
+        }
+    }
+
+    /// Remove a node from the index, the parent index and the data indexes
+    fn index_remove(&mut self, id: &NodeRefId<R>) -> Option<R> {
+        self.unlink(id);
+        for index in self.secondary_indexes.iter_mut() {
+            index.remove(id);
+        }
+        self.index.remove(id)
+    }
+
+    /// Remove a node from the indexes of live nodes. Tombstoned nodes are unlinked, but remain
+    /// in the index until they are collected.
+    fn unlink(&mut self, id: &NodeRefId<R>) {
+        self.parents.remove(id);
+        if let Some(hashes) = &mut self.subtree_hashes {
+            hashes.remove(id);
+        }
+    }
+
+    /// Update the indexes after the children of a node were added, removed or replaced
+    fn children_changed(&mut self, id: Option<NodeRefId<R>>) {
+        let Some(node) = id.and_then(|id| self.index.get(&id)) else {
+            return;
+        };
+
+        for index in self.secondary_indexes.iter_mut() {
+            index.insert(node);
+        }
+        self.refresh_subtree_hashes(id);
+        self.refresh_paths(id);
+    }
+
+    /// Update the indexed subtree hashes of a node and its ancestors after a mutation
+    fn refresh_subtree_hashes(&mut self, id: Option<NodeRefId<R>>) {
+        let (Some(id), Some(_)) = (id, &self.subtree_hashes) else {
+            return;
+        };
+
+        let ids: Vec<NodeRefId<R>> = std::iter::once(id).chain(self.ancestors_of(id)).collect();
+        for id in ids {
+            let Some(hash) = self
+                .index
+                .get(&id)
+                .map(|node| node.node().get_subtree_hash())
+            else {
+                continue;
+            };
+            if let Some(hashes) = &mut self.subtree_hashes {
+                hashes.insert(id, hash);
+            }
+        }
+    }
+
+    /// Rebuild the path index entries within the subtree of a node after its children changed
+    fn refresh_paths(&mut self, id: Option<NodeRefId<R>>) {
+        let (Some(id), Some(paths)) = (id, &mut self.paths) else {
+            return;
+        };
+
+        let root_id = self.tree.root.as_ref().map(|root| root.node().id());
+        let prefix = match paths.path_of(&id) {
+            Some(path) => path.to_vec(),
+            None if root_id == Some(id) => Vec::new(),
+            None => return,
+        };
+        let Some(node) = self.index.get(&id) else {
+            return;
+        };
+
+        paths.remove_prefix(&prefix);
+
+        let mut stack = Vec::from([(node.clone(), prefix)]);
+        while let Some((node, path)) = stack.pop() {
+            let (id, children) = {
+                let inner = node.node();
+                let children = inner.children().map(|c| c.to_vec()).unwrap_or_default();
+                (inner.id(), children)
+            };
+
+            for (index, child) in children.into_iter().enumerate() {
+                let mut child_path = path.clone();
+                child_path.push(index);
+                stack.push((child, child_path));
+            }
+
+            paths.insert(path, id);
+        }
+    }
+
+    /// Rebuild the path index from the root, if enabled
+    fn index_paths(&mut self) {
+        let Some(paths) = &mut self.paths else {
+            return;
+        };
+
+        paths.clear();
+        let root_id = self.tree.root.as_ref().map(|root| root.node().id());
+        self.refresh_paths(root_id);
+    }
+
+    /// Rebuild the subtree hash index from the nodes reachable from the root, if enabled
+    fn index_subtree_hashes(&mut self) {
+        let Some(hashes) = &mut self.subtree_hashes else {
+            return;
+        };
+
+        hashes.clear();
+        let Some(root) = &self.tree.root else {
+            return;
+        };
+
+        for node in root.clone() {
+            let (id, hash) = {
+                let inner = node.node();
+                (inner.id(), inner.get_subtree_hash())
+            };
+            hashes.insert(id, hash);
+        }
+    }
+
+    /// Rebuild the parent index from the parent links of the nodes reachable from the root
+    fn link_parents(&mut self) {
+        self.parents.clear();
+
+        let Some(root) = &self.tree.root else {
+            return;
+        };
+
+        for node in root.clone() {
+            let id = node.node().id();
+            let parent_id = node.node().parent().map(|parent| parent.node().id());
+            if let Some(parent_id) = parent_id {
+                self.parents.insert(id, parent_id);
+            }
+        }
+    }
+
+    pub fn leaves(&self) -> &Vec<R> {
+        &self.leaves
+    }
+
+    /// Get the [`Tree::xxhash_positional`] hash of the tree without walking it on every call.
+    ///
+    /// The positional hash is determined by the shape and data of the tree, which the root
+    /// subtree hash already tracks through every mutation. The positional hash is cached
+    /// alongside the root subtree hash it was computed at, and only recomputed once the root
+    /// subtree hash changes, so polling an unchanged tree is cheap. Hashes deferred in
+    /// [`HashMode::Deferred`] must be flushed first.
+    pub fn positional_fingerprint(&self) -> u64 {
+        if self.is_empty() {
+            return 0;
+        }
+
+        let root_hash = self.tree.root_ref().node().get_subtree_hash();
+        let mut cached = self.positional.lock();
+        match *cached {
+            Some((hash, positional)) if hash == root_hash => positional,
+            _ => {
+                let positional = self.tree.xxhash_positional();
+                *cached = Some((root_hash, positional));
+                positional
+            }
+        }
+    }
+
+    /// Register a callback called with every [`TreeEvent`] sent by this tree, including events
+    /// from patches applied with [`TreePatch::patch_tree`](crate::TreePatch::patch_tree).
+    /// See [`Tree::on_event`].
+    pub fn on_event<F>(&mut self, f: F) -> Result<TreeEventListener<R>, Error>
+    where
+        F: for<'c> FnMut(&'c TreeEvent<R>) + Send + 'static,
+    {
+        self.tree.on_event(f)
+    }
+
+    /// Register a callback called with the [`TreeEvent`]s sent by this tree which pass the
+    /// filter. See [`Tree::on_event_filtered`].
+    pub fn on_event_filtered<F>(
+        &mut self,
+        filter: EventFilter<NodeRefId<R>>,
+        f: F,
+    ) -> Result<TreeEventListener<R>, Error>
+    where
+        F: for<'c> FnMut(&'c TreeEvent<R>) + Send + 'static,
+        NodeRefId<R>: Send,
+    {
+        self.tree.on_event_filtered(filter, f)
+    }
+
+    /// Replay the retained events after `seq` to a callback, then register it. See
+    /// [`Tree::on_sequenced_event_since`].
+    pub fn on_sequenced_event_since<F>(
+        &mut self,
+        seq: u64,
+        f: F,
+    ) -> Result<TreeEventListener<R>, Error>
+    where
+        F: for<'c> FnMut(u64, &'c TreeEvent<R>) + Send + 'static,
+    {
+        self.tree.on_sequenced_event_since(seq, f)
+    }
+
+    /// Register a callback called with `owner` and every [`TreeEvent`] sent by this tree, for as
+    /// long as `owner` is alive. See [`Tree::on_event_weak`].
+    pub fn on_event_weak<O, F>(&mut self, owner: &Arc<O>, f: F) -> Result<(), Error>
+    where
+        O: Send + Sync + 'static,
+        F: for<'c> FnMut(&O, &'c TreeEvent<R>) + Send + 'static,
+    {
+        self.tree.on_event_weak(owner, f)
+    }
+
+    /// Register a callback called with every [`TreeEvent`] sent by this tree, along with its
+    /// sequence number. See [`Tree::on_sequenced_event`].
+    pub fn on_sequenced_event<F>(&mut self, f: F) -> Result<TreeEventListener<R>, Error>
+    where
+        F: for<'c> FnMut(u64, &'c TreeEvent<R>) + Send + 'static,
+    {
+        self.tree.on_sequenced_event(f)
+    }
+
+    /// Run `f` with event delivery batched. See [`Tree::batch`].
+    pub fn batch<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let started = self.tree.begin_batch();
+        let result = f(self);
+        if started {
+            self.tree.end_batch(false);
+        }
+        result
+    }
+
+    /// Run `f` without sending events. See [`Tree::silent`].
+    pub fn silent<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let started = self.tree.begin_silent();
+        let result = f(self);
+        if started {
+            self.tree.end_silent(false);
+        }
+        result
+    }
+
+    /// Run `f` without sending events, then send a summary of the dropped events. See
+    /// [`Tree::silent_summarized`].
+    pub fn silent_summarized<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let started = self.tree.begin_silent();
+        let result = f(self);
+        if started {
+            self.tree.end_silent(true);
+        }
+        result
+    }
+
+    /// Run `f` with event delivery batched and coalesced. See [`Tree::batch_coalesced`].
+    pub fn batch_coalesced<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let started = self.tree.begin_batch();
+        let result = f(self);
+        if started {
+            self.tree.end_batch(true);
+        }
+        result
+    }
+
+    /// Register a callback called with each [`TreeEvent`] concerning the node with the provided
+    /// ID or any of its descendants, such as to invalidate a view of the subtree. Membership is
+    /// checked by walking up the ancestors of the changed node during dispatch. Returns
+    /// [`Error::NodeNotFound`] if the node is not in the tree.
+    pub fn watch_subtree<F>(
+        &mut self,
+        id: NodeRefId<R>,
+        f: F,
+    ) -> Result<TreeEventListener<R>, Error>
+    where
+        F: for<'c> FnMut(&'c TreeEvent<R>) + Send + 'static,
+        NodeRefId<R>: Send,
+    {
+        if self.live_node(id).is_none() {
+            return Err(Error::NodeNotFound);
+        }
+        self.on_event_filtered(EventFilter::new().subtree(id), f)
+    }
+
+    /// Set the external hash context of the tree, and update the subtree hash index.
+    /// See [`Tree::set_hash_context`].
+    pub fn set_hash_context<C>(&mut self, context: &C)
+    where
+        C: std::hash::Hash + ?Sized,
+    {
+        self.tree.set_hash_context(context);
+        self.index_subtree_hashes();
+    }
+
+    /// Remove the external hash context of the tree, and update the subtree hash index.
+    /// See [`Tree::clear_hash_context`].
+    pub fn clear_hash_context(&mut self) {
+        self.tree.clear_hash_context();
+        self.index_subtree_hashes();
+    }
+
+    /// Set the [`HashMode`] used by subsequent mutations. See [`Tree::set_hash_mode`].
+    pub fn set_hash_mode(&mut self, mode: HashMode) {
+        if mode == HashMode::Eager {
+            self.flush_hashes();
+        }
+        self.tree.set_hash_mode(mode);
+    }
+
+    /// Set the [`RemovalMode`] used by subsequent removals. See [`Tree::set_removal_mode`].
+    pub fn set_removal_mode(&mut self, mode: RemovalMode) {
+        self.tree.set_removal_mode(mode);
+    }
+
+    /// Set the [`IdMode`] used by subsequent calls to [`IndexedTree::set_children`] and
+    /// [`IndexedTree::replace_child`]. See [`Tree::set_id_mode`].
+    pub fn set_id_mode(&mut self, mode: IdMode) {
+        self.tree.set_id_mode(mode);
+    }
+
+    /// Retain up to `len` of the most recently delivered events. See [`Tree::set_event_history`].
+    pub fn set_event_history(&mut self, len: usize) {
+        self.tree.set_event_history(len);
+    }
+
+    /// Mark every node as clean. See [`Tree::clear_dirty`].
+    pub fn clear_dirty(&mut self) {
+        self.tree.clear_dirty();
+    }
+
+    /// Recompute the stored [`NodePosition`] of every node. See [`Tree::update_positions`].
+    pub fn update_positions(&mut self) {
+        self.tree.update_positions();
+    }
+
+    /// Recompute the stored [`NodePosition`] of `node` and its descendants.
+    /// See [`Tree::reposition_subtree`].
+    pub fn reposition_subtree(&mut self, node: &R) {
+        self.tree.reposition_subtree(node);
+    }
+
+    /// Recompute pending subtree hashes, and update the subtree hash index.
+    /// See [`Tree::flush_hashes`].
+    pub fn flush_hashes(&mut self) -> bool {
+        let ids: Vec<NodeRefId<R>> = self
+            .tree
+            .stale_hashes
+            .iter()
+            .map(|node| node.node().id())
+            .collect();
+
+        if !self.tree.flush_hashes() {
+            return false;
+        }
+
+        for id in ids {
+            self.refresh_subtree_hashes(Some(id));
+        }
+        true
+    }
+
+    /// Replace the ID of every node with the result of `map`, and rebuild the indexes.
+    /// See [`Tree::remap_ids`].
+    pub fn remap_ids<F>(&mut self, map: F)
+    where
+        F: FnMut(NodeRefId<R>) -> NodeRefId<R>,
+    {
+        self.tree.remap_ids(map);
+        self.reindex();
+    }
+
+    pub fn reindex(&mut self) {
+        self.shape = Shape::default();
+        self.index = I::new();
+
+        if let Some(root) = &self.tree.root {
+            self.index = I::from_node(root);
+            self.shape.add(root, 0);
+        }
+
+        // Retain tombstoned nodes in the index until they are collected
+        for subtree in self.tree.tombstones() {
+            for node in subtree.clone() {
+                self.index.insert(node.node().id(), node.clone());
+            }
+        }
+
+        self.link_parents();
+        self.index_subtree_hashes();
+        self.index_paths();
+
+        for index in self.secondary_indexes.iter_mut() {
+            index.clear();
+        }
+        for id in self.index.get_ids() {
+            if let Some(node) = self.index.get(&id) {
+                for index in self.secondary_indexes.iter_mut() {
+                    index.insert(node);
+                }
+            }
+        }
+
+        let mut leaves = Vec::new();
+        // Find all leaves
+        if let Some(root) = &self.tree.root {
+            for node in root.clone() {
+                if node.node().num_children() == 0 {
+                    leaves.push(node.clone())
+                }
+            }
+        }
+        self.leaves = leaves;
+    }
+
+    /// Rebuild the indexes and leaves for the nodes of a subtree, after the subtree has been
+    /// mutated outside of this [`IndexedTree`]. Unlike [`IndexedTree::reindex`], only the subtree
+    /// is walked. The nodes previously in the subtree are found from the parent index without
+    /// locking any nodes.
+    pub fn reindex_subtree(&mut self, node: &R) -> Result<(), Error> {
+        let id = node.node().id();
+        if !self.contains(id) {
+            return Err(Error::NodeNotFound);
+        }
+        let depth = Tree::<R, G>::node_depth(node);
+
+        let mut children: HashMap<NodeRefId<R>, Vec<NodeRefId<R>>> = HashMap::new();
+        for (&child, &parent) in &self.parents {
+            children.entry(parent).or_default().push(child);
+        }
+
+        // Walk the subtree as it was last indexed
+        let mut stale = Vec::from([(id, depth)]);
+        let mut next = 0;
+        while let Some(&(id, depth)) = stale.get(next) {
+            for &child in children.get(&id).into_iter().flatten() {
+                stale.push((child, depth + 1));
+            }
+            next += 1;
+        }
+
+        let mut ids = HashSet::new();
+        for (id, depth) in stale {
+            self.shape.remove_id(&id, depth);
+            self.index_remove(&id);
+            ids.insert(id);
+        }
+        self.leaves.retain(|leaf| !ids.contains(&leaf.node().id()));
+
+        self.index_inserted(node.clone());
+
+        Ok(())
+    }
+
+    /// Get a [`LeafIter`] instance for this tree, providing an iterator which
+    /// traverses backwards through the tree starting from the leaves
+    pub fn leaf_iter(&self) -> LeafIter<R>
+    where
+        R: std::fmt::Debug,
+    {
+        LeafIter::new(self.leaves())
+    }
+}
+
+impl<R, G, I> Default for IndexedTree<R, G, I>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Deref IndexedTree into Tree. The tree is only exposed immutably, as mutations must go
+/// through the [`IndexedTree`] methods to keep the indexes in sync.
+impl<R, G, I> Deref for IndexedTree<R, G, I>
+where
+    R: TreeNodeRef + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+{
+    type Target = Tree<R, G>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tracing_test::traced_test;
+
+    use crate::{
+        index::TreeIndex as _,
+        noderef::rc::NodeRef,
+        test::{test_tree, test_tree_node, Colliding, TestNode},
+        Error, EventFilter, HashMode, IdMode, NodePosition, RemovalMode, Tree, TreeDiff, TreeEvent,
+        TreeEventKind, TreeNode as _, TreeNodeRef as _, TreeWeakNodeRef as _,
+    };
+
+    use super::SwapMode;
+
+    #[traced_test]
+    #[test]
+    fn shape() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![TestNode("3", vec![])]),
+        ]);
+
+        fn assert_shape<R>(tree: &crate::IndexedTree<R>)
+        where
+            R: crate::TreeNodeRef<Inner: crate::TreeNode<Id = crate::NodeId>> + std::fmt::Debug,
+        {
+            assert_eq!(tree.node_count(), tree.root().into_iter().count());
+            assert_eq!(tree.depth(), tree.tree().depth());
+            assert_eq!(tree.width(), tree.tree().width());
+
+            for depth in 0..=tree.depth() {
+                let mut ids: Vec<_> = tree
+                    .root()
+                    .into_iter()
+                    .filter(|node| node.position().depth == depth)
+                    .map(|node| node.node().id())
+                    .collect();
+                ids.sort();
+                assert_eq!(tree.ids_at_depth(depth).collect::<Vec<_>>(), ids);
+                assert_eq!(tree.nodes_at_depth(depth).count(), ids.len());
+            }
+        }
+
+        assert_shape(&tree);
+        assert_eq!(tree.node_count(), 6);
+        assert_eq!(tree.width_at(2), 3);
+
+        let children = tree.root().node().children().unwrap().clone();
+        let one = children[0].node().children().unwrap()[0].clone();
+        tree.remove_node(&one);
+        assert_shape(&tree);
+        assert_eq!(tree.width_at(2), 2);
+
+        let b = children[1].node().id();
+        tree.insert_child(b, 0, "4").unwrap();
+        tree.insert_after(b, "c").unwrap();
+        assert_shape(&tree);
+        assert_eq!(tree.width_at(1), 3);
+
+        let four = tree.find(|data| *data == "4").unwrap().node().id();
+        let a = children[0].node().id();
+        tree.move_node(four, a, 0, false).unwrap();
+        assert_shape(&tree);
+
+        tree.retain(|data| *data != "b");
+        assert_shape(&tree);
+        assert_eq!(tree.node_count(), 5);
+
+        tree.detach(&children[0]).unwrap();
+        assert_shape(&tree);
+        assert_eq!(tree.depth(), 1);
+    }
+
+    #[traced_test]
+    #[test]
+    fn insert_children() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![]), TestNode("d", vec![])]);
+        let other = test_tree_node(vec![
+            TestNode("b", vec![TestNode("1", vec![])]),
+            TestNode("c", vec![]),
+        ]);
+        let expected = test_tree_node(vec![
+            TestNode("a", vec![]),
+            TestNode("b", vec![TestNode("1", vec![])]),
+            TestNode("c", vec![]),
+            TestNode("d", vec![]),
+        ]);
+
+        let mut root = tree.root();
+        let children = other.root().node().children().unwrap().to_vec();
+        tree.tree.insert_children(&mut root, 1, children).unwrap();
+
+        assert_eq!(
+            root.node().get_subtree_hash(),
+            expected.root().node().get_subtree_hash()
+        );
+        assert_eq!(root.node().subtree_size(), 6);
+
+        // Inserted nodes are assigned IDs from the tree
+        let mut ids: Vec<_> = root.clone().into_iter().map(|n| n.node().id()).collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 6);
+
+        let c = root.node().children().unwrap()[2].clone();
+        assert_eq!(c.node().get_position().unwrap().child_index, 2);
+
+        assert_eq!(
+            tree.tree.insert_children(&mut root, 5, Vec::new()),
+            Err(Error::IndexOutOfBounds { index: 5, len: 4 })
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn indexed_mutations() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![]),
+        ])
+        .with_subtree_hash_index()
+        .with_path_index();
+
+        // Subtrees taken from separate trees
+        let subtree = |node: TestNode| {
+            let other = test_tree_node(vec![node]);
+            let child = other.root().node().children().unwrap()[0].clone();
+            child
+        };
+        let x = || {
+            let z = TestNode("z", vec![]);
+            subtree(TestNode("x", vec![TestNode("y", vec![z])]))
+        };
+        let w = || subtree(TestNode("w", vec![]));
+
+        type TestTree = crate::IndexedTree<
+            crate::noderef::arc::NodeRef<crate::node::arc::Node<&'static str, crate::NodeId>>,
+        >;
+        let id = |tree: &TestTree, data: &str| tree.find(|d| *d == data).unwrap().node().id();
+        let (root, a, b) = (id(&tree, "root"), id(&tree, "a"), id(&tree, "b"));
+
+        tree.append_child(b, "c").unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.prepend_child(b, "p").unwrap();
+        tree.insert_subtree(b, 1, x()).unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.depth(), tree.tree().depth());
+
+        tree.replace_child(b, 1, w()).unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.depth(), tree.tree().depth());
+
+        tree.insert_children(a, 0, vec![x(), w()]).unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+
+        let removed = tree.remove_child(a, 0).unwrap();
+        assert!(removed.node().parent().is_none());
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.move_node(a, b, 0, false).unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.depth_of(id(&tree, "1")), Some(3));
+
+        let c = id(&tree, "c");
+        tree.swap(c, id(&tree, "a"), SwapMode::Subtree).unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.set_children(b, vec![x()]).unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+
+        assert_eq!(tree.remove_children(root).unwrap().len(), 1);
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.node_count(), 1);
+
+        assert_eq!(
+            tree.remove_child(root, 0).err(),
+            Some(Error::IndexOutOfBounds { index: 0, len: 0 })
+        );
+        assert_eq!(tree.append_child(b, "x").err(), Some(Error::NodeNotFound));
+    }
+
+    #[traced_test]
+    #[test]
+    fn hash_context() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![TestNode("1", vec![])])]);
+        let mut fork = test_tree_node(vec![TestNode("a", vec![TestNode("1", vec![])])]);
+        let plain = tree.root().node().get_subtree_hash();
+
+        tree.set_hash_context(&("dark", "en-US"));
+        assert_ne!(tree.hash_context(), 0);
+        assert_ne!(tree.root().node().get_subtree_hash(), plain);
+        // Compare root hashes directly, as strict equality compares nodes and ignores the context
+        assert_ne!(
+            tree.root().node().get_subtree_hash(),
+            fork.root().node().get_subtree_hash()
+        );
+        assert_eq!(tree.validate(), Ok(()));
+
+        // Mutations and proofs hash with the context
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        tree.append_child(a, "2").unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+        let proof = tree.merkle_proof(a).unwrap();
+        let a_hash = tree.get_node(&a).unwrap().node().get_subtree_hash();
+        let root_hash = tree.root().node().get_subtree_hash();
+        assert!(crate::verify_proof(&proof, a_hash, root_hash));
+
+        fork.set_hash_context(&("dark", "en-US"));
+        let a = fork.find(|data| *data == "a").unwrap().node().id();
+        fork.append_child(a, "2").unwrap();
+        assert_eq!(
+            tree.root().node().get_subtree_hash(),
+            fork.root().node().get_subtree_hash()
+        );
+
+        fork.set_hash_context(&("light", "en-US"));
+        assert_ne!(
+            tree.root().node().get_subtree_hash(),
+            fork.root().node().get_subtree_hash()
+        );
+
+        tree.clear_hash_context();
+        fork.clear_hash_context();
+        assert_eq!(tree.hash_context(), 0);
+        assert_eq!(
+            tree.root().node().get_subtree_hash(),
+            fork.root().node().get_subtree_hash()
+        );
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn structural_eq() {
+        let a = test_tree_node(vec![TestNode("a", vec![TestNode("1", vec![])])]);
+        let b = test_tree_node(vec![TestNode("a", vec![TestNode("1", vec![])])]);
+        let c = test_tree_node(vec![TestNode("a", vec![TestNode("2", vec![])])]);
+        assert!(a.structural_eq(&b));
+        assert!(!a.structural_eq(&c));
+
+        // A stale root hash fools the hash comparison, but not the structural one
+        let hash = a.root().node().get_subtree_hash();
+        c.root().node_mut().set_subtree_hash(hash);
+        assert!(!a.structural_eq(&c));
+        assert_eq!(a == c, cfg!(not(feature = "strict-eq")));
+    }
+
+    #[traced_test]
+    #[test]
+    fn positional_fingerprint() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![]), TestNode("b", vec![])]);
+        let before = tree.positional_fingerprint();
+        assert_eq!(before, tree.xxhash_positional());
+        assert_eq!(tree.positional_fingerprint(), before);
+
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        let b = tree.find(|data| *data == "b").unwrap().node().id();
+        tree.append_child(a, "1").unwrap();
+        let after = tree.positional_fingerprint();
+        assert_ne!(after, before);
+        assert_eq!(after, tree.xxhash_positional());
+
+        // Swapping siblings keeps the content but changes positions
+        tree.swap(a, b, SwapMode::Subtree).unwrap();
+        assert_ne!(tree.positional_fingerprint(), after);
+        assert_eq!(tree.positional_fingerprint(), tree.xxhash_positional());
+    }
+
+    #[traced_test]
+    #[test]
+    fn deferred_hashes() {
+        let nodes = || {
+            vec![
+                TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+                TestNode("b", vec![TestNode("3", vec![])]),
+            ]
+        };
+        let mut tree = test_tree_node(vec![TestNode("a", vec![]), TestNode("b", vec![])])
+            .with_subtree_hash_index();
+        tree.set_hash_mode(HashMode::Deferred);
+
+        type TestTree = crate::IndexedTree<
+            crate::noderef::arc::NodeRef<crate::node::arc::Node<&'static str, crate::NodeId>>,
+        >;
+        let hash = tree.root().node().get_subtree_hash();
+        let id = |tree: &TestTree, data: &str| tree.find(|d| *d == data).unwrap().node().id();
+        let (a, b) = (id(&tree, "a"), id(&tree, "b"));
+        tree.append_child(a, "1").unwrap();
+        tree.append_child(a, "2").unwrap();
+        tree.append_child(b, "3").unwrap();
+
+        // Ancestor hashes are only updated by the flush
+        assert_eq!(tree.root().node().get_subtree_hash(), hash);
+        assert!(tree.flush_hashes());
+        assert!(!tree.flush_hashes());
+        assert_eq!(tree, test_tree_node(nodes()));
+        assert_eq!(tree.validate(), Ok(()));
+
+        // Patches are applied with a single flush
+        let source = test_tree_node(vec![TestNode("c", vec![TestNode("4", vec![])])]);
+        let mut diff = TreeDiff::new(tree.root(), source.root());
+        diff.diff().patch_tree(&mut tree);
+        tree.set_hash_mode(HashMode::Eager);
+        assert_eq!(tree, source);
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn remap_ids() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ])
+        .with_subtree_hash_index()
+        .with_path_index();
+        tree.set_removal_mode(RemovalMode::Tombstone);
+
+        let b = tree.find(|data| *data == "b").unwrap();
+        let (root, b_id) = (tree.root().node().id(), b.node().id());
+        tree.remove_child(root, 1).unwrap();
+        tree.clear_dirty();
+
+        let ids: Vec<_> = tree.root().into_iter().map(|n| n.node().id()).collect();
+        tree.remap_ids(|id| id + 1000);
+
+        for id in ids {
+            assert!(tree.get_node(&id).is_none());
+            assert!(tree.get_node(&(id + 1000)).is_some());
+        }
+        assert_eq!(b.node().id(), b_id + 1000);
+        assert_eq!(tree.iter_dirty().count(), 0);
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn preserve_ids() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+        tree.set_id_mode(IdMode::Preserve);
+
+        type TestTree = crate::IndexedTree<
+            crate::noderef::arc::NodeRef<crate::node::arc::Node<&'static str, crate::NodeId>>,
+        >;
+        let node = |tree: &TestTree, data: &str| tree.find(|d| *d == data).unwrap();
+        let (a, one, two) = (node(&tree, "a"), node(&tree, "1"), node(&tree, "2"));
+        let (a_id, one_id, two_id) = (a.node().id(), one.node().id(), two.node().id());
+
+        // Reordered children keep their IDs, while a new child is assigned a fresh ID
+        let fresh = test_tree_node(vec![TestNode("w", vec![])]);
+        let w = fresh.root().node().children().unwrap()[0].clone();
+        tree.set_children(a_id, vec![two.clone(), one.clone(), w.clone()])
+            .unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+
+        assert_eq!(node(&tree, "1").node().id(), one_id);
+        assert_eq!(node(&tree, "2").node().id(), two_id);
+        let w_id = node(&tree, "w").node().id();
+        assert!(![a_id, one_id, two_id].contains(&w_id));
+        assert_eq!(
+            tree.get_node(&two_id)
+                .unwrap()
+                .node()
+                .get_position()
+                .unwrap()
+                .child_index,
+            0
+        );
+
+        // Replacing a node with one of its descendants keeps the ID of the descendant
+        let root_id = tree.root().node().id();
+        tree.replace_child(root_id, 0, one).unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(
+            tree.get_node(&one_id)
+                .unwrap()
+                .node()
+                .parent()
+                .unwrap()
+                .node()
+                .id(),
+            root_id
+        );
+        assert!(tree.get_node(&a_id).is_none());
+        assert!(tree.get_node(&two_id).is_none());
+
+        // Regenerating assigns new IDs to reattached nodes
+        tree.set_id_mode(IdMode::Regenerate);
+        let b = node(&tree, "b");
+        tree.set_children(root_id, vec![b.clone(), node(&tree, "1")])
+            .unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_ne!(node(&tree, "1").node().id(), one_id);
+    }
+
+    #[traced_test]
+    #[test]
+    fn ancestry() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![TestNode("x", vec![])])]),
+            TestNode("b", vec![]),
+        ]);
+
+        let id = |data: &str| tree.find(|d| *d == data).unwrap().node().id();
+        let (root, a, one, x, b) = (id("root"), id("a"), id("1"), id("x"), id("b"));
+
+        assert!(tree.contains(x));
+        assert!(!tree.contains(100));
+        assert_eq!(tree.depth_of(root), Some(0));
+        assert_eq!(tree.depth_of(x), Some(3));
+        assert_eq!(tree.depth_of(100), None);
+
+        assert!(tree.is_ancestor_of(root, x));
+        assert!(tree.is_ancestor_of(a, x));
+        assert!(tree.is_ancestor_of(one, x));
+        assert!(!tree.is_ancestor_of(x, x));
+        assert!(!tree.is_ancestor_of(x, a));
+        assert!(!tree.is_ancestor_of(b, x));
+        assert!(tree.is_descendant_of(x, a));
+
+        assert_eq!(tree.parent_id(x), Some(one));
+        assert_eq!(tree.parent_id(root), None);
+        assert_eq!(tree.ancestors_of(x).collect::<Vec<_>>(), [one, a, root]);
+
+        // The parent index follows moved subtrees
+        tree.swap(one, b, SwapMode::Subtree).unwrap();
+        assert_eq!(tree.ancestors_of(x).collect::<Vec<_>>(), [one, root]);
+        tree.move_node(b, one, 0, true).unwrap();
+        assert_eq!(tree.parent_id(b), Some(one));
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.set_removal_mode(RemovalMode::Tombstone);
+        let node = tree.get_node(&one).unwrap().clone();
+        tree.remove_node(&node).unwrap();
+        assert!(!tree.contains(x));
+        assert!(!tree.is_ancestor_of(a, x));
+    }
+
+    #[traced_test]
+    #[test]
+    fn listeners() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![])]);
+        let source = test_tree_node(vec![TestNode("a", vec![]), TestNode("b", vec![])]);
+
+        let events = Arc::new(Mutex::new(0));
+        let listener_events = events.clone();
+        let listener = tree
+            .on_event(move |_| *listener_events.lock().unwrap() += 1)
+            .unwrap();
+
+        // Patches applied to an indexed tree reach its listeners
+        TreeDiff::new(tree.root(), source.root())
+            .diff()
+            .patch_tree(&mut tree);
+        assert!(*events.lock().unwrap() > 0);
+        let count = *events.lock().unwrap();
+
+        // Dropping the registration removes the listener
+        drop(listener);
+        let root = tree.root().node().id();
+        tree.append_child(root, "c").unwrap();
+        assert_eq!(*events.lock().unwrap(), count);
+    }
+
+    #[traced_test]
+    #[test]
+    fn filtered_listeners() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        let one = tree.find(|data| *data == "1").unwrap().node().id();
+        let b = tree.find(|data| *data == "b").unwrap().node().id();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let listener_events = events.clone();
+        let _subtree = tree
+            .on_event_filtered(EventFilter::new().subtree(a), move |event| {
+                listener_events
+                    .lock()
+                    .unwrap()
+                    .push(("subtree", event.node().unwrap().node().id()))
+            })
+            .unwrap();
+        let listener_events = events.clone();
+        let _replaced = tree
+            .on_event_filtered(
+                EventFilter::new().kind(TreeEventKind::NodeReplaced),
+                move |event| {
+                    listener_events
+                        .lock()
+                        .unwrap()
+                        .push(("replaced", event.node().unwrap().node().id()))
+                },
+            )
+            .unwrap();
+
+        tree.append_child(one, "x").unwrap();
+        tree.append_child(b, "y").unwrap();
+        tree.set_data(b, "B").unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![("subtree", one), ("replaced", b)]
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn batch_events() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![]), TestNode("b", vec![])]);
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        let b = tree.find(|data| *data == "b").unwrap().node().id();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let listener_events = events.clone();
+        let _listener = tree
+            .on_event(move |event| {
+                let kinds = match event {
+                    TreeEvent::Batch(events) => events.iter().map(|event| event.kind()).collect(),
+                    event => vec![event.kind()],
+                };
+                listener_events.lock().unwrap().push(kinds);
+            })
+            .unwrap();
+
+        let appended = tree.batch(|tree| {
+            tree.set_data(a, "1").unwrap();
+            tree.batch(|tree| tree.append_child(b, "c").unwrap());
+            tree.set_data(a, "2").unwrap();
+            tree.append_child(b, "d").is_ok()
+        });
+        assert!(appended);
+
+        tree.batch_coalesced(|tree| {
+            tree.set_data(a, "3").unwrap();
+            tree.set_data(b, "B").unwrap();
+            tree.set_data(a, "4").unwrap();
+        });
+
+        // Empty batches aren't delivered
+        tree.batch(|_| {});
+
+        use TreeEventKind::*;
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                vec![NodeReplaced, ChildInserted, NodeReplaced, ChildInserted],
+                vec![NodeReplaced, NodeReplaced],
+            ]
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn event_payloads() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![]), TestNode("b", vec![])]);
+        let root = tree.root().node().id();
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        let b = tree.find(|data| *data == "b").unwrap().node().id();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let listener_events = events.clone();
+        let _listener = tree
+            .on_event(move |event| {
+                let events = match event {
+                    TreeEvent::Batch(events) => events.iter().collect(),
+                    event => vec![event],
+                };
+                for event in events {
+                    let payload = match event {
+                        TreeEvent::NodeReplaced { node, previous } => (node.node().id(), *previous),
+                        TreeEvent::ChildRemoved { node, .. } => (node.node().id(), "removed"),
+                        _ => continue,
+                    };
+                    listener_events.lock().unwrap().push(payload);
+                }
+            })
+            .unwrap();
+
+        tree.set_data(a, "1").unwrap();
+        tree.batch_coalesced(|tree| {
+            tree.set_data(a, "2").unwrap();
+            tree.set_data(a, "3").unwrap();
+        });
+        tree.remove_child(root, 1).unwrap();
+
+        // Coalesced replacements report the data from before the batch
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![(a, "a"), (a, "1"), (b, "removed")]
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn event_sequence() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![])]);
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        assert_eq!(tree.event_sequence(), 0);
+
+        tree.set_data(a, "1").unwrap();
+        assert_eq!(tree.event_sequence(), 1);
+
+        let sequences = Arc::new(Mutex::new(Vec::new()));
+        let listener_sequences = sequences.clone();
+        let _listener = tree
+            .on_sequenced_event(move |seq, event| {
+                listener_sequences.lock().unwrap().push((seq, event.kind()));
+            })
+            .unwrap();
+
+        tree.set_data(a, "2").unwrap();
+        tree.batch(|tree| {
+            tree.append_child(a, "b").unwrap();
+            tree.set_data(a, "3").unwrap();
+        });
+        tree.append_child(a, "c").unwrap();
+
+        use TreeEventKind::*;
+        assert_eq!(
+            *sequences.lock().unwrap(),
+            vec![(2, NodeReplaced), (3, Batch), (4, ChildInserted)]
+        );
+        assert_eq!(tree.event_sequence(), 4);
+    }
+
+    #[traced_test]
+    #[test]
+    fn silent_events() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![]), TestNode("b", vec![])]);
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        let b = tree.find(|data| *data == "b").unwrap().node().id();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let listener_events = events.clone();
+        let _listener = tree
+            .on_event(move |event| {
+                let count = match event {
+                    TreeEvent::Suppressed { count } => Some(*count),
+                    _ => None,
+                };
+                listener_events.lock().unwrap().push((event.kind(), count));
+            })
+            .unwrap();
+
+        let filtered = Arc::new(Mutex::new(0));
+        let watcher_filtered = filtered.clone();
+        let _watcher = tree
+            .watch_subtree(b, move |_| *watcher_filtered.lock().unwrap() += 1)
+            .unwrap();
+
+        tree.silent(|tree| {
+            tree.set_data(a, "1").unwrap();
+            tree.batch(|tree| tree.append_child(a, "c").unwrap());
+        });
+        assert!(events.lock().unwrap().is_empty());
+
+        tree.silent_summarized(|tree| {
+            tree.set_data(a, "2").unwrap();
+            tree.silent(|tree| tree.append_child(a, "d").unwrap());
+            tree.append_child(a, "e").unwrap();
+        });
+
+        // Nothing to summarize
+        tree.silent_summarized(|_| {});
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![(TreeEventKind::Suppressed, Some(3))]
+        );
+        assert!(tree.find(|data| *data == "e").is_some());
+
+        // Summaries may concern any subtree
+        assert_eq!(*filtered.lock().unwrap(), 1);
+    }
+
+    #[traced_test]
+    #[test]
+    fn events_across_threads() {
+        fn thread_safe<T: Send + Sync>(_: &T) {}
+
+        let mut tree = test_tree_node(vec![TestNode("a", vec![])]);
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let listener = tree
+            .on_event(move |event| sender.send(event.kind()).unwrap())
+            .unwrap();
+        thread_safe(&tree);
+        thread_safe(&listener);
+
+        let tree = Arc::new(Mutex::new(tree));
+        std::thread::scope(|scope| {
+            for data in ["1", "2"] {
+                let tree = tree.clone();
+                scope.spawn(move || tree.lock().unwrap().set_data(a, data).unwrap());
+            }
+        });
+
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            vec![TreeEventKind::NodeReplaced; 2]
+        );
+        drop(listener);
+    }
+
+    #[traced_test]
+    #[test]
+    fn update_data() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![])]);
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        let root_hash = tree.root().node().get_subtree_hash();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let listener_events = events.clone();
+        let _listener = tree
+            .on_event(move |event| {
+                if let TreeEvent::NodeReplaced { previous, .. } = event {
+                    listener_events.lock().unwrap().push(*previous);
+                }
+            })
+            .unwrap();
+
+        let len = tree
+            .update_data(a, |data| {
+                *data = "b";
+                data.len()
+            })
+            .unwrap();
+        assert_eq!(len, 1);
+        assert!(tree.find(|data| *data == "b").is_some());
+        assert_ne!(tree.root().node().get_subtree_hash(), root_hash);
+        assert_eq!(*events.lock().unwrap(), vec!["a"]);
+
+        assert!(matches!(
+            tree.update_data(u64::MAX, |_| ()),
+            Err(Error::NodeNotFound)
+        ));
+    }
+
+    #[traced_test]
+    #[test]
+    fn event_index_sync() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![]),
+        ])
+        .with_subtree_hash_index()
+        .with_path_index();
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        let b = tree.find(|data| *data == "b").unwrap().node().id();
+
+        // Indexes are updated from the recorded events, even when none are delivered
+        tree.silent(|tree| {
+            tree.batch(|tree| {
+                tree.append_child(b, "c").unwrap();
+                tree.set_data(a, "A").unwrap();
+                tree.remove_child(a, 0).unwrap();
+            })
+        });
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.depth(), 2);
+        assert_eq!(tree.path_of(b), Some(vec![1]));
+
+        assert_eq!(tree.retain(|data| *data != "A"), 2);
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.path_of(b), Some(vec![0]));
+        assert_eq!(tree.node_count(), 3);
+
+        let c = tree.find(|data| *data == "c").unwrap().clone();
+        tree.remove_node(&c).unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.leaves().len(), 1);
+        assert_eq!(tree.depth(), 1);
+    }
+
+    #[traced_test]
+    #[test]
+    fn weak_listeners() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![])]);
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        let registered = |tree: &crate::IndexedTree<_>| tree.event_listeners.lock().unwrap().len();
+
+        let owner = Arc::new(Mutex::new(0));
+        tree.on_event_weak(&owner, |count, _| *count.lock().unwrap() += 1)
+            .unwrap();
+
+        let events = Arc::new(Mutex::new(0));
+        let listener_events = events.clone();
+        let widget = Arc::new(());
+        tree.on_event(move |_| *listener_events.lock().unwrap() += 1)
+            .unwrap()
+            .downgrade(&widget);
+        assert_eq!(registered(&tree), 2);
+
+        tree.set_data(a, "1").unwrap();
+        assert_eq!((*owner.lock().unwrap(), *events.lock().unwrap()), (1, 1));
+
+        // Callbacks are removed by the first event after their owner is dropped
+        drop(owner);
+        drop(widget);
+        assert_eq!(registered(&tree), 2);
+        tree.set_data(a, "2").unwrap();
+        assert_eq!(registered(&tree), 0);
+        assert_eq!(*events.lock().unwrap(), 1);
+    }
+
+    #[traced_test]
+    #[test]
+    fn event_replay() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![])]);
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        tree.set_event_history(2);
+
+        for data in ["1", "2", "3"] {
+            tree.set_data(a, data).unwrap();
+        }
+
+        type TestTree = crate::IndexedTree<
+            crate::noderef::arc::NodeRef<crate::node::arc::Node<&'static str, crate::NodeId>>,
+        >;
+        let replayed = |tree: &TestTree, seq| -> Result<Vec<u64>, Error> {
+            Ok(tree.replay_since(seq)?.map(|(seq, _)| seq).collect())
+        };
+        assert_eq!(replayed(&tree, 1), Ok(vec![2, 3]));
+        assert_eq!(replayed(&tree, 3), Ok(vec![]));
+        assert_eq!(
+            replayed(&tree, 0),
+            Err(Error::HistoryTruncated { oldest: 2 })
+        );
+
+        let sequences = Arc::new(Mutex::new(Vec::new()));
+        let listener_sequences = sequences.clone();
+        let _listener = tree
+            .on_sequenced_event_since(2, move |seq, _| {
+                listener_sequences.lock().unwrap().push(seq)
+            })
+            .unwrap();
+        tree.set_data(a, "4").unwrap();
+        assert_eq!(*sequences.lock().unwrap(), vec![3, 4]);
+
+        assert!(tree.on_sequenced_event_since(0, |_, _| {}).is_err());
+
+        // Without a history, only an up to date listener can catch up
+        tree.set_event_history(0);
+        assert_eq!(replayed(&tree, 4), Ok(vec![]));
+        assert!(replayed(&tree, 3).is_err());
+    }
+
+    #[traced_test]
+    #[test]
+    fn watch_subtree() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![TestNode("2", vec![])])]),
+            TestNode("b", vec![]),
+        ]);
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        let two = tree.find(|data| *data == "2").unwrap().node().id();
+        let b = tree.find(|data| *data == "b").unwrap().node().id();
+
+        let changes = Arc::new(Mutex::new(0));
+        let watcher_changes = changes.clone();
+        let _watcher = tree
+            .watch_subtree(a, move |_| *watcher_changes.lock().unwrap() += 1)
+            .unwrap();
+
+        tree.set_data(b, "B").unwrap();
+        assert_eq!(*changes.lock().unwrap(), 0);
+
+        tree.set_data(two, "3").unwrap();
+        tree.append_child(two, "4").unwrap();
+        assert_eq!(*changes.lock().unwrap(), 2);
+
+        assert_eq!(
+            tree.watch_subtree(1000, |_| {}).err(),
+            Some(Error::NodeNotFound)
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn set_data() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![TestNode("1", vec![])])]);
+        let expected = test_tree_node(vec![TestNode("a", vec![TestNode("2", vec![])])]);
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let listener_events = events.clone();
+        let _listener = tree
+            .on_event(move |event| {
+                if let TreeEvent::NodeReplaced { node, .. } = event {
+                    listener_events.lock().unwrap().push(node.node().id());
+                }
+            })
+            .unwrap();
+
+        let one = tree.root().node().children().unwrap()[0]
+            .node()
+            .children()
+            .unwrap()[0]
+            .clone();
+        let id = one.node().id();
+
+        assert_eq!(tree.set_data(id, "2"), Ok("1"));
+        assert_eq!(*one.node().data(), "2");
+        assert_eq!(
+            tree.root().node().get_subtree_hash(),
+            expected.root().node().get_subtree_hash()
+        );
+        assert_eq!(*events.lock().unwrap(), [id]);
+
+        assert_eq!(tree.set_data(100, "x"), Err(Error::NodeNotFound));
+    }
+
+    #[traced_test]
+    #[test]
+    fn send_sync() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let mut tree = test_tree_node(vec![TestNode("a", vec![])])
+            .with_subtree_hash_index()
+            .with_path_index()
+            .with_data_index(|data| data.len());
+        assert_send_sync(&tree);
+
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| assert!(tree.find(|data| *data == "a").is_some()));
+            }
+        });
+
+        let tree = std::thread::spawn(move || {
+            let a = tree.find(|data| *data == "a").unwrap().node().id();
+            tree.append_child(a, "b").unwrap();
+            tree
+        })
+        .join()
+        .unwrap();
+        assert_eq!(tree.node_count(), 3);
+        assert!(tree.validate().is_ok());
+    }
+
+    #[traced_test]
+    #[test]
+    fn weak() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![TestNode("1", vec![])])]);
+        let a = tree.find(|data| *data == "a").unwrap().downgrade();
+        let one = tree.find(|data| *data == "1").unwrap();
+        let weak_one = one.downgrade();
+        assert_eq!(weak_one.upgrade().unwrap().node().id(), one.node().id());
+
+        // Weak handles don't keep removed nodes alive
+        drop(tree.remove_node(&one));
+        drop(one);
+        assert!(weak_one.upgrade().is_none());
+        assert_eq!(*a.upgrade().unwrap().node().data(), "a");
+    }
+
+    #[traced_test]
+    #[test]
+    fn generation() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![TestNode("1", vec![])])]);
+        let a = tree.find(|data| *data == "a").unwrap().node().id();
+        let one = tree.find(|data| *data == "1").unwrap().node().id();
+        let a_generation = tree.get_node(&a).unwrap().node().generation();
+        let one_generation = tree.get_node(&one).unwrap().node().generation();
+
+        assert!(tree.get_node_checked(&a, a_generation).is_ok());
+        assert_eq!(
+            tree.get_node_checked(&100, 0).unwrap_err(),
+            Error::NodeNotFound
+        );
+
+        // Mutating the data or children of a node advances its generation
+        tree.set_data(one, "2").unwrap();
+        assert!(matches!(
+            tree.get_node_checked(&one, one_generation),
+            Err(Error::StaleNode { expected, found }) if expected == one_generation && found > expected
+        ));
+
+        tree.append_child(a, "3").unwrap();
+        assert!(tree.get_node_checked(&a, a_generation).is_err());
+    }
+
+    #[traced_test]
+    #[test]
+    fn data_index() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![]),
+        ])
+        .with_data_index(|data: &&str| data.parse::<u32>().is_ok());
+
+        type TestTree = crate::IndexedTree<
+            crate::noderef::arc::NodeRef<crate::node::arc::Node<&'static str, crate::NodeId>>,
+        >;
+        let numeric = |tree: &TestTree| -> Vec<&str> {
+            tree.get_by_key(&true)
+                .map(|node| *node.node().data())
+                .collect()
+        };
+        assert_eq!(numeric(&tree), ["1", "2"]);
+
+        let root = tree.root().node().id();
+        let (a, b) = {
+            let children = tree.root().node().children().unwrap().clone();
+            (children[0].node().id(), children[1].node().id())
+        };
+
+        tree.set_data(b, "3").unwrap();
+        assert_eq!(numeric(&tree), ["1", "2", "3"]);
+
+        tree.append_child(root, "4").unwrap();
+        tree.remove_child(root, 0).unwrap();
+        assert!(tree.get_node(&a).is_none());
+        assert_eq!(numeric(&tree), ["3", "4"]);
+
+        tree.reindex();
+        assert_eq!(numeric(&tree), ["3", "4"]);
+        assert_eq!(tree.get_by_key(&false).count(), 1);
+
+        // Keys of a type without a data index find nothing
+        assert_eq!(tree.get_by_key(&0u32).count(), 0);
+    }
+
+    #[traced_test]
+    #[test]
+    fn subtree_hash_index() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![TestNode("1", vec![])]),
+            TestNode("c", vec![]),
+        ])
+        .with_subtree_hash_index();
+
+        let children = tree.root().node().children().unwrap().clone();
+        let ids: Vec<_> = children.iter().map(|child| child.node().id()).collect();
+        let (a, b, c) = (ids[0], ids[1], ids[2]);
+        let one_a = children[0].node().children().unwrap()[0].node().id();
+        let one_b = children[1].node().children().unwrap()[0].node().id();
+
+        assert_eq!(tree.find_duplicate_subtrees(), [vec![one_a, one_b]]);
+
+        tree.set_data(b, "a").unwrap();
+        assert_eq!(tree.find_duplicate_subtrees().len(), 2);
+        let hash = children[0].node().get_subtree_hash();
+        let found: Vec<_> = tree
+            .nodes_with_subtree_hash(hash)
+            .map(|node| node.node().id())
+            .collect();
+        assert_eq!(found, [a, b]);
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.move_node(one_b, c, 0, true).unwrap();
+        assert_eq!(tree.nodes_with_subtree_hash(hash).count(), 1);
+        assert!(tree.contains_subtree_hash(hash));
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.swap(a, c, SwapMode::Subtree).unwrap();
+        tree.remove_child(a, 0).unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.retain(|data| *data != "1");
+        assert_eq!(tree.find_duplicate_subtrees().len(), 1);
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.reindex();
+        assert_eq!(tree.validate(), Ok(()));
+
+        // Disabled unless requested
+        let tree = test_tree_node(vec![TestNode("1", vec![]), TestNode("1", vec![])]);
+        assert!(tree.find_duplicate_subtrees().is_empty());
+    }
+
+    #[traced_test]
+    #[test]
+    fn paths() {
+        let nodes = || {
+            vec![
+                TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+                TestNode("b", vec![TestNode("3", vec![])]),
+            ]
+        };
+        let mut tree = test_tree_node(nodes()).with_path_index();
+        let walked = test_tree_node(nodes());
+
+        for tree in [&tree, &walked] {
+            let data = |path: &[usize]| tree.get_by_path(path).map(|node| *node.node().data());
+            assert_eq!(data(&[]), Some("root"));
+            assert_eq!(data(&[0, 1]), Some("2"));
+            assert_eq!(data(&[1, 0]), Some("3"));
+            assert_eq!(data(&[1, 1]), None);
+
+            let three = tree.find(|data| *data == "3").unwrap().node().id();
+            assert_eq!(tree.path_of(three), Some(vec![1, 0]));
+        }
+
+        let root = tree.root().node().id();
+        let three = tree.find(|data| *data == "3").unwrap().node().id();
+
+        // Inserting before a subtree shifts the paths of its nodes
+        tree.insert_child(root, 0, "c").unwrap();
+        assert_eq!(tree.path_of(three), Some(vec![2, 0]));
+        assert_eq!(*tree.get_by_path(&[0]).unwrap().node().data(), "c");
+
+        tree.remove_child(root, 1).unwrap();
+        assert_eq!(tree.path_of(three), Some(vec![1, 0]));
+        assert!(tree.get_by_path(&[2]).is_none());
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn empty_index() {
+        type EmptyTree =
+            crate::IndexedTree<NodeRef<crate::node::rc::Node<&'static str, crate::NodeId>>>;
+
+        // Indexing a tree without a root gives an empty index
+        let mut tree = EmptyTree::from_tree(Tree::new());
+        assert!(tree.is_empty());
+        assert!(tree.leaves().is_empty());
+        assert_eq!(tree.node_count(), 0);
+
+        tree.reindex();
+        assert!(tree.index().get_ids().is_empty());
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn reindex_subtree() {
+        use crate::node::internal::NodeInternal as _;
+
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![]),
+        ])
+        .with_subtree_hash_index()
+        .with_path_index();
+
+        let mut a = tree.root().node().children().unwrap()[0].clone();
+        let (one, mut two) = {
+            let children = a.node().children().unwrap().clone();
+            (children[0].clone(), children[1].clone())
+        };
+        let one_id = one.node().id();
+
+        // Mutate the subtree of a without going through the indexed tree
+        let mut x = tree.tree.create_node("x").unwrap();
+        let x_id = x.node().id();
+        a.node_mut().remove_child_index(0);
+        x.node_mut().set_parent(two.clone());
+        two.node_mut().push_child(x.clone());
+        crate::hash::rehash_subtree(tree.root(), 0);
+        tree.tree.update_positions();
+        assert!(tree.validate().is_err());
+
+        tree.reindex_subtree(&a).unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+        assert!(!tree.contains(one_id));
+        assert_eq!(tree.parent_id(x_id), Some(two.node().id()));
+        assert_eq!(tree.ids_at_depth(3).collect::<Vec<_>>(), [x_id]);
+        assert_eq!(tree.node_count(), 5);
+
+        assert_eq!(tree.reindex_subtree(&one), Err(Error::NodeNotFound));
+    }
+
+    #[traced_test]
+    #[test]
+    fn split_off() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+        tree.set_hash_context("context");
+
+        let a = tree.root().node().children().unwrap()[0].clone();
+        let split = tree.split_off(&a).unwrap();
+
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(split.validate(), Ok(()));
+        assert_eq!(split.hash_context(), tree.hash_context());
+        assert_eq!(tree.node_count(), 2);
+        assert_eq!(split.node_count(), 3);
+        assert_eq!(*split.root().node().data(), "a");
+        assert_eq!(split.leaves().len(), 2);
+
+        // Splitting at the root moves the whole tree
+        let root = tree.root();
+        let split = tree.split_off(&root).unwrap();
+        assert!(tree.is_empty());
+        assert_eq!(tree.node_count(), 0);
+        assert_eq!(split.node_count(), 2);
+    }
+
+    #[traced_test]
+    #[test]
+    fn find() {
+        let tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![TestNode("2", vec![])]),
+        ]);
+
+        let two = tree.find(|data| *data == "2").unwrap();
+        assert_eq!(*two.node().parent().unwrap().node().data(), "a");
+        assert!(tree.find(|data| *data == "x").is_none());
+
+        // The traversal stops at the first match
+        let mut visited = 0;
+        let (b, len) = tree
+            .find_map(|data| {
+                visited += 1;
+                data.starts_with('b').then_some(data.len())
+            })
+            .unwrap();
+        assert_eq!(*b.node().data(), "b");
+        assert_eq!(len, 1);
+        assert_eq!(visited, 5);
+
+        let empty = Tree::<NodeRef<crate::node::rc::Node<&str, crate::NodeId>>>::new();
+        assert!(empty.find(|_| true).is_none());
+    }
+
+    #[traced_test]
+    #[test]
+    fn remove_node_detaches() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+
+        let a = tree.root().node().children().unwrap()[0].clone();
+        let removed = tree.remove_node(&a).unwrap();
+        assert_eq!(removed.node().id(), a.node().id());
+        assert!(removed.node().parent().is_none());
+        assert_eq!(removed.node().num_children(), 1);
+        assert_eq!(tree.root().node().num_children(), 1);
+
+        // The root and removed nodes are not children of a parent
+        let root = tree.root();
+        assert!(tree.tree.remove_node(&root).is_none());
+        assert!(tree.tree.remove_node(&removed).is_none());
+    }
+
+    #[traced_test]
+    #[test]
+    fn subtree_size() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+
+        let mut root = tree.root();
+        let children = root.node().children().unwrap().clone();
+        let (mut a, mut b) = (children[0].clone(), children[1].clone());
+        assert_eq!(root.node().subtree_size(), 5);
+        assert_eq!(a.node().subtree_size(), 3);
+        assert_eq!(b.node().subtree_size(), 1);
+
+        tree.tree.append_child(&mut b, "c").unwrap();
+        assert_eq!(b.node().subtree_size(), 2);
+        assert_eq!(root.node().subtree_size(), 6);
+
+        tree.tree.move_node(&a, &mut b, 0, true).unwrap();
+        assert_eq!(b.node().subtree_size(), 5);
+        assert_eq!(root.node().subtree_size(), 6);
+
+        tree.tree.remove_child(&mut a, 0);
+        assert_eq!(a.node().subtree_size(), 2);
+        assert_eq!(root.node().subtree_size(), 5);
+
+        tree.tree.remove_node(&a);
+        assert_eq!(b.node().subtree_size(), 2);
+        assert_eq!(root.node().subtree_size(), 3);
+
+        tree.tree.remove_children(&mut root);
+        assert_eq!(root.node().subtree_size(), 1);
+    }
+
+    #[traced_test]
+    #[test]
+    fn positions() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+        let other = test_tree_node(vec![TestNode("x", vec![TestNode("y", vec![])])]);
+
+        let mut root = tree.root();
+        let children = root.node().children().unwrap().clone();
+        let (a, mut b) = (children[0].clone(), children[1].clone());
+
+        // Child indices of the following siblings are shifted automatically
+        let c = tree.create_node("c").unwrap();
+        tree.tree.insert_child(&mut root, 0, c);
+        assert_eq!(a.node().get_position().unwrap().child_index, 1);
+        assert_eq!(b.node().get_position().unwrap().child_index, 2);
+
+        // Depths of an inserted subtree are updated
+        let x = other.root().node().children().unwrap()[0].clone();
+        tree.tree.insert_subtree(&mut b, 0, x.clone());
+        let y = x.node().children().unwrap()[0].clone();
+        assert_eq!(x.node().get_position().unwrap().depth, 2);
+        assert_eq!(y.node().get_position().unwrap().depth, 3);
+
+        // Explicit repositioning recomputes the horizontal index
+        tree.reposition_subtree(&b);
+        assert_eq!(
+            *x.node().get_position().unwrap(),
+            NodePosition {
+                depth: 2,
+                index: 1,
+                child_index: 0
+            }
+        );
+
+        let expected: Vec<_> = tree.root().into_iter().map(|n| *n.position()).collect();
+        tree.update_positions();
+        let stored: Vec<_> = tree
+            .root()
+            .into_iter()
+            .map(|n| *n.node().get_position().unwrap())
+            .collect();
+        assert_eq!(stored, expected);
+    }
+
+    #[traced_test]
+    #[test]
+    fn map() {
+        let tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+
+        let mapped: Tree<NodeRef<crate::node::rc::Node<String, crate::NodeId>>> =
+            tree.map(|data| data.to_uppercase());
+
+        let source: Vec<_> = tree.root().into_iter().map(|n| n.node().id()).collect();
+        let nodes: Vec<_> = mapped
+            .root()
+            .into_iter()
+            .map(|n| (n.node().id(), n.node().data().clone()))
+            .collect();
+        assert_eq!(
+            nodes,
+            [
+                (source[0], "ROOT".to_string()),
+                (source[1], "A".to_string()),
+                (source[2], "1".to_string()),
+                (source[3], "B".to_string()),
+            ]
+        );
+
+        let one = mapped.root().node().children().unwrap()[0]
+            .node()
+            .children()
+            .unwrap()[0]
+            .clone();
+        assert_eq!(one.node().parent().unwrap().node().id(), source[1]);
+        assert_eq!(one.node().get_position().unwrap().depth, 2);
+
+        let result: Result<Tree<NodeRef<crate::node::rc::Node<u32, crate::NodeId>>>, _> =
+            tree.try_map(|data| data.parse::<u32>());
+        assert!(result.is_err());
+    }
+
+    #[traced_test]
+    #[test]
+    fn intern() {
+        let nodes = || {
+            vec![
+                TestNode("a", vec![TestNode("x", vec![])]),
+                TestNode("a", vec![TestNode("x", vec![])]),
+                TestNode("b", vec![TestNode("x", vec![])]),
+            ]
+        };
+        // Interning applies to a plain tree, as an IndexedTree doesn't expose its tree mutably
+        type ArcNodeRef =
+            crate::noderef::arc::NodeRef<crate::node::arc::Node<&'static str, crate::NodeId>>;
+        let mut tree = test_tree_node(nodes()).map::<ArcNodeRef, _>(Clone::clone);
+        let hash = tree.root().node().get_subtree_hash();
+
+        // The second a, and the x under b
+        assert_eq!(tree.intern(), 2);
+        assert_eq!(tree.intern(), 0);
+
+        let children = tree.root().node().children().unwrap().clone();
+        assert_eq!(children[0].node().id(), children[1].node().id());
+        assert_eq!(
+            children[0].node().children().unwrap()[0].node().id(),
+            children[2].node().children().unwrap()[0].node().id()
+        );
+
+        // The shared nodes render the same tree
+        assert_eq!(tree.root().node().get_subtree_hash(), hash);
+        assert_eq!(tree.root().into_iter().count(), 7);
+
+        // Indexing copies the shared subtrees under new IDs
+        let indexed = tree.index();
+        assert_eq!(indexed.node_count(), 7);
+        assert_eq!(indexed.validate(), Ok(()));
+        assert_eq!(indexed, test_tree_node(nodes()));
+
+        // Subtrees with colliding hashes are not shared
+        let Ok(tree) = crate::TreeBuilder::<Colliding>::new()
+            .root_ok(Colliding("root"), |root| {
+                root.child_ok(Colliding("a"), |_| ());
+                root.child_ok(Colliding("b"), |_| ());
+                root.child_ok(Colliding("a"), |_| ());
+            })
+            .done();
+        let mut tree = tree.unwrap();
+        assert_eq!(tree.intern(), 1);
+
+        let children = tree.root().node().children().unwrap().clone();
+        let data: Vec<_> = children.iter().map(|child| child.node().data().0).collect();
+        assert_eq!(data, ["a", "b", "a"]);
+        assert!(children[0].ptr_eq(&children[2]));
+        assert_eq!(tree.unshare(), Ok(1));
+        assert_eq!(tree.unshare(), Ok(0));
+        assert!(!children[0].ptr_eq(&tree.root().node().children().unwrap()[2]));
+    }
+
+    #[traced_test]
+    #[test]
+    fn dirty() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+        type TestTree = crate::IndexedTree<
+            crate::noderef::arc::NodeRef<crate::node::arc::Node<&'static str, crate::NodeId>>,
+        >;
+        let dirty = |tree: &TestTree| -> Vec<&str> {
+            tree.iter_dirty().map(|node| *node.node().data()).collect()
+        };
+
+        // New nodes are dirty
+        assert_eq!(dirty(&tree), ["root", "a", "1", "b"]);
+        tree.clear_dirty();
+        assert!(dirty(&tree).is_empty());
+
+        let one = tree.find(|data| *data == "1").unwrap().node().id();
+        tree.set_data(one, "2").unwrap();
+        assert_eq!(dirty(&tree), ["2"]);
+
+        let b = tree.find(|data| *data == "b").unwrap().node().id();
+        tree.append_child(b, "3").unwrap();
+        assert_eq!(dirty(&tree), ["2", "b", "3"]);
+
+        tree.clear_dirty();
+        assert!(dirty(&tree).is_empty());
+    }
+
+    #[traced_test]
+    #[test]
+    fn retain() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("x", vec![]), TestNode("1", vec![])]),
+            TestNode("x", vec![TestNode("2", vec![])]),
+            TestNode("b", vec![TestNode("x", vec![])]),
+        ]);
+        let expected = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+
+        assert_eq!(tree.retain(|data| *data != "x"), 4);
+
+        assert_eq!(
+            tree.root().node().get_subtree_hash(),
+            expected.root().node().get_subtree_hash()
+        );
+        assert_eq!(tree.index().get_ids().len(), 4);
+
+        let mut leaves: Vec<_> = tree.leaves().iter().map(|l| *l.node().data()).collect();
+        leaves.sort();
+        assert_eq!(leaves, ["1", "b"]);
+
+        assert_eq!(tree.retain(|_| true), 0);
+    }
+
+    #[traced_test]
+    #[test]
+    fn sort_children() {
+        let mut tree = test_tree_node(vec![
+            TestNode("b", vec![TestNode("2", vec![]), TestNode("1", vec![])]),
+            TestNode("a", vec![]),
+        ]);
+
+        let root = tree.root();
+        let root_id = root.node().id();
+        tree.sort_children_by(root_id, |a, b| a.cmp(b)).unwrap();
+        let expected = test_tree_node(vec![
+            TestNode("a", vec![]),
+            TestNode("b", vec![TestNode("2", vec![]), TestNode("1", vec![])]),
+        ]);
+        assert_eq!(
+            root.node().get_subtree_hash(),
+            expected.root().node().get_subtree_hash()
+        );
+        let b = root.node().children().unwrap()[1].clone();
+        assert_eq!(b.node().get_position().unwrap().child_index, 1);
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.sort_all_by(root_id, |a, b| b.cmp(a)).unwrap();
+        let expected = test_tree_node(vec![
+            TestNode("b", vec![TestNode("2", vec![]), TestNode("1", vec![])]),
+            TestNode("a", vec![]),
+        ]);
+        assert_eq!(
+            root.node().get_subtree_hash(),
+            expected.root().node().get_subtree_hash()
+        );
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(
+            tree.sort_children_by(u64::MAX, |a, b| a.cmp(b)),
+            Err(Error::NodeNotFound)
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn swap() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![TestNode("3", vec![])]),
+        ]);
+
+        let children = tree.root().node().children().unwrap().clone();
+        let (a, b) = (children[0].clone(), children[1].clone());
+        let two = a.node().children().unwrap()[1].clone();
+        let three = b.node().children().unwrap()[0].clone();
+
+        tree.tree.swap(&a, &b, SwapMode::Data).unwrap();
+        let expected = test_tree_node(vec![
+            TestNode("b", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("a", vec![TestNode("3", vec![])]),
+        ]);
+        assert_eq!(
+            tree.root().node().get_subtree_hash(),
+            expected.root().node().get_subtree_hash()
+        );
+
+        // Swap subtrees across parents
+        tree.tree.swap(&two, &three, SwapMode::Subtree).unwrap();
+        let expected = test_tree_node(vec![
+            TestNode("b", vec![TestNode("1", vec![]), TestNode("3", vec![])]),
+            TestNode("a", vec![TestNode("2", vec![])]),
+        ]);
+        assert_eq!(
+            tree.root().node().get_subtree_hash(),
+            expected.root().node().get_subtree_hash()
+        );
+        assert_eq!(three.node().parent().unwrap().node().id(), a.node().id());
+        assert_eq!(three.node().get_position().unwrap().child_index, 1);
+        assert_eq!(two.node().get_position().unwrap().child_index, 0);
+
+        assert_eq!(
+            tree.tree.swap(&a, &three, SwapMode::Subtree),
+            Err(Error::CyclicMove)
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn append_prepend() {
+        let mut tree = test_tree_node(vec![TestNode("b", vec![])]);
+        let expected = test_tree_node(vec![
+            TestNode("a", vec![]),
+            TestNode("b", vec![TestNode("1", vec![])]),
+            TestNode("c", vec![]),
+        ]);
+
+        let mut root = tree.root();
+        tree.tree.prepend_child(&mut root, "a").unwrap();
+        let c = tree.tree.append_child(&mut root, "c").unwrap();
+        assert_eq!(*c.node().data(), "c");
+
+        let mut b = root.node().children().unwrap()[1].clone();
+        tree.tree.append_child(&mut b, "1").unwrap();
+
+        assert_eq!(
+            tree.root().node().get_subtree_hash(),
+            expected.root().node().get_subtree_hash()
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn insert_sibling() {
+        let mut tree = test_tree_node(vec![TestNode("b", vec![])]);
+        let b = tree.root().node().children().unwrap()[0].clone();
+        let b_id = b.node().id();
+
+        let a = tree.create_node("a").unwrap();
+        tree.tree.insert_before(&b, a).unwrap();
+        tree.insert_after(b_id, "c").unwrap();
+
+        let data: Vec<_> = tree
+            .root()
+            .node()
+            .children()
+            .unwrap()
+            .iter()
+            .map(|child| *child.node().data())
+            .collect();
+        assert_eq!(data, ["a", "b", "c"]);
+        // Only nodes inserted through the IndexedTree are indexed
+        assert_eq!(tree.index().get_ids().len(), 3);
+
+        let root = tree.root();
+        let new = tree.create_node("x").unwrap();
+        assert_eq!(tree.tree.insert_after(&root, new), Err(Error::NoParent));
+    }
+
+    #[traced_test]
+    #[test]
+    fn insert_sibling_hashes() {
+        let mut tree = test_tree_node(vec![TestNode("b", vec![])]);
+        let b = tree.root().node().children().unwrap()[0].clone();
+
+        let a = tree.create_node("a").unwrap();
+        tree.tree.insert_before(&b, a).unwrap();
+        let c = tree.create_node("c").unwrap();
+        tree.tree.insert_after(&b, c).unwrap();
+
+        let expected = test_tree_node(vec![
+            TestNode("a", vec![]),
+            TestNode("b", vec![]),
+            TestNode("c", vec![]),
+        ]);
+        assert_eq!(
+            tree.root().node().get_subtree_hash(),
+            expected.root().node().get_subtree_hash()
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn detach() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+        let expected = test_tree_node(vec![TestNode("b", vec![])]);
+
+        let a = tree.root().node().children().unwrap()[0].clone();
+        let a_hash = a.node().get_subtree_hash();
+        let old_ids: Vec<_> = a.clone().into_iter().map(|n| n.node().id()).collect();
+
+        let detached = tree.detach(&a).unwrap();
+
+        assert!(detached.root().node().parent().is_none());
+        assert_eq!(*detached.root().node().data(), "a");
+        assert_eq!(detached.root().node().get_subtree_hash(), a_hash);
+        assert_eq!(detached.root().into_iter().count(), 2);
+
+        assert_eq!(
+            tree.root().node().get_subtree_hash(),
+            expected.root().node().get_subtree_hash()
+        );
+        for id in old_ids {
+            assert!(tree.get_node(&id).is_none());
+        }
+        assert_eq!(tree.leaves().len(), 1);
+
+        // Detaching the root empties the tree
+        let root = tree.root();
+        let detached = tree.detach(&root).unwrap();
+        assert!(tree.is_empty());
+        assert_eq!(detached.root().into_iter().count(), 2);
+    }
+
+    #[traced_test]
+    #[test]
+    fn detach_removal() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+        tree.set_removal_mode(RemovalMode::Tombstone);
+
+        let a = tree.find(|data| *data == "a").unwrap();
+        let a_id = a.node().id();
+
+        // Events delivered after the batch still see the IDs the nodes had in this tree
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let listener_events = events.clone();
+        let _listener = tree
+            .on_event(move |event| {
+                if let TreeEvent::Batch(batch) = event {
+                    for event in batch {
+                        listener_events
+                            .lock()
+                            .unwrap()
+                            .push(event.node().unwrap().node().id());
+                    }
+                }
+            })
+            .unwrap();
+        let detached = tree.batch(|tree| tree.detach(&a).unwrap());
+        assert_eq!(*events.lock().unwrap(), vec![a_id]);
+
+        // The removed nodes are tombstoned like any other removal, while the detached tree holds
+        // renumbered copies
+        assert_eq!(a.node().id(), a_id);
+        assert!(!detached.root().ptr_eq(&a));
+        assert!(a.node().is_tombstoned());
+        assert_eq!(tree.tombstones().count(), 1);
+        assert!(!tree.contains(a_id));
+        assert!(tree.index().get(&a_id).is_some());
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.gc(u64::MAX);
+        assert!(tree.index().get(&a_id).is_none());
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn move_node() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+        let expected = test_tree_node(vec![
+            TestNode("a", vec![TestNode("2", vec![])]),
+            TestNode("b", vec![TestNode("1", vec![])]),
+        ]);
+
+        let children = tree.root().node().children().unwrap().clone();
+        let (mut a, mut b) = (children[0].clone(), children[1].clone());
+        let one = a.node().children().unwrap()[0].clone();
+        let one_id = one.node().id();
+
+        tree.tree.move_node(&one, &mut b, 0, true).unwrap();
+
+        assert_eq!(one.node().id(), one_id);
+        assert_eq!(one.node().parent().unwrap().node().id(), b.node().id());
+        assert_eq!(a.node().num_children(), 1);
+        assert_eq!(
+            tree.root().node().get_subtree_hash(),
+            expected.root().node().get_subtree_hash()
+        );
+
+        // Moving within the same parent
+        tree.tree.move_node(&one, &mut b, 0, false).unwrap();
+        assert_ne!(one.node().id(), one_id);
+
+        assert_eq!(
+            tree.tree.move_node(&a, &mut a.clone(), 0, true),
+            Err(Error::CyclicMove)
+        );
+        assert_eq!(
+            tree.tree.move_node(&one, &mut a, 2, true),
+            Err(Error::IndexOutOfBounds { index: 2, len: 1 })
+        );
+        let root = tree.root();
+        assert_eq!(
+            tree.tree.move_node(&root, &mut b, 0, true),
+            Err(Error::NoParent)
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn empty() {
+        let tree = Tree::<NodeRef<crate::node::rc::Node<u32, crate::NodeId>>>::new();
+        assert!(tree.is_empty());
+        assert_eq!(tree.try_root().unwrap_err(), Error::EmptyTree);
+        assert_eq!(tree.try_depth().unwrap_err(), Error::EmptyTree);
+        assert_eq!(tree.try_width().unwrap_err(), Error::EmptyTree);
+        assert_eq!(tree.try_generate_id().unwrap_err(), Error::NoGenerator);
+        assert_eq!(format!("{tree:?}"), "Tree(empty)");
+
+        let tree = test_tree(vec!["a"]);
+        assert!(!tree.is_empty());
+        assert_eq!(tree.try_depth(), Ok(1));
+        assert!(tree.try_generate_id().is_ok());
+    }
+
+    #[traced_test]
+    #[test]
+    fn tombstone() {
+        let mut tree = test_tree(vec!["a", "b", "c"]);
+        tree.set_removal_mode(RemovalMode::Tombstone);
+
+        let b = tree.root().node().children().unwrap()[1].clone();
+        let b_id = b.node().id();
+        tree.remove_node(&b);
+
+        // Tombstoned nodes are excluded from iteration, but can still be resolved
+        assert_eq!(tree.root().into_iter().count(), 3);
+        assert!(tree.get_node(&b_id).unwrap().node().is_tombstoned());
+        assert_eq!(tree.tombstones().count(), 1);
+
+        // Nothing was removed before the first removal
+        assert!(tree.gc(0).is_empty());
+
+        let collected = tree.gc(tree.removal_seq());
+        assert_eq!(collected.len(), 1);
+        assert!(tree.get_node(&b_id).is_none());
     }
 }
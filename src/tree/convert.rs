@@ -0,0 +1,228 @@
+//! Conversions between [`Tree`] and the trees of other crates, enabled with the `ego-tree` and
+//! `indextree` features. Structure and data are preserved. Node IDs are not, as each crate
+//! allocates its own, so converted trees are assigned IDs from a default generator.
+//!
+//! The `proptest` and `fs` features build generated and loaded trees with the same conversion.
+
+use crate::{
+    hash::rehash_subtree,
+    node::TreeNode,
+    noderef::{NodeRefId, TreeNodeRef},
+    UniqueGenerator,
+};
+
+#[cfg(any(feature = "ego-tree", feature = "indextree"))]
+use crate::Error;
+
+use super::Tree;
+
+impl<R, G> Tree<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    /// Build a tree from the nodes of another tree, starting at `root`, with the data and
+    /// ordered children of each node provided by `data` and `children`
+    pub(crate) fn from_foreign<N>(
+        root: N,
+        data: impl Fn(&N) -> <R::Inner as TreeNode>::Data,
+        children: impl Fn(&N) -> Vec<N>,
+    ) -> Self {
+        let mut tree = Self::new();
+        tree.node_id_generator = Some(G::default());
+
+        let mut converted_root = None;
+
+        // Stack of source nodes to convert, with the converted parent to attach them to
+        let mut stack: Vec<(N, Option<R>)> = vec![(root, None)];
+
+        while let Some((source, parent)) = stack.pop() {
+            let mut node = R::Inner::new(tree.generate_id(), data(&source), None);
+            if let Some(parent) = &parent {
+                node = node.with_parent(parent.clone());
+            }
+
+            let node_ref = R::new_in(node, &tree.allocator);
+            match parent {
+                Some(mut parent) => parent.node_mut().push_child(node_ref.clone()),
+                None => converted_root = Some(node_ref.clone()),
+            }
+
+            // Reversed so children are popped, and attached to the parent, in order
+            for child in children(&source).into_iter().rev() {
+                stack.push((child, Some(node_ref.clone())));
+            }
+        }
+
+        if let Some(root) = converted_root {
+            tree.root = Some(root.clone());
+            tree.update_positions();
+            rehash_subtree(root, tree.hash_context);
+        }
+
+        tree
+    }
+
+    /// Visit the nodes of the tree in depth first order, with the value returned for the parent
+    /// of each node, or `None` for the root
+    #[cfg(any(feature = "ego-tree", feature = "indextree"))]
+    fn visit_with_parent<T: Clone>(&self, mut f: impl FnMut(&R, Option<&T>) -> T) {
+        let Some(root) = &self.root else {
+            return;
+        };
+
+        let mut stack: Vec<(R, Option<T>)> = vec![(root.clone(), None)];
+        while let Some((node, parent)) = stack.pop() {
+            let value = f(&node, parent.as_ref());
+
+            // Reversed so children are visited in order
+            let inner = node.node();
+            if let Some(children) = inner.children() {
+                for child in children.iter().rev() {
+                    stack.push((child.clone(), Some(value.clone())));
+                }
+            };
+        }
+    }
+}
+
+#[cfg(feature = "ego-tree")]
+impl<R, G> From<&ego_tree::Tree<<R::Inner as TreeNode>::Data>> for Tree<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    fn from(source: &ego_tree::Tree<<R::Inner as TreeNode>::Data>) -> Self {
+        Tree::from_foreign(
+            source.root(),
+            |node| node.value().clone(),
+            |node| node.children().collect(),
+        )
+    }
+}
+
+/// Converts a non-empty tree, or fails with [`Error::EmptyTree`], as an [`ego_tree::Tree`]
+/// always has a root
+#[cfg(feature = "ego-tree")]
+impl<R, G> TryFrom<&Tree<R, G>> for ego_tree::Tree<<R::Inner as TreeNode>::Data>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    type Error = Error;
+
+    fn try_from(tree: &Tree<R, G>) -> Result<Self, Error> {
+        let root = tree.try_root()?;
+        let data = root.node().data().clone();
+        let mut converted = ego_tree::Tree::new(data);
+
+        tree.visit_with_parent(|node, parent: Option<&ego_tree::NodeId>| {
+            let Some(parent) = parent else {
+                return converted.root().id();
+            };
+            let data = node.node().data().clone();
+            let mut parent = converted.get_mut(*parent).expect("converted parent");
+            parent.append(data).id()
+        });
+
+        Ok(converted)
+    }
+}
+
+#[cfg(feature = "indextree")]
+impl<R, G> Tree<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    /// Build a tree from the subtree of an [`indextree::Arena`] rooted at `root`. Fails with
+    /// [`Error::NodeNotFound`] if `root` is not in the arena, or was removed.
+    pub fn from_indextree(
+        arena: &indextree::Arena<<R::Inner as TreeNode>::Data>,
+        root: indextree::NodeId,
+    ) -> Result<Self, Error> {
+        if arena.get(root).is_none_or(|node| node.is_removed()) {
+            return Err(Error::NodeNotFound);
+        }
+
+        Ok(Tree::from_foreign(
+            root,
+            |id| arena[*id].get().clone(),
+            |id| id.children(arena).collect(),
+        ))
+    }
+
+    /// Convert the tree into a new [`indextree::Arena`], returning the arena and the ID of the
+    /// root node in it, or `None` for an empty tree
+    pub fn to_indextree(
+        &self,
+    ) -> (
+        indextree::Arena<<R::Inner as TreeNode>::Data>,
+        Option<indextree::NodeId>,
+    ) {
+        let mut arena = indextree::Arena::new();
+        let mut root = None;
+
+        self.visit_with_parent(|node, parent: Option<&indextree::NodeId>| {
+            let data = node.node().data().clone();
+            let id = arena.new_node(data);
+            match parent {
+                Some(parent) => parent.append(id, &mut arena),
+                None => root = Some(id),
+            }
+            id
+        });
+
+        (arena, root)
+    }
+}
+
+#[cfg(all(test, any(feature = "ego-tree", feature = "indextree")))]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{noderef::arc::NodeRef, test::test_tree_vec, Tree};
+
+    type StrTree = Tree<NodeRef<crate::node::arc::Node<&'static str, crate::NodeId>>>;
+
+    #[cfg(feature = "ego-tree")]
+    #[traced_test]
+    #[test]
+    fn ego_tree() {
+        let tree = test_tree_vec(vec![("a", vec!["1", "2"]), ("b", vec![])]);
+
+        let converted = ego_tree::Tree::try_from(tree.tree()).unwrap();
+        let values: Vec<_> = converted.root().descendants().map(|n| *n.value()).collect();
+        assert_eq!(values, ["root", "a", "1", "2", "b"]);
+
+        let back = StrTree::from(&converted);
+        assert!(back == *tree.tree());
+        assert_eq!(back.to_sexpr(), "(root (a 1 2) b)");
+
+        assert!(ego_tree::Tree::try_from(&StrTree::new()).is_err());
+    }
+
+    #[cfg(feature = "indextree")]
+    #[traced_test]
+    #[test]
+    fn indextree() {
+        let tree = test_tree_vec(vec![("a", vec!["1", "2"]), ("b", vec![])]);
+
+        let (arena, root) = tree.to_indextree();
+        let root = root.unwrap();
+        let values: Vec<_> = root.descendants(&arena).map(|id| arena[id].get()).collect();
+        assert_eq!(values, [&"root", &"a", &"1", &"2", &"b"]);
+
+        let back = StrTree::from_indextree(&arena, root).unwrap();
+        assert!(back == *tree.tree());
+
+        // Subtrees of the arena can be converted on their own
+        let a = root.children(&arena).next().unwrap();
+        assert_eq!(
+            StrTree::from_indextree(&arena, a).unwrap().to_sexpr(),
+            "(a 1 2)"
+        );
+
+        assert_eq!(StrTree::new().to_indextree().1, None);
+    }
+}
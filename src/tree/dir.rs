@@ -0,0 +1,234 @@
+//! Loading a directory hierarchy of the filesystem as a [`Tree`], enabled with the `fs` feature.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    node::TreeNode,
+    noderef::{NodeRefId, TreeNodeRef},
+    UniqueGenerator,
+};
+
+use super::Tree;
+
+/// Type of a filesystem entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileKind {
+    File,
+    Dir,
+    /// A symbolic link which was not followed, or whose target doesn't exist
+    Symlink,
+    /// Any other entry, such as a socket or device
+    Other,
+}
+
+/// Node data of a tree loaded by [`Tree::from_dir`], describing one filesystem entry.
+/// Displayed as its name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileEntry {
+    /// File name of the entry, or the loaded path for the root
+    pub name: String,
+
+    /// Path of the entry, starting with the loaded path
+    pub path: PathBuf,
+
+    pub kind: FileKind,
+
+    /// Size in bytes
+    pub len: u64,
+
+    /// Last modification time, if supported by the platform
+    pub modified: Option<SystemTime>,
+
+    pub readonly: bool,
+}
+
+impl std::fmt::Display for FileEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl FileEntry {
+    /// Read the metadata of the entry at `path`, following it if it's a symbolic link and
+    /// `follow_symlinks` is set
+    fn read(path: PathBuf, follow_symlinks: bool) -> std::io::Result<Self> {
+        let mut metadata = fs::symlink_metadata(&path)?;
+        if follow_symlinks && metadata.file_type().is_symlink() {
+            // Broken links are kept as links
+            if let Ok(target) = fs::metadata(&path) {
+                metadata = target;
+            }
+        }
+
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_dir() {
+            FileKind::Dir
+        } else if file_type.is_file() {
+            FileKind::File
+        } else if file_type.is_symlink() {
+            FileKind::Symlink
+        } else {
+            FileKind::Other
+        };
+
+        let name = path.file_name().map_or_else(
+            || path.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+
+        Ok(Self {
+            name,
+            kind,
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            readonly: metadata.permissions().readonly(),
+            path,
+        })
+    }
+}
+
+/// Options of [`Tree::from_dir`]
+#[derive(Debug, Clone, Default)]
+pub struct DirOptions {
+    follow_symlinks: bool,
+    max_depth: Option<usize>,
+}
+
+impl DirOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Follow symbolic links, loading the entries they point to. A directory reached more than
+    /// once through links is only descended into the first time, so cyclic links terminate.
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Don't descend into directories at a depth of `max_depth` or deeper, where the loaded
+    /// path has depth 0. Such directories are loaded without children.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+}
+
+impl<R, G> Tree<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    /// Load the directory hierarchy at `path` as a tree of [`FileEntry`] nodes, with the
+    /// children of each directory ordered by name. A file path loads a tree of only that file.
+    /// Fails with the first error reading an entry or directory.
+    pub fn from_dir(path: impl AsRef<Path>, options: &DirOptions) -> std::io::Result<Self>
+    where
+        R::Inner: TreeNode<Data = FileEntry>,
+    {
+        // Entries with the indexes of their children, in the order they were read
+        let mut entries: Vec<(FileEntry, Vec<usize>)> = Vec::new();
+        let mut visited = HashSet::new();
+
+        // Stack of paths to read, with their depth and the index of their parent entry
+        let mut stack = vec![(path.as_ref().to_path_buf(), 0, None)];
+
+        while let Some((path, depth, parent)) = stack.pop() {
+            let entry = FileEntry::read(path, options.follow_symlinks)?;
+
+            let index = entries.len();
+            if let Some(parent) = parent {
+                let (_, children): &mut (FileEntry, Vec<usize>) = &mut entries[parent];
+                children.push(index);
+            }
+
+            let descend = entry.kind == FileKind::Dir
+                && options.max_depth.is_none_or(|max| depth < max)
+                && (!options.follow_symlinks || visited.insert(fs::canonicalize(&entry.path)?));
+
+            if descend {
+                let mut children = fs::read_dir(&entry.path)?
+                    .map(|child| child.map(|child| child.path()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                children.sort_unstable();
+
+                // Reversed so children are read, and attached to the parent, in order
+                for child in children.into_iter().rev() {
+                    stack.push((child, depth + 1, Some(index)));
+                }
+            }
+
+            entries.push((entry, Vec::new()));
+        }
+
+        Ok(Tree::from_foreign(
+            0,
+            |index| entries[*index].0.clone(),
+            |index| entries[*index].1.clone(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tracing_test::traced_test;
+
+    use crate::{noderef::arc::NodeRef, Tree, TreeNode as _, TreeNodeRef as _};
+
+    use super::{DirOptions, FileEntry, FileKind};
+
+    type DirTree = Tree<NodeRef<crate::node::arc::Node<FileEntry, crate::NodeId>>>;
+
+    #[traced_test]
+    #[test]
+    fn from_dir() {
+        let root = std::env::temp_dir().join(format!("arbutus-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(root.join("a/nested")).unwrap();
+        fs::create_dir(root.join("b")).unwrap();
+        fs::write(root.join("a/1.txt"), "one").unwrap();
+        fs::write(root.join("c.txt"), "three").unwrap();
+
+        let tree = DirTree::from_dir(&root, &DirOptions::new()).unwrap();
+        let name = tree.root().node().data().name.clone();
+        assert_eq!(
+            tree.to_sexpr(),
+            format!("({name} (a 1.txt nested) b c.txt)")
+        );
+
+        let file = tree.find(|entry| entry.name == "c.txt").unwrap();
+        let file = file.node().data().clone();
+        assert_eq!((file.kind, file.len), (FileKind::File, 5));
+        assert_eq!(file.path, root.join("c.txt"));
+
+        let shallow = DirTree::from_dir(&root, &DirOptions::new().with_max_depth(1)).unwrap();
+        assert_eq!(shallow.to_sexpr(), format!("({name} a b c.txt)"));
+
+        #[cfg(unix)]
+        {
+            // Links are loaded as links unless followed, and cyclic links terminate
+            std::os::unix::fs::symlink(&root, root.join("b/up")).unwrap();
+
+            let tree = DirTree::from_dir(&root, &DirOptions::new()).unwrap();
+            let up = tree.find(|entry| entry.name == "up").unwrap();
+            assert_eq!(up.node().data().kind, FileKind::Symlink);
+
+            let options = DirOptions::new().with_follow_symlinks(true);
+            let tree = DirTree::from_dir(&root, &options).unwrap();
+            assert_eq!(
+                tree.to_sexpr(),
+                format!("({name} (a 1.txt nested) (b up) c.txt)")
+            );
+        }
+
+        assert!(DirTree::from_dir(root.join("missing"), &DirOptions::new()).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
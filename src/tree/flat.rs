@@ -0,0 +1,358 @@
+//! Flat record representation of a [`Tree`], with one record per node referring to its parent
+//! by ID, for streaming trees to and from tables such as databases or CSV and Parquet files.
+
+use std::collections::{hash_map::Entry, HashMap};
+
+use crate::{
+    hash::rehash_subtree,
+    id::SeedableGenerator,
+    node::{internal::NodeInternal as _, TreeNode},
+    noderef::{NodeRefId, TreeNodeRef},
+    UniqueGenerator,
+};
+
+use super::Tree;
+
+/// A node of a tree, referring to its parent by ID. Produced by [`Tree::to_flat_records`] and
+/// consumed by [`Tree::from_flat_records`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FlatRecord<Id, D> {
+    /// ID of the node
+    pub id: Id,
+
+    /// ID of the parent of the node, or `None` for the root
+    pub parent: Option<Id>,
+
+    /// Index of the node within the children of its parent
+    pub index: usize,
+
+    /// Data of the node
+    pub data: D,
+}
+
+/// Error returned by [`Tree::from_flat_records`] when the records don't describe a single tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FlatRecordError<Id> {
+    /// More than one record has the same ID
+    DuplicateId { id: Id },
+
+    /// No record is without a parent
+    NoRoot,
+
+    /// More than one record is without a parent
+    MultipleRoots { first: Id, second: Id },
+
+    /// A record refers to a parent which has no record
+    Orphan { id: Id, parent: Id },
+
+    /// More than one child of a parent has the same index
+    DuplicateChildIndex { parent: Id, index: usize },
+
+    /// No child of a parent has an index below the number of its children
+    MissingChildIndex { parent: Id, index: usize },
+
+    /// A record is not connected to the root, as its ancestors form a cycle
+    Unreachable { id: Id },
+
+    /// A record has the largest representable ID, leaving none for the generator of the tree
+    IdsExhausted { id: Id },
+}
+
+impl<Id> std::fmt::Display for FlatRecordError<Id>
+where
+    Id: std::fmt::Display,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlatRecordError::DuplicateId { id } => write!(f, "duplicate node ID {id}"),
+            FlatRecordError::NoRoot => write!(f, "no record without a parent"),
+            FlatRecordError::MultipleRoots { first, second } => {
+                write!(f, "nodes {first} and {second} are both without a parent")
+            }
+            FlatRecordError::Orphan { id, parent } => {
+                write!(f, "node {id} refers to missing parent {parent}")
+            }
+            FlatRecordError::DuplicateChildIndex { parent, index } => {
+                write!(f, "node {parent} has more than one child at index {index}")
+            }
+            FlatRecordError::MissingChildIndex { parent, index } => {
+                write!(f, "node {parent} has no child at index {index}")
+            }
+            FlatRecordError::Unreachable { id } => {
+                write!(f, "node {id} is not connected to the root")
+            }
+            FlatRecordError::IdsExhausted { id } => {
+                write!(f, "node ID {id} leaves no IDs for new nodes")
+            }
+        }
+    }
+}
+
+impl<Id> std::error::Error for FlatRecordError<Id> where Id: std::fmt::Debug + std::fmt::Display {}
+
+/// Iterate a [`FlatRecord`] for each node of the subtree rooted at `root` in depth first order.
+/// The record of `root` has no parent.
+pub(crate) fn flat_records<R>(
+    root: Option<R>,
+) -> impl Iterator<Item = FlatRecord<NodeRefId<R>, <R::Inner as TreeNode>::Data>>
+where
+    R: TreeNodeRef,
+{
+    let mut stack: Vec<(R, Option<NodeRefId<R>>, usize)> =
+        root.into_iter().map(|root| (root, None, 0)).collect();
+
+    std::iter::from_fn(move || {
+        let (node, parent, index) = stack.pop()?;
+        let inner = node.node();
+        let id = inner.id();
+
+        if let Some(children) = inner.children() {
+            stack.extend(
+                children
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(index, child)| (child.clone(), Some(id), index)),
+            );
+        }
+
+        let data = inner.data().clone();
+        Some(FlatRecord {
+            id,
+            parent,
+            index,
+            data,
+        })
+    })
+}
+
+impl<R, G> Tree<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    /// Iterate a [`FlatRecord`] for each node of the tree in depth first order, so every parent
+    /// precedes its children. Records are produced as the iterator advances.
+    pub fn to_flat_records(
+        &self,
+    ) -> impl Iterator<Item = FlatRecord<NodeRefId<R>, <R::Inner as TreeNode>::Data>> + '_ {
+        flat_records(self.root.clone())
+    }
+
+    /// Build a tree from [`FlatRecord`]s in any order, keeping their IDs. The records must
+    /// describe a single tree, with one root, every parent present, and the children of each
+    /// parent indexed from zero without gaps. The ID generator of the tree is seeded past the
+    /// largest loaded ID, so loading fails if a record has the largest representable ID. An empty
+    /// iterator produces an empty tree.
+    pub fn from_flat_records<I>(records: I) -> Result<Self, FlatRecordError<NodeRefId<R>>>
+    where
+        I: IntoIterator<Item = FlatRecord<NodeRefId<R>, <R::Inner as TreeNode>::Data>>,
+        G: SeedableGenerator,
+        NodeRefId<R>: Into<u64>,
+    {
+        let mut nodes: HashMap<NodeRefId<R>, R> = HashMap::new();
+        let mut links = Vec::new();
+        let mut root = None;
+        let allocator = R::Allocator::default();
+
+        for record in records {
+            let node = R::new_in(R::Inner::new(record.id, record.data, None), &allocator);
+            match nodes.entry(record.id) {
+                Entry::Occupied(_) => return Err(FlatRecordError::DuplicateId { id: record.id }),
+                Entry::Vacant(entry) => entry.insert(node),
+            };
+
+            match record.parent {
+                Some(parent) => links.push((parent, record.index, record.id)),
+                None => match root {
+                    Some(first) => {
+                        return Err(FlatRecordError::MultipleRoots {
+                            first,
+                            second: record.id,
+                        })
+                    }
+                    None => root = Some(record.id),
+                },
+            }
+        }
+
+        let seed = match nodes.keys().max() {
+            Some(&id) => id
+                .into()
+                .checked_add(1)
+                .ok_or(FlatRecordError::IdsExhausted { id })?,
+            None => 0,
+        };
+        let generator = G::from_seed(seed);
+
+        let Some(root_id) = root else {
+            if nodes.is_empty() {
+                let mut tree = Tree::new();
+                tree.node_id_generator = Some(generator);
+                return Ok(tree);
+            }
+            return Err(FlatRecordError::NoRoot);
+        };
+
+        // Group the children of each parent, ordered by index
+        let mut children: HashMap<NodeRefId<R>, Vec<(usize, NodeRefId<R>)>> = HashMap::new();
+        for (parent, index, id) in links {
+            if !nodes.contains_key(&parent) {
+                return Err(FlatRecordError::Orphan { id, parent });
+            }
+            children.entry(parent).or_default().push((index, id));
+        }
+
+        for (parent, mut entries) in children {
+            entries.sort_unstable_by_key(|(index, _)| *index);
+
+            let mut linked = Vec::with_capacity(entries.len());
+            for (expected, (index, id)) in entries.into_iter().enumerate() {
+                if index < expected {
+                    return Err(FlatRecordError::DuplicateChildIndex { parent, index });
+                }
+                if index > expected {
+                    return Err(FlatRecordError::MissingChildIndex {
+                        parent,
+                        index: expected,
+                    });
+                }
+
+                let mut child = nodes[&id].clone();
+                child.node_mut().set_parent(nodes[&parent].clone());
+                linked.push(child);
+            }
+
+            nodes[&parent].clone().node_mut().set_children(Some(linked));
+        }
+
+        // Records whose ancestors form a cycle are never reached from the root
+        let root = nodes[&root_id].clone();
+        let reached = root.clone().into_iter().count();
+        if reached < nodes.len() {
+            let mut reachable: Vec<NodeRefId<R>> = root
+                .clone()
+                .into_iter()
+                .map(|node| node.node().id())
+                .collect();
+            reachable.sort_unstable();
+
+            let id = nodes
+                .keys()
+                .filter(|id| reachable.binary_search(id).is_err())
+                .min()
+                .copied()
+                .expect("unreachable node");
+            return Err(FlatRecordError::Unreachable { id });
+        }
+
+        let mut tree = Tree::from_node(root.clone(), Some(generator)).with_allocator(allocator);
+        tree.update_positions();
+        rehash_subtree(root, tree.hash_context);
+
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{noderef::arc::NodeRef, test::test_tree, Tree};
+
+    use super::{FlatRecord, FlatRecordError};
+
+    type StringTree = Tree<NodeRef<crate::node::arc::Node<String, crate::NodeId>>>;
+
+    fn record(id: u64, parent: Option<u64>, index: usize) -> FlatRecord<u64, String> {
+        FlatRecord {
+            id,
+            parent,
+            index,
+            data: id.to_string(),
+        }
+    }
+
+    #[traced_test]
+    #[test]
+    fn roundtrip() {
+        let tree: StringTree = test_tree(vec!["a", "b", "c"])
+            .tree()
+            .map(|data| format!("{data:?}"));
+
+        let records: Vec<_> = tree.to_flat_records().collect();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0].parent, None);
+        assert_eq!(
+            records[1..].iter().map(|r| r.index).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+
+        // Records may arrive in any order
+        let loaded = StringTree::from_flat_records(records.iter().rev().cloned()).unwrap();
+        assert!(loaded == tree);
+        assert_eq!(loaded.to_flat_records().collect::<Vec<_>>(), records);
+
+        let empty = StringTree::from_flat_records(Vec::new()).unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[traced_test]
+    #[test]
+    fn invalid() {
+        let load =
+            |records: Vec<FlatRecord<u64, String>>| StringTree::from_flat_records(records).err();
+
+        assert_eq!(
+            load(vec![record(0, None, 0), record(0, Some(0), 0)]),
+            Some(FlatRecordError::DuplicateId { id: 0 })
+        );
+        assert_eq!(
+            load(vec![record(0, None, 0), record(1, None, 0)]),
+            Some(FlatRecordError::MultipleRoots {
+                first: 0,
+                second: 1
+            })
+        );
+        assert_eq!(
+            load(vec![record(0, None, 0), record(1, Some(5), 0)]),
+            Some(FlatRecordError::Orphan { id: 1, parent: 5 })
+        );
+        assert_eq!(
+            load(vec![
+                record(0, None, 0),
+                record(1, Some(0), 0),
+                record(2, Some(0), 0)
+            ]),
+            Some(FlatRecordError::DuplicateChildIndex {
+                parent: 0,
+                index: 0
+            })
+        );
+        assert_eq!(
+            load(vec![record(0, None, 0), record(1, Some(0), 1)]),
+            Some(FlatRecordError::MissingChildIndex {
+                parent: 0,
+                index: 0
+            })
+        );
+        assert_eq!(
+            load(vec![
+                record(0, None, 0),
+                record(1, Some(2), 0),
+                record(2, Some(1), 0)
+            ]),
+            Some(FlatRecordError::Unreachable { id: 1 })
+        );
+        assert_eq!(
+            load(vec![record(1, Some(0), 0), record(0, Some(1), 0)]),
+            Some(FlatRecordError::NoRoot)
+        );
+        assert_eq!(
+            load(vec![record(0, None, 0), record(u64::MAX, Some(0), 0)]),
+            Some(FlatRecordError::IdsExhausted { id: u64::MAX })
+        );
+    }
+}
@@ -0,0 +1,72 @@
+//! Parallel hashing of trees with thread safe nodes, enabled with the `rayon` feature.
+
+use crate::{
+    hash::rehash_subtree_parallel,
+    index::TreeIndex,
+    noderef::{NodeRefId, TreeNodeRef},
+    UniqueGenerator,
+};
+
+use super::{IndexedTree, Tree};
+
+impl<R, G> Tree<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + Send + Sync + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    /// Recompute the subtree hash and size of every node from scratch, hashing sibling
+    /// subtrees in parallel on the rayon thread pool. Requires thread safe node references,
+    /// such as [`noderef::arc`](crate::noderef::arc). Any hashes deferred in
+    /// [`HashMode::Deferred`](crate::HashMode::Deferred) are recomputed as well.
+    pub fn rehash_all_parallel(&mut self) {
+        if let Some(root) = &self.root {
+            rehash_subtree_parallel(root.clone(), self.hash_context);
+        }
+        self.stale_hashes.clear();
+    }
+}
+
+impl<R, G, I> IndexedTree<R, G, I>
+where
+    R: TreeNodeRef + std::fmt::Debug + Send + Sync + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+{
+    /// Recompute every subtree hash in parallel, and rebuild the subtree hash index.
+    /// See [`Tree::rehash_all_parallel`].
+    pub fn rehash_all_parallel(&mut self) {
+        self.tree.rehash_all_parallel();
+        self.index_subtree_hashes();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        test::{test_tree_node, TestNode},
+        TreeNode as _, TreeNodeRef as _,
+    };
+
+    #[traced_test]
+    #[test]
+    fn rehash_all_parallel() {
+        let children = (0..64)
+            .map(|_| TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]))
+            .collect();
+        let mut tree = test_tree_node(children).with_subtree_hash_index();
+        let expected = tree.root().node().get_subtree_hash();
+
+        let mut root = tree.root();
+        root.for_each_mut(|node| {
+            node.node_mut().set_subtree_hash(0);
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+
+        tree.rehash_all_parallel();
+        assert_eq!(tree.root().node().get_subtree_hash(), expected);
+        assert_eq!(tree.validate(), Ok(()));
+    }
+}
@@ -0,0 +1,270 @@
+//! Serde support for [`Tree`] and [`IndexedTree`], enabled with the `serde` feature.
+//!
+//! Trees are represented as nested nodes, each with its ID, data and children, along with the
+//! hash context of the tree. Subtree hashes, sizes and positions are derived from the structure,
+//! so they are recomputed on load rather than stored.
+
+use std::collections::HashSet;
+
+use serde::{
+    de::Error as _, ser::SerializeStruct as _, Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::{
+    hash::rehash_subtree,
+    id::SeedableGenerator,
+    index::TreeIndex,
+    node::{internal::NodeInternal as _, TreeNode},
+    noderef::{NodeRefId, TreeNodeRef},
+    UniqueGenerator,
+};
+
+use super::{IndexedTree, Tree};
+
+/// Borrowed node serialized along with its subtree
+struct NodeSer<'a, R>(&'a R);
+
+impl<R> Serialize for NodeSer<'_, R>
+where
+    R: TreeNodeRef,
+    NodeRefId<R>: Serialize,
+    <R::Inner as TreeNode>::Data: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let node = self.0.node();
+        let children = node.children();
+        let children = children.as_deref().map(|children| &children[..]);
+
+        let mut state = serializer.serialize_struct("Node", 3)?;
+        state.serialize_field("id", &node.id())?;
+        state.serialize_field("data", &*node.data())?;
+        state.serialize_field("children", &ChildrenSer(children.unwrap_or_default()))?;
+        state.end()
+    }
+}
+
+/// Borrowed children of a node, serialized as a sequence of nodes
+struct ChildrenSer<'a, R>(&'a [R]);
+
+impl<R> Serialize for ChildrenSer<'_, R>
+where
+    R: TreeNodeRef,
+    NodeRefId<R>: Serialize,
+    <R::Inner as TreeNode>::Data: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(NodeSer))
+    }
+}
+
+/// Owned node read by deserialization, before it is attached to a tree
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "Id: Deserialize<'de>, D: Deserialize<'de>"))]
+struct NodeRecord<Id, D> {
+    id: Id,
+    data: D,
+    #[serde(default)]
+    children: Vec<NodeRecord<Id, D>>,
+}
+
+impl<Id, D> NodeRecord<Id, D>
+where
+    Id: Copy + Eq + std::hash::Hash + std::fmt::Display,
+{
+    /// Create the nodes of this record and its descendants, linking each child to its parent.
+    /// Fails on the first ID seen twice.
+    fn attach<R>(self, ids: &mut HashSet<Id>, allocator: &R::Allocator) -> Result<R, String>
+    where
+        R: TreeNodeRef,
+        R::Inner: TreeNode<Id = Id, Data = D>,
+    {
+        if !ids.insert(self.id) {
+            return Err(format!("duplicate node ID {}", self.id));
+        }
+
+        let mut node = R::new_in(R::Inner::new(self.id, self.data, None), allocator);
+        if !self.children.is_empty() {
+            let mut children = Vec::with_capacity(self.children.len());
+            for child in self.children {
+                let mut child: R = child.attach(ids, allocator)?;
+                child.node_mut().set_parent(node.clone());
+                children.push(child);
+            }
+            node.node_mut().set_children(Some(children));
+        }
+
+        Ok(node)
+    }
+}
+
+/// Serialized form of a tree
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "Id: Deserialize<'de>, D: Deserialize<'de>"))]
+struct TreeRecord<Id, D> {
+    #[serde(default)]
+    hash_context: u64,
+    root: Option<NodeRecord<Id, D>>,
+}
+
+/// Serializes the hash context and the nested nodes of the tree. Tombstoned nodes are not
+/// serialized.
+impl<R, G> Serialize for Tree<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    NodeRefId<R>: Serialize,
+    <R::Inner as TreeNode>::Data: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Tree", 2)?;
+        state.serialize_field("hash_context", &self.hash_context)?;
+        state.serialize_field("root", &self.root.as_ref().map(NodeSer))?;
+        state.end()
+    }
+}
+
+/// Deserializes a tree with the node IDs it was serialized with, recomputing subtree hashes and
+/// positions. The ID generator of the tree is seeded past the largest loaded ID, so new nodes
+/// don't collide with loaded ones. Fails if an ID appears more than once, or if a node has the
+/// largest representable ID.
+impl<'de, R, G> Deserialize<'de> for Tree<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: SeedableGenerator<Output = NodeRefId<R>> + 'static,
+    NodeRefId<R>: Deserialize<'de> + Into<u64>,
+    <R::Inner as TreeNode>::Data: Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let record =
+            TreeRecord::<NodeRefId<R>, <R::Inner as TreeNode>::Data>::deserialize(deserializer)?;
+
+        let mut ids = HashSet::new();
+        let allocator = R::Allocator::default();
+        let root: Option<R> = record
+            .root
+            .map(|root| root.attach(&mut ids, &allocator))
+            .transpose()
+            .map_err(D::Error::custom)?;
+
+        let seed = match ids.into_iter().max() {
+            Some(id) => id
+                .into()
+                .checked_add(1)
+                .ok_or_else(|| D::Error::custom("largest node ID leaves no IDs for new nodes"))?,
+            None => 0,
+        };
+        let generator = G::from_seed(seed);
+
+        let Some(root) = root else {
+            let mut tree = Tree::new();
+            tree.node_id_generator = Some(generator);
+            tree.hash_context = record.hash_context;
+            return Ok(tree);
+        };
+
+        let mut tree = Tree::from_node(root.clone(), Some(generator)).with_allocator(allocator);
+        tree.hash_context = record.hash_context;
+        tree.update_positions();
+        rehash_subtree(root, tree.hash_context);
+
+        Ok(tree)
+    }
+}
+
+/// Serializes the tree of an [`IndexedTree`]. Indexes are rebuilt on load.
+impl<R, G, I> Serialize for IndexedTree<R, G, I>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+    NodeRefId<R>: Serialize,
+    <R::Inner as TreeNode>::Data: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.tree.serialize(serializer)
+    }
+}
+
+impl<'de, R, G, I> Deserialize<'de> for IndexedTree<R, G, I>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: SeedableGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+    NodeRefId<R>: Deserialize<'de> + Into<u64>,
+    <R::Inner as TreeNode>::Data: Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Tree::deserialize(deserializer).map(IndexedTree::from_tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        noderef::arc::NodeRef, test::test_tree, IndexedTree, Tree, TreeNode as _, TreeNodeRef as _,
+    };
+
+    type StringNodeRef = NodeRef<crate::node::arc::Node<String, crate::NodeId>>;
+    type StringTree = Tree<StringNodeRef>;
+
+    #[traced_test]
+    #[test]
+    fn roundtrip() {
+        let mut tree: StringTree = test_tree(vec!["a", "b", "c"])
+            .tree()
+            .map(|data| format!("{data:?}"));
+        tree.set_hash_context("context");
+
+        let json = serde_json::to_string(&tree).unwrap();
+        let loaded: StringTree = serde_json::from_str(&json).unwrap();
+
+        let nodes = |tree: &StringTree| -> Vec<_> {
+            tree.root()
+                .into_iter()
+                .map(|node| {
+                    let node = node.node();
+                    (node.id(), node.data().clone(), node.get_position().cloned())
+                })
+                .collect()
+        };
+        assert_eq!(nodes(&loaded), nodes(&tree));
+        assert_eq!(loaded.hash_context(), tree.hash_context());
+        assert!(loaded == tree);
+
+        // New IDs don't collide with loaded IDs
+        let max = nodes(&tree).into_iter().map(|(id, ..)| id).max().unwrap();
+        assert!(loaded.generate_id() > max);
+
+        let mut indexed: IndexedTree<StringNodeRef> = serde_json::from_str(&json).unwrap();
+        assert_eq!(indexed.validate(), Ok(()));
+        assert_eq!(indexed.node_count(), 4);
+        let root = indexed.root().node().id();
+        indexed.append_child(root, "d".to_string()).unwrap();
+        assert_eq!(indexed.validate(), Ok(()));
+
+        let empty: StringTree = serde_json::from_str(r#"{"root":null}"#).unwrap();
+        assert!(empty.is_empty());
+
+        let empty: IndexedTree<StringNodeRef> = serde_json::from_str(r#"{"root":null}"#).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(empty.validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn duplicate_ids() {
+        let json = r#"{"root":{"id":1,"data":"a","children":[{"id":1,"data":"b"}]}}"#;
+        let err = serde_json::from_str::<StringTree>(json).unwrap_err();
+        assert!(err.to_string().contains("duplicate node ID 1"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn ids_exhausted() {
+        let json = format!(r#"{{"root":{{"id":{},"data":"a"}}}}"#, u64::MAX);
+        let err = serde_json::from_str::<StringTree>(&json).unwrap_err();
+        assert!(err.to_string().contains("no IDs for new nodes"));
+    }
+}
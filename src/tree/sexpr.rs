@@ -0,0 +1,359 @@
+//! S-expression representation of a [`Tree`], where each node is written as the text of its
+//! data, and nodes with children as a list of their data followed by their children, such as
+//! `(root (a 1 2) b)`. Atoms containing whitespace, parentheses, quotes or semicolons are quoted,
+//! and `;` starts a comment running to the end of the line.
+
+use std::{fmt::Write as _, str::FromStr};
+
+use crate::{
+    hash::rehash_subtree,
+    node::{internal::NodeInternal as _, TreeNode},
+    noderef::{NodeRefId, TreeNodeRef},
+    UniqueGenerator,
+};
+
+use super::Tree;
+
+/// Error returned by [`Tree::from_sexpr`], with the byte offset in the input where it occurred
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SexprError {
+    /// The input ended inside a list or quoted atom
+    UnexpectedEnd,
+
+    /// A character which can't appear at this position, such as a list not starting with an atom
+    Unexpected { position: usize, found: char },
+
+    /// Input after the end of the root node
+    TrailingInput { position: usize },
+
+    /// The text of an atom could not be parsed as node data
+    InvalidAtom { position: usize, message: String },
+}
+
+impl std::fmt::Display for SexprError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SexprError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            SexprError::Unexpected { position, found } => {
+                write!(f, "unexpected {found:?} at {position}")
+            }
+            SexprError::TrailingInput { position } => {
+                write!(f, "input continues after the root node at {position}")
+            }
+            SexprError::InvalidAtom { position, message } => {
+                write!(f, "invalid atom at {position}: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SexprError {}
+
+/// Token of an s-expression
+enum Token {
+    Open,
+    Close,
+    Atom(String),
+}
+
+/// Split s-expression input into tokens, skipping whitespace and comments
+struct Tokens<'a> {
+    input: &'a str,
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            input,
+            chars: input.char_indices().peekable(),
+        }
+    }
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = Result<(usize, Token), SexprError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Skip whitespace and comments
+        loop {
+            match self.chars.peek() {
+                Some((_, c)) if c.is_whitespace() => {
+                    self.chars.next();
+                }
+                Some((_, ';')) => while self.chars.next_if(|(_, c)| *c != '\n').is_some() {},
+                _ => break,
+            }
+        }
+
+        let (start, c) = self.chars.next()?;
+        let token = match c {
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '"' => {
+                let mut atom = String::new();
+                loop {
+                    match self.chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match self.chars.next() {
+                            Some((_, c)) => atom.push(c),
+                            None => return Some(Err(SexprError::UnexpectedEnd)),
+                        },
+                        Some((_, c)) => atom.push(c),
+                        None => return Some(Err(SexprError::UnexpectedEnd)),
+                    }
+                }
+                Token::Atom(atom)
+            }
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) = self.chars.next_if(|(_, c)| !is_delimiter(*c)) {
+                    end = i + c.len_utf8();
+                }
+                Token::Atom(self.input[start..end].to_string())
+            }
+        };
+
+        Some(Ok((start, token)))
+    }
+}
+
+/// Characters ending an unquoted atom
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '"' | ';')
+}
+
+/// Write an atom, quoting it if it would otherwise not be read back as a single atom
+fn write_atom(out: &mut String, atom: &str) {
+    if !atom.is_empty() && !atom.contains(is_delimiter) && !atom.contains('\\') {
+        out.push_str(atom);
+        return;
+    }
+
+    out.push('"');
+    for c in atom.chars() {
+        if matches!(c, '"' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+}
+
+impl<R, G> Tree<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    /// Parse a tree from an s-expression such as `(root (a 1 2) b)`, parsing the data of each
+    /// node from the text of its atom. Nodes are assigned new IDs from a default generator.
+    /// Empty input produces an empty tree.
+    pub fn from_sexpr(input: &str) -> Result<Self, SexprError>
+    where
+        <R::Inner as TreeNode>::Data: FromStr,
+        <<R::Inner as TreeNode>::Data as FromStr>::Err: std::fmt::Display,
+    {
+        let mut tree = Tree::new();
+        tree.node_id_generator = Some(G::default());
+
+        let mut tokens = Tokens::new(input);
+        let mut root: Option<R> = None;
+
+        // Lists being parsed, with the children parsed so far
+        let mut open: Vec<(R, Vec<R>)> = Vec::new();
+
+        let new_node = |tree: &Self, position: usize, atom: String| {
+            let data =
+                atom.parse()
+                    .map_err(|e: <<R::Inner as TreeNode>::Data as FromStr>::Err| {
+                        SexprError::InvalidAtom {
+                            position,
+                            message: e.to_string(),
+                        }
+                    })?;
+            Ok::<_, SexprError>(R::new_in(
+                R::Inner::new(tree.generate_id(), data, None),
+                tree.allocator(),
+            ))
+        };
+
+        while let Some(token) = tokens.next() {
+            let (position, token) = token?;
+            if root.is_some() {
+                return Err(SexprError::TrailingInput { position });
+            }
+
+            let mut node = match token {
+                Token::Open => match tokens.next().transpose()? {
+                    Some((position, Token::Atom(atom))) => {
+                        let node = new_node(&tree, position, atom)?;
+                        open.push((node, Vec::new()));
+                        continue;
+                    }
+                    Some((position, _)) => {
+                        return Err(SexprError::Unexpected {
+                            position,
+                            found: input[position..].chars().next().unwrap_or_default(),
+                        })
+                    }
+                    None => return Err(SexprError::UnexpectedEnd),
+                },
+                Token::Close => {
+                    let Some((mut node, children)) = open.pop() else {
+                        return Err(SexprError::Unexpected {
+                            position,
+                            found: ')',
+                        });
+                    };
+                    if !children.is_empty() {
+                        node.node_mut().set_children(Some(children));
+                    }
+                    node
+                }
+                Token::Atom(atom) => new_node(&tree, position, atom)?,
+            };
+
+            match open.last_mut() {
+                Some((parent, children)) => {
+                    node.node_mut().set_parent(parent.clone());
+                    children.push(node);
+                }
+                None => root = Some(node),
+            }
+        }
+
+        if !open.is_empty() {
+            return Err(SexprError::UnexpectedEnd);
+        }
+
+        if let Some(root) = root {
+            tree.root = Some(root.clone());
+            tree.update_positions();
+            rehash_subtree(root, tree.hash_context);
+        }
+
+        Ok(tree)
+    }
+
+    /// Write the tree as an s-expression which [`Tree::from_sexpr`] reads back, with each node
+    /// written as the [`Display`](std::fmt::Display) text of its data. Empty trees are written as
+    /// an empty string.
+    pub fn to_sexpr(&self) -> String
+    where
+        <R::Inner as TreeNode>::Data: std::fmt::Display,
+    {
+        let mut out = String::new();
+        let Some(root) = &self.root else {
+            return out;
+        };
+
+        // Nodes to write, or None to close the innermost list
+        let mut stack = vec![Some(root.clone())];
+        let mut atom = String::new();
+
+        while let Some(next) = stack.pop() {
+            let Some(node) = next else {
+                out.push(')');
+                continue;
+            };
+
+            if !out.is_empty() && !out.ends_with('(') {
+                out.push(' ');
+            }
+
+            let children: Vec<R> = {
+                let inner = node.node();
+                atom.clear();
+                let _ = write!(atom, "{}", *inner.data());
+
+                let children = inner.children();
+                children
+                    .map(|children| children.iter().rev().cloned().collect())
+                    .unwrap_or_default()
+            };
+
+            if children.is_empty() {
+                write_atom(&mut out, &atom);
+            } else {
+                out.push('(');
+                write_atom(&mut out, &atom);
+                stack.push(None);
+                stack.extend(children.into_iter().map(Some));
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{noderef::arc::NodeRef, TreeNode as _, TreeNodeRef as _};
+
+    use super::SexprError;
+
+    type StringTree = crate::Tree<NodeRef<crate::node::arc::Node<String, crate::NodeId>>>;
+    type NumberTree = crate::Tree<NodeRef<crate::node::arc::Node<u32, crate::NodeId>>>;
+
+    #[traced_test]
+    #[test]
+    fn roundtrip() {
+        let tree =
+            StringTree::from_sexpr("(root ; comment\n  (a 1 2)\n  (\"b c\" \"\" \"(\\\"q\\\")\"))")
+                .unwrap();
+
+        let data: Vec<_> = tree
+            .root()
+            .into_iter()
+            .map(|node| node.node().data().clone())
+            .collect();
+        assert_eq!(data, ["root", "a", "1", "2", "b c", "", "(\"q\")"]);
+        assert_eq!(tree.root().node().num_children(), 2);
+
+        let sexpr = tree.to_sexpr();
+        assert_eq!(sexpr, r#"(root (a 1 2) ("b c" "" "(\"q\")"))"#);
+        assert!(StringTree::from_sexpr(&sexpr).unwrap() == tree);
+
+        assert_eq!(StringTree::from_sexpr("leaf").unwrap().to_sexpr(), "leaf");
+        assert!(StringTree::from_sexpr("  ").unwrap().is_empty());
+        assert_eq!(StringTree::new().to_sexpr(), "");
+    }
+
+    #[traced_test]
+    #[test]
+    fn invalid() {
+        assert_eq!(
+            StringTree::from_sexpr("(a (b)").err(),
+            Some(SexprError::UnexpectedEnd)
+        );
+        assert_eq!(
+            StringTree::from_sexpr("(a \"b)").err(),
+            Some(SexprError::UnexpectedEnd)
+        );
+        assert_eq!(
+            StringTree::from_sexpr("(a ((b)))").err(),
+            Some(SexprError::Unexpected {
+                position: 4,
+                found: '('
+            })
+        );
+        assert_eq!(
+            StringTree::from_sexpr("(a) b").err(),
+            Some(SexprError::TrailingInput { position: 4 })
+        );
+        assert_eq!(
+            StringTree::from_sexpr(")").err(),
+            Some(SexprError::Unexpected {
+                position: 0,
+                found: ')'
+            })
+        );
+        assert!(matches!(
+            NumberTree::from_sexpr("(1 2 x)"),
+            Err(SexprError::InvalidAtom { position: 5, .. })
+        ));
+    }
+}
@@ -0,0 +1,533 @@
+//! Compact binary snapshots of an [`IndexedTree`], enabled with the `snapshot` feature.
+//!
+//! Unlike the nested serde representation, a snapshot stores the subtree hash, subtree size and
+//! position of every node, listed in depth first order with the number of children of each.
+//! Loading a snapshot links the nodes and fills the index as they are decoded, so a tree is
+//! restored without rehashing it or walking it to rebuild the index.
+//!
+//! A [`SnapshotReader`] decodes the nodes of a snapshot one at a time from any reader, so large
+//! snapshots can be scanned without loading them as a tree.
+
+use std::{io::Read, marker::PhantomData};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    hash::{HashVersion, IncompatibleHashVersion},
+    id::SeedableGenerator,
+    index::TreeIndex,
+    node::{internal::NodeInternal as _, TreeNode},
+    noderef::{NodeRefId, TreeNodeRef},
+    Error, NodeDepth, NodePosition, UniqueGenerator,
+};
+
+use super::{IndexedTree, Tree};
+
+/// Version of the snapshot layout, checked on load
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Node of a snapshot, followed by the nodes of its subtree
+#[derive(Serialize, Deserialize)]
+struct SnapshotNode<Id, D> {
+    id: Id,
+    data: D,
+    children: usize,
+    subtree_hash: u64,
+    subtree_size: usize,
+    position: (usize, usize, usize),
+}
+
+/// Encoded form of an indexed tree
+#[derive(Serialize, Deserialize)]
+struct Snapshot<Id, D> {
+    version: u32,
+    hash_version: u8,
+    hash_context: u64,
+    nodes: Vec<SnapshotNode<Id, D>>,
+}
+
+/// Fields of a [`Snapshot`] before its nodes. Postcard encodes the length of the node sequence
+/// as a varint, the same as a `usize`, so the header decodes from the start of a snapshot.
+#[derive(Deserialize)]
+struct SnapshotHeader {
+    version: u32,
+    hash_version: u8,
+    hash_context: u64,
+    nodes: usize,
+}
+
+/// Check the layout and hash versions of a snapshot are supported by this release
+fn check_versions(version: u32, hash_version: u8) -> Result<(), Error> {
+    if version != SNAPSHOT_VERSION {
+        return Err(Error::Snapshot(format!(
+            "unsupported snapshot version {version}"
+        )));
+    }
+
+    let found = HashVersion::from_u8(hash_version)
+        .ok_or_else(|| Error::Snapshot(format!("unknown hash version {hash_version}")))?;
+    if found != HashVersion::CURRENT {
+        return Err(IncompatibleHashVersion {
+            expected: HashVersion::CURRENT,
+            found,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+impl<R, G, I> IndexedTree<R, G, I>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+{
+    /// Encode the tree as a binary snapshot, including the subtree hashes and positions of
+    /// every node. Hashes deferred in [`HashMode::Deferred`](crate::HashMode::Deferred) should
+    /// be flushed first, as they are stored as they are.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error>
+    where
+        NodeRefId<R>: Serialize,
+        <R::Inner as TreeNode>::Data: Serialize,
+    {
+        let mut nodes = Vec::with_capacity(self.node_count());
+        if let Some(root) = self.tree.root.clone() {
+            for node in root {
+                let inner = node.node();
+                let data = inner.data().clone();
+                let position = inner
+                    .get_position()
+                    .copied()
+                    .unwrap_or_else(NodePosition::zero);
+
+                nodes.push(SnapshotNode {
+                    id: inner.id(),
+                    data,
+                    children: inner.num_children(),
+                    subtree_hash: inner.get_subtree_hash(),
+                    subtree_size: inner.subtree_size(),
+                    position: (position.depth, position.index, position.child_index),
+                });
+            }
+        }
+
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            hash_version: HashVersion::CURRENT.as_u8(),
+            hash_context: self.hash_context(),
+            nodes,
+        };
+
+        postcard::to_allocvec(&snapshot).map_err(|e| Error::Snapshot(e.to_string()))
+    }
+
+    /// Decode a tree from a snapshot produced by [`IndexedTree::to_bytes`], keeping the stored
+    /// subtree hashes rather than recomputing them. The ID generator of the tree is seeded past
+    /// the largest loaded ID, so snapshots holding the largest representable ID are rejected.
+    /// Snapshots with hashes of another [`HashVersion`] are rejected, as their hashes would not
+    /// match hashes computed by this release.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error>
+    where
+        G: SeedableGenerator,
+        NodeRefId<R>: for<'de> Deserialize<'de> + Into<u64>,
+        <R::Inner as TreeNode>::Data: for<'de> Deserialize<'de>,
+    {
+        let snapshot: Snapshot<NodeRefId<R>, <R::Inner as TreeNode>::Data> =
+            postcard::from_bytes(bytes).map_err(|e| Error::Snapshot(e.to_string()))?;
+
+        check_versions(snapshot.version, snapshot.hash_version)?;
+
+        let mut index = I::new();
+        let mut leaves = Vec::new();
+        let allocator = R::Allocator::default();
+        let mut root: Option<R> = None;
+        let mut max_id: Option<NodeRefId<R>> = None;
+
+        // Nodes whose children are still being decoded, with their expected number of children
+        let mut open: Vec<(R, usize, Vec<R>)> = Vec::new();
+
+        let count = snapshot.nodes.len();
+        for (position, record) in snapshot.nodes.into_iter().enumerate() {
+            if root.is_some() && open.is_empty() {
+                return Err(Error::Snapshot("nodes after the end of the tree".into()));
+            }
+            if index.get(&record.id).is_some() {
+                return Err(Error::Snapshot(format!("duplicate node ID {}", record.id)));
+            }
+
+            let mut node = R::new_in(R::Inner::new(record.id, record.data, None), &allocator);
+            {
+                let (depth, index, child_index) = record.position;
+                let mut inner = node.node_mut();
+                inner.set_subtree_hash(record.subtree_hash);
+                inner.set_subtree_size(record.subtree_size);
+                inner.set_position(NodePosition {
+                    depth,
+                    index,
+                    child_index,
+                });
+                if let Some((parent, ..)) = open.last() {
+                    inner.set_parent(parent.clone());
+                }
+            }
+
+            index.insert(record.id, node.clone());
+            max_id = max_id.max(Some(record.id));
+            if root.is_none() {
+                root = Some(node.clone());
+            }
+
+            // Each child is at least one of the remaining records. This also bounds the
+            // preallocation below, as the count is read from untrusted input.
+            if record.children > count - position - 1 {
+                return Err(Error::Snapshot(format!(
+                    "node {} has more children than remaining nodes",
+                    record.id
+                )));
+            }
+
+            if record.children > 0 {
+                open.push((node, record.children, Vec::with_capacity(record.children)));
+                continue;
+            }
+            leaves.push(node.clone());
+
+            // Close every parent whose last child was just decoded
+            while let Some((_, expected, children)) = open.last_mut() {
+                children.push(node);
+                if children.len() < *expected {
+                    break;
+                }
+
+                let (mut parent, _, children) = open.pop().expect("open parent");
+                parent.node_mut().set_children(Some(children));
+                node = parent;
+            }
+        }
+
+        if !open.is_empty() {
+            return Err(Error::Snapshot("tree ends before all children".into()));
+        }
+
+        let seed = match max_id {
+            Some(id) => id.into().checked_add(1).ok_or_else(|| {
+                Error::Snapshot("largest node ID leaves no IDs for new nodes".into())
+            })?,
+            None => 0,
+        };
+        let generator = G::from_seed(seed);
+        let mut tree = match root {
+            Some(root) => Tree::from_node(root, Some(generator)).with_allocator(allocator),
+            None => {
+                let mut tree = Tree::new();
+                tree.node_id_generator = Some(generator);
+                tree
+            }
+        };
+        tree.hash_context = snapshot.hash_context;
+
+        Ok(IndexedTree::from_parts(tree, index, leaves))
+    }
+}
+
+/// Number of bytes requested from the underlying reader at a time
+const READ_CHUNK: usize = 8 * 1024;
+
+/// Pull-based decoder of a snapshot produced by [`IndexedTree::to_bytes`], yielding the depth and
+/// data of each node in depth first order without building a tree. Only the node being decoded
+/// is buffered, so snapshots larger than memory can be filtered or aggregated.
+///
+/// `Id` must be the node ID type of the tree the snapshot was taken from. Each item is an error
+/// if the snapshot is truncated, malformed or the reader fails, after which iteration ends.
+pub struct SnapshotReader<Rd, Id, D> {
+    reader: Rd,
+    buf: Vec<u8>,
+
+    // Offset of the first undecoded byte in buf
+    start: usize,
+
+    hash_context: u64,
+    node_count: usize,
+    remaining: usize,
+
+    // Number of children still to be decoded for each open ancestor of the next node
+    open: Vec<usize>,
+
+    failed: bool,
+    _marker: PhantomData<fn() -> (Id, D)>,
+}
+
+impl<Rd, Id, D> SnapshotReader<Rd, Id, D>
+where
+    Rd: Read,
+    Id: DeserializeOwned,
+    D: DeserializeOwned,
+{
+    /// Start reading a snapshot, decoding its header. Fails if the snapshot has an unsupported
+    /// layout or [`HashVersion`].
+    pub fn new(reader: Rd) -> Result<Self, Error> {
+        let mut snapshot = Self {
+            reader,
+            buf: Vec::new(),
+            start: 0,
+            hash_context: 0,
+            node_count: 0,
+            remaining: 0,
+            open: Vec::new(),
+            failed: false,
+            _marker: PhantomData,
+        };
+
+        let header: SnapshotHeader = snapshot.decode()?;
+        check_versions(header.version, header.hash_version)?;
+
+        snapshot.hash_context = header.hash_context;
+        snapshot.node_count = header.nodes;
+        snapshot.remaining = header.nodes;
+        Ok(snapshot)
+    }
+
+    /// Hash context of the tree the snapshot was taken from
+    pub fn hash_context(&self) -> u64 {
+        self.hash_context
+    }
+
+    /// Total number of nodes in the snapshot
+    pub fn node_count(&self) -> usize {
+        self.node_count
+    }
+
+    /// Decode the next value, reading more of the snapshot until it is complete
+    fn decode<T: DeserializeOwned>(&mut self) -> Result<T, Error> {
+        loop {
+            match postcard::take_from_bytes(&self.buf[self.start..]) {
+                Ok((value, rest)) => {
+                    self.start = self.buf.len() - rest.len();
+                    return Ok(value);
+                }
+                Err(postcard::Error::DeserializeUnexpectedEnd) => {}
+                Err(e) => return Err(Error::Snapshot(e.to_string())),
+            }
+
+            // Drop decoded bytes before reading more
+            self.buf.drain(..self.start);
+            self.start = 0;
+
+            let mut chunk = [0; READ_CHUNK];
+            match self.reader.read(&mut chunk) {
+                Ok(0) => return Err(Error::Snapshot("unexpected end of snapshot".into())),
+                Ok(read) => self.buf.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(Error::Snapshot(e.to_string())),
+            }
+        }
+    }
+
+    /// Decode the next node, returning its depth and data
+    fn next_node(&mut self) -> Result<(NodeDepth, D), Error> {
+        if self.open.is_empty() && self.remaining < self.node_count {
+            return Err(Error::Snapshot("nodes after the end of the tree".into()));
+        }
+
+        let node: SnapshotNode<Id, D> = self.decode()?;
+        self.remaining -= 1;
+
+        let depth = self.open.len();
+        if let Some(siblings) = self.open.last_mut() {
+            *siblings -= 1;
+        }
+        self.open.push(node.children);
+
+        // Close every node whose children have all been decoded
+        while self.open.last() == Some(&0) {
+            self.open.pop();
+        }
+
+        if self.remaining == 0 && !self.open.is_empty() {
+            return Err(Error::Snapshot("tree ends before all children".into()));
+        }
+
+        Ok((depth, node.data))
+    }
+}
+
+impl<Rd, Id, D> Iterator for SnapshotReader<Rd, Id, D>
+where
+    Rd: Read,
+    Id: DeserializeOwned,
+    D: DeserializeOwned,
+{
+    type Item = Result<(NodeDepth, D), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.remaining == 0 {
+            return None;
+        }
+
+        let node = self.next_node();
+        self.failed = node.is_err();
+        Some(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        noderef::arc::NodeRef,
+        test::{test_tree_node, TestNode},
+        Error, IndexedTree, Tree, TreeNode as _, TreeNodeRef as _,
+    };
+
+    use super::{HashVersion, Snapshot, SnapshotNode, SnapshotReader, SNAPSHOT_VERSION};
+
+    type StringTree = IndexedTree<NodeRef<crate::node::arc::Node<String, crate::NodeId>>>;
+
+    #[traced_test]
+    #[test]
+    fn roundtrip() {
+        let mut tree: StringTree = IndexedTree::from_tree(
+            test_tree_node(vec![
+                TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+                TestNode("b", vec![TestNode("3", vec![TestNode("4", vec![])])]),
+                TestNode("c", vec![]),
+            ])
+            .tree()
+            .map(|data| data.to_string()),
+        );
+        tree.set_hash_context("context");
+
+        let bytes = tree.to_bytes().unwrap();
+        let mut loaded = StringTree::from_bytes(&bytes).unwrap();
+
+        let nodes = |tree: &StringTree| -> Vec<_> {
+            tree.root()
+                .into_iter()
+                .map(|node| {
+                    let node = node.node();
+                    (
+                        node.id(),
+                        node.data().clone(),
+                        node.get_subtree_hash(),
+                        node.subtree_size(),
+                        node.get_position().cloned(),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(nodes(&loaded), nodes(&tree));
+        assert_eq!(loaded.hash_context(), tree.hash_context());
+        assert_eq!(loaded.leaves().len(), tree.leaves().len());
+        assert_eq!(loaded.node_count(), tree.node_count());
+        assert_eq!(loaded.validate(), Ok(()));
+
+        // Loaded trees can be edited, with new IDs not colliding with loaded IDs
+        let root = loaded.root().node().id();
+        loaded.append_child(root, "d".to_string()).unwrap();
+        assert_eq!(loaded.validate(), Ok(()));
+
+        let empty = StringTree::from_bytes(&StringTree::new().to_bytes().unwrap()).unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[traced_test]
+    #[test]
+    fn invalid() {
+        let tree: StringTree = IndexedTree::from_tree(
+            test_tree_node(vec![TestNode("a", vec![]), TestNode("b", vec![])])
+                .tree()
+                .map(|data| data.to_string()),
+        );
+        let bytes = tree.to_bytes().unwrap();
+
+        assert!(matches!(
+            StringTree::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(Error::Snapshot(_))
+        ));
+
+        let mut version = bytes.clone();
+        version[0] = 2;
+        assert_eq!(
+            StringTree::from_bytes(&version).err(),
+            Some(Error::Snapshot("unsupported snapshot version 2".into()))
+        );
+
+        let root = NodeRef::new(crate::node::arc::Node::new(u64::MAX, "a".to_string(), None));
+        let max: StringTree = IndexedTree::from_tree(Tree::from_node(root, None));
+        assert_eq!(
+            StringTree::from_bytes(&max.to_bytes().unwrap()).err(),
+            Some(Error::Snapshot(
+                "largest node ID leaves no IDs for new nodes".into()
+            ))
+        );
+
+        // A corrupt child count must not be trusted for allocation
+        let corrupt = postcard::to_allocvec(&Snapshot {
+            version: SNAPSHOT_VERSION,
+            hash_version: HashVersion::CURRENT.as_u8(),
+            hash_context: 0,
+            nodes: vec![SnapshotNode {
+                id: 0u64,
+                data: "a".to_string(),
+                children: usize::MAX,
+                subtree_hash: 0,
+                subtree_size: 1,
+                position: (0, 0, 0),
+            }],
+        })
+        .unwrap();
+        assert_eq!(
+            StringTree::from_bytes(&corrupt).err(),
+            Some(Error::Snapshot(
+                "node 0 has more children than remaining nodes".into()
+            ))
+        );
+    }
+
+    #[traced_test]
+    #[test]
+    fn reader() {
+        let tree: StringTree = IndexedTree::from_tree(
+            test_tree_node(vec![
+                TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+                TestNode("b", vec![TestNode("3", vec![TestNode("4", vec![])])]),
+                TestNode("c", vec![]),
+            ])
+            .tree()
+            .map(|data| data.to_string()),
+        );
+        let bytes = tree.to_bytes().unwrap();
+
+        let reader = SnapshotReader::<_, crate::NodeId, String>::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.node_count(), tree.node_count());
+        assert_eq!(reader.hash_context(), tree.hash_context());
+
+        let events: Vec<_> = reader.map(Result::unwrap).collect();
+        let expected: Vec<_> = tree
+            .root()
+            .into_iter()
+            .map(|node| {
+                let node = node.node();
+                (node.get_position().unwrap().depth, node.data().clone())
+            })
+            .collect();
+        assert_eq!(events, expected);
+
+        // Truncated snapshots fail once the missing node is reached, then end
+        let results: Vec<_> =
+            SnapshotReader::<_, crate::NodeId, String>::new(&bytes[..bytes.len() - 1])
+                .unwrap()
+                .collect();
+        assert_eq!(results.len(), tree.node_count());
+        assert_eq!(
+            results.last().cloned().unwrap().err(),
+            Some(Error::Snapshot("unexpected end of snapshot".into()))
+        );
+
+        let empty = StringTree::new().to_bytes().unwrap();
+        let mut reader = SnapshotReader::<_, crate::NodeId, String>::new(empty.as_slice()).unwrap();
+        assert!(reader.next().is_none());
+    }
+}
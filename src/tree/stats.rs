@@ -0,0 +1,135 @@
+//! Size statistics of an [`IndexedTree`].
+
+use std::mem::size_of;
+
+use crate::{
+    index::TreeIndex,
+    noderef::{NodeRefId, TreeNodeRef},
+    UniqueGenerator,
+};
+
+use super::IndexedTree;
+
+/// Counts and estimated memory usage of an [`IndexedTree`], returned by [`IndexedTree::stats`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TreeStats {
+    /// Number of nodes reachable from the root
+    pub nodes: usize,
+
+    /// Number of entries in the index, including tombstoned nodes awaiting collection
+    pub index_entries: usize,
+
+    /// Number of leaves
+    pub leaves: usize,
+
+    /// Maximum depth of the tree
+    pub depth: usize,
+
+    /// Estimated heap bytes of the nodes, excluding any heap memory owned by the node data
+    pub node_bytes: usize,
+
+    /// Estimated heap bytes of the index, leaves and built-in secondary indexes. Indexes
+    /// attached with [`IndexedTree::with_index`] are not included.
+    pub index_bytes: usize,
+}
+
+impl std::fmt::Display for TreeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} nodes, {} index entries, {} leaves, depth {}, ~{} node bytes, ~{} index bytes",
+            self.nodes,
+            self.index_entries,
+            self.leaves,
+            self.depth,
+            self.node_bytes,
+            self.index_bytes
+        )
+    }
+}
+
+impl<R, G, I> IndexedTree<R, G, I>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+{
+    /// Get the node counts and estimated memory usage of the tree, without traversing it
+    pub fn stats(&self) -> TreeStats {
+        let id = size_of::<NodeRefId<R>>();
+        let node_ref = size_of::<R>();
+
+        let nodes = self.node_count();
+        let index_entries = self.index.len();
+
+        // Each node is a shared allocation with reference counts, referenced from the children
+        // of its parent
+        let node_bytes = nodes * (size_of::<R::Inner>() + 2 * size_of::<usize>() + node_ref);
+
+        let mut index_bytes = index_entries * (id + node_ref)
+            + self.leaves.capacity() * node_ref
+            + self.parents.len() * 2 * id
+            + self.shape.nodes * id;
+
+        if let Some(hashes) = &self.subtree_hashes {
+            index_bytes += hashes.len() * 2 * (id + size_of::<u64>());
+        }
+
+        // Paths are stored twice, with a length proportional to the depth of the node
+        if let Some(paths) = &self.paths {
+            let path = size_of::<Vec<usize>>() + self.depth() * size_of::<usize>();
+            index_bytes += paths.len() * 2 * (id + path);
+        }
+
+        TreeStats {
+            nodes,
+            index_entries,
+            leaves: self.leaves.len(),
+            depth: self.depth(),
+            node_bytes,
+            index_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        test::{test_tree_node, TestNode},
+        RemovalMode,
+    };
+
+    #[traced_test]
+    #[test]
+    fn stats() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+
+        let stats = tree.stats();
+        assert_eq!(stats.nodes, 4);
+        assert_eq!(stats.index_entries, 4);
+        assert_eq!(stats.leaves, 2);
+        assert_eq!(stats.depth, 2);
+        assert!(stats.node_bytes > 0);
+
+        // Optional indexes are included in the estimate
+        let tree_bytes = stats.index_bytes;
+        tree = tree.with_path_index();
+        assert!(tree.stats().index_bytes > tree_bytes);
+
+        // Tombstoned nodes remain in the index
+        tree.set_removal_mode(RemovalMode::Tombstone);
+        let a = tree.find(|data| *data == "a").unwrap();
+        tree.remove_node(&a).unwrap();
+
+        let stats = tree.stats();
+        assert_eq!(stats.nodes, 2);
+        assert_eq!(stats.index_entries, 4);
+        assert_eq!(stats.leaves, 1);
+        assert_eq!(stats.depth, 1);
+    }
+}
@@ -0,0 +1,899 @@
+//! Keeping a replica of a tree converged with an authoritative tree over a byte stream, such as
+//! a socket, enabled with the `sync` feature.
+//!
+//! Messages are framed with a version byte and a big-endian `u32` payload length, followed by
+//! the payload encoded with postcard. Frames with payloads longer than [`MAX_PAYLOAD_LEN`] are
+//! rejected before the payload is read. A session runs as follows:
+//!
+//! 1. The client sends [`SyncMessage::Hello`] with the root hash of its replica.
+//! 2. The server replies [`SyncMessage::Converged`] if the replica matches its tree, node IDs
+//!    included. Otherwise it replies [`SyncMessage::Patch`], diffing its tree against its copy
+//!    of the replica, or [`SyncMessage::Snapshot`] with the whole tree if the client holds an
+//!    unknown tree, or the patch doesn't converge the copy.
+//! 3. The client applies the patch or snapshot and replies [`SyncMessage::Ack`] with its new
+//!    root hash, which the server answers as in step 2, until the trees have converged.
+//!
+//! The server applies each patch to its copy of the replica, as the client will, and sends
+//! along the IDs of the patched nodes which differ from the nodes at the same position of its
+//! tree. The client takes those IDs after applying the patch, so the replica shares the IDs of
+//! the tree, even for nodes which the diff matched with nodes of another ID.
+
+use std::io::{Read, Write};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    hash::{rehash_subtree, HashVersion, IncompatibleHashVersion},
+    id::SeedableGenerator,
+    index::TreeIndex,
+    node::{internal::NodeInternal, TreeNode},
+    noderef::{NodeRefId, TreeNodeRef},
+    Error, FlatRecord, TreeDiff, TreePatch, TreePatchOperation, UniqueGenerator,
+};
+
+use super::{flat_records, IndexedTree, Tree};
+
+/// Version of the sync protocol, written at the start of every frame
+pub const SYNC_PROTOCOL_VERSION: u8 = 1;
+
+/// Largest payload length of a frame, so a peer can't force the allocation of a buffer for an
+/// arbitrarily long payload
+pub const MAX_PAYLOAD_LEN: usize = 64 * 1024 * 1024;
+
+/// Length of the frame header, holding the protocol version and payload length
+const HEADER_LEN: usize = 5;
+
+/// Operation of a [`TreePatch`] encoded for the wire, with destination nodes referred to by ID
+/// and source subtrees as [`FlatRecord`]s
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WireOperation<Id, D> {
+    InsertChild {
+        dest: Id,
+        index: usize,
+        source: Vec<FlatRecord<Id, D>>,
+    },
+    DeleteChild {
+        dest: Id,
+        index: usize,
+    },
+    ReplaceChild {
+        dest: Id,
+        index: usize,
+        source: Vec<FlatRecord<Id, D>>,
+    },
+    RemoveChildren {
+        dest: Id,
+    },
+    SetChildren {
+        dest: Id,
+        nodes: Vec<Vec<FlatRecord<Id, D>>>,
+    },
+    ReplaceNode {
+        dest: Id,
+        data: D,
+    },
+}
+
+/// Message exchanged by a [`SyncSession`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SyncMessage<Id, D> {
+    /// Sent by the client to start a session, with the root hash of its replica
+    Hello {
+        hash_version: u8,
+        hash_context: u64,
+        root_hash: Option<u64>,
+    },
+
+    /// Sent by the server once the replica matches its tree
+    Converged { root_hash: Option<u64> },
+
+    /// Sent by the server with the operations converting the replica into its tree, and the
+    /// IDs taken by nodes of the patched replica, by their position in depth first order
+    Patch {
+        operations: Vec<WireOperation<Id, D>>,
+        ids: Vec<(usize, Id)>,
+    },
+
+    /// Sent by the server with its whole tree, when the replica can't be patched
+    Snapshot {
+        hash_context: u64,
+        records: Vec<FlatRecord<Id, D>>,
+    },
+
+    /// Sent by the client after applying a patch or snapshot, with the new root hash of its
+    /// replica
+    Ack { root_hash: Option<u64> },
+}
+
+impl<Id, D> SyncMessage<Id, D>
+where
+    Id: Serialize,
+    D: Serialize,
+{
+    /// Encode the message as a frame
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let header = vec![SYNC_PROTOCOL_VERSION, 0, 0, 0, 0];
+        let mut frame =
+            postcard::to_extend(self, header).map_err(|e| Error::Sync(e.to_string()))?;
+
+        let len = frame.len() - HEADER_LEN;
+        if len > MAX_PAYLOAD_LEN {
+            return Err(Self::oversized(len));
+        }
+        frame[1..HEADER_LEN].copy_from_slice(&(len as u32).to_be_bytes());
+
+        Ok(frame)
+    }
+
+    /// Encode the message and write the frame to `writer`
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let frame = self.encode().map_err(std::io::Error::other)?;
+        writer.write_all(&frame)
+    }
+}
+
+impl<Id, D> SyncMessage<Id, D> {
+    fn oversized(len: usize) -> Error {
+        Error::Sync(format!(
+            "payload of {len} bytes exceeds the maximum of {MAX_PAYLOAD_LEN}"
+        ))
+    }
+}
+
+impl<Id, D> SyncMessage<Id, D>
+where
+    Id: DeserializeOwned,
+    D: DeserializeOwned,
+{
+    /// Decode the frame at the start of `buf`, returning the message and the length of the
+    /// frame, or `None` if `buf` doesn't hold a whole frame yet. Fails as soon as the header
+    /// announces a payload longer than [`MAX_PAYLOAD_LEN`].
+    pub fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, Error> {
+        let Some(header) = buf.get(..HEADER_LEN) else {
+            return Ok(None);
+        };
+        let len = Self::check_header(header)?;
+
+        let Some(payload) = buf.get(HEADER_LEN..HEADER_LEN + len) else {
+            return Ok(None);
+        };
+        let message = postcard::from_bytes(payload).map_err(|e| Error::Sync(e.to_string()))?;
+
+        Ok(Some((message, HEADER_LEN + len)))
+    }
+
+    /// Read and decode one frame from `reader`, failing before the payload is read if it is
+    /// longer than [`MAX_PAYLOAD_LEN`]
+    pub fn read_from<Rd: Read>(reader: &mut Rd) -> std::io::Result<Self> {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        let len = Self::check_header(&header).map_err(std::io::Error::other)?;
+
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload)?;
+        postcard::from_bytes(&payload).map_err(std::io::Error::other)
+    }
+
+    /// Check the protocol version and payload length of a frame header, returning the payload
+    /// length
+    fn check_header(header: &[u8]) -> Result<usize, Error> {
+        if header[0] != SYNC_PROTOCOL_VERSION {
+            return Err(Error::Sync(format!(
+                "unsupported protocol version {}",
+                header[0]
+            )));
+        }
+
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if len > MAX_PAYLOAD_LEN {
+            return Err(Self::oversized(len));
+        }
+        Ok(len)
+    }
+}
+
+/// Message carrying the IDs and data of nodes of type `R`
+type NodeMessage<R> = SyncMessage<NodeRefId<R>, <<R as TreeNodeRef>::Inner as TreeNode>::Data>;
+
+/// Root subtree hash of a tree, or `None` for an empty tree
+fn root_hash<R, G>(tree: &Tree<R, G>) -> Option<u64>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    tree.root
+        .as_ref()
+        .map(|root| root.node().get_subtree_hash())
+}
+
+/// One side of a sync session. The server keeps a copy of the replica as of the last message
+/// sent to the client, so later changes are sent as patches. The client side holds no state.
+pub struct SyncSession<R, G = crate::IdGenerator>
+where
+    R: TreeNodeRef + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    // Replica as the client is expected to hold it after applying the last message sent
+    shadow: Option<IndexedTree<R, G>>,
+}
+
+impl<R, G> Default for SyncSession<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R, G> SyncSession<R, G>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    pub fn new() -> Self {
+        Self { shadow: None }
+    }
+
+    /// Start a session from the client, with the root hash of `replica`
+    pub fn hello<I>(&self, replica: &IndexedTree<R, G, I>) -> NodeMessage<R>
+    where
+        I: TreeIndex<R>,
+    {
+        SyncMessage::Hello {
+            hash_version: HashVersion::CURRENT.as_u8(),
+            hash_context: replica.hash_context(),
+            root_hash: root_hash(&replica.tree),
+        }
+    }
+
+    /// Handle a message from the client on the server, returning the reply to send. Fails if
+    /// the client hashes with another [`HashVersion`], or the message is not sent by clients.
+    pub fn respond<I>(
+        &mut self,
+        tree: &IndexedTree<R, G, I>,
+        message: NodeMessage<R>,
+    ) -> Result<NodeMessage<R>, Error>
+    where
+        G: SeedableGenerator,
+        I: TreeIndex<R>,
+        R::Data: Clone,
+        NodeRefId<R>: Into<u64>,
+    {
+        match message {
+            SyncMessage::Hello {
+                hash_version,
+                hash_context,
+                root_hash,
+            } => {
+                let found = HashVersion::from_u8(hash_version)
+                    .ok_or_else(|| Error::Sync(format!("unknown hash version {hash_version}")))?;
+                if found != HashVersion::CURRENT {
+                    return Err(IncompatibleHashVersion {
+                        expected: HashVersion::CURRENT,
+                        found,
+                    }
+                    .into());
+                }
+
+                // Hashes computed in another context can't be compared
+                if hash_context != tree.hash_context() {
+                    self.shadow = None;
+                    return Ok(self.reply(&tree.tree, None));
+                }
+
+                Ok(self.reply(&tree.tree, root_hash))
+            }
+            SyncMessage::Ack { root_hash } => Ok(self.reply(&tree.tree, root_hash)),
+            _ => Err(Error::Sync("unexpected message from client".into())),
+        }
+    }
+
+    /// Reply to a client holding a replica with the provided root hash
+    fn reply(&mut self, tree: &Tree<R, G>, client_hash: Option<u64>) -> NodeMessage<R>
+    where
+        G: SeedableGenerator,
+        R::Data: Clone,
+        NodeRefId<R>: Into<u64>,
+    {
+        // Patch the copy of the replica, if the client holds it
+        let shadow = self
+            .shadow
+            .take()
+            .filter(|shadow| root_hash(&shadow.tree) == client_hash);
+        if let Some(mut shadow) = shadow {
+            if let Some(message) = Self::patch(&mut shadow, tree) {
+                self.shadow = Some(shadow);
+                return message;
+            }
+        }
+
+        self.shadow = Some(IndexedTree::from_tree(tree.map(Clone::clone)));
+        SyncMessage::Snapshot {
+            hash_context: tree.hash_context,
+            records: tree.to_flat_records().collect(),
+        }
+    }
+
+    /// Patch the copy of the replica to match `tree`, returning the message which patches the
+    /// replica the same way. Returns `None` if the patch doesn't converge the copy, or the root
+    /// itself differs, so the client should be sent a snapshot.
+    fn patch(shadow: &mut IndexedTree<R, G>, tree: &Tree<R, G>) -> Option<NodeMessage<R>>
+    where
+        G: SeedableGenerator,
+        R::Data: Clone,
+        NodeRefId<R>: Into<u64>,
+    {
+        let hash = root_hash(tree);
+
+        // Emptying or filling the replica replaces it entirely
+        let (Some(replica_root), Some(root)) = (shadow.tree.root.clone(), tree.root.clone()) else {
+            return (shadow.is_empty() && tree.is_empty())
+                .then_some(SyncMessage::Converged { root_hash: None });
+        };
+
+        let operations: Vec<_> = if root_hash(&shadow.tree) == hash {
+            Vec::new()
+        } else {
+            // The diff only describes changes below the root
+            if replica_root.node().data_xxhash() != root.node().data_xxhash() {
+                debug!("Sending a snapshot, as the root differs");
+                return None;
+            }
+
+            let patch = TreeDiff::new(replica_root, root).diff();
+            patch.operations().iter().map(encode_operation).collect()
+        };
+
+        if let Err(e) = apply_operations(shadow, operations.clone()) {
+            debug!("Sending a snapshot, as the patch can't be decoded: {e}");
+            return None;
+        }
+        if root_hash(&shadow.tree) != hash {
+            debug!("Sending a snapshot, as the patch doesn't converge the replica");
+            return None;
+        }
+
+        let ids = renumbered(shadow, tree)?;
+        if operations.is_empty() && ids.is_empty() {
+            return Some(SyncMessage::Converged { root_hash: hash });
+        }
+        renumber(shadow, &ids).ok()?;
+
+        Some(SyncMessage::Patch { operations, ids })
+    }
+
+    /// Handle a message from the server on the client, applying it to `replica`. Returns the
+    /// reply to send, or `None` once the replica has converged. A snapshot replaces the
+    /// replica with a new tree, keeping its event listeners, but not its attached indexes. A
+    /// patch seeds the ID generator of the replica past the IDs it holds.
+    pub fn apply<I>(
+        &mut self,
+        replica: &mut IndexedTree<R, G, I>,
+        message: NodeMessage<R>,
+    ) -> Result<Option<NodeMessage<R>>, Error>
+    where
+        G: SeedableGenerator,
+        I: TreeIndex<R>,
+        R::Data: Clone,
+        NodeRefId<R>: Into<u64>,
+    {
+        match message {
+            SyncMessage::Converged { .. } => return Ok(None),
+            SyncMessage::Patch { operations, ids } => {
+                apply_operations(replica, operations)?;
+                renumber(replica, &ids)?;
+                reseed(replica)?;
+            }
+            SyncMessage::Snapshot {
+                hash_context,
+                records,
+            } => {
+                let mut tree: Tree<R, G> = load_records(records, hash_context)?;
+                std::mem::swap(&mut tree.event_listeners, &mut replica.tree.event_listeners);
+                std::mem::swap(
+                    &mut tree.next_listener_id,
+                    &mut replica.tree.next_listener_id,
+                );
+                *replica = IndexedTree::from_tree(tree);
+            }
+            SyncMessage::Hello { .. } | SyncMessage::Ack { .. } => {
+                return Err(Error::Sync("unexpected message from server".into()))
+            }
+        }
+
+        Ok(Some(SyncMessage::Ack {
+            root_hash: root_hash(&replica.tree),
+        }))
+    }
+}
+
+/// Decode patch operations against `tree` and apply them. The diff may produce operations
+/// which fail once earlier operations replaced their node, and are skipped, so the server checks
+/// its copy of the replica converges when patched the same way.
+fn apply_operations<R, G, I>(
+    tree: &mut IndexedTree<R, G, I>,
+    operations: Vec<WireOperation<NodeRefId<R>, <R::Inner as TreeNode>::Data>>,
+) -> Result<(), Error>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    R::Data: Clone,
+    G: SeedableGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+    NodeRefId<R>: Into<u64>,
+{
+    let context = tree.hash_context();
+    let operations = operations
+        .into_iter()
+        .map(|operation| decode_operation(tree, context, operation))
+        .collect::<Result<Vec<_>, Error>>()?;
+    TreePatch::new(operations).patch_tree(tree);
+    Ok(())
+}
+
+/// Find the nodes of `replica` with another ID than the node at the same position of `tree`
+/// in depth first order. Returns `None` if the trees differ in shape.
+fn renumbered<R, G>(
+    replica: &IndexedTree<R, G>,
+    tree: &Tree<R, G>,
+) -> Option<Vec<(usize, NodeRefId<R>)>>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+{
+    let replica_ids: Vec<_> = replica.root().into_iter().map(|n| n.node().id()).collect();
+    let ids: Vec<_> = tree.root().into_iter().map(|n| n.node().id()).collect();
+    if replica_ids.len() != ids.len() {
+        return None;
+    }
+
+    Some(
+        replica_ids
+            .into_iter()
+            .zip(ids)
+            .enumerate()
+            .filter(|(_, (replica_id, id))| replica_id != id)
+            .map(|(position, (_, id))| (position, id))
+            .collect(),
+    )
+}
+
+/// Assign IDs to the nodes of `tree` at the provided positions in depth first order, and
+/// rebuild its indexes
+fn renumber<R, G, I>(
+    tree: &mut IndexedTree<R, G, I>,
+    ids: &[(usize, NodeRefId<R>)],
+) -> Result<(), Error>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+{
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    let nodes: Vec<R> = tree.try_root()?.into_iter().map(|n| (*n).clone()).collect();
+    for &(position, id) in ids {
+        let mut node = nodes
+            .get(position)
+            .cloned()
+            .ok_or_else(|| Error::Sync(format!("no node at position {position}")))?;
+        node.node_mut().set_id(id);
+    }
+
+    tree.reindex();
+    Ok(())
+}
+
+/// Seed the ID generator of a replica past the IDs it was sent, so nodes inserted by later
+/// patches don't take the ID of another node before they are renumbered
+fn reseed<R, G, I>(replica: &mut IndexedTree<R, G, I>) -> Result<(), Error>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: SeedableGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+    NodeRefId<R>: Into<u64>,
+{
+    let largest = replica.index().get_ids().into_iter().map(Into::into).max();
+    let seed = match largest {
+        Some(id) => id
+            .checked_add(1)
+            .ok_or_else(|| Error::Sync("largest node ID leaves no IDs for new nodes".into()))?,
+        None => 0,
+    };
+
+    replica.tree.node_id_generator = Some(G::from_seed(seed));
+    Ok(())
+}
+
+/// Encode a patch operation, with the subtrees of source nodes
+fn encode_operation<R>(
+    operation: &TreePatchOperation<R>,
+) -> WireOperation<NodeRefId<R>, <R::Inner as TreeNode>::Data>
+where
+    R: TreeNodeRef,
+{
+    let id = |node: &R| node.node().id();
+    let subtree = |node: &R| flat_records(Some(node.clone())).collect();
+
+    match operation {
+        TreePatchOperation::InsertChild {
+            dest,
+            index,
+            source,
+        } => WireOperation::InsertChild {
+            dest: id(dest),
+            index: *index,
+            source: subtree(source),
+        },
+        TreePatchOperation::DeleteChild { dest, index } => WireOperation::DeleteChild {
+            dest: id(dest),
+            index: *index,
+        },
+        TreePatchOperation::ReplaceChild {
+            dest,
+            index,
+            source,
+        } => WireOperation::ReplaceChild {
+            dest: id(dest),
+            index: *index,
+            source: subtree(source),
+        },
+        TreePatchOperation::RemoveChildren { dest } => {
+            WireOperation::RemoveChildren { dest: id(dest) }
+        }
+        TreePatchOperation::SetChildren { dest, nodes } => WireOperation::SetChildren {
+            dest: id(dest),
+            nodes: nodes.iter().map(subtree).collect(),
+        },
+        TreePatchOperation::ReplaceNode { dest, source } => {
+            let data = source.node().data().clone();
+            WireOperation::ReplaceNode {
+                dest: id(dest),
+                data,
+            }
+        }
+    }
+}
+
+/// Decode a patch operation, resolving destination nodes in `replica`
+fn decode_operation<R, G, I>(
+    replica: &IndexedTree<R, G, I>,
+    context: u64,
+    operation: WireOperation<NodeRefId<R>, <R::Inner as TreeNode>::Data>,
+) -> Result<TreePatchOperation<R>, Error>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: SeedableGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+    NodeRefId<R>: Into<u64>,
+{
+    let node = |id| replica.get_node(&id).cloned().ok_or(Error::NodeNotFound);
+    let subtree = |records| load_records::<R, G>(records, context).and_then(|tree| tree.try_root());
+
+    Ok(match operation {
+        WireOperation::InsertChild {
+            dest,
+            index,
+            source,
+        } => TreePatchOperation::InsertChild {
+            dest: node(dest)?,
+            index,
+            source: subtree(source)?,
+        },
+        WireOperation::DeleteChild { dest, index } => TreePatchOperation::DeleteChild {
+            dest: node(dest)?,
+            index,
+        },
+        WireOperation::ReplaceChild {
+            dest,
+            index,
+            source,
+        } => TreePatchOperation::ReplaceChild {
+            dest: node(dest)?,
+            index,
+            source: subtree(source)?,
+        },
+        WireOperation::RemoveChildren { dest } => {
+            TreePatchOperation::RemoveChildren { dest: node(dest)? }
+        }
+        WireOperation::SetChildren { dest, nodes } => TreePatchOperation::SetChildren {
+            dest: node(dest)?,
+            nodes: nodes
+                .into_iter()
+                .map(subtree)
+                .collect::<Result<Vec<_>, Error>>()?,
+        },
+        WireOperation::ReplaceNode { dest, data } => TreePatchOperation::ReplaceNode {
+            source: R::new_in(R::Inner::new(dest, data, None), replica.allocator()),
+            dest: node(dest)?,
+        },
+    })
+}
+
+/// Build a tree from records sent by the server, hashed with the provided context
+fn load_records<R, G>(
+    records: Vec<FlatRecord<NodeRefId<R>, <R::Inner as TreeNode>::Data>>,
+    context: u64,
+) -> Result<Tree<R, G>, Error>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: SeedableGenerator<Output = NodeRefId<R>> + 'static,
+    NodeRefId<R>: Into<u64>,
+{
+    let mut tree = Tree::from_flat_records(records).map_err(|e| Error::Sync(e.to_string()))?;
+    tree.hash_context = context;
+    if let (Some(root), true) = (tree.root.clone(), context != 0) {
+        rehash_subtree(root, context);
+    }
+    Ok(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        noderef::arc::NodeRef, Error, IndexedTree, NodeId, TreeNode as _, TreeNodeRef as _,
+    };
+
+    use super::{SyncMessage, SyncSession, MAX_PAYLOAD_LEN, SYNC_PROTOCOL_VERSION};
+
+    type StringTree = IndexedTree<NodeRef<crate::node::arc::Node<String, crate::NodeId>>>;
+    type Message = SyncMessage<NodeId, String>;
+
+    /// Run a session until the replica has converged, returning the messages sent by the server
+    fn converge(
+        server: &mut SyncSession<NodeRef<crate::node::arc::Node<String, crate::NodeId>>>,
+        tree: &StringTree,
+        replica: &mut StringTree,
+    ) -> Vec<Message> {
+        let mut client = SyncSession::new();
+        let mut sent = Vec::new();
+
+        let mut message = client.hello(replica);
+        loop {
+            // Every message crosses the wire
+            let (decoded, _) = Message::decode(&message.encode().unwrap())
+                .unwrap()
+                .unwrap();
+            let reply = server.respond(tree, decoded).unwrap();
+            sent.push(reply.clone());
+
+            match client.apply(replica, reply).unwrap() {
+                Some(next) => message = next,
+                None => return sent,
+            }
+        }
+    }
+
+    #[traced_test]
+    #[test]
+    fn session() {
+        let mut tree =
+            StringTree::from_tree(crate::Tree::from_sexpr("(root (a 1 2) (b 3) c)").unwrap());
+        tree.set_hash_context("context");
+
+        let mut server = SyncSession::new();
+        let mut replica = StringTree::new();
+
+        // A fresh replica is sent a snapshot
+        let sent = converge(&mut server, &tree, &mut replica);
+        assert!(matches!(
+            sent.as_slice(),
+            [SyncMessage::Snapshot { .. }, SyncMessage::Converged { .. }]
+        ));
+        assert_eq!(replica.to_sexpr(), "(root (a 1 2) (b 3) c)");
+        assert_eq!(replica.root().node().id(), tree.root().node().id());
+        assert_eq!(replica.hash_context(), tree.hash_context());
+        assert_eq!(replica.validate(), Ok(()));
+
+        // Later changes are sent as patches
+        let b = tree.find(|data| data == "b").unwrap().node().id();
+        tree.append_child(b, "4".to_string()).unwrap();
+        let c = tree.find(|data| data == "c").unwrap().node().id();
+        tree.set_data(c, "d".to_string()).unwrap();
+
+        let sent = converge(&mut server, &tree, &mut replica);
+        assert!(matches!(
+            sent.as_slice(),
+            [SyncMessage::Patch { .. }, SyncMessage::Converged { .. }]
+        ));
+        assert_eq!(replica.to_sexpr(), "(root (a 1 2) (b 3 4) d)");
+        assert!(replica.tree() == tree.tree());
+        assert_eq!(replica.validate(), Ok(()));
+
+        // A converged replica is told so
+        let sent = converge(&mut server, &tree, &mut replica);
+        assert!(matches!(sent.as_slice(), [SyncMessage::Converged { .. }]));
+
+        assert!(matches!(
+            SyncSession::new().apply(&mut replica, Message::Ack { root_hash: None }),
+            Err(Error::Sync(_))
+        ));
+    }
+
+    #[traced_test]
+    #[test]
+    fn root_changes() {
+        let mut tree = StringTree::from_tree(crate::Tree::from_sexpr("a").unwrap());
+        let mut server = SyncSession::new();
+        let mut replica = StringTree::new();
+        converge(&mut server, &tree, &mut replica);
+
+        // A change to the root itself replaces the replica
+        let root = tree.root().node().id();
+        tree.set_data(root, "b".to_string()).unwrap();
+        let sent = converge(&mut server, &tree, &mut replica);
+        assert!(matches!(
+            sent.as_slice(),
+            [SyncMessage::Snapshot { .. }, SyncMessage::Converged { .. }]
+        ));
+        assert_eq!(replica.to_sexpr(), "b");
+
+        tree.append_child(root, "1".to_string()).unwrap();
+        converge(&mut server, &tree, &mut replica);
+        tree.set_data(root, "c".to_string()).unwrap();
+        let sent = converge(&mut server, &tree, &mut replica);
+        assert!(matches!(
+            sent.as_slice(),
+            [SyncMessage::Snapshot { .. }, SyncMessage::Converged { .. }]
+        ));
+        assert_eq!(replica.to_sexpr(), "(c 1)");
+        assert!(replica.tree() == tree.tree());
+    }
+
+    #[traced_test]
+    #[test]
+    fn empty() {
+        let mut tree = StringTree::new();
+        let mut server = SyncSession::new();
+        let mut replica = StringTree::new();
+
+        // An empty tree converges
+        let sent = converge(&mut server, &tree, &mut replica);
+        assert!(matches!(
+            sent.as_slice(),
+            [
+                SyncMessage::Snapshot { .. },
+                SyncMessage::Converged { root_hash: None }
+            ]
+        ));
+        let sent = converge(&mut server, &tree, &mut replica);
+        assert!(matches!(
+            sent.as_slice(),
+            [SyncMessage::Converged { root_hash: None }]
+        ));
+
+        // Filling and emptying the tree replaces the replica
+        tree = StringTree::from_tree(crate::Tree::from_sexpr("(a 1)").unwrap());
+        let sent = converge(&mut server, &tree, &mut replica);
+        assert!(matches!(
+            sent.as_slice(),
+            [SyncMessage::Snapshot { .. }, SyncMessage::Converged { .. }]
+        ));
+        assert_eq!(replica.to_sexpr(), "(a 1)");
+
+        let root = tree.root();
+        tree.detach(&root).unwrap();
+        let sent = converge(&mut server, &tree, &mut replica);
+        assert!(matches!(
+            sent.as_slice(),
+            [
+                SyncMessage::Snapshot { .. },
+                SyncMessage::Converged { root_hash: None }
+            ]
+        ));
+        assert!(replica.is_empty());
+        assert_eq!(replica.validate(), Ok(()));
+    }
+
+    /// IDs of the nodes of a tree in depth first order
+    fn ids(tree: &StringTree) -> Vec<NodeId> {
+        tree.root()
+            .into_iter()
+            .map(|node| node.node().id())
+            .collect()
+    }
+
+    #[traced_test]
+    #[test]
+    fn churn() {
+        let mut tree =
+            StringTree::from_tree(crate::Tree::from_sexpr("(root (a 1 2) b c)").unwrap());
+        let mut server = SyncSession::new();
+        let mut replica = StringTree::new();
+        converge(&mut server, &tree, &mut replica);
+
+        // Remove a node, and insert one with the same data, which leaves the hashes unchanged
+        let root = tree.root().node().id();
+        tree.remove_child(root, 2).unwrap();
+        let c = tree
+            .append_child(root, "c".to_string())
+            .unwrap()
+            .node()
+            .id();
+
+        let sent = converge(&mut server, &tree, &mut replica);
+        assert!(matches!(
+            sent.as_slice(),
+            [SyncMessage::Patch { operations, .. }, SyncMessage::Converged { .. }]
+                if operations.is_empty()
+        ));
+        assert_eq!(ids(&replica), ids(&tree));
+
+        // Remove a node, and insert a new one in its place
+        tree.remove_child(root, 0).unwrap();
+        let d = tree
+            .prepend_child(root, "d".to_string())
+            .unwrap()
+            .node()
+            .id();
+        converge(&mut server, &tree, &mut replica);
+        assert_eq!(replica.to_sexpr(), "(root d b c)");
+        assert_eq!(ids(&replica), ids(&tree));
+
+        // Patch under the newly inserted nodes
+        tree.append_child(c, "3".to_string()).unwrap();
+        tree.append_child(d, "4".to_string()).unwrap();
+        let sent = converge(&mut server, &tree, &mut replica);
+        assert!(matches!(
+            sent.as_slice(),
+            [SyncMessage::Patch { .. }, SyncMessage::Converged { .. }]
+        ));
+        assert_eq!(replica.to_sexpr(), "(root (d 4) b (c 3))");
+        assert_eq!(ids(&replica), ids(&tree));
+        assert_eq!(replica.validate(), Ok(()));
+
+        // Nodes inserted into the replica don't take the IDs it was sent
+        let e = replica
+            .append_child(root, "e".to_string())
+            .unwrap()
+            .node()
+            .id();
+        assert!(!ids(&tree).contains(&e));
+    }
+
+    #[traced_test]
+    #[test]
+    fn framing() {
+        let message = Message::Ack {
+            root_hash: Some(42),
+        };
+        let mut frame = message.encode().unwrap();
+
+        // Partial frames decode once complete
+        assert_eq!(Message::decode(&frame[..3]).unwrap(), None);
+        assert_eq!(Message::decode(&frame[..frame.len() - 1]).unwrap(), None);
+
+        let mut stream = frame.clone();
+        stream.extend_from_slice(&frame);
+        assert_eq!(
+            Message::decode(&stream).unwrap(),
+            Some((message.clone(), frame.len()))
+        );
+
+        let mut reader = stream.as_slice();
+        assert_eq!(Message::read_from(&mut reader).unwrap(), message);
+        assert_eq!(Message::read_from(&mut reader).unwrap(), message);
+        assert!(Message::read_from(&mut reader).is_err());
+
+        // Oversized payloads are rejected from the header alone
+        let header = [SYNC_PROTOCOL_VERSION, 0xff, 0xff, 0xff, 0xff];
+        let oversized = Some(Error::Sync(format!(
+            "payload of {} bytes exceeds the maximum of {MAX_PAYLOAD_LEN}",
+            u32::MAX
+        )));
+        assert_eq!(Message::decode(&header).err(), oversized);
+        assert!(Message::read_from(&mut header.as_slice()).is_err());
+
+        frame[0] = 2;
+        assert_eq!(
+            Message::decode(&frame).err(),
+            Some(Error::Sync("unsupported protocol version 2".into()))
+        );
+    }
+}
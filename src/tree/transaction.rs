@@ -0,0 +1,539 @@
+//! Transactional edits of an [`IndexedTree`].
+//!
+//! Mutations made through a [`TreeTransaction`] are buffered, and only applied to the tree once
+//! the transaction closure succeeds. If the closure fails, or any buffered operation cannot be
+//! applied, the tree is left unchanged.
+
+use std::collections::HashMap;
+
+use tracing::debug;
+
+use crate::{
+    hash::rehash_subtree,
+    index::{BTreeIndex, TreeIndex},
+    node::{internal::NodeInternal as _, TreeNode},
+    noderef::{NodeRefId, TreeNodeRef},
+    Error, TreeEvent, UniqueGenerator,
+};
+
+use super::{IndexedTree, Tree};
+
+type NodeData<R> = <<R as TreeNodeRef>::Inner as TreeNode>::Data;
+
+/// Buffered operation of a transaction
+enum TxOp<R>
+where
+    R: TreeNodeRef,
+{
+    Insert {
+        id: NodeRefId<R>,
+        parent: NodeRefId<R>,
+        index: usize,
+        data: NodeData<R>,
+    },
+    Remove {
+        node: NodeRefId<R>,
+    },
+    Move {
+        node: NodeRefId<R>,
+        parent: NodeRefId<R>,
+        index: usize,
+    },
+    SetData {
+        node: NodeRefId<R>,
+        data: NodeData<R>,
+    },
+}
+
+/// Record of an applied operation, used to roll it back
+enum Undo<R>
+where
+    R: TreeNodeRef,
+{
+    Inserted {
+        node: R,
+        parent: R,
+        index: usize,
+    },
+    Removed {
+        node: R,
+        parent: R,
+        index: usize,
+    },
+    Moved {
+        node: R,
+        old_parent: R,
+        old_index: usize,
+        new_parent: R,
+        new_index: usize,
+    },
+    DataSet {
+        node: R,
+        data: NodeData<R>,
+    },
+}
+
+/// Mutations of an [`IndexedTree`] buffered by [`IndexedTree::transaction`]
+pub struct TreeTransaction<'a, R, G = crate::IdGenerator, I = BTreeIndex<R>>
+where
+    R: TreeNodeRef + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+{
+    tree: &'a IndexedTree<R, G, I>,
+    ops: Vec<TxOp<R>>,
+}
+
+impl<R, G, I> TreeTransaction<'_, R, G, I>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+{
+    /// Get the tree as it was before the transaction. Buffered operations are not visible.
+    pub fn tree(&self) -> &IndexedTree<R, G, I> {
+        self.tree
+    }
+
+    /// Number of buffered operations
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Insert a new node with the provided data as the child `index` of `parent`. The ID of the
+    /// new node is allocated immediately, so it can be referenced by later operations.
+    pub fn insert_child(
+        &mut self,
+        parent: NodeRefId<R>,
+        index: usize,
+        data: NodeData<R>,
+    ) -> Result<NodeRefId<R>, Error> {
+        let id = self.tree.try_generate_id()?;
+        self.ops.push(TxOp::Insert {
+            id,
+            parent,
+            index,
+            data,
+        });
+        Ok(id)
+    }
+
+    /// Remove a node and its subtree
+    pub fn remove_node(&mut self, node: NodeRefId<R>) {
+        self.ops.push(TxOp::Remove { node });
+    }
+
+    /// Move a node and its subtree to the child `index` of `parent`. See [`Tree::move_node`].
+    pub fn move_node(&mut self, node: NodeRefId<R>, parent: NodeRefId<R>, index: usize) {
+        self.ops.push(TxOp::Move {
+            node,
+            parent,
+            index,
+        });
+    }
+
+    /// Replace the data of a node
+    pub fn set_data(&mut self, node: NodeRefId<R>, data: NodeData<R>) {
+        self.ops.push(TxOp::SetData { node, data });
+    }
+}
+
+impl<R, G, I> IndexedTree<R, G, I>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+{
+    /// Run `f` with a [`TreeTransaction`] buffering mutations of the tree.
+    ///
+    /// When `f` succeeds, the buffered operations are applied in order, followed by a single
+    /// rehash and reindex of the tree, and the events of every operation are then sent to the
+    /// listeners. If `f` returns an error, or an operation fails to apply, the tree is left
+    /// unchanged and no events are sent.
+    ///
+    /// Inserted nodes are reported with [`TreeEvent::ChildrenAdded`], which carries the inserted
+    /// node, since its index may be shifted by later operations before the event is sent.
+    pub fn transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
+    where
+        E: From<Error>,
+        F: FnOnce(&mut TreeTransaction<'_, R, G, I>) -> Result<T, E>,
+    {
+        let mut tx = TreeTransaction {
+            tree: self,
+            ops: Vec::new(),
+        };
+
+        let value = f(&mut tx)?;
+        let ops = tx.ops;
+
+        debug!("Applying transaction of {} operations", ops.len());
+
+        let mut applied = Applied {
+            created: HashMap::new(),
+            undo: Vec::new(),
+            events: Vec::new(),
+            removed: Vec::new(),
+        };
+
+        for op in ops {
+            if let Err(e) = self.apply_op(op, &mut applied) {
+                debug!("Transaction failed with {e}, rolling back");
+                for undo in applied.undo.into_iter().rev() {
+                    Self::rollback(undo);
+                }
+                return Err(e.into());
+            }
+        }
+
+        self.tree.bury(&applied.removed);
+        if let Some(root) = self.tree.root.clone() {
+            rehash_subtree(root, self.tree.hash_context);
+        }
+        self.tree.update_positions();
+        self.reindex();
+
+        for event in applied.events {
+            self.tree.send_event(event);
+        }
+
+        Ok(value)
+    }
+
+    /// Resolve a node by ID from the nodes created in the transaction, or the live nodes of the
+    /// index. Nodes removed by an earlier operation of the transaction are not found.
+    fn resolve(&self, created: &HashMap<NodeRefId<R>, R>, id: &NodeRefId<R>) -> Result<R, Error> {
+        let node = created
+            .get(id)
+            .or_else(|| self.live_node(*id))
+            .cloned()
+            .ok_or(Error::NodeNotFound)?;
+
+        let mut top = node.clone();
+        loop {
+            let parent = top.node().parent().cloned();
+            match parent {
+                Some(parent) => top = parent,
+                None => break,
+            }
+        }
+        match &self.tree.root {
+            Some(root) if root.ptr_eq(&top) => Ok(node),
+            _ => Err(Error::NodeNotFound),
+        }
+    }
+
+    fn apply_op(&mut self, op: TxOp<R>, applied: &mut Applied<R>) -> Result<(), Error> {
+        match op {
+            TxOp::Insert {
+                id,
+                parent,
+                index,
+                data,
+            } => {
+                let mut parent = self.resolve(&applied.created, &parent)?;
+                let len = parent.node().num_children();
+                if index > len {
+                    return Err(Error::IndexOutOfBounds { index, len });
+                }
+
+                let node = R::new_in(
+                    R::Inner::new(id, data, None).with_parent(parent.clone()),
+                    self.tree.allocator(),
+                );
+                parent.node_mut().insert_child(node.clone(), index);
+
+                // Reported with the inserted node, as later operations may shift its index
+                applied.created.insert(id, node.clone());
+                applied.events.push(TreeEvent::ChildrenAdded {
+                    parent: parent.clone(),
+                    children: vec![node.clone()],
+                });
+                applied.undo.push(Undo::Inserted {
+                    node,
+                    parent,
+                    index,
+                });
+            }
+            TxOp::Remove { node } => {
+                let node = self.resolve(&applied.created, &node)?;
+                let (mut parent, index) = Tree::<R, G>::child_index(&node)?;
+
+                parent.node_mut().remove_child_index(index);
+                node.clone().node_mut().take_parent();
+
+                applied.removed.push(node.clone());
+                applied
+                    .events
+                    .push(TreeEvent::NodeRemoved { node: node.clone() });
+                applied.undo.push(Undo::Removed {
+                    node,
+                    parent,
+                    index,
+                });
+            }
+            TxOp::Move {
+                node,
+                parent,
+                index,
+            } => {
+                let node = self.resolve(&applied.created, &node)?;
+                let mut new_parent = self.resolve(&applied.created, &parent)?;
+                let (mut old_parent, old_index) = Tree::<R, G>::child_index(&node)?;
+
+                if Tree::<R, G>::has_ancestor(&new_parent, node.node().id()) {
+                    return Err(Error::CyclicMove);
+                }
+
+                let same_parent = old_parent.node().id() == new_parent.node().id();
+                let len = new_parent.node().num_children() - usize::from(same_parent);
+                if index > len {
+                    return Err(Error::IndexOutOfBounds { index, len });
+                }
+
+                old_parent.node_mut().remove_child_index(old_index);
+                node.clone().node_mut().set_parent(new_parent.clone());
+                new_parent.node_mut().insert_child(node.clone(), index);
+
+                applied.events.push(TreeEvent::NodeMoved {
+                    node: node.clone(),
+                    old_parent: old_parent.clone(),
+                    new_parent: new_parent.clone(),
+                    index,
+                });
+                applied.undo.push(Undo::Moved {
+                    node,
+                    old_parent,
+                    old_index,
+                    new_parent,
+                    new_index: index,
+                });
+            }
+            TxOp::SetData { node, data } => {
+                let node = self.resolve(&applied.created, &node)?;
+                let old = std::mem::replace(&mut *node.clone().node_mut().data_mut(), data);
+
+                applied.events.push(TreeEvent::NodeReplaced {
+                    node: node.clone(),
+                    previous: old.clone(),
+                });
+                applied.undo.push(Undo::DataSet { node, data: old });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Revert an applied operation
+    fn rollback(undo: Undo<R>) {
+        match undo {
+            Undo::Inserted {
+                mut node,
+                mut parent,
+                index,
+            } => {
+                parent.node_mut().remove_child_index(index);
+                node.node_mut().take_parent();
+            }
+            Undo::Removed {
+                mut node,
+                mut parent,
+                index,
+            } => {
+                node.node_mut().set_parent(parent.clone());
+                parent.node_mut().insert_child(node, index);
+            }
+            Undo::Moved {
+                mut node,
+                mut old_parent,
+                old_index,
+                mut new_parent,
+                new_index,
+            } => {
+                new_parent.node_mut().remove_child_index(new_index);
+                node.node_mut().set_parent(old_parent.clone());
+                old_parent.node_mut().insert_child(node, old_index);
+            }
+            Undo::DataSet { mut node, data } => {
+                *node.node_mut().data_mut() = data;
+            }
+        }
+    }
+}
+
+/// State of a transaction being applied
+struct Applied<R>
+where
+    R: TreeNodeRef,
+{
+    // Nodes inserted by the transaction, which are not yet indexed
+    created: HashMap<NodeRefId<R>, R>,
+    undo: Vec<Undo<R>>,
+    events: Vec<TreeEvent<R>>,
+    removed: Vec<R>,
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        test::{test_tree_node, TestNode},
+        Error, RemovalMode, TreeEvent, TreeNode as _, TreeNodeRef as _,
+    };
+
+    #[traced_test]
+    #[test]
+    fn commit() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+        let expected = test_tree_node(vec![
+            TestNode("b", vec![TestNode("x", vec![TestNode("1", vec![])])]),
+            TestNode("c", vec![]),
+        ]);
+
+        let children = tree.root().node().children().unwrap().clone();
+        let (a, b) = (children[0].node().id(), children[1].node().id());
+        let one = children[0].node().children().unwrap()[0].node().id();
+
+        tree.transaction(|tx| {
+            let x = tx.insert_child(b, 0, "x")?;
+            tx.move_node(one, x, 0);
+            tx.remove_node(a);
+            tx.insert_child(tx.tree().root().node().id(), 1, "c")?;
+            Ok::<_, Error>(())
+        })
+        .unwrap();
+
+        assert_eq!(
+            tree.root().node().get_subtree_hash(),
+            expected.root().node().get_subtree_hash()
+        );
+        assert!(tree.get_node(&a).is_none());
+        assert_eq!(tree.root().into_iter().count(), 5);
+    }
+
+    #[traced_test]
+    #[test]
+    fn rollback() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+        let hash = tree.root().node().get_subtree_hash();
+
+        let children = tree.root().node().children().unwrap().clone();
+        let (a, b) = (children[0].node().id(), children[1].node().id());
+        let one = children[0].node().children().unwrap()[0].node().id();
+
+        // The final operation fails to apply, rolling back the earlier operations
+        let result = tree.transaction(|tx| {
+            tx.insert_child(b, 0, "x")?;
+            tx.set_data(b, "c");
+            tx.move_node(one, b, 0);
+            tx.remove_node(a);
+            tx.move_node(a, b, 5);
+            Ok::<_, Error>(())
+        });
+        assert_eq!(result, Err(Error::NodeNotFound));
+
+        // Errors from the closure discard the buffered operations
+        let result = tree.transaction(|tx| {
+            tx.remove_node(a);
+            Err::<(), _>(Error::EmptyTree)
+        });
+        assert_eq!(result, Err(Error::EmptyTree));
+
+        crate::hash::rehash_subtree(tree.root(), 0);
+        assert_eq!(tree.root().node().get_subtree_hash(), hash);
+        assert_eq!(*tree.get_node(&b).unwrap().node().data(), "b");
+        assert_eq!(tree.root().into_iter().count(), 4);
+    }
+
+    #[traced_test]
+    #[test]
+    fn events() {
+        use std::sync::{Arc, Mutex};
+
+        let mut tree = test_tree_node(vec![TestNode("a", vec![]), TestNode("b", vec![])]);
+        let root = tree.root().node().id();
+        let a = tree.root().node().children().unwrap()[0].clone();
+        let a_id = a.node().id();
+
+        let inserted = Arc::new(Mutex::new(Vec::new()));
+        let listener_inserted = inserted.clone();
+        let _listener = tree
+            .on_event(move |event| {
+                if let TreeEvent::ChildrenAdded { children, .. } = event {
+                    for child in children {
+                        listener_inserted.lock().unwrap().push(*child.node().data());
+                    }
+                }
+            })
+            .unwrap();
+
+        // Each insert reports its own node, even when later inserts shift its index
+        tree.transaction(|tx| {
+            tx.insert_child(root, 0, "x")?;
+            tx.insert_child(root, 0, "y")?;
+            tx.remove_node(a_id);
+            Ok::<_, Error>(())
+        })
+        .unwrap();
+        assert_eq!(*inserted.lock().unwrap(), vec!["x", "y"]);
+
+        // Removed nodes are unlinked from their parent
+        assert!(a.node().parent().is_none());
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn tombstoned() {
+        let mut tree = test_tree_node(vec![TestNode("a", vec![]), TestNode("b", vec![])]);
+        tree.set_removal_mode(RemovalMode::Tombstone);
+
+        let a = tree.root().node().children().unwrap()[0].clone();
+        let a_id = a.node().id();
+        tree.remove_node(&a).unwrap();
+
+        // Tombstoned nodes remain indexed, but can't be referenced by a transaction
+        let result = tree.transaction(|tx| {
+            tx.set_data(a_id, "c");
+            Ok::<_, Error>(())
+        });
+        assert_eq!(result, Err(Error::NodeNotFound));
+        assert_eq!(*a.node().data(), "a");
+
+        // Rolled back removals are relinked to their parent
+        let b = tree.root().node().children().unwrap()[0].clone();
+        let b_id = b.node().id();
+        let result = tree.transaction(|tx| {
+            tx.remove_node(b_id);
+            tx.remove_node(a_id);
+            Ok::<_, Error>(())
+        });
+        assert_eq!(result, Err(Error::NodeNotFound));
+        assert_eq!(
+            b.node().parent().unwrap().node().id(),
+            tree.root().node().id()
+        );
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn empty() {
+        let mut tree: crate::IndexedTree<
+            crate::noderef::arc::NodeRef<crate::node::arc::Node<&'static str, crate::NodeId>>,
+        > = crate::IndexedTree::new();
+
+        tree.transaction(|_| Ok::<_, Error>(())).unwrap();
+        assert!(tree.is_empty());
+    }
+}
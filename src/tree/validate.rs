@@ -0,0 +1,503 @@
+//! Structural validation and repair of an [`IndexedTree`].
+
+use std::{collections::HashSet, hash::Hasher as _};
+
+use xxhash_rust::xxh64::Xxh64;
+
+use crate::{
+    hash::rehash_subtree,
+    index::TreeIndex,
+    node::{internal::NodeInternal as _, TreeNode},
+    noderef::{NodeRefId, TreeNodeRef},
+    UniqueGenerator,
+};
+
+use super::IndexedTree;
+
+/// An invariant of an [`IndexedTree`] which does not hold, reported by [`IndexedTree::validate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Invariant<Id> {
+    /// The parent link of a node does not point to the node whose children contain it
+    ParentMismatch {
+        node: Id,
+        expected: Option<Id>,
+        found: Option<Id>,
+    },
+
+    /// The parent index entry of a node does not match the node whose children contain it
+    ParentIndex {
+        id: Id,
+        expected: Option<Id>,
+        found: Option<Id>,
+    },
+
+    /// The subtree hash index entry of a node does not match its stored subtree hash, or refers
+    /// to a node which is not reachable from the root
+    SubtreeHashIndex {
+        id: Id,
+        stored: Option<u64>,
+        indexed: Option<u64>,
+    },
+
+    /// The path index entry of a node does not match its path of child indices from the root,
+    /// or refers to a node which is not reachable from the root
+    PathIndex {
+        id: Id,
+        expected: Option<Vec<usize>>,
+        found: Option<Vec<usize>>,
+    },
+
+    /// More than one node reachable from the root has the same ID
+    DuplicateId { id: Id },
+
+    /// A node reachable from the root is missing from the index
+    NotIndexed { id: Id },
+
+    /// The index contains a node which is neither reachable from the root nor tombstoned
+    StaleIndex { id: Id },
+
+    /// The index entry for an ID refers to a node with a different ID
+    IndexMismatch { id: Id, found: Id },
+
+    /// A node without children is missing from the leaves
+    MissingLeaf { id: Id },
+
+    /// The leaves contain a node which is not a leaf reachable from the root
+    StaleLeaf { id: Id },
+
+    /// A node appears in the leaves more than once
+    DuplicateLeaf { id: Id },
+
+    /// The stored subtree hash of a node does not match the hash computed from its children
+    SubtreeHash { id: Id, stored: u64, computed: u64 },
+
+    /// The stored subtree size of a node does not match the size computed from its children
+    SubtreeSize {
+        id: Id,
+        stored: usize,
+        computed: usize,
+    },
+}
+
+impl<Id> std::fmt::Display for Invariant<Id>
+where
+    Id: std::fmt::Display,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let id = |id: &Option<Id>| match id {
+            Some(id) => id.to_string(),
+            None => "none".to_string(),
+        };
+
+        match self {
+            Invariant::ParentMismatch {
+                node,
+                expected,
+                found,
+            } => write!(
+                f,
+                "node {node} has parent {}, expected {}",
+                id(found),
+                id(expected)
+            ),
+            Invariant::ParentIndex {
+                id: node,
+                expected,
+                found,
+            } => write!(
+                f,
+                "parent index has parent {} for node {node}, expected {}",
+                id(found),
+                id(expected)
+            ),
+            Invariant::SubtreeHashIndex {
+                id: node,
+                stored,
+                indexed,
+            } => {
+                let hash = |hash: &Option<u64>| match hash {
+                    Some(hash) => format!("0x{hash:X}"),
+                    None => "none".to_string(),
+                };
+                write!(
+                    f,
+                    "subtree hash index has {} for node {node}, expected {}",
+                    hash(indexed),
+                    hash(stored)
+                )
+            }
+            Invariant::PathIndex {
+                id: node,
+                expected,
+                found,
+            } => write!(
+                f,
+                "path index has path {found:?} for node {node}, expected {expected:?}"
+            ),
+            Invariant::DuplicateId { id } => write!(f, "duplicate node id {id}"),
+            Invariant::NotIndexed { id } => write!(f, "node {id} is not indexed"),
+            Invariant::StaleIndex { id } => write!(f, "index contains unreachable node {id}"),
+            Invariant::IndexMismatch { id, found } => {
+                write!(f, "index entry {id} refers to node {found}")
+            }
+            Invariant::MissingLeaf { id } => write!(f, "leaf {id} is missing from the leaves"),
+            Invariant::StaleLeaf { id } => write!(f, "leaves contain non-leaf node {id}"),
+            Invariant::DuplicateLeaf { id } => write!(f, "leaf {id} appears more than once"),
+            Invariant::SubtreeHash {
+                id,
+                stored,
+                computed,
+            } => write!(
+                f,
+                "node {id} has subtree hash 0x{stored:X}, computed 0x{computed:X}"
+            ),
+            Invariant::SubtreeSize {
+                id,
+                stored,
+                computed,
+            } => write!(
+                f,
+                "node {id} has subtree size {stored}, computed {computed}"
+            ),
+        }
+    }
+}
+
+impl<R, G, I> IndexedTree<R, G, I>
+where
+    R: TreeNodeRef + std::fmt::Debug + 'static,
+    G: UniqueGenerator<Output = NodeRefId<R>> + 'static,
+    I: TreeIndex<R>,
+{
+    /// Check the internal consistency of the tree, returning every invariant which does not hold.
+    ///
+    /// The children of each node are taken as the ground truth. Parent links, ID uniqueness,
+    /// the index and the leaves are checked against them, and the stored subtree hash and size of
+    /// each node are checked against the values stored in its children.
+    pub fn validate(&self) -> Result<(), Vec<Invariant<NodeRefId<R>>>> {
+        let mut violations = Vec::new();
+        let mut reachable = HashSet::new();
+        let mut leaves = Vec::new();
+
+        let mut stack: Vec<(R, Option<NodeRefId<R>>, Vec<usize>)> = self
+            .tree
+            .root
+            .iter()
+            .map(|root| (root.clone(), None, Vec::new()))
+            .collect();
+
+        while let Some((node, expected, path)) = stack.pop() {
+            let (id, found, children, stored_hash, stored_size) = {
+                let inner = node.node();
+                (
+                    inner.id(),
+                    inner.parent().map(|parent| parent.node().id()),
+                    inner.children().map(|c| c.to_vec()).unwrap_or_default(),
+                    inner.get_subtree_hash(),
+                    inner.subtree_size(),
+                )
+            };
+
+            // Don't descend into a shared subtree more than once
+            if !reachable.insert(id) {
+                violations.push(Invariant::DuplicateId { id });
+                continue;
+            }
+
+            if found != expected {
+                violations.push(Invariant::ParentMismatch {
+                    node: id,
+                    expected,
+                    found,
+                });
+            }
+
+            let indexed_parent = self.parents.get(&id).copied();
+            if indexed_parent != expected {
+                violations.push(Invariant::ParentIndex {
+                    id,
+                    expected,
+                    found: indexed_parent,
+                });
+            }
+
+            if let Some(paths) = &self.paths {
+                let found = paths.path_of(&id);
+                if found != Some(path.as_slice()) {
+                    violations.push(Invariant::PathIndex {
+                        id,
+                        expected: Some(path.clone()),
+                        found: found.map(<[usize]>::to_vec),
+                    });
+                }
+            }
+
+            match self.index.get(&id).map(|indexed| indexed.node().id()) {
+                None => violations.push(Invariant::NotIndexed { id }),
+                Some(found) if found != id => {
+                    violations.push(Invariant::IndexMismatch { id, found })
+                }
+                Some(_) => {}
+            }
+
+            if children.is_empty() {
+                leaves.push(id);
+            }
+
+            let mut hasher = Xxh64::new(self.tree.hash_context);
+            let mut computed_size = 1;
+            for child in &children {
+                let child = child.node();
+                hasher.write_u64(child.get_subtree_hash());
+                computed_size += child.subtree_size();
+            }
+            node.hash(&mut hasher);
+
+            if let Some(hashes) = &self.subtree_hashes {
+                let indexed = hashes.hash_of(&id);
+                if indexed != Some(stored_hash) {
+                    violations.push(Invariant::SubtreeHashIndex {
+                        id,
+                        stored: Some(stored_hash),
+                        indexed,
+                    });
+                }
+            }
+
+            let computed = hasher.finish();
+            if computed != stored_hash {
+                violations.push(Invariant::SubtreeHash {
+                    id,
+                    stored: stored_hash,
+                    computed,
+                });
+            }
+
+            if computed_size != stored_size {
+                violations.push(Invariant::SubtreeSize {
+                    id,
+                    stored: stored_size,
+                    computed: computed_size,
+                });
+            }
+
+            stack.extend(
+                children
+                    .into_iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(index, child)| {
+                        let mut path = path.clone();
+                        path.push(index);
+                        (child, Some(id), path)
+                    }),
+            );
+        }
+
+        // Tombstoned nodes remain in the index until they are collected
+        for id in self.index.get_ids() {
+            if !reachable.contains(&id)
+                && !self
+                    .index
+                    .get(&id)
+                    .is_some_and(|node| node.node().is_tombstoned())
+            {
+                violations.push(Invariant::StaleIndex { id });
+            }
+        }
+
+        for (&id, &parent) in &self.parents {
+            if !reachable.contains(&id) {
+                violations.push(Invariant::ParentIndex {
+                    id,
+                    expected: None,
+                    found: Some(parent),
+                });
+            }
+        }
+
+        for (&id, &hash) in self.subtree_hashes.iter().flat_map(|hashes| hashes.iter()) {
+            if !reachable.contains(&id) {
+                violations.push(Invariant::SubtreeHashIndex {
+                    id,
+                    stored: None,
+                    indexed: Some(hash),
+                });
+            }
+        }
+
+        for (&id, path) in self.paths.iter().flat_map(|paths| paths.iter()) {
+            if !reachable.contains(&id) {
+                violations.push(Invariant::PathIndex {
+                    id,
+                    expected: None,
+                    found: Some(path.clone()),
+                });
+            }
+        }
+
+        let leaf_ids: HashSet<_> = leaves.iter().copied().collect();
+        let mut listed = HashSet::new();
+        for leaf in &self.leaves {
+            let id = leaf.node().id();
+            if !listed.insert(id) {
+                violations.push(Invariant::DuplicateLeaf { id });
+            } else if !leaf_ids.contains(&id) {
+                violations.push(Invariant::StaleLeaf { id });
+            }
+        }
+
+        for id in leaves {
+            if !listed.contains(&id) {
+                violations.push(Invariant::MissingLeaf { id });
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Rebuild the parent links, positions, subtree hashes and sizes, the index and the leaves
+    /// from the children of each node, bringing the tree back in sync after external mutation
+    /// of the nodes.
+    pub fn repair(&mut self) {
+        let Some(mut root) = self.tree.root.clone() else {
+            self.reindex();
+            return;
+        };
+
+        root.node_mut().take_parent();
+
+        let mut stack = Vec::from([root.clone()]);
+        while let Some(parent) = stack.pop() {
+            let children = parent
+                .node()
+                .children()
+                .map(|children| children.to_vec())
+                .unwrap_or_default();
+
+            for mut child in children {
+                child.node_mut().set_parent(parent.clone());
+                stack.push(child);
+            }
+        }
+
+        rehash_subtree(root, self.tree.hash_context);
+        self.tree.update_positions();
+        self.reindex();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::{
+        test::{test_tree_node, TestNode},
+        TreeNode as _, TreeNodeRef as _,
+    };
+
+    use super::Invariant;
+
+    #[traced_test]
+    #[test]
+    fn valid() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![]), TestNode("2", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+        assert_eq!(tree.validate(), Ok(()));
+
+        let children = tree.root().node().children().unwrap().clone();
+        let (a, b) = (children[0].clone(), children[1].clone());
+
+        let b_id = b.node().id();
+        tree.insert_child(b_id, 0, "c").unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+
+        let one = a.node().children().unwrap()[0].clone();
+        tree.remove_node(&one).unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.retain(|data| *data != "2");
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.detach(&b).unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[traced_test]
+    #[test]
+    fn repair() {
+        let mut tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+
+        let mut root = tree.root();
+        let children = root.node().children().unwrap().clone();
+        let (mut a, b) = (children[0].clone(), children[1].clone());
+
+        root.node_mut().children_mut().unwrap().pop();
+        a.node_mut().push_child(b.clone());
+        *a.node_mut().data_mut() = "x";
+        assert!(tree.validate().is_err());
+
+        tree.repair();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(b.node().parent().unwrap().node().id(), a.node().id());
+        assert_eq!(b.node().get_position().unwrap().depth, 2);
+        assert_eq!(root.node().subtree_size(), 4);
+    }
+
+    #[traced_test]
+    #[test]
+    fn violations() {
+        let tree = test_tree_node(vec![
+            TestNode("a", vec![TestNode("1", vec![])]),
+            TestNode("b", vec![]),
+        ]);
+
+        let mut root = tree.root();
+        let children = root.node().children().unwrap().clone();
+        let (mut a, b) = (children[0].clone(), children[1].clone());
+        let one = a.node().children().unwrap()[0].clone();
+        let (root_id, a_id, b_id, one_id) = (
+            root.node().id(),
+            a.node().id(),
+            b.node().id(),
+            one.node().id(),
+        );
+
+        // Move b under a without updating the parent link, hashes, index or leaves
+        root.node_mut().children_mut().unwrap().pop();
+        a.node_mut().push_child(b.clone());
+
+        let violations = tree.validate().unwrap_err();
+
+        assert!(violations.contains(&Invariant::ParentMismatch {
+            node: b_id,
+            expected: Some(a_id),
+            found: Some(root_id),
+        }));
+        assert!(violations
+            .iter()
+            .any(|v| matches!(v, Invariant::SubtreeHash { id, .. } if *id == root_id)));
+        assert!(violations.contains(&Invariant::SubtreeSize {
+            id: a_id,
+            stored: 2,
+            computed: 3,
+        }));
+        assert!(!violations
+            .iter()
+            .any(|v| matches!(v, Invariant::SubtreeSize { id, .. } if *id == one_id)));
+        assert!(!violations.iter().any(|v| matches!(
+            v,
+            Invariant::NotIndexed { .. } | Invariant::StaleIndex { .. }
+        )));
+    }
+}